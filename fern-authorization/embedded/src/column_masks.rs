@@ -0,0 +1,684 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Column masks, enforced by rewriting statements so that values of masked
+//! columns are never sent by the server.
+//!
+//! Unlike masking of results, masked columns in projections are replaced by
+//! masking expressions, e.g. `ssn` by `NULL AS ssn`, while statements using
+//! masked columns anywhere else, e.g. in `WHERE`, `JOIN ... ON`, `GROUP BY`,
+//! `ORDER BY`, or `RETURNING` clauses, are refused, as they would allow to
+//! infer values, e.g. with `WHERE ssn LIKE '1%'`.
+//!
+//! As columns of tables are unknown to Fern, `*` and whole-row references
+//! of tables with masked columns are refused, as are `TABLE` and `COPY` of
+//! such tables, and functions which run SQL passed as a string, e.g.
+//! `query_to_xml`. Outside of projections, columns are recognized by name: in
+//! statements referencing tables with masked columns, columns named as a
+//! masked one cannot be used unless qualified by another table.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use sqlparser::ast::{
+    CopySource, Expr, FunctionArg, FunctionArgExpr, FunctionArguments, Ident, ObjectName, Query,
+    SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value, Visit, VisitMut, Visitor,
+    VisitorMut,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
+
+use fern_protocol_postgresql::codec::frontend;
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+use crate::row_filters::{parse_expr, Qualifier, SqlFunctions};
+use crate::statements::{normalize, parse, parse_table, table};
+use crate::{Denials, Table};
+
+/// Prefix of identifiers standing for masking expressions while statements
+/// are checked.
+const PLACEHOLDER_PREFIX: &str = "fern_masked_column_";
+
+/// Masked columns of a table.
+#[derive(Debug, Clone)]
+struct TableMasks {
+    table: Table,
+
+    /// Masking expressions, by column name.
+    columns: BTreeMap<String, Expr>,
+}
+
+/// Returns the masks of `masks` applying to the table `name`, if any.
+fn find<'a>(masks: &'a [TableMasks], name: &ObjectName) -> Option<&'a TableMasks> {
+    let reference = table(name);
+    masks.iter().find(|masks| masks.table.matches(&reference))
+}
+
+/// Returns the name designating rows of the table `name` with `alias`.
+fn binding(name: &ObjectName, alias: Option<&Ident>) -> Ident {
+    alias
+        .or_else(|| name.0.last())
+        .cloned()
+        .unwrap_or_else(|| Ident::new(""))
+}
+
+/// A table with masked columns of a `FROM` clause.
+struct Binding<'a> {
+    /// Name designating rows of the table, i.e. its alias or name.
+    name: Ident,
+
+    masks: &'a TableMasks,
+}
+
+/// Adds tables with masked columns of `table_factor` to `bindings`.
+fn bind<'a>(masks: &'a [TableMasks], table_factor: &TableFactor, bindings: &mut Vec<Binding<'a>>) {
+    match table_factor {
+        TableFactor::Table { name, alias, .. } => {
+            if let Some(table_masks) = find(masks, name) {
+                bindings.push(Binding {
+                    name: binding(name, alias.as_ref().map(|alias| &alias.name)),
+                    masks: table_masks,
+                });
+            }
+        }
+        TableFactor::NestedJoin {
+            table_with_joins, ..
+        } => bind_tables(masks, table_with_joins, bindings),
+        _ => {}
+    }
+}
+
+/// Adds tables with masked columns of `table_with_joins` to `bindings`.
+fn bind_tables<'a>(
+    masks: &'a [TableMasks],
+    table_with_joins: &TableWithJoins,
+    bindings: &mut Vec<Binding<'a>>,
+) {
+    bind(masks, &table_with_joins.relation, bindings);
+    for join in &table_with_joins.joins {
+        bind(masks, &join.relation, bindings);
+    }
+}
+
+/// A `VisitorMut` replacing masked columns of an expression of a projection
+/// by placeholders of their masking expressions, outside of subqueries.
+struct Masker<'a, 'b> {
+    bindings: &'b [Binding<'a>],
+
+    /// Masking expressions, by index of their placeholder.
+    masked: &'b mut Vec<Expr>,
+
+    depth: usize,
+}
+
+impl Masker<'_, '_> {
+    /// Returns the masking expression of `column`, of the table `qualifier`
+    /// or of any table of the `FROM` clause.
+    fn mask(&self, column: &Ident, qualifier: Option<&Ident>) -> Option<Expr> {
+        let column = normalize(column);
+        let binding = self.bindings.iter().find(|binding| {
+            qualifier.map_or(true, |qualifier| {
+                normalize(qualifier) == normalize(&binding.name)
+            }) && binding.masks.columns.contains_key(&column)
+        })?;
+        let mut expr = binding.masks.columns[&column].clone();
+        let _ = VisitMut::visit(&mut expr, &mut Qualifier::new(binding.name.clone()));
+        Some(expr)
+    }
+}
+
+impl VisitorMut for Masker<'_, '_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &mut Query) -> ControlFlow<()> {
+        self.depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &mut Query) -> ControlFlow<()> {
+        self.depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        if self.depth > 0 {
+            return ControlFlow::Continue(());
+        }
+        let mask = match &*expr {
+            Expr::Identifier(column) => self.mask(column, None),
+            Expr::CompoundIdentifier(parts) if parts.len() > 1 => {
+                self.mask(&parts[parts.len() - 1], Some(&parts[parts.len() - 2]))
+            }
+            _ => None,
+        };
+        if let Some(mask) = mask {
+            let placeholder = format!("{}{}", PLACEHOLDER_PREFIX, self.masked.len());
+            self.masked.push(mask);
+            *expr = Expr::Identifier(Ident::new(placeholder));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// A `VisitorMut` masking projections of queries, breaking with the reason
+/// why a statement cannot be rewritten.
+struct Rewriter<'a> {
+    masks: &'a [TableMasks],
+
+    /// Masking expressions, by index of their placeholder.
+    masked: Vec<Expr>,
+}
+
+impl Rewriter<'_> {
+    /// Masks projections of `set_expr`.
+    fn mask_set_expr(&mut self, set_expr: &mut SetExpr) -> ControlFlow<String> {
+        match set_expr {
+            SetExpr::Select(select) => {
+                let mut bindings = vec![];
+                for table_with_joins in &select.from {
+                    bind_tables(self.masks, table_with_joins, &mut bindings);
+                }
+                if bindings.is_empty() {
+                    return ControlFlow::Continue(());
+                }
+                for item in &mut select.projection {
+                    match item {
+                        SelectItem::Wildcard(_) => {
+                            let table = &bindings[0].masks.table;
+                            return ControlFlow::Break(format!(
+                                "cannot expand * of table {} with masked columns",
+                                table
+                            ));
+                        }
+                        SelectItem::QualifiedWildcard(name, _) => {
+                            let qualifier = name.0.last().map(normalize);
+                            let masked = bindings
+                                .iter()
+                                .find(|binding| Some(normalize(&binding.name)) == qualifier);
+                            if let Some(binding) = masked {
+                                return ControlFlow::Break(format!(
+                                    "cannot expand * of table {} with masked columns",
+                                    binding.masks.table
+                                ));
+                            }
+                        }
+                        SelectItem::UnnamedExpr(expr) => {
+                            // Masked columns keep their name.
+                            let name = match expr {
+                                Expr::Identifier(column) => Some(column.clone()),
+                                Expr::CompoundIdentifier(parts) => parts.last().cloned(),
+                                _ => None,
+                            };
+                            let count = self.masked.len();
+                            self.mask_expr(&bindings, expr);
+                            if let (true, Some(alias)) = (self.masked.len() > count, name) {
+                                let expr = expr.clone();
+                                *item = SelectItem::ExprWithAlias { expr, alias };
+                            }
+                        }
+                        SelectItem::ExprWithAlias { expr, .. } => self.mask_expr(&bindings, expr),
+                    }
+                }
+                ControlFlow::Continue(())
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                self.mask_set_expr(left)?;
+                self.mask_set_expr(right)
+            }
+            SetExpr::Table(table) => {
+                let name = ObjectName(
+                    [&table.schema_name, &table.table_name]
+                        .iter()
+                        .filter_map(|part| part.as_deref().map(Ident::new))
+                        .collect(),
+                );
+                match find(self.masks, &name) {
+                    Some(masks) => {
+                        ControlFlow::Break(format!("cannot mask columns of TABLE {}", masks.table))
+                    }
+                    None => ControlFlow::Continue(()),
+                }
+            }
+            // Subqueries are rewritten on their own.
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    /// Masks columns of `bindings` in `expr`.
+    fn mask_expr(&mut self, bindings: &[Binding<'_>], expr: &mut Expr) {
+        let _ = VisitMut::visit(
+            expr,
+            &mut Masker {
+                bindings,
+                masked: &mut self.masked,
+                depth: 0,
+            },
+        );
+    }
+}
+
+impl VisitorMut for Rewriter<'_> {
+    type Break = String;
+
+    fn post_visit_query(&mut self, query: &mut Query) -> ControlFlow<String> {
+        self.mask_set_expr(&mut query.body)
+    }
+
+    fn post_visit_statement(&mut self, statement: &mut Statement) -> ControlFlow<String> {
+        // Tables copied are not relations of visitors.
+        if let Statement::Copy {
+            source: CopySource::Table { table_name, .. },
+            ..
+        } = statement
+        {
+            if let Some(masks) = find(self.masks, table_name) {
+                return ControlFlow::Break(format!("cannot mask columns of COPY {}", masks.table));
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// A `Visitor` collecting names designating rows of tables with masked
+/// columns, and their masked columns.
+#[derive(Default)]
+struct References<'a> {
+    masks: &'a [TableMasks],
+    bindings: BTreeSet<String>,
+    columns: BTreeSet<String>,
+}
+
+impl References<'_> {
+    fn add(&mut self, name: &ObjectName, alias: Option<&Ident>) {
+        if let Some(masks) = find(self.masks, name) {
+            self.bindings.insert(normalize(&binding(name, alias)));
+            self.columns.extend(masks.columns.keys().cloned());
+        }
+    }
+}
+
+impl Visitor for References<'_> {
+    type Break = ();
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        self.add(relation, None);
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        if let TableFactor::Table {
+            name,
+            alias: Some(alias),
+            ..
+        } = table_factor
+        {
+            self.add(name, Some(&alias.name));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// A `Visitor` breaking with the reason why masked columns are used
+/// outside of projections, once these are masked.
+struct Checker<'a>(&'a References<'a>);
+
+impl Checker<'_> {
+    fn whole_row(&self, name: &ObjectName) -> ControlFlow<String> {
+        match name.0.last().map(normalize) {
+            Some(name) if self.0.bindings.contains(&name) => ControlFlow::Break(format!(
+                "cannot reference whole rows of {} with masked columns",
+                name
+            )),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+impl Visitor for Checker<'_> {
+    type Break = String;
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<String> {
+        let column = match expr {
+            Expr::Identifier(ident) => {
+                self.whole_row(&ObjectName(vec![ident.clone()]))?;
+                Some(normalize(ident))
+            }
+            Expr::CompoundIdentifier(parts) if parts.len() > 1 => {
+                let qualifier = normalize(&parts[parts.len() - 2]);
+                Some(normalize(&parts[parts.len() - 1]))
+                    .filter(|_| self.0.bindings.contains(&qualifier))
+            }
+            Expr::QualifiedWildcard(name, _) => {
+                self.whole_row(name)?;
+                None
+            }
+            Expr::Function(function) => {
+                if let FunctionArguments::List(list) = &function.args {
+                    for arg in &list.args {
+                        let arg = match arg {
+                            FunctionArg::Named { arg, .. }
+                            | FunctionArg::ExprNamed { arg, .. }
+                            | FunctionArg::Unnamed(arg) => arg,
+                        };
+                        if let FunctionArgExpr::QualifiedWildcard(name) = arg {
+                            self.whole_row(name)?;
+                        }
+                    }
+                }
+                None
+            }
+            _ => None,
+        };
+        match column {
+            Some(column) if self.0.columns.contains(&column) => ControlFlow::Break(format!(
+                "masked column {} cannot be used outside of projections",
+                column
+            )),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// A `VisitorMut` replacing placeholders by their masking expressions.
+struct Unmasker(Vec<Expr>);
+
+impl VisitorMut for Unmasker {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        let mask = match &*expr {
+            Expr::Identifier(ident) => ident
+                .value
+                .strip_prefix(PLACEHOLDER_PREFIX)
+                .and_then(|idx| idx.parse::<usize>().ok())
+                .and_then(|idx| self.0.get(idx)),
+            _ => None,
+        };
+        if let Some(mask) = mask {
+            *expr = match mask {
+                Expr::Value(_) | Expr::Function(_) => mask.clone(),
+                _ => Expr::Nested(Box::new(mask.clone())),
+            };
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Returns `query` rewritten with column `masks`, `None` if unchanged, or
+/// why it cannot be rewritten.
+fn rewrite(query: &str, masks: &[TableMasks]) -> Result<Option<String>, String> {
+    let mut statements = parse(query)?;
+    if let ControlFlow::Break(reason) = Visit::visit(&statements, &mut SqlFunctions) {
+        return Err(reason);
+    }
+    let mut rewriter = Rewriter {
+        masks,
+        masked: vec![],
+    };
+    if let ControlFlow::Break(reason) = VisitMut::visit(&mut statements, &mut rewriter) {
+        return Err(reason);
+    }
+
+    let mut references = References {
+        masks,
+        ..References::default()
+    };
+    let _ = Visit::visit(&statements, &mut references);
+    if references.bindings.is_empty() {
+        return Ok(None);
+    }
+    if let ControlFlow::Break(reason) = Visit::visit(&statements, &mut Checker(&references)) {
+        return Err(reason);
+    }
+    if rewriter.masked.is_empty() {
+        return Ok(None);
+    }
+
+    let _ = VisitMut::visit(&mut statements, &mut Unmasker(rewriter.masked));
+    let statements: Vec<String> = statements.iter().map(ToString::to_string).collect();
+    Ok(Some(statements.join("; ")))
+}
+
+/// An `SQLMessageHandler` rewriting statements of `Query` and `Parse`
+/// messages with column masks, and denying those which cannot be rewritten.
+///
+/// Masking expressions are defined by table and column in
+/// `authorization.column_masks`, e.g. `ssn = "'***-**-' || right(ssn, 4)"`
+/// for `users`, where columns are those of the table.
+#[derive(Debug, Default)]
+pub struct ColumnMaskHandler {
+    masks: Vec<TableMasks>,
+
+    /// Denials, shared with a `DenialHandler`.
+    denials: Denials,
+}
+
+impl ColumnMaskHandler {
+    /// Records denials in `denials`, e.g. shared with other handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+
+    /// Returns the denials, to be shared with a `DenialHandler`.
+    pub fn denials(&self) -> Denials {
+        self.denials.clone()
+    }
+
+    /// Returns the statement to run instead of `query`, or why it is denied.
+    fn rewrite(&self, query: Bytes) -> Result<Bytes, String> {
+        if self.masks.is_empty() {
+            return Ok(query);
+        }
+        match rewrite(&String::from_utf8_lossy(&query), &self.masks) {
+            Ok(None) => Ok(query),
+            Ok(Some(rewritten)) => {
+                log::trace!("rewritten statement with column masks: {}", rewritten);
+                Ok(Bytes::from(rewritten))
+            }
+            Err(reason) => {
+                log::warn!("denying statement which cannot be masked - {}", reason);
+                Err(format!("permission denied by Fern, {}", reason))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for ColumnMaskHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        let mut masks = vec![];
+        if let Ok(tables) = config.get_table("authorization.column_masks") {
+            for (name, columns) in tables {
+                let table = match parse_table(&name) {
+                    Ok(table) => table,
+                    Err(err) => {
+                        log::error!(
+                            "ignoring column masks of invalid table '{}' - {}",
+                            name,
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let columns = match columns.into_table() {
+                    Ok(columns) => columns,
+                    Err(err) => {
+                        log::error!("ignoring invalid column masks of '{}' - {}", name, err);
+                        continue;
+                    }
+                };
+                let columns = columns
+                    .into_iter()
+                    .map(|(column, mask)| {
+                        // Invalid masks still hide values.
+                        let mask = mask
+                            .into_string()
+                            .map_err(|err| err.to_string())
+                            .and_then(|mask| parse_expr(&mask))
+                            .unwrap_or_else(|err| {
+                                log::error!("masking {}.{} with NULL - {}", name, column, err);
+                                Expr::Value(Value::Null)
+                            });
+                        (column, mask)
+                    })
+                    .collect();
+                masks.push(TableMasks { table, columns });
+            }
+        }
+
+        Self {
+            masks,
+            ..Self::default()
+        }
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::Query(query) => match self.rewrite(query) {
+                Ok(query) => Some(frontend::Message::Query(query)),
+                Err(message) => {
+                    self.denials.deny_query(message);
+                    None
+                }
+            },
+            frontend::Message::Parse {
+                stmt_name,
+                query,
+                parameters_types,
+            } => match self.rewrite(query) {
+                Ok(query) => Some(frontend::Message::Parse {
+                    stmt_name,
+                    query,
+                    parameters_types,
+                }),
+                Err(message) => {
+                    self.denials.deny_parse(message);
+                    None
+                }
+            },
+            _ => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use config::{File, FileFormat};
+    use fern_protocol_postgresql::codec::{backend, frontend};
+    use fern_proxy_interfaces::SQLMessageHandler;
+
+    use super::{rewrite, ColumnMaskHandler};
+    use crate::{Denials, SQLHandlerConfig};
+
+    /// Helper function building a handler masking `ssn` and `email` of
+    /// `users`, and `iban` of `billing.accounts`.
+    fn handler() -> ColumnMaskHandler {
+        let settings = r#"
+            [authorization.column_masks.users]
+            ssn = "'***-**-' || right(ssn, 4)"
+            email = "NULL"
+            [authorization.column_masks.'billing.accounts']
+            iban = "'******'"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(File::from_str(settings, FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(crate::has_column_masks(&config));
+        ColumnMaskHandler::new(&config)
+    }
+
+    #[test]
+    fn valid_rewrite() {
+        let handler = handler();
+        let data = vec![
+            (
+                "SELECT id, ssn, u.email AS contact FROM users u",
+                "SELECT id, ('***-**-' || right(u.ssn, 4)) AS ssn, NULL AS contact FROM users AS u",
+            ),
+            (
+                "SELECT a.iban, upper(c.email) FROM billing.accounts a JOIN contacts c ON c.id = a.id",
+                "SELECT '******' AS iban, upper(c.email) FROM billing.accounts AS a \
+                 JOIN contacts AS c ON c.id = a.id",
+            ),
+            (
+                "SELECT s.email FROM (SELECT id, email FROM users) s WHERE s.email IS NULL",
+                "SELECT s.email FROM (SELECT id, NULL AS email FROM users) AS s \
+                 WHERE s.email IS NULL",
+            ),
+            (
+                "INSERT INTO archive SELECT id, email FROM users WHERE id = $1",
+                "INSERT INTO archive SELECT id, NULL AS email FROM users WHERE id = $1",
+            ),
+        ];
+        for (query, expected) in data {
+            assert_eq!(
+                Ok(Some(expected.to_string())),
+                rewrite(query, &handler.masks),
+                "{}",
+                query
+            );
+        }
+        let unchanged = vec![
+            "SELECT id FROM users WHERE created_at > now()",
+            "SELECT email FROM contacts ORDER BY email",
+            "UPDATE users SET email = 'x' WHERE id = 1",
+            "BEGIN",
+        ];
+        for query in unchanged {
+            assert_eq!(Ok(None), rewrite(query, &handler.masks), "{}", query);
+        }
+    }
+
+    #[test]
+    fn invalid_rewrite() {
+        let handler = handler();
+        let data = vec![
+            "SELECT id FROM users WHERE ssn LIKE '1%'",
+            "SELECT id FROM users ORDER BY email",
+            "SELECT count(*) FROM users GROUP BY ssn",
+            "SELECT c.id FROM contacts c JOIN users u ON u.email = c.email",
+            "SELECT (SELECT 1 WHERE u.ssn LIKE '1%') FROM users u",
+            "SELECT * FROM users",
+            "SELECT u.* FROM users u",
+            "SELECT row_to_json(u) FROM users u",
+            "TABLE billing.accounts",
+            "COPY users TO STDOUT",
+            "UPDATE users SET name = 'x' RETURNING ssn",
+            "DELETE FROM users WHERE email IS NULL",
+            "SELECT ssn FROM ONLY users",
+            "SELECT id FROM ONLY users WHERE ssn LIKE '1%'",
+            "SELECT query_to_xml('SELECT ssn FROM users', true, false, '')",
+        ];
+        for query in data {
+            assert!(rewrite(query, &handler.masks).is_err(), "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn masked_session() {
+        let mut handler = handler();
+        let msg = frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(b"SELECT email FROM users WHERE id = $1"),
+            parameters_types: vec![],
+        };
+        let expected = frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(b"SELECT NULL AS email FROM users WHERE id = $1"),
+            parameters_types: vec![],
+        };
+        assert_eq!(Some(expected), handler.process(msg).await);
+
+        let (replies, mut answers) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler.with_denials(Denials::new().with_replies(replies));
+        let msg = frontend::Message::Query(Bytes::from_static(b"SELECT * FROM users"));
+        assert_eq!(None, handler.process(msg).await);
+        assert!(matches!(
+            answers.try_recv(),
+            Ok(backend::Message::ErrorResponse(_))
+        ));
+    }
+}
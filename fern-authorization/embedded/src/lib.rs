@@ -1,8 +1,795 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
+//! Embedded SQL-aware authorization for Fern proxy.
+//!
+//! Statements of `Query` and `Parse` messages are analyzed by an
+//! [`AuthorizationHandler`] to extract the accesses they request, i.e.
+//! operations on tables and columns (see [`Access`]), which are evaluated
+//! against allow and deny rules of the session user (see [`Rule`]).
+//!
+//! Denied statements never reach the server: the proxy answers them with
+//! an `ErrorResponse` of SQLSTATE `42501` (insufficient privilege) and a
+//! `ReadyForQuery`, skipping following extended query messages until
+//! `Sync`. A [`DenialHandler`] sends these answers once the server answered
+//! preceding requests. Unlike errors of the server, denials do not abort
+//! the ongoing transaction, and statements preceding a denied one in an
+//! extended query are still run.
+//!
+//! Sessions may also be read-only, where a [`ReadOnlyHandler`] denies
+//! statements which may write, whatever the rules. Finally, statements
+//! may be rewritten, for rows of tables to be filtered by a
+//! [`RowFilterHandler`], and for columns to be masked by the server with a
+//! [`ColumnMaskHandler`], refusing statements which could infer values.
+//!
+//! Rules are defined in the `authorization` table of `SQLHandlerConfig`,
+//! and accesses may also be evaluated against a policy document, through a
+//! `fern_policy_engine::Session` (see [`AuthorizationHandler::with_policy`]).
+
+use async_trait::async_trait;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::mpsc;
+
+use fern_policy_engine::{Resource, Session};
+use fern_protocol_postgresql::codec::{backend, frontend};
+use fern_proxy_interfaces::SQLMessageHandler;
+
+// Re-export.
+pub use column_masks::ColumnMaskHandler;
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use read_only::{check_read_only, ReadOnlyHandler};
+pub use row_filters::RowFilterHandler;
+pub use rules::{evaluate, Effect, Rule};
+pub use statements::{analyze, Access, Table};
+
+mod column_masks;
+mod read_only;
+mod row_filters;
+mod rules;
+mod statements;
+
+/// SQLSTATE of insufficient privilege errors.
+const INSUFFICIENT_PRIVILEGE: &str = "42501";
+
+/// Returns whether authorization is defined in `config`.
+pub fn is_enabled(config: &SQLHandlerConfig) -> bool {
+    ["authorization.users", "authorization.roles"]
+        .iter()
+        .any(|key| {
+            config
+                .get_table(key)
+                .map_or(false, |table| !table.is_empty())
+        })
+}
+
+/// Returns whether read-only sessions are defined in `config`.
+pub fn is_read_only(config: &SQLHandlerConfig) -> bool {
+    config
+        .get::<bool>("authorization.read_only.all")
+        .unwrap_or(false)
+        || !config
+            .get::<Vec<String>>("authorization.read_only.users")
+            .unwrap_or_default()
+            .is_empty()
+}
+
+/// Returns whether column masks are defined in `config`.
+pub fn has_column_masks(config: &SQLHandlerConfig) -> bool {
+    config
+        .get_table("authorization.column_masks")
+        .map_or(false, |table| !table.is_empty())
+}
+
+/// Returns whether row filters are defined in `config`.
+pub fn has_row_filters(config: &SQLHandlerConfig) -> bool {
+    config
+        .get_table("authorization.row_filters")
+        .map_or(false, |table| !table.is_empty())
+}
+
+/// Parses `rules`, replacing invalid ones by rules denying any access.
+fn parse_rules(rules: Vec<String>) -> Vec<Rule> {
+    rules
+        .iter()
+        .map(|text| {
+            Rule::parse(text).unwrap_or_else(|err| {
+                log::error!("denying all accesses instead of invalid rule - {}", err);
+                Rule::deny_all(text)
+            })
+        })
+        .collect()
+}
+
+/// Rules of a principal, either a user or a role.
+#[derive(Debug, Clone, Default)]
+struct Principal {
+    /// Users members of a role.
+    users: Vec<String>,
+
+    rules: Vec<Rule>,
+}
+
+/// Gets principals defined in `prefix` table of `config`, e.g.
+/// `authorization.roles`, with their `rules`, and `users` if roles.
+fn principals_from_config(config: &SQLHandlerConfig, prefix: &str) -> BTreeMap<String, Principal> {
+    let mut principals = BTreeMap::new();
+    if let Ok(table) = config.get_table(prefix) {
+        for name in table.keys() {
+            let key = format!("{}.{}.rules", prefix, name);
+            let rules = parse_rules(config.get::<Vec<String>>(&key).unwrap_or_default());
+            let key = format!("{}.{}.users", prefix, name);
+            let users = config.get::<Vec<String>>(&key).unwrap_or_default();
+            principals.insert(name.clone(), Principal { users, rules });
+        }
+    }
+    principals
+}
+
+/// Request awaiting its `ReadyForQuery`, i.e. a `Query`, `Sync` or
+/// `FunctionCall`.
+#[derive(Debug)]
+enum SyncPoint {
+    /// Forwarded to the server, answered after the error of a statement
+    /// denied in its extended query, if any.
+    Server(Option<String>),
+
+    /// Denied with a message, answered by the proxy.
+    Denied(String),
+
+    /// Answered by the proxy, whose `ReadyForQuery` is on its way.
+    Answered,
+}
+
+/// Denials state, behind the shared `Denials`.
+#[derive(Debug)]
+struct DenialsState {
+    /// Requests awaiting their `ReadyForQuery`, in order.
+    pending: VecDeque<SyncPoint>,
+
+    /// Message of the statement denied in the ongoing extended query, if
+    /// any, whose messages are skipped until `Sync`.
+    denied: Option<String>,
+
+    /// Whether extended query messages were forwarded since the last `Sync`.
+    forwarded: bool,
+
+    /// Transaction status of the last `ReadyForQuery`.
+    status: u8,
+}
+
+impl Default for DenialsState {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            denied: None,
+            forwarded: false,
+            status: b'I',
+        }
+    }
+}
+
+/// Denials, shared by handlers of both directions.
+///
+/// Cloning `Denials` gives access to the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Denials {
+    state: Arc<Mutex<DenialsState>>,
+
+    /// Channel of answers to the client, short-circuiting the server.
+    replies: Option<mpsc::UnboundedSender<backend::Message>>,
+}
+
+impl Denials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends answers to denied statements through `replies`.
+    #[must_use]
+    pub fn with_replies(mut self, replies: mpsc::UnboundedSender<backend::Message>) -> Self {
+        self.replies = Some(replies);
+        self
+    }
+
+    /// Locks the state, regardless of poisoning as it is always consistent.
+    fn state(&self) -> MutexGuard<'_, DenialsState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Denies a `Query` or `FunctionCall` with `message`, answered once
+    /// preceding requests are.
+    pub fn deny_query(&self, message: String) {
+        let mut state = self.state();
+        state.pending.push_back(SyncPoint::Denied(message));
+        self.answer(&mut state);
+    }
+
+    /// Denies a `Parse` with `message`, skipping following extended query
+    /// messages until `Sync`.
+    pub fn deny_parse(&self, message: String) {
+        self.state().denied.get_or_insert(message);
+    }
+
+    /// Answers the oldest pending request, if denied.
+    fn answer(&self, state: &mut DenialsState) {
+        if let Some(SyncPoint::Denied(message)) = state.pending.front() {
+            self.reply(backend::Message::error_response(
+                INSUFFICIENT_PRIVILEGE,
+                message,
+            ));
+            self.reply(backend::Message::ReadyForQuery(state.status));
+            state.pending[0] = SyncPoint::Answered;
+        }
+    }
+
+    /// Sends `msg` to the client.
+    fn reply(&self, msg: backend::Message) {
+        let sent = match &self.replies {
+            Some(replies) => replies.send(msg).is_ok(),
+            None => false,
+        };
+        if !sent {
+            log::warn!("cannot answer denied statement, no channel to the client");
+        }
+    }
+}
+
+/// An `SQLMessageHandler` denying statements of `Query` and `Parse`
+/// messages which session user is not allowed to run.
+///
+/// Rules are defined in `authorization.users.<user>.rules`, and in
+/// `authorization.roles.<role>.rules` for users listed in
+/// `authorization.roles.<role>.users`. Accesses matching no rule are
+/// denied, unless `authorization.default` is `allow`.
+#[derive(Debug)]
+pub struct AuthorizationHandler {
+    users: BTreeMap<String, Principal>,
+    roles: BTreeMap<String, Principal>,
+
+    /// Whether accesses matching no rule are allowed.
+    default_allow: bool,
+
+    /// Whether rules of `authorization` are enforced, or only the policy.
+    enforced: bool,
+
+    /// Session of the policy document, if any.
+    policy: Option<Session>,
+
+    /// User of the session, once known.
+    user: Option<String>,
+
+    /// Rules applying to the session user.
+    rules: Vec<Rule>,
+
+    /// Denials, shared with a `DenialHandler`.
+    denials: Denials,
+}
+
+impl AuthorizationHandler {
+    /// Records denials in `denials`, e.g. shared with other handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+
+    /// Returns the denials, to be shared with a `DenialHandler`.
+    pub fn denials(&self) -> Denials {
+        self.denials.clone()
+    }
+
+    /// Evaluates accesses against the policy document of `session` too.
+    #[must_use]
+    pub fn with_policy(mut self, session: Session) -> Self {
+        self.policy = Some(session);
+        self
+    }
+
+    /// Returns the first access of `accesses` denied by the policy, if any,
+    /// logging audited ones.
+    fn check_policy(&self, accesses: &[Access]) -> Option<Access> {
+        let session = self.policy.as_ref()?;
+        for access in accesses {
+            // Statements without table, e.g. `SET`, are not resources.
+            let table = match &access.table {
+                Some(table) => table,
+                None => continue,
+            };
+            let resource = Resource {
+                database: None,
+                schema: table.schema.clone(),
+                table: Some(table.name.clone()),
+                column: None,
+            };
+            // Accesses to no column in particular, e.g. `count(*)`, are to the table.
+            let resources = match &access.columns {
+                Some(columns) if !columns.is_empty() => columns
+                    .iter()
+                    .map(|column| Resource {
+                        column: Some(column.clone()),
+                        ..resource.clone()
+                    })
+                    .collect(),
+                _ => vec![resource],
+            };
+            for resource in &resources {
+                let verdict = session.authorize(&access.operation, resource);
+                if verdict.audited {
+                    log::info!(
+                        target: "fern::audit",
+                        "{} {} on {} by user '{}'",
+                        if verdict.allowed { "allowing" } else { "denying" },
+                        access.operation,
+                        resource,
+                        self.user.as_deref().unwrap_or_default()
+                    );
+                }
+                if !verdict.allowed {
+                    return Some(access.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// Sets the session `user`, gathering rules applying to it.
+    fn set_user(&mut self, user: String) {
+        let mut rules = vec![];
+        if let Some(principal) = self.users.get(&user) {
+            rules.extend(principal.rules.iter().cloned());
+        }
+        for (name, role) in &self.roles {
+            if role.users.contains(&user) {
+                log::debug!("user '{}' has role '{}'", user, name);
+                rules.extend(role.rules.iter().cloned());
+            }
+        }
+        if rules.is_empty() {
+            log::debug!("no authorization rule for user '{}'", user);
+        }
+        self.rules = rules;
+        self.user = Some(user);
+    }
+
+    /// Returns why `query` is denied, if it is.
+    fn check(&self, query: &[u8]) -> Option<String> {
+        let query = String::from_utf8_lossy(query);
+        let user = self.user.as_deref().unwrap_or_default();
+        let accesses = match analyze(&query) {
+            Ok(accesses) => accesses,
+            Err(err) => {
+                // Unless rules may deny anything, e.g. with a candidate policy only.
+                let denied = self.enforced
+                    || self.policy.as_ref().map_or(true, |session| {
+                        session.deny_unverifiable("which cannot be analyzed")
+                    });
+                if !denied {
+                    return None;
+                }
+                log::warn!("denying statement which cannot be analyzed - {}", err);
+                return Some("permission denied by Fern, statement cannot be analyzed".to_string());
+            }
+        };
+
+        if self.enforced {
+            if let Err((access, reason)) = evaluate(&self.rules, &accesses, self.default_allow) {
+                log::warn!("denying {} to user '{}', {}", access, user, reason);
+                return Some(format!("permission denied by Fern for {}", access));
+            }
+        }
+        self.check_policy(&accesses).map(|access| {
+            log::warn!("denying {} to user '{}' by policy", access, user);
+            format!("permission denied by Fern for {}", access)
+        })
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for AuthorizationHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        let default_allow = match config.get::<String>("authorization.default").as_deref() {
+            Ok("deny") | Err(_) => false,
+            Ok("allow") => true,
+            Ok(other) => {
+                log::warn!("unknown authorization default '{}', using 'deny'", other);
+                false
+            }
+        };
+
+        Self {
+            users: principals_from_config(config, "authorization.users"),
+            roles: principals_from_config(config, "authorization.roles"),
+            default_allow,
+            enforced: is_enabled(config),
+            policy: None,
+            user: None,
+            rules: vec![],
+            denials: Denials::new(),
+        }
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::StartupMessage { ref parameters, .. } => {
+                let user = parameters
+                    .iter()
+                    .find(|parameter| parameter.name == "user")
+                    .map(|parameter| String::from_utf8_lossy(&parameter.value).into_owned());
+                if let Some(user) = user {
+                    self.set_user(user);
+                }
+                Some(msg)
+            }
+            frontend::Message::Query(ref query) => match self.check(query) {
+                Some(message) => {
+                    self.denials.deny_query(message);
+                    None
+                }
+                None => Some(msg),
+            },
+            frontend::Message::Parse { ref query, .. } => match self.check(query) {
+                Some(message) => {
+                    self.denials.deny_parse(message);
+                    None
+                }
+                None => Some(msg),
+            },
+            _ => Some(msg),
+        }
+    }
+}
+
+/// An `SQLMessageHandler` of both directions answering denied statements,
+/// once the server answered preceding requests.
+///
+/// Following authorization handlers in the chain of the Client side, it
+/// skips messages of extended queries with a denied statement until `Sync`.
+/// It comes first in the chain of the Server side, for following handlers
+/// to see answers of the proxy as the server ones.
+#[derive(Debug, Clone, Default)]
+pub struct DenialHandler {
+    /// Denials, shared with authorization handlers.
+    denials: Denials,
+}
+
+impl DenialHandler {
+    /// Uses `denials` of authorization handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for DenialHandler {
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self::default()
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        let mut state = self.denials.state();
+        match msg {
+            frontend::Message::Sync() => {
+                let denied = state.denied.take();
+                let forwarded = std::mem::replace(&mut state.forwarded, false);
+                match denied {
+                    // Messages preceding the denied statement are answered first.
+                    Some(message) if forwarded => {
+                        state.pending.push_back(SyncPoint::Server(Some(message)));
+                        Some(msg)
+                    }
+                    Some(message) => {
+                        state.pending.push_back(SyncPoint::Denied(message));
+                        self.denials.answer(&mut state);
+                        None
+                    }
+                    None => {
+                        state.pending.push_back(SyncPoint::Server(None));
+                        Some(msg)
+                    }
+                }
+            }
+            frontend::Message::Query(_) | frontend::Message::FunctionCall { .. } => {
+                state.pending.push_back(SyncPoint::Server(None));
+                Some(msg)
+            }
+            frontend::Message::Parse { .. }
+            | frontend::Message::Bind { .. }
+            | frontend::Message::Describe { .. }
+            | frontend::Message::Execute { .. }
+            | frontend::Message::Close { .. }
+            | frontend::Message::Flush() => {
+                if state.denied.is_some() {
+                    return None;
+                }
+                state.forwarded = true;
+                Some(msg)
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for DenialHandler {
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self::default()
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        if let backend::Message::ReadyForQuery(status) = msg {
+            let mut state = self.denials.state();
+            match state.pending.pop_front() {
+                // The error comes before the `ReadyForQuery` of the server.
+                Some(SyncPoint::Server(Some(message))) => {
+                    state.status = status;
+                    state.pending.push_front(SyncPoint::Answered);
+                    self.denials.reply(msg);
+                    return Some(backend::Message::error_response(
+                        INSUFFICIENT_PRIVILEGE,
+                        &message,
+                    ));
+                }
+                Some(SyncPoint::Answered) => {}
+                _ => state.status = status,
+            }
+            self.denials.answer(&mut state);
+        }
+        Some(msg)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn it_works() {}
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend;
+    use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+
+    use tokio::sync::mpsc;
+
+    use super::{AuthorizationHandler, DenialHandler, Denials, SQLHandlerConfig};
+
+    /// Helper function building handlers sharing denials, answered through
+    /// the returned channel, where "analyst" may only read `public` tables
+    /// but `cards`, and "app" has no rule.
+    fn handlers() -> (
+        AuthorizationHandler,
+        DenialHandler,
+        mpsc::UnboundedReceiver<backend::Message>,
+    ) {
+        let config = SQLHandlerConfig::builder()
+            .set_override("authorization.roles.analyst.users", vec!["alice"])
+            .unwrap()
+            .set_override(
+                "authorization.roles.analyst.rules",
+                vec!["SELECT on public.*", "deny on payments.cards"],
+            )
+            .unwrap()
+            .set_override("authorization.users.bob.rules", vec!["* on *"])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(super::is_enabled(&config));
+        let (replies, answers) = mpsc::unbounded_channel();
+        let denials = Denials::new().with_replies(replies);
+        let authorization = AuthorizationHandler::new(&config).with_denials(denials.clone());
+        let denial = DenialHandler::default().with_denials(denials);
+        (authorization, denial, answers)
+    }
+
+    /// Helper function building a `StartupMessage` of `user`.
+    fn startup(user: &'static str) -> frontend::Message {
+        frontend::Message::StartupMessage {
+            frame_length: 0,
+            parameters: vec![Parameter {
+                name: Bytes::from_static(b"user"),
+                value: Bytes::from_static(user.as_bytes()),
+            }],
+        }
+    }
+
+    /// Helper function returning the query forwarded for `query`, if any.
+    async fn forwarded(handler: &mut AuthorizationHandler, query: &'static str) -> Option<Bytes> {
+        let msg = frontend::Message::Query(Bytes::from_static(query.as_bytes()));
+        match handler.process(msg).await {
+            Some(frontend::Message::Query(query)) => Some(query),
+            None => None,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    /// Helper function building a `Parse` message of `query`.
+    fn parse(query: &'static str) -> frontend::Message {
+        frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(query.as_bytes()),
+            parameters_types: vec![],
+        }
+    }
+
+    /// Helper function building the messages of an extended query running
+    /// `query`, up to `Sync`.
+    fn extended(query: &'static str) -> Vec<frontend::Message> {
+        vec![
+            parse(query),
+            frontend::Message::Describe {
+                kind: b'S',
+                name: Bytes::new(),
+            },
+            frontend::Message::Execute {
+                portal: Bytes::new(),
+                max_rows: 0,
+            },
+            frontend::Message::Sync(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn query_allowed() {
+        let (mut handler, _, _) = handlers();
+        handler.process(startup("alice")).await;
+        for query in ["SELECT * FROM public.users", "BEGIN"] {
+            let forwarded = forwarded(&mut handler, query).await;
+            assert_eq!(Some(query.as_bytes()), forwarded.as_deref());
+        }
+    }
+
+    #[tokio::test]
+    async fn query_denied() {
+        let (mut handler, mut denial, mut answers) = handlers();
+        handler.process(startup("alice")).await;
+
+        // Preceding requests are answered by the server first.
+        let begin = frontend::Message::Query(Bytes::from_static(b"BEGIN"));
+        let begin = handler.process(begin).await.unwrap();
+        assert!(denial.process(begin).await.is_some());
+        let query = "SELECT number FROM payments.cards";
+        assert_eq!(None, forwarded(&mut handler, query).await);
+        assert!(answers.try_recv().is_err());
+
+        let ready = backend::Message::ReadyForQuery(b'T');
+        assert_eq!(Some(ready.clone()), denial.process(ready.clone()).await);
+        let expected = backend::Message::error_response(
+            "42501",
+            "permission denied by Fern for SELECT on payments.cards(number)",
+        );
+        assert_eq!(Ok(expected), answers.try_recv());
+        assert_eq!(
+            Ok(ready.clone()),
+            answers.try_recv(),
+            "still in transaction"
+        );
+        assert_eq!(Some(ready.clone()), denial.process(ready).await);
+
+        // Other errors are forwarded as is.
+        let other = backend::Message::error_response("42P01", "relation does not exist");
+        assert_eq!(Some(other.clone()), denial.process(other).await);
+    }
+
+    #[tokio::test]
+    async fn parse_denied() {
+        let (mut handler, mut denial, mut answers) = handlers();
+        handler.process(startup("carol")).await;
+        for msg in extended("DELETE FROM users WHERE id = $1") {
+            if let Some(msg) = handler.process(msg).await {
+                assert_eq!(None, denial.process(msg).await, "skipped until `Sync`");
+            }
+        }
+
+        let expected = backend::Message::error_response(
+            "42501",
+            "permission denied by Fern for DELETE on users",
+        );
+        assert_eq!(Ok(expected), answers.try_recv(), "no rule");
+        let ready = backend::Message::ReadyForQuery(b'I');
+        assert_eq!(Ok(ready), answers.try_recv());
+    }
+
+    #[tokio::test]
+    async fn parse_denied_after_forwarded() {
+        let (mut handler, mut denial, mut answers) = handlers();
+        handler.process(startup("alice")).await;
+        let mut messages = extended("SELECT * FROM public.users");
+        messages.pop();
+        messages.extend(extended("SELECT number FROM payments.cards"));
+        let mut forwarded = vec![];
+        for msg in messages {
+            if let Some(msg) = handler.process(msg).await {
+                forwarded.extend(denial.process(msg).await);
+            }
+        }
+        assert_eq!(
+            4,
+            forwarded.len(),
+            "messages before the denied statement, and `Sync`"
+        );
+        assert_eq!(Some(&frontend::Message::Sync()), forwarded.last());
+
+        // The error comes before the `ReadyForQuery` of the server.
+        let ready = backend::Message::ReadyForQuery(b'I');
+        let expected = backend::Message::error_response(
+            "42501",
+            "permission denied by Fern for SELECT on payments.cards(number)",
+        );
+        assert_eq!(Some(expected), denial.process(ready.clone()).await);
+        assert_eq!(Ok(ready.clone()), answers.try_recv());
+        assert_eq!(Some(ready.clone()), denial.process(ready).await);
+        assert!(answers.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unparsable_denied() {
+        let (mut handler, _, _) = handlers();
+        handler.process(startup("bob")).await;
+        let query = "SELECT * FROM users";
+        let allowed = forwarded(&mut handler, query).await;
+        assert_eq!(Some(query.as_bytes()), allowed.as_deref());
+        assert_eq!(None, forwarded(&mut handler, "SELEC * FROM users").await);
+    }
+
+    #[tokio::test]
+    async fn policy_denied() {
+        let document = r#"
+            [policy]
+            default = "deny"
+            [policy.subjects.analysts]
+            users = ["alice"]
+            [[policy.rules]]
+            subjects = ["analysts"]
+            resources = ["*.public"]
+            operations = ["SELECT"]
+            action = "allow"
+            [[policy.rules]]
+            resources = ["*.*.users.ssn"]
+            action = "deny"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(!super::is_enabled(&config));
+        let mut session = fern_policy_engine::SessionHandler::new(&config);
+        let mut handler = AuthorizationHandler::new(&config).with_policy(session.session());
+        session.process(startup("alice")).await;
+        handler.process(startup("alice")).await;
+        for query in ["SELECT id, email FROM public.users", "SELECT 1"] {
+            let forwarded = forwarded(&mut handler, query).await;
+            assert_eq!(Some(query.as_bytes()), forwarded.as_deref());
+        }
+        for query in [
+            "SELECT ssn FROM public.users",
+            "SELECT * FROM public.users",
+            "DELETE FROM public.users",
+            "SELECT number FROM payments.cards",
+            "SELECT count(*) FROM payments.cards",
+        ] {
+            assert_eq!(None, forwarded(&mut handler, query).await, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn policy_shadowed() {
+        let document = r#"
+            [[policy.shadow.rules]]
+            resources = ["*.*.users.ssn"]
+            action = "deny"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        let mut session = fern_policy_engine::SessionHandler::new(&config);
+        let policy = session.session();
+        let mut handler = AuthorizationHandler::new(&config).with_policy(session.session());
+        session.process(startup("alice")).await;
+        handler.process(startup("alice")).await;
+        for query in ["SELECT ssn FROM users", "SELEC * FROM users"] {
+            let forwarded = forwarded(&mut handler, query).await;
+            assert_eq!(Some(query.as_bytes()), forwarded.as_deref());
+        }
+        assert_eq!(2, policy.divergences());
+    }
 }
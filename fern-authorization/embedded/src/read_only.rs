@@ -0,0 +1,642 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only sessions, where only statements which cannot write are run.
+//!
+//! Statements are checked against an allow-list: queries, `EXPLAIN`,
+//! `SHOW`, transaction control, cursors, prepared statements, `COPY ... TO
+//! STDOUT`, and most `SET` commands. Nested statements, e.g. of `EXPLAIN`
+//! or `PREPARE`, are checked as well, and functions with side effects,
+//! e.g. `pg_terminate_backend` or `lo_import`, are denied wherever called.
+//!
+//! As defence in depth, sessions are also made read-only by the server,
+//! with `SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY` sent once
+//! authenticated, before the client gets its first `ReadyForQuery`.
+//! Statements changing it back, e.g. `RESET ALL` or `DISCARD ALL`, are
+//! denied as any statement not allowed.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use sqlparser::ast::{
+    CopyTarget, Expr, ObjectName, Query, SetExpr, Statement, TableFactor, TransactionAccessMode,
+    TransactionMode, Visit, Visitor,
+};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::mpsc;
+
+use fern_protocol_postgresql::codec::backend;
+use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+use crate::rules::glob;
+use crate::statements::{normalize, operation, parse};
+use crate::Denials;
+
+/// Functions with side effects, denied in read-only sessions, where `*`
+/// matches any characters.
+const DENIED_FUNCTIONS: &[&str] = &[
+    "pg_terminate_backend",
+    "pg_cancel_backend",
+    "pg_reload_conf",
+    "pg_rotate_logfile",
+    "pg_promote",
+    "pg_switch_wal",
+    "pg_create_restore_point",
+    "pg_logical_emit_message",
+    "pg_*_replication_slot",
+    "pg_replication_origin_*",
+    "pg_file_*",
+    "set_config",
+    "nextval",
+    "setval",
+    "lo_*",
+    "dblink*",
+];
+
+/// Settings making transactions read-only, which cannot be changed.
+const READ_ONLY_SETTINGS: &[&str] = &["default_transaction_read_only", "transaction_read_only"];
+
+/// Statement making the session read-only on the server.
+const READ_ONLY_SESSION: &str = "SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY";
+
+/// Returns whether `modes` make a transaction read-write.
+fn is_read_write(modes: &[TransactionMode]) -> bool {
+    modes.contains(&TransactionMode::AccessMode(
+        TransactionAccessMode::ReadWrite,
+    ))
+}
+
+/// A `Visitor` breaking with the reason why a statement may write.
+struct Checker<'a> {
+    /// Patterns of denied functions.
+    functions: &'a [String],
+}
+
+impl Checker<'_> {
+    fn check_function(&self, name: &ObjectName) -> ControlFlow<String> {
+        let name = name.0.last().map(normalize).unwrap_or_default();
+        if self.functions.iter().any(|pattern| glob(pattern, &name)) {
+            return ControlFlow::Break(format!("function {} is not allowed", name));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl Visitor for Checker<'_> {
+    type Break = String;
+
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<String> {
+        match statement {
+            Statement::Query(_)
+            | Statement::Explain { .. }
+            | Statement::ExplainTable { .. }
+            | Statement::ShowVariable { .. }
+            | Statement::ShowVariables { .. }
+            | Statement::ShowStatus { .. }
+            | Statement::ShowColumns { .. }
+            | Statement::ShowTables { .. }
+            | Statement::ShowFunctions { .. }
+            | Statement::ShowCreate { .. }
+            | Statement::ShowDatabases { .. }
+            | Statement::ShowSchemas { .. }
+            | Statement::ShowViews { .. }
+            | Statement::ShowCollation { .. }
+            | Statement::Commit { .. }
+            | Statement::Rollback { .. }
+            | Statement::Savepoint { .. }
+            | Statement::ReleaseSavepoint { .. }
+            | Statement::Declare { .. }
+            | Statement::Fetch { .. }
+            | Statement::Close { .. }
+            | Statement::Prepare { .. }
+            | Statement::Execute { .. }
+            | Statement::Deallocate { .. }
+            | Statement::LISTEN { .. }
+            | Statement::UNLISTEN { .. }
+            | Statement::SetTimeZone { .. }
+            | Statement::SetNames { .. }
+            | Statement::SetNamesDefault {}
+            | Statement::SetRole { .. } => ControlFlow::Continue(()),
+            Statement::StartTransaction { modes, .. } | Statement::SetTransaction { modes, .. } => {
+                match is_read_write(modes) {
+                    true => ControlFlow::Break("read-write transactions are not allowed".into()),
+                    false => ControlFlow::Continue(()),
+                }
+            }
+            Statement::SetVariable { variables, .. } => {
+                let changed = variables.iter().find_map(|variable| {
+                    let name = variable.0.last().map(normalize).unwrap_or_default();
+                    READ_ONLY_SETTINGS.contains(&name.as_str()).then_some(name)
+                });
+                match changed {
+                    Some(name) => ControlFlow::Break(format!("changing {} is not allowed", name)),
+                    None => ControlFlow::Continue(()),
+                }
+            }
+            Statement::Copy {
+                to: true,
+                target: CopyTarget::Stdout,
+                ..
+            } => ControlFlow::Continue(()),
+            Statement::Copy { to: true, .. } => {
+                ControlFlow::Break("COPY TO a file or program is not allowed".into())
+            }
+            Statement::Copy { to: false, .. } => {
+                ControlFlow::Break("COPY FROM is not allowed".into())
+            }
+            _ => ControlFlow::Break(format!("{} is not allowed", operation(statement))),
+        }
+    }
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<String> {
+        if !query.locks.is_empty() {
+            return ControlFlow::Break("SELECT with a locking clause is not allowed".into());
+        }
+        match query.body.as_ref() {
+            SetExpr::Select(select) if select.into.is_some() => {
+                ControlFlow::Break("SELECT INTO is not allowed".into())
+            }
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<String> {
+        match table_factor {
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            }
+            | TableFactor::Function { name, .. } => self.check_function(name),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<String> {
+        match expr {
+            Expr::Function(function) => self.check_function(&function.name),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// Returns why `query` may write, if it may, with `functions` patterns of
+/// denied functions.
+pub fn check_read_only(query: &str, functions: &[String]) -> Result<(), String> {
+    let statements = parse(query).map_err(|err| {
+        log::debug!("statement cannot be analyzed - {}", err);
+        "statements which cannot be analyzed are not allowed".to_string()
+    })?;
+    let mut checker = Checker { functions };
+    match statements.visit(&mut checker) {
+        ControlFlow::Break(reason) => Err(reason),
+        ControlFlow::Continue(()) => Ok(()),
+    }
+}
+
+/// Progress of making a session read-only on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Progress {
+    /// Not a read-only session, or already read-only on the server.
+    #[default]
+    Idle,
+
+    /// Awaiting the server to be ready, once authenticated.
+    Authenticating,
+
+    /// Awaiting the server to complete `READ_ONLY_SESSION`.
+    Setting,
+}
+
+/// An `SQLMessageHandler` of both directions denying statements of `Query`
+/// and `Parse` messages which may write, and `FunctionCall`s, in read-only
+/// sessions.
+///
+/// Sessions of all users are read-only if `authorization.read_only.all`
+/// is `true`, otherwise those of users listed in
+/// `authorization.read_only.users`. Functions denied in addition to
+/// built-in ones are listed in `authorization.read_only.functions`.
+///
+/// Read-only sessions are made read-only by the server as well, sending
+/// `SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY` through
+/// requests (see [`ReadOnlyHandler::with_requests`]). It comes last in
+/// the chain of the Server side, dropping answers to the `SET` once other
+/// handlers tracked them.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOnlyHandler {
+    /// Whether sessions of all users are read-only.
+    all: bool,
+
+    /// Users whose sessions are read-only.
+    users: Vec<String>,
+
+    /// Patterns of denied functions.
+    functions: Vec<String>,
+
+    /// Whether the session is read-only, once its user is known.
+    active: bool,
+
+    /// Denials, shared with a `DenialHandler`.
+    denials: Denials,
+
+    /// Progress of making the session read-only on the server, shared by
+    /// both directions.
+    progress: Arc<Mutex<Progress>>,
+
+    /// Channel of requests to the server, short-circuiting the client.
+    requests: Option<mpsc::UnboundedSender<frontend::Message>>,
+}
+
+impl ReadOnlyHandler {
+    /// Builds a handler of read-only sessions defined in `config`.
+    pub fn from_config(config: &SQLHandlerConfig) -> Self {
+        let mut functions: Vec<String> = DENIED_FUNCTIONS.iter().map(|f| f.to_string()).collect();
+        functions.extend(
+            config
+                .get::<Vec<String>>("authorization.read_only.functions")
+                .unwrap_or_default()
+                .iter()
+                .map(|function| function.to_lowercase()),
+        );
+
+        Self {
+            all: config
+                .get::<bool>("authorization.read_only.all")
+                .unwrap_or(false),
+            users: config
+                .get::<Vec<String>>("authorization.read_only.users")
+                .unwrap_or_default(),
+            functions,
+            ..Self::default()
+        }
+    }
+
+    /// Sends the statement making sessions read-only through `requests`.
+    #[must_use]
+    pub fn with_requests(mut self, requests: mpsc::UnboundedSender<frontend::Message>) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// Records denials in `denials`, e.g. shared with other handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+
+    /// Returns the denials, to be shared with a `DenialHandler`.
+    pub fn denials(&self) -> Denials {
+        self.denials.clone()
+    }
+
+    /// Returns why `query` is denied, if it is.
+    fn check(&self, query: &Bytes) -> Option<String> {
+        if !self.active {
+            return None;
+        }
+        let reason = check_read_only(&String::from_utf8_lossy(query), &self.functions).err()?;
+        log::warn!("denying statement in read-only session, {}", reason);
+        Some(format!(
+            "permission denied by Fern, {} in read-only mode",
+            reason
+        ))
+    }
+
+    /// Locks the progress, regardless of poisoning as it is always consistent.
+    fn progress(&self) -> MutexGuard<'_, Progress> {
+        self.progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts the session read-only, if its user is, with `parameters` of
+    /// its `StartupMessage`.
+    fn start(&mut self, parameters: &[Parameter]) {
+        let user = parameters
+            .iter()
+            .find(|parameter| parameter.name == "user")
+            .map(|parameter| String::from_utf8_lossy(&parameter.value).into_owned())
+            .unwrap_or_default();
+        self.active = self.all || self.users.contains(&user);
+        if self.active {
+            log::debug!("starting read-only session of user '{}'", user);
+            *self.progress() = Progress::Authenticating;
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for ReadOnlyHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_config(config)
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::StartupMessage { ref parameters, .. } => {
+                self.start(parameters);
+                Some(msg)
+            }
+            frontend::Message::Query(ref query) => match self.check(query) {
+                Some(message) => {
+                    self.denials.deny_query(message);
+                    None
+                }
+                None => Some(msg),
+            },
+            frontend::Message::Parse { ref query, .. } => match self.check(query) {
+                Some(message) => {
+                    self.denials.deny_parse(message);
+                    None
+                }
+                None => Some(msg),
+            },
+            // Functions called by OID, e.g. large objects ones of `libpq`,
+            // are denied as well, answered the same way as queries.
+            frontend::Message::FunctionCall { function_oid, .. } if self.active => {
+                log::warn!(
+                    "denying call of function {} in read-only session",
+                    function_oid
+                );
+                let message =
+                    "permission denied by Fern, function calls are not allowed in read-only mode";
+                self.denials.deny_query(message.to_string());
+                None
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for ReadOnlyHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_config(config)
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        let mut progress = self.progress();
+        match (*progress, &msg) {
+            (Progress::Authenticating, backend::Message::ReadyForQuery(_)) => {
+                let request =
+                    frontend::Message::Query(Bytes::from_static(READ_ONLY_SESSION.as_bytes()));
+                let sent = match &self.requests {
+                    Some(requests) => requests.send(request).is_ok(),
+                    None => false,
+                };
+                if !sent {
+                    log::error!("cannot make session read-only, no channel to the server");
+                    *progress = Progress::Idle;
+                    return Some(msg);
+                }
+                // The client is ready once the session is read-only.
+                *progress = Progress::Setting;
+                None
+            }
+            (Progress::Setting, backend::Message::CommandComplete(_)) => None,
+            (Progress::Setting, backend::Message::ReadyForQuery(_)) => {
+                *progress = Progress::Idle;
+                Some(msg)
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend;
+    use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+    use tokio::sync::mpsc;
+
+    use super::{check_read_only, ReadOnlyHandler, DENIED_FUNCTIONS, READ_ONLY_SESSION};
+    use crate::{DenialHandler, Denials, SQLHandlerConfig};
+
+    /// Helper function building a handler where sessions of "bi" are read-only.
+    fn handler() -> ReadOnlyHandler {
+        let config = SQLHandlerConfig::builder()
+            .set_override("authorization.read_only.users", vec!["bi"])
+            .unwrap()
+            .set_override("authorization.read_only.functions", vec!["Audit_*"])
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(crate::is_read_only(&config));
+        ReadOnlyHandler::from_config(&config)
+    }
+
+    /// Helper function building a `StartupMessage` with `parameters`.
+    fn startup(parameters: &[(&'static str, &'static str)]) -> frontend::Message {
+        let parameters: Vec<Parameter> = parameters
+            .iter()
+            .map(|(name, value)| Parameter {
+                name: Bytes::from_static(name.as_bytes()),
+                value: Bytes::from_static(value.as_bytes()),
+            })
+            .collect();
+        let length = parameters
+            .iter()
+            .map(|parameter| parameter.name.len() + parameter.value.len() + 2)
+            .sum::<usize>();
+        frontend::Message::StartupMessage {
+            frame_length: 8 + length + 1,
+            parameters,
+        }
+    }
+
+    /// Helper function returning whether a denial was answered through
+    /// `answers`, passing the answer through `denial` as a chain would.
+    async fn answered(
+        denial: &mut DenialHandler,
+        answers: &mut mpsc::UnboundedReceiver<backend::Message>,
+    ) -> bool {
+        let error = matches!(answers.try_recv(), Ok(backend::Message::ErrorResponse(_)));
+        match answers.try_recv() {
+            Ok(ready) => error && denial.process(ready).await.is_some(),
+            Err(_) => false,
+        }
+    }
+
+    /// Helper function returning the query forwarded for `query`.
+    async fn forwarded(handler: &mut ReadOnlyHandler, query: &'static str) -> Bytes {
+        let msg = frontend::Message::Query(Bytes::from_static(query.as_bytes()));
+        match handler.process(msg).await {
+            Some(frontend::Message::Query(query)) => query,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_session() {
+        let (replies, mut answers) = mpsc::unbounded_channel();
+        let denials = Denials::new().with_replies(replies);
+        let mut handler = handler().with_denials(denials.clone());
+        let mut denial = DenialHandler::default().with_denials(denials);
+        let msg = startup(&[("user", "bi")]);
+        assert_eq!(Some(msg.clone()), handler.process(msg).await);
+
+        let query = "SELECT * FROM users";
+        assert_eq!(query.as_bytes(), &forwarded(&mut handler, query).await[..]);
+        // Including configured functions.
+        for query in ["DELETE FROM users", "SELECT audit_log('read')"] {
+            let msg = frontend::Message::Query(Bytes::from_static(query.as_bytes()));
+            assert_eq!(None, handler.process(msg).await, "{}", query);
+            assert!(answered(&mut denial, &mut answers).await, "{}", query);
+        }
+
+        let msg = frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(b"UPDATE users SET email = $1"),
+            parameters_types: vec![],
+        };
+        assert_eq!(None, handler.process(msg).await);
+        assert!(!answered(&mut denial, &mut answers).await, "until `Sync`");
+        assert_eq!(None, denial.process(frontend::Message::Sync()).await);
+        assert!(answered(&mut denial, &mut answers).await);
+
+        let msg = frontend::Message::FunctionCall {
+            function_oid: 764, // `lo_import`
+            arguments: vec![],
+            result_format: 0,
+        };
+        assert_eq!(None, handler.process(msg).await);
+        assert!(answered(&mut denial, &mut answers).await);
+    }
+
+    #[tokio::test]
+    async fn read_only_server() {
+        let (requests, mut sent) = mpsc::unbounded_channel();
+        let mut handler = handler().with_requests(requests);
+        let msg = startup(&[("user", "bi")]);
+        assert_eq!(Some(msg.clone()), handler.process(msg).await);
+
+        let msg = backend::Message::AuthenticationOk();
+        assert_eq!(Some(msg.clone()), handler.process(msg).await);
+        let ready = backend::Message::ReadyForQuery(b'I');
+        assert_eq!(None, handler.process(ready.clone()).await);
+        let request = frontend::Message::Query(Bytes::from_static(READ_ONLY_SESSION.as_bytes()));
+        assert_eq!(Ok(request), sent.try_recv());
+
+        let msg = backend::Message::CommandComplete(Bytes::from_static(b"SET"));
+        assert_eq!(None, handler.process(msg).await);
+        assert_eq!(Some(ready.clone()), handler.process(ready.clone()).await);
+        // Only once.
+        assert_eq!(Some(ready.clone()), handler.process(ready).await);
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_write_session() {
+        let mut handler = handler();
+        let msg = startup(&[("user", "app")]);
+        assert_eq!(Some(msg.clone()), handler.process(msg).await);
+        let ready = backend::Message::ReadyForQuery(b'I');
+        assert_eq!(Some(ready.clone()), handler.process(ready).await);
+        let query = "DELETE FROM users";
+        assert_eq!(query.as_bytes(), &forwarded(&mut handler, query).await[..]);
+    }
+
+    /// Helper function checking `query` with default denied functions.
+    fn check(query: &str) -> Result<(), String> {
+        let functions: Vec<String> = DENIED_FUNCTIONS.iter().map(|f| f.to_string()).collect();
+        check_read_only(query, &functions)
+    }
+
+    #[test]
+    fn valid_read_only() {
+        let queries = [
+            "SELECT * FROM users WHERE id = $1",
+            "WITH recent AS (SELECT * FROM orders) SELECT count(*) FROM recent",
+            "EXPLAIN SELECT * FROM users",
+            "SHOW search_path",
+            "BEGIN READ ONLY; SELECT 1; COMMIT",
+            "START TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+            "SET search_path = app",
+            "COPY users TO STDOUT",
+            "COPY (SELECT email FROM users) TO STDOUT",
+            "PREPARE p AS SELECT * FROM users; EXECUTE p",
+            "DECLARE c CURSOR FOR SELECT * FROM users",
+            "SELECT * FROM generate_series(1, 10)",
+            READ_ONLY_SESSION,
+        ];
+        for query in queries {
+            assert_eq!(Ok(()), check(query), "{}", query);
+        }
+    }
+
+    #[test]
+    fn invalid_read_only() {
+        let queries = [
+            ("INSERT INTO users VALUES (1)", "INSERT is not allowed"),
+            ("UPDATE users SET email = ''", "UPDATE is not allowed"),
+            ("DELETE FROM users", "DELETE is not allowed"),
+            (
+                "MERGE INTO users u USING staging s ON u.id = s.id WHEN MATCHED THEN DELETE",
+                "MERGE is not allowed",
+            ),
+            ("TRUNCATE users", "TRUNCATE is not allowed"),
+            ("CREATE TABLE t (id int)", "CREATE is not allowed"),
+            ("DROP TABLE users", "DROP is not allowed"),
+            ("GRANT SELECT ON users TO bob", "GRANT is not allowed"),
+            ("COPY users FROM STDIN", "COPY FROM is not allowed"),
+            (
+                "COPY users TO '/tmp/users'",
+                "COPY TO a file or program is not allowed",
+            ),
+            (
+                "SET default_transaction_read_only = off",
+                "changing default_transaction_read_only is not allowed",
+            ),
+            (
+                "SET SESSION transaction_read_only TO off",
+                "changing transaction_read_only is not allowed",
+            ),
+            (
+                "SET SESSION CHARACTERISTICS AS TRANSACTION READ WRITE",
+                "read-write transactions are not allowed",
+            ),
+            (
+                "BEGIN READ WRITE",
+                "read-write transactions are not allowed",
+            ),
+            (
+                "SELECT pg_terminate_backend(42)",
+                "function pg_terminate_backend is not allowed",
+            ),
+            (
+                "SELECT pg_catalog.set_config('default_transaction_read_only', 'off', false)",
+                "function set_config is not allowed",
+            ),
+            (
+                "SELECT lo_import('/etc/passwd')",
+                "function lo_import is not allowed",
+            ),
+            (
+                "SELECT * FROM dblink('host=db', 'DELETE FROM users') AS t(x int)",
+                "function dblink is not allowed",
+            ),
+            (
+                "EXPLAIN ANALYZE UPDATE users SET email = ''",
+                "UPDATE is not allowed",
+            ),
+            ("PREPARE p AS DELETE FROM users", "DELETE is not allowed"),
+            (
+                "SELECT * FROM users FOR UPDATE",
+                "SELECT with a locking clause is not allowed",
+            ),
+            (
+                "SELECT * INTO copy FROM users",
+                "SELECT INTO is not allowed",
+            ),
+            ("SELECT 1; DELETE FROM users", "DELETE is not allowed"),
+        ];
+        for (query, reason) in queries {
+            assert_eq!(Err(reason.to_string()), check(query), "{}", query);
+        }
+        assert!(check("SELEC 1").is_err(), "cannot be analyzed");
+        let query = "WITH gone AS (DELETE FROM users RETURNING *) SELECT * FROM gone";
+        assert!(check(query).is_err(), "data-modifying WITH");
+    }
+}
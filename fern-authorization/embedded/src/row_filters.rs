@@ -0,0 +1,845 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Row-level filtering, by rewriting statements so that only rows of
+//! filtered tables matching a predicate are read, updated, or deleted.
+//!
+//! Predicates are SQL expressions on columns of a table, possibly with
+//! `{session.<name>}` placeholders, replaced by the value of the session
+//! variable `<name>` as a string literal, e.g. `tenant_id = {session.tenant}`.
+//!
+//! Filtered tables read by queries, including subqueries and tables joined
+//! by `UPDATE ... FROM` or `DELETE ... USING`, are replaced by a filtered
+//! subquery under the same alias, e.g. `(SELECT * FROM orders WHERE
+//! tenant_id = 'acme') AS orders`, while the predicate is added to the
+//! `WHERE` clause of `UPDATE` and `DELETE` statements of filtered tables.
+//! Statements which cannot be rewritten safely are refused, e.g. those
+//! changing filtered columns, `COPY` and `TRUNCATE` of filtered tables, or
+//! calling functions which run SQL passed as a string, e.g. `query_to_xml`.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use sqlparser::ast::{
+    Assignment, AssignmentTarget, BinaryOperator, CopySource, Expr, FromTable, Ident, ObjectName,
+    Query, SetExpr, Statement, TableAlias, TableFactor, TableWithJoins, Visit, VisitMut, Visitor,
+    VisitorMut,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::Token;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::ControlFlow;
+
+use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+use crate::rules::glob;
+use crate::statements::{normalize, parse, parse_table, table};
+use crate::{Denials, Table};
+
+/// Prefix of `StartupMessage` parameters defining session variables,
+/// which are not forwarded to the server.
+const SESSION_PARAMETER_PREFIX: &str = "fern.";
+
+/// Functions running SQL passed as a string, or reading tables by name,
+/// which cannot be rewritten, where `*` matches any characters.
+const SQL_FUNCTIONS: &[&str] = &[
+    "query_to_xml*",
+    "query_to_json*",
+    "cursor_to_xml*",
+    "table_to_xml*",
+    "schema_to_xml*",
+    "database_to_xml*",
+    "ts_stat",
+    "ts_rewrite",
+    "dblink*",
+];
+
+/// Returns `template` with `{session.<name>}` placeholders replaced by
+/// string literals of `session` variables.
+fn substitute(template: &str, session: &HashMap<String, String>) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{session.") {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "unterminated session placeholder".to_string())?;
+        let name = &rest[start + "{session.".len()..start + end];
+        let value = session
+            .get(name)
+            .ok_or_else(|| format!("undefined session variable '{}'", name))?;
+        result.push('\'');
+        result.push_str(&value.replace('\'', "''"));
+        result.push('\'');
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parses a single SQL expression.
+pub(crate) fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser::new(&PostgreSqlDialect {})
+        .try_with_sql(text)
+        .map_err(|err| err.to_string())?;
+    let expr = parser.parse_expr().map_err(|err| err.to_string())?;
+    match parser.peek_token().token {
+        Token::EOF => Ok(expr),
+        token => Err(format!("unexpected '{}' after predicate", token)),
+    }
+}
+
+/// Returns `left AND right`.
+fn and(left: Expr, right: Expr) -> Expr {
+    Expr::BinaryOp {
+        left: Box::new(Expr::Nested(Box::new(left))),
+        op: BinaryOperator::And,
+        right: Box::new(Expr::Nested(Box::new(right))),
+    }
+}
+
+/// A `VisitorMut` qualifying columns of a predicate with a table name,
+/// outside of subqueries.
+pub(crate) struct Qualifier {
+    table: Ident,
+    depth: usize,
+}
+
+impl Qualifier {
+    pub(crate) fn new(table: Ident) -> Self {
+        Self { table, depth: 0 }
+    }
+}
+
+impl VisitorMut for Qualifier {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &mut Query) -> ControlFlow<()> {
+        self.depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &mut Query) -> ControlFlow<()> {
+        self.depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        if let (0, Expr::Identifier(column)) = (self.depth, &*expr) {
+            *expr = Expr::CompoundIdentifier(vec![self.table.clone(), column.clone()]);
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// A `Visitor` collecting names of identifiers of a predicate.
+#[derive(Default)]
+struct Columns(BTreeSet<String>);
+
+impl Visitor for Columns {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::Identifier(column) => {
+                self.0.insert(normalize(column));
+            }
+            Expr::CompoundIdentifier(parts) => {
+                self.0.extend(parts.last().map(normalize));
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// A row filter of a table, once session variables are known.
+#[derive(Debug, Clone)]
+struct RowFilter {
+    table: Table,
+
+    /// Predicate of rows, or why it is unavailable.
+    predicate: Result<Expr, String>,
+}
+
+impl RowFilter {
+    fn predicate(&self) -> Result<&Expr, String> {
+        self.predicate
+            .as_ref()
+            .map_err(|err| format!("no row filter for table {} - {}", self.table, err))
+    }
+}
+
+/// A `VisitorMut` rewriting statements with row filters, breaking with
+/// the reason why a statement cannot be rewritten.
+struct Rewriter<'a> {
+    filters: &'a [RowFilter],
+
+    /// Whether a statement was rewritten.
+    rewritten: bool,
+}
+
+/// Returns the filter of `filters` applying to `name`, if any.
+fn find<'a>(filters: &'a [RowFilter], name: &ObjectName) -> Option<&'a RowFilter> {
+    let reference = table(name);
+    filters
+        .iter()
+        .find(|filter| filter.table.matches(&reference))
+}
+
+impl<'a> Rewriter<'a> {
+    /// Returns the filter applying to `name`, if any.
+    fn filter(&self, name: &ObjectName) -> Option<&'a RowFilter> {
+        find(self.filters, name)
+    }
+
+    /// Replaces filtered tables of `table_factor` by filtered subqueries.
+    fn filter_table_factor(&mut self, table_factor: &mut TableFactor) -> ControlFlow<String> {
+        let (name, alias) = match table_factor {
+            TableFactor::Table {
+                name,
+                alias,
+                args: None,
+                ..
+            } => (name.clone(), alias.clone()),
+            TableFactor::NestedJoin {
+                table_with_joins, ..
+            } => return self.filter_tables(table_with_joins),
+            _ => return ControlFlow::Continue(()),
+        };
+        let filter = match self.filter(&name) {
+            Some(filter) => filter,
+            None => return ControlFlow::Continue(()),
+        };
+        let predicate = match filter.predicate() {
+            Ok(predicate) => predicate,
+            Err(reason) => return ControlFlow::Break(reason),
+        };
+
+        let sql = format!("SELECT * FROM {} WHERE {}", name, predicate);
+        let subquery = match Parser::parse_sql(&PostgreSqlDialect {}, &sql) {
+            Ok(mut statements) => match statements.pop() {
+                Some(Statement::Query(subquery)) => subquery,
+                _ => return ControlFlow::Break(format!("cannot filter table {}", name)),
+            },
+            Err(err) => return ControlFlow::Break(err.to_string()),
+        };
+        let alias = alias.unwrap_or_else(|| TableAlias {
+            name: name.0.last().cloned().unwrap_or_else(|| Ident::new("")),
+            columns: vec![],
+        });
+        *table_factor = TableFactor::Derived {
+            lateral: false,
+            subquery,
+            alias: Some(alias),
+        };
+        self.rewritten = true;
+        ControlFlow::Continue(())
+    }
+
+    /// Replaces filtered tables of `table_with_joins` by filtered subqueries.
+    fn filter_tables(&mut self, table_with_joins: &mut TableWithJoins) -> ControlFlow<String> {
+        self.filter_table_factor(&mut table_with_joins.relation)?;
+        for join in &mut table_with_joins.joins {
+            self.filter_table_factor(&mut join.relation)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Replaces filtered tables of `set_expr` by filtered subqueries.
+    fn filter_set_expr(&mut self, set_expr: &mut SetExpr) -> ControlFlow<String> {
+        match set_expr {
+            SetExpr::Select(select) => {
+                for table_with_joins in &mut select.from {
+                    self.filter_tables(table_with_joins)?;
+                }
+                ControlFlow::Continue(())
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                self.filter_set_expr(left)?;
+                self.filter_set_expr(right)
+            }
+            SetExpr::Table(table) => {
+                let name = ObjectName(
+                    [&table.schema_name, &table.table_name]
+                        .iter()
+                        .filter_map(|part| part.as_deref().map(Ident::new))
+                        .collect(),
+                );
+                match self.filter(&name) {
+                    Some(_) => ControlFlow::Break(format!("cannot filter TABLE {}", name)),
+                    None => ControlFlow::Continue(()),
+                }
+            }
+            // Subqueries are rewritten on their own.
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    /// Adds the filter of `target` to `selection` of an `UPDATE` or a
+    /// `DELETE` statement, returning columns of the filter.
+    fn filter_target(
+        &mut self,
+        target: &TableWithJoins,
+        selection: &mut Option<Expr>,
+    ) -> ControlFlow<String, BTreeSet<String>> {
+        if !target.joins.is_empty() {
+            return ControlFlow::Break("cannot filter joined targets".to_string());
+        }
+        let (name, alias) = match &target.relation {
+            TableFactor::Table { name, alias, .. } => (name, alias),
+            _ => return ControlFlow::Continue(BTreeSet::new()),
+        };
+        let filter = match self.filter(name) {
+            Some(filter) => filter,
+            None => return ControlFlow::Continue(BTreeSet::new()),
+        };
+        let mut predicate = match filter.predicate() {
+            Ok(predicate) => predicate.clone(),
+            Err(reason) => return ControlFlow::Break(reason),
+        };
+
+        let mut columns = Columns::default();
+        let _ = Visit::visit(&predicate, &mut columns);
+        let qualifier = match alias {
+            Some(alias) => alias.name.clone(),
+            None => name.0.last().cloned().unwrap_or_else(|| Ident::new("")),
+        };
+        let _ = VisitMut::visit(&mut predicate, &mut Qualifier::new(qualifier));
+        *selection = Some(match selection.take() {
+            Some(selection) => and(selection, predicate),
+            None => predicate,
+        });
+        self.rewritten = true;
+        ControlFlow::Continue(columns.0)
+    }
+}
+
+/// Returns the names of columns assigned by `assignments`.
+fn assigned(assignments: &[Assignment]) -> BTreeSet<String> {
+    let mut columns = BTreeSet::new();
+    for assignment in assignments {
+        match &assignment.target {
+            AssignmentTarget::ColumnName(name) => columns.extend(name.0.last().map(normalize)),
+            AssignmentTarget::Tuple(names) => {
+                columns.extend(names.iter().filter_map(|name| name.0.last().map(normalize)))
+            }
+        }
+    }
+    columns
+}
+
+/// A `Visitor` breaking with functions of `SQL_FUNCTIONS` called.
+pub(crate) struct SqlFunctions;
+
+impl SqlFunctions {
+    fn check_function(&self, name: &ObjectName) -> ControlFlow<String> {
+        let name = name.0.last().map(normalize).unwrap_or_default();
+        if SQL_FUNCTIONS.iter().any(|pattern| glob(pattern, &name)) {
+            return ControlFlow::Break(format!("cannot rewrite SQL run by function {}", name));
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl Visitor for SqlFunctions {
+    type Break = String;
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<String> {
+        match table_factor {
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            }
+            | TableFactor::Function { name, .. } => self.check_function(name),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<String> {
+        match expr {
+            Expr::Function(function) => self.check_function(&function.name),
+            _ => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// A `Visitor` breaking with a filtered table referenced.
+struct References<'a>(&'a [RowFilter]);
+
+impl Visitor for References<'_> {
+    type Break = String;
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<String> {
+        match find(self.0, relation) {
+            Some(filter) => ControlFlow::Break(filter.table.to_string()),
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+impl VisitorMut for Rewriter<'_> {
+    type Break = String;
+
+    fn pre_visit_query(&mut self, query: &mut Query) -> ControlFlow<String> {
+        // CTEs named as filtered tables would be mistaken for them.
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                let name = ObjectName(vec![cte.alias.name.clone()]);
+                if self.filter(&name).is_some() {
+                    return ControlFlow::Break(format!("cannot filter CTE {}", name));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Queries are rewritten once their subqueries are, so that filtered
+    // subqueries added are not rewritten again.
+    fn post_visit_query(&mut self, query: &mut Query) -> ControlFlow<String> {
+        self.filter_set_expr(&mut query.body)
+    }
+
+    fn post_visit_statement(&mut self, statement: &mut Statement) -> ControlFlow<String> {
+        match statement {
+            Statement::Query(_)
+            | Statement::Explain { .. }
+            | Statement::Prepare { .. }
+            | Statement::Declare { .. } => ControlFlow::Continue(()),
+            // Upserts would update rows of any tenant.
+            Statement::Insert(insert) => match (&insert.on, self.filter(&insert.table_name)) {
+                (Some(_), Some(filter)) => {
+                    ControlFlow::Break(format!("cannot filter ON CONFLICT of {}", filter.table))
+                }
+                _ => ControlFlow::Continue(()),
+            },
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                ..
+            } => {
+                if let Some(from) = from {
+                    self.filter_tables(from)?;
+                }
+                let columns = self.filter_target(table, selection)?;
+                match assigned(assignments).intersection(&columns).next() {
+                    Some(column) => {
+                        ControlFlow::Break(format!("cannot update filtered column {}", column))
+                    }
+                    None => ControlFlow::Continue(()),
+                }
+            }
+            Statement::Delete(delete) => {
+                if let Some(using) = &mut delete.using {
+                    for table_with_joins in using {
+                        self.filter_tables(table_with_joins)?;
+                    }
+                }
+                let from = match &delete.from {
+                    FromTable::WithFromKeyword(from) | FromTable::WithoutKeyword(from) => from,
+                };
+                if from.len() != 1 || !delete.tables.is_empty() {
+                    return ControlFlow::Break("cannot filter multiple-table DELETE".to_string());
+                }
+                self.filter_target(&from[0], &mut delete.selection)?;
+                ControlFlow::Continue(())
+            }
+            // Tables dropped or copied are not relations of the `Visitor`.
+            Statement::Drop { names, .. } => {
+                match names.iter().find_map(|name| self.filter(name)) {
+                    Some(filter) => {
+                        ControlFlow::Break(format!("cannot filter table {}", filter.table))
+                    }
+                    None => ControlFlow::Continue(()),
+                }
+            }
+            Statement::Copy {
+                source: CopySource::Table { table_name, .. },
+                ..
+            } if self.filter(table_name).is_some() => {
+                ControlFlow::Break(format!("cannot filter table {}", table(table_name)))
+            }
+            _ => match Visit::visit(statement, &mut References(self.filters)) {
+                ControlFlow::Break(table) => {
+                    ControlFlow::Break(format!("cannot filter table {}", table))
+                }
+                ControlFlow::Continue(()) => ControlFlow::Continue(()),
+            },
+        }
+    }
+}
+
+/// Returns `query` rewritten with row `filters`, `None` if unchanged, or
+/// why it cannot be rewritten.
+fn rewrite(query: &str, filters: &[RowFilter]) -> Result<Option<String>, String> {
+    let mut statements = parse(query)?;
+    if let ControlFlow::Break(reason) = Visit::visit(&statements, &mut SqlFunctions) {
+        return Err(reason);
+    }
+    let mut rewriter = Rewriter {
+        filters,
+        rewritten: false,
+    };
+    if let ControlFlow::Break(reason) = VisitMut::visit(&mut statements, &mut rewriter) {
+        return Err(reason);
+    }
+    if !rewriter.rewritten {
+        return Ok(None);
+    }
+    let statements: Vec<String> = statements.iter().map(ToString::to_string).collect();
+    Ok(Some(statements.join("; ")))
+}
+
+/// An `SQLMessageHandler` rewriting statements of `Query` and `Parse`
+/// messages with row filters, and denying those which cannot be rewritten.
+///
+/// Predicates are defined by table in `authorization.row_filters`, e.g.
+/// `orders = "tenant_id = {session.tenant}"`. Session variables are
+/// parameters of the `StartupMessage`, e.g. `user`, and those prefixed by
+/// `fern.`, e.g. `fern.tenant` for `tenant`, which are consumed by Fern:
+/// as clients set them, they should only come from trusted clients. Other
+/// parameters prevail, e.g. `fern.user` never stands for `user`.
+#[derive(Debug, Default)]
+pub struct RowFilterHandler {
+    /// Predicate templates, by table.
+    templates: Vec<(Table, String)>,
+
+    /// Row filters of the session, once started.
+    filters: Vec<RowFilter>,
+
+    /// Denials, shared with a `DenialHandler`.
+    denials: Denials,
+}
+
+impl RowFilterHandler {
+    /// Records denials in `denials`, e.g. shared with other handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+
+    /// Returns the denials, to be shared with a `DenialHandler`.
+    pub fn denials(&self) -> Denials {
+        self.denials.clone()
+    }
+
+    /// Starts the session, with `parameters` of its `StartupMessage`,
+    /// returning its new length if session parameters were consumed.
+    fn start(&mut self, parameters: &mut Vec<Parameter>) -> Option<usize> {
+        // Note: parameters sent to the server prevail on `fern.` ones, so that
+        // e.g. `user` is always the user authenticated by the server.
+        let mut session = HashMap::new();
+        let (fern, server): (Vec<&Parameter>, Vec<&Parameter>) =
+            parameters.iter().partition(|parameter| {
+                parameter
+                    .name
+                    .starts_with(SESSION_PARAMETER_PREFIX.as_bytes())
+            });
+        for parameter in fern.into_iter().chain(server) {
+            let name = String::from_utf8_lossy(&parameter.name);
+            let name = name.strip_prefix(SESSION_PARAMETER_PREFIX).unwrap_or(&name);
+            let value = String::from_utf8_lossy(&parameter.value).into_owned();
+            session.insert(name.to_string(), value);
+        }
+        self.filters = self
+            .templates
+            .iter()
+            .map(|(table, template)| RowFilter {
+                table: table.clone(),
+                predicate: substitute(template, &session).and_then(|text| parse_expr(&text)),
+            })
+            .collect();
+
+        let count = parameters.len();
+        parameters.retain(|parameter| {
+            !parameter
+                .name
+                .starts_with(SESSION_PARAMETER_PREFIX.as_bytes())
+        });
+        if parameters.len() == count {
+            return None;
+        }
+        // Length and protocol version, parameters, and terminator.
+        let length = parameters
+            .iter()
+            .map(|parameter| parameter.name.len() + parameter.value.len() + 2)
+            .sum::<usize>();
+        Some(8 + length + 1)
+    }
+
+    /// Returns the statement to run instead of `query`, or why it is denied.
+    fn rewrite(&self, query: Bytes) -> Result<Bytes, String> {
+        if self.filters.is_empty() {
+            return Ok(query);
+        }
+        match rewrite(&String::from_utf8_lossy(&query), &self.filters) {
+            Ok(None) => Ok(query),
+            Ok(Some(rewritten)) => {
+                log::trace!("rewritten statement with row filters: {}", rewritten);
+                Ok(Bytes::from(rewritten))
+            }
+            Err(reason) => {
+                log::warn!("denying statement which cannot be filtered - {}", reason);
+                Err(format!("permission denied by Fern, {}", reason))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for RowFilterHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        let mut templates = vec![];
+        if let Ok(filters) = config.get_table("authorization.row_filters") {
+            for (name, predicate) in filters {
+                let reference = match parse_table(&name) {
+                    Ok(reference) => reference,
+                    Err(err) => {
+                        log::error!("ignoring row filter of invalid table '{}' - {}", name, err);
+                        continue;
+                    }
+                };
+                match predicate.into_string() {
+                    Ok(predicate) => templates.push((reference, predicate)),
+                    Err(err) => log::error!("ignoring invalid row filter of '{}' - {}", name, err),
+                }
+            }
+        }
+
+        Self {
+            templates,
+            ..Self::default()
+        }
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::StartupMessage {
+                frame_length,
+                mut parameters,
+            } => {
+                let frame_length = self.start(&mut parameters).unwrap_or(frame_length);
+                Some(frontend::Message::StartupMessage {
+                    frame_length,
+                    parameters,
+                })
+            }
+            frontend::Message::Query(query) => match self.rewrite(query) {
+                Ok(query) => Some(frontend::Message::Query(query)),
+                Err(message) => {
+                    self.denials.deny_query(message);
+                    None
+                }
+            },
+            frontend::Message::Parse {
+                stmt_name,
+                query,
+                parameters_types,
+            } => match self.rewrite(query) {
+                Ok(query) => Some(frontend::Message::Parse {
+                    stmt_name,
+                    query,
+                    parameters_types,
+                }),
+                Err(message) => {
+                    self.denials.deny_parse(message);
+                    None
+                }
+            },
+            _ => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend;
+    use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+    use std::collections::HashMap;
+
+    use super::{rewrite, substitute, RowFilter, RowFilterHandler};
+    use crate::{Denials, SQLHandlerConfig, Table};
+
+    /// Helper function filtering `orders` of tenant "acme".
+    fn filters() -> Vec<RowFilter> {
+        vec![RowFilter {
+            table: Table {
+                schema: None,
+                name: "orders".to_string(),
+            },
+            predicate: super::parse_expr("tenant_id = 'acme'"),
+        }]
+    }
+
+    #[test]
+    fn valid_substitute() {
+        let session = HashMap::from([("tenant".to_string(), "o'neil".to_string())]);
+        let substituted = substitute("tenant_id = {session.tenant} AND NOT archived", &session);
+        assert_eq!(
+            Ok("tenant_id = 'o''neil' AND NOT archived".to_string()),
+            substituted
+        );
+        assert!(substitute("tenant_id = {session.team}", &session).is_err());
+        assert!(substitute("tenant_id = {session.tenant", &session).is_err());
+    }
+
+    #[test]
+    fn valid_rewrite() {
+        let filters = filters();
+        let data = vec![
+            (
+                "SELECT o.id, c.name FROM orders o JOIN customers c ON c.id = o.customer_id",
+                "SELECT o.id, c.name FROM (SELECT * FROM orders WHERE tenant_id = 'acme') AS o \
+                 JOIN customers AS c ON c.id = o.customer_id",
+            ),
+            (
+                "SELECT * FROM customers WHERE id IN (SELECT customer_id FROM public.orders)",
+                "SELECT * FROM customers WHERE id IN (SELECT customer_id FROM \
+                 (SELECT * FROM public.orders WHERE tenant_id = 'acme') AS orders)",
+            ),
+            (
+                "UPDATE orders SET status = 'paid' WHERE id = $1",
+                "UPDATE orders SET status = 'paid' WHERE (id = $1) AND (orders.tenant_id = 'acme')",
+            ),
+            (
+                "DELETE FROM orders AS o",
+                "DELETE FROM orders AS o WHERE o.tenant_id = 'acme'",
+            ),
+        ];
+        for (query, expected) in data {
+            assert_eq!(
+                Ok(Some(expected.to_string())),
+                rewrite(query, &filters),
+                "{}",
+                query
+            );
+        }
+        assert_eq!(
+            Ok(None),
+            rewrite("SELECT * FROM customers; BEGIN", &filters)
+        );
+    }
+
+    #[test]
+    fn invalid_rewrite() {
+        let filters = filters();
+        let data = vec![
+            "UPDATE orders SET tenant_id = 'other'",
+            "TRUNCATE orders",
+            "COPY orders TO STDOUT",
+            "CREATE VIEW all_orders AS SELECT * FROM orders",
+            "WITH orders AS (SELECT 1) SELECT * FROM orders",
+            "TABLE orders",
+            "INSERT INTO orders (id) VALUES (1) ON CONFLICT (id) DO UPDATE SET id = 2",
+            "SELECT * FROM customers; DROP TABLE orders",
+            "SELECT * FROM ONLY orders",
+            "SELECT * FROM customers c JOIN ONLY orders ON true",
+            "SELECT * FROM ONLY (orders)",
+            "UPDATE ONLY orders SET status = 'paid'",
+            "SELECT query_to_xml('SELECT * FROM orders', true, false, '')",
+            "SELECT pg_catalog.table_to_xml('orders', true, false, '')",
+            "SELECT * FROM dblink('dbname=shop', 'SELECT id FROM orders') AS t (id int)",
+        ];
+        for query in data {
+            assert!(rewrite(query, &filters).is_err(), "{}", query);
+        }
+
+        let unavailable = vec![RowFilter {
+            table: Table {
+                schema: None,
+                name: "orders".to_string(),
+            },
+            predicate: Err("undefined session variable 'tenant'".to_string()),
+        }];
+        assert!(rewrite("SELECT * FROM orders", &unavailable).is_err());
+    }
+
+    #[tokio::test]
+    async fn filtered_session() {
+        let config = SQLHandlerConfig::builder()
+            .set_override(
+                "authorization.row_filters.orders",
+                "tenant_id = {session.tenant}",
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(crate::has_row_filters(&config));
+        let mut handler = RowFilterHandler::new(&config);
+
+        let parameter = |name: &'static str, value: &'static str| Parameter {
+            name: Bytes::from_static(name.as_bytes()),
+            value: Bytes::from_static(value.as_bytes()),
+        };
+        let msg = frontend::Message::StartupMessage {
+            frame_length: 45,
+            parameters: vec![parameter("user", "alice"), parameter("fern.tenant", "acme")],
+        };
+        let expected = frontend::Message::StartupMessage {
+            frame_length: 20,
+            parameters: vec![parameter("user", "alice")],
+        };
+        assert_eq!(Some(expected), handler.process(msg).await);
+
+        let msg = frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(b"SELECT id FROM orders WHERE id = $1"),
+            parameters_types: vec![],
+        };
+        let expected = frontend::Message::Parse {
+            stmt_name: Bytes::new(),
+            query: Bytes::from_static(
+                b"SELECT id FROM (SELECT * FROM orders WHERE tenant_id = 'acme') AS orders \
+                  WHERE id = $1",
+            ),
+            parameters_types: vec![],
+        };
+        assert_eq!(Some(expected), handler.process(msg).await);
+
+        let (replies, mut answers) = tokio::sync::mpsc::unbounded_channel();
+        let mut handler = handler.with_denials(Denials::new().with_replies(replies));
+        let msg = frontend::Message::Query(Bytes::from_static(b"TRUNCATE orders"));
+        assert_eq!(None, handler.process(msg).await);
+        assert!(matches!(
+            answers.try_recv(),
+            Ok(backend::Message::ErrorResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_parameters_prevail() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("authorization.row_filters.orders", "owner = {session.user}")
+            .unwrap()
+            .build()
+            .unwrap();
+        let parameter = |name: &'static str, value: &'static str| Parameter {
+            name: Bytes::from_static(name.as_bytes()),
+            value: Bytes::from_static(value.as_bytes()),
+        };
+        for parameters in [
+            vec![parameter("user", "me"), parameter("fern.user", "victim")],
+            vec![parameter("fern.user", "victim"), parameter("user", "me")],
+        ] {
+            let mut handler = RowFilterHandler::new(&config);
+            let msg = frontend::Message::StartupMessage {
+                frame_length: 0,
+                parameters,
+            };
+            handler.process(msg).await;
+
+            let msg = frontend::Message::Query(Bytes::from_static(b"SELECT id FROM orders"));
+            let expected = frontend::Message::Query(Bytes::from_static(
+                b"SELECT id FROM (SELECT * FROM orders WHERE owner = 'me') AS orders",
+            ));
+            assert_eq!(Some(expected), handler.process(msg).await);
+        }
+    }
+}
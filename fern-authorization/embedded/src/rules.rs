@@ -0,0 +1,488 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Allow and deny rules, and their evaluation against statement accesses.
+//!
+//! A rule reads `[allow|deny] [<operations>] on <tables>`, e.g.
+//! `SELECT on public.*`, `deny on payments.cards`, or
+//! `SELECT, UPDATE on hr.employees(name, title)`:
+//! - rules allow unless starting with `deny`,
+//! - operations are separated by commas, all of them if omitted or `*`,
+//! - tables are separated by commas, optionally qualified by their schema,
+//!   with `*` matching any characters, and optionally restricted to columns,
+//! - functions are written as tables prefixed by `function:`, e.g.
+//!   `EXECUTE on function:lower, function:count`.
+//!
+//! An access is allowed if an allow rule matches it, and no deny rule does.
+//! Functions called are only allowed by rules of functions, as they may
+//! read or write anything, e.g. `pg_read_file` or `dblink`, and are denied
+//! by rules of functions or of any table.
+//! As the `search_path` of sessions is unknown, and may be changed by clients,
+//! tables referenced without schema may be in any schema: they only match
+//! allow rules of any schema, e.g. `SELECT on users` or `SELECT on *.users`,
+//! and match deny rules of any schema. An allow rule restricted to columns
+//! only matches accesses to these columns, while a deny rule restricted to
+//! columns matches accesses to any of them, or to all columns.
+
+use std::fmt;
+
+use crate::statements::{Access, Table};
+
+/// Effect of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// Tables, and optionally columns, or functions a rule applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TablePattern {
+    /// Whether the pattern applies to functions instead of tables.
+    function: bool,
+
+    /// Schema pattern, any schema if `None`.
+    schema: Option<String>,
+
+    /// Table name pattern.
+    name: String,
+
+    /// Columns, all of them if `None`.
+    columns: Option<Vec<String>>,
+}
+
+/// Returns whether `value` matches `pattern`, where `*` matches any characters.
+pub(crate) fn glob(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, _)) => *last,
+        // No wildcard, exact match.
+        None => return rest.is_empty(),
+    };
+    for part in &parts[..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl TablePattern {
+    /// Parses a table pattern, e.g. `hr.employees(name, title)`, or a
+    /// function pattern, e.g. `function:pg_catalog.*`.
+    fn parse(text: &str) -> Result<Self, String> {
+        let (function, text) = match text.trim().strip_prefix("function:") {
+            Some(name) => (true, name),
+            None => (false, text),
+        };
+        let (table, columns) = match text.split_once('(') {
+            Some(_) if function => return Err(format!("columns of function '{}'", text)),
+            Some((table, columns)) => {
+                let columns = columns
+                    .strip_suffix(')')
+                    .ok_or_else(|| format!("unbalanced parentheses in '{}'", text))?;
+                let columns: Vec<String> = columns
+                    .split(',')
+                    .map(|column| column.trim().to_string())
+                    .filter(|column| !column.is_empty())
+                    .collect();
+                (table.trim(), Some(columns))
+            }
+            None => (text.trim(), None),
+        };
+        let (schema, name) = match table.rsplit_once('.') {
+            Some((schema, name)) => (Some(schema.trim().to_string()), name.trim()),
+            None => (None, table),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("invalid table '{}'", table));
+        }
+        Ok(Self {
+            function,
+            schema,
+            name: name.to_string(),
+            columns,
+        })
+    }
+
+    /// Returns whether `table` matches, where tables referenced without
+    /// schema match patterns of any schema, and others only if `conservative`.
+    fn matches_table(&self, table: &Table, conservative: bool) -> bool {
+        if !glob(&self.name, &table.name) {
+            return false;
+        }
+        match (&self.schema, &table.schema) {
+            (None, _) => true,
+            (Some(pattern), Some(schema)) => glob(pattern, schema),
+            (Some(pattern), None) => conservative || pattern == "*",
+        }
+    }
+
+    /// Returns whether the pattern applies to any table.
+    fn is_any_table(&self) -> bool {
+        !self.function && self.name == "*" && matches!(self.schema.as_deref(), None | Some("*"))
+    }
+}
+
+/// An allow or deny rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    effect: Effect,
+
+    /// Uppercase operations, all of them if `None`.
+    operations: Option<Vec<String>>,
+
+    tables: Vec<TablePattern>,
+
+    /// Rule as written.
+    text: String,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Splits `text` at commas outside of parentheses.
+fn split_list(text: &str) -> Vec<&str> {
+    let (mut parts, mut depth, mut start) = (vec![], 0, 0);
+    for (idx, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&text[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+impl Rule {
+    /// Parses a rule, e.g. `deny SELECT on payments.*`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let trimmed = text.trim();
+        let (effect, rest) = match trimmed.split_once(char::is_whitespace) {
+            Some((word, rest)) if word.eq_ignore_ascii_case("allow") => (Effect::Allow, rest),
+            Some((word, rest)) if word.eq_ignore_ascii_case("deny") => (Effect::Deny, rest),
+            _ => (Effect::Allow, trimmed),
+        };
+
+        // Operations are followed by `on`, which may start the rule.
+        let padded = format!(" {}", rest);
+        let idx = padded
+            .to_lowercase()
+            .find(" on ")
+            .ok_or_else(|| format!("missing 'on' in rule '{}'", text))?;
+        let (operations, tables) = (&padded[..idx], &padded[idx + 4..]);
+
+        let operations: Vec<String> = operations
+            .split(',')
+            .map(|operation| operation.trim().to_uppercase())
+            .filter(|operation| !operation.is_empty())
+            .collect();
+        let operations = match operations.iter().any(|operation| operation == "*") {
+            true => None,
+            false if operations.is_empty() => None,
+            false => Some(operations),
+        };
+        let tables = split_list(tables)
+            .into_iter()
+            .map(TablePattern::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            effect,
+            operations,
+            tables,
+            text: trimmed.to_string(),
+        })
+    }
+
+    /// Returns a rule denying any access, standing for an invalid rule.
+    pub fn deny_all(text: &str) -> Self {
+        Self {
+            effect: Effect::Deny,
+            operations: None,
+            tables: vec![TablePattern {
+                function: false,
+                schema: None,
+                name: "*".to_string(),
+                columns: None,
+            }],
+            text: format!("invalid rule '{}'", text),
+        }
+    }
+
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    /// Returns whether the rule applies to `access`.
+    pub fn matches(&self, access: &Access) -> bool {
+        if let Some(operations) = &self.operations {
+            if !operations.contains(&access.operation) {
+                return false;
+            }
+        }
+
+        let table = match (&access.table, &access.function) {
+            (Some(table), _) => table,
+            (None, Some(function)) => {
+                let deny = self.effect == Effect::Deny;
+                return self.tables.iter().any(|pattern| {
+                    (pattern.function && pattern.matches_table(function, deny))
+                        || (deny && pattern.is_any_table())
+                });
+            }
+            // Other accesses to no table are allowed by any rule of the
+            // operation, and only denied by rules of any table.
+            (None, None) => {
+                return match self.effect {
+                    Effect::Allow => true,
+                    Effect::Deny => self.tables.iter().any(TablePattern::is_any_table),
+                }
+            }
+        };
+
+        self.tables.iter().any(|pattern| match self.effect {
+            _ if pattern.function => false,
+            Effect::Allow => {
+                pattern.matches_table(table, false)
+                    && match (&pattern.columns, &access.columns) {
+                        (None, _) => true,
+                        (Some(_), None) => false,
+                        (Some(allowed), Some(columns)) => {
+                            columns.iter().all(|column| allowed.contains(column))
+                        }
+                    }
+            }
+            Effect::Deny => {
+                pattern.matches_table(table, true)
+                    && match (&pattern.columns, &access.columns) {
+                        (None, _) | (_, None) => true,
+                        (Some(denied), Some(columns)) => {
+                            columns.iter().any(|column| denied.contains(column))
+                        }
+                    }
+            }
+        })
+    }
+}
+
+/// Evaluates `accesses` against `rules`, returning the first access denied
+/// and why, if any. Accesses matching no rule are allowed if `default_allow`.
+pub fn evaluate<'a>(
+    rules: &[Rule],
+    accesses: &'a [Access],
+    default_allow: bool,
+) -> Result<(), (&'a Access, String)> {
+    for access in accesses {
+        if let Some(rule) = rules
+            .iter()
+            .find(|rule| rule.effect == Effect::Deny && rule.matches(access))
+        {
+            return Err((access, format!("denied by rule '{}'", rule)));
+        }
+        let allowed = rules
+            .iter()
+            .any(|rule| rule.effect == Effect::Allow && rule.matches(access));
+        if !allowed && !default_allow {
+            return Err((access, "no rule allows it".to_string()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::statements::analyze;
+
+    /// Helper function evaluating `query` against `rules`, denied by default.
+    fn allowed(rules: &[&str], query: &str) -> bool {
+        let rules: Vec<Rule> = rules
+            .iter()
+            .map(|rule| Rule::parse(rule).unwrap())
+            .collect();
+        evaluate(&rules, &analyze(query).unwrap(), false).is_ok()
+    }
+
+    #[test]
+    fn valid_glob() {
+        assert!(glob("*", "users"));
+        assert!(glob("users", "users"));
+        assert!(!glob("users", "users2"));
+        assert!(glob("user*", "users"));
+        assert!(glob("*_archive", "orders_archive"));
+        assert!(glob("a*b*c", "axxbyyc"));
+        assert!(!glob("a*b*c", "axxcyyb"));
+    }
+
+    #[test]
+    fn valid_parse() {
+        let rule = Rule::parse("SELECT on public.*").unwrap();
+        assert_eq!(Effect::Allow, rule.effect());
+        assert_eq!(Some(vec!["SELECT".to_string()]), rule.operations);
+
+        let rule = Rule::parse("deny on payments.cards").unwrap();
+        assert_eq!(Effect::Deny, rule.effect());
+        assert_eq!(None, rule.operations, "all operations");
+
+        let rule = Rule::parse("allow select, update ON hr.employees(name, title), hr.teams");
+        let rule = rule.unwrap();
+        assert_eq!(2, rule.tables.len());
+        let columns = Some(vec!["name".to_string(), "title".to_string()]);
+        assert_eq!(columns, rule.tables[0].columns);
+        assert_eq!(
+            Some(vec!["SELECT".into(), "UPDATE".into()]),
+            rule.operations
+        );
+    }
+
+    #[test]
+    fn invalid_parse() {
+        assert!(Rule::parse("SELECT public.*").is_err(), "no on");
+        assert!(Rule::parse("SELECT on").is_err(), "no table");
+        assert!(Rule::parse("SELECT on users(email").is_err(), "parenthesis");
+    }
+
+    #[test]
+    fn valid_evaluate() {
+        let rules = ["SELECT on public.*", "deny on payments.cards"];
+        assert!(allowed(&rules, "SELECT * FROM public.users"));
+        assert!(
+            !allowed(&rules, "SELECT * FROM users"),
+            "unqualified table may resolve to any schema"
+        );
+        assert!(allowed(&rules, "SELECT 1"), "no table");
+        assert!(!allowed(&rules, "DELETE FROM users"), "operation");
+        assert!(!allowed(&rules, "SELECT * FROM app.users"), "schema");
+        assert!(
+            !allowed(&rules, "SELECT * FROM cards"),
+            "any schema when denied"
+        );
+        let query = "SELECT * FROM users WHERE id IN (SELECT user_id FROM payments.cards)";
+        assert!(!allowed(&rules, query), "subquery");
+        assert!(allowed(&rules, "BEGIN"), "transaction control");
+        assert!(!allowed(&rules, "SET ROLE admin"));
+
+        let rules = ["SELECT on hr.employees(id, name)"];
+        assert!(allowed(
+            &rules,
+            "SELECT name FROM hr.employees WHERE id = 1"
+        ));
+        assert!(!allowed(&rules, "SELECT salary FROM hr.employees"));
+        assert!(!allowed(&rules, "SELECT * FROM hr.employees"), "wildcard");
+
+        let rules = ["* on hr.*", "deny SELECT on hr.employees(salary)"];
+        assert!(allowed(&rules, "SELECT name FROM hr.employees"));
+        assert!(
+            !allowed(&rules, "SELECT e.salary FROM hr.employees e"),
+            "alias"
+        );
+        assert!(!allowed(&rules, "SELECT * FROM hr.employees"), "wildcard");
+        assert!(allowed(&rules, "UPDATE hr.employees SET salary = 0"));
+    }
+
+    #[test]
+    fn search_path_changes() {
+        let rules = ["SELECT on public.*"];
+        let query = "SELECT set_config('search_path', 'private', false)";
+        assert!(!allowed(&rules, query), "function");
+        assert!(!allowed(&rules, "SELECT * FROM secret"));
+        assert!(allowed(&rules, "SELECT * FROM public.secret"));
+
+        let rules = ["SELECT on users", "SELECT on *.orders"];
+        assert!(allowed(&rules, "SELECT * FROM users"));
+        assert!(allowed(&rules, "SELECT * FROM orders"));
+        assert!(allowed(&rules, "SELECT * FROM private.users"));
+    }
+
+    #[test]
+    fn functions() {
+        let rules = ["SELECT on public.*"];
+        for query in [
+            "SELECT pg_read_file('/etc/passwd')",
+            "SELECT dblink('host=db', 'SELECT 1')",
+            "SELECT set_config('role', 'admin', false)",
+            "SELECT * FROM public.users WHERE id = pg_catalog.abs(-1)",
+            "SELECT * FROM generate_series(1, 3)",
+        ] {
+            assert!(!allowed(&rules, query), "{}", query);
+        }
+
+        let rules = [
+            "SELECT on public.*",
+            "EXECUTE on function:count, function:pg_catalog.*",
+            "deny on function:pg_catalog.pg_read_*",
+        ];
+        assert!(allowed(&rules, "SELECT count(*) FROM public.users"));
+        assert!(allowed(&rules, "SELECT pg_catalog.now()"));
+        assert!(
+            !allowed(&rules, "SELECT now()"),
+            "unqualified function may resolve to any schema"
+        );
+        assert!(!allowed(&rules, "SELECT pg_read_file('/etc/passwd')"));
+        assert!(!allowed(
+            &rules,
+            "SELECT pg_catalog.pg_read_file('/etc/passwd')"
+        ));
+        assert!(!allowed(&["* on *"], "SELECT lower('A')"), "tables only");
+        assert!(!allowed(
+            &["* on function:*", "deny on *"],
+            "SELECT lower('A')"
+        ));
+
+        assert!(Rule::parse("EXECUTE on function:lower(x)").is_err());
+        let accesses = analyze("SELECT lower(email) FROM users").unwrap();
+        assert_eq!("EXECUTE on function:lower", accesses[1].to_string());
+    }
+
+    #[test]
+    fn whole_row_references() {
+        let rules = ["SELECT on *", "deny on users(ssn)"];
+        assert!(allowed(&rules, "SELECT u.id FROM users u"));
+        for query in [
+            "SELECT u FROM users u",
+            "SELECT row_to_json(u) FROM users u",
+            "SELECT users FROM users",
+            "SELECT public.users FROM public.users",
+            "SELECT row_to_json(u.*) FROM users u",
+            "SELECT (u).ssn FROM users u",
+            "SELECT id FROM orders WHERE EXISTS (SELECT 1 FROM users u WHERE u = NULL)",
+        ] {
+            assert!(!allowed(&rules, query), "{}", query);
+        }
+        let accesses = analyze("SELECT u FROM users u").unwrap();
+        assert_eq!(None, accesses[0].columns, "all columns");
+    }
+
+    #[test]
+    fn default_allow() {
+        let rules = vec![Rule::parse("deny on payments.cards").unwrap()];
+        let accesses = analyze("SELECT * FROM users").unwrap();
+        assert!(evaluate(&rules, &accesses, true).is_ok());
+        let accesses = analyze("SELECT * FROM payments.cards").unwrap();
+        let (access, reason) = evaluate(&rules, &accesses, true).unwrap_err();
+        assert_eq!("SELECT on payments.cards", access.to_string());
+        assert_eq!("denied by rule 'deny on payments.cards'", reason);
+        let accesses = analyze("SELECT * FROM cards").unwrap();
+        assert!(
+            evaluate(&rules, &accesses, true).is_err(),
+            "unqualified table may resolve to any schema"
+        );
+    }
+}
@@ -0,0 +1,689 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Analysis of SQL statements, extracting the accesses they request: an
+//! operation, e.g. `SELECT` or `DELETE`, on a table, with the columns
+//! involved when known.
+//!
+//! Tables written by `INSERT`, `UPDATE`, `DELETE`, and `COPY ... FROM`
+//! statements are accessed with the statement operation, as are those of
+//! data-modifying CTEs, e.g. `WITH d AS (UPDATE users ...) SELECT ...`,
+//! and tables created by `SELECT INTO` with `CREATE`, while all other
+//! tables they reference, e.g. in subqueries, are accessed with `SELECT`.
+//! Functions called, which may read or write anything, are accessed with
+//! `EXECUTE`.
+//! Columns are attributed conservatively: unqualified columns are deemed
+//! to belong to every table of a statement, and wildcards to all columns,
+//! as well as references to whole rows, e.g. `SELECT row_to_json(u) FROM
+//! users u`, or to columns which may be whole rows, e.g. `SELECT status
+//! FROM status`.
+
+use sqlparser::ast::{
+    visit_relations, AssignmentTarget, CopySource, Expr, FromTable, FunctionArg, FunctionArgExpr,
+    FunctionArguments, Ident, ObjectName, ObjectType, Query, SelectItem, SetExpr, Statement,
+    TableFactor, TableWithJoins, Visit, Visitor,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::ControlFlow;
+
+/// A table, optionally qualified by its schema.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Table {
+    pub schema: Option<String>,
+    pub name: String,
+}
+
+impl Table {
+    /// Returns whether `reference` may designate this table, where tables
+    /// without schema may be in any schema.
+    pub(crate) fn matches(&self, reference: &Table) -> bool {
+        self.name == reference.name
+            && match (&self.schema, &reference.schema) {
+                (Some(schema), Some(other)) => schema == other,
+                _ => true,
+            }
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.schema {
+            Some(schema) => write!(f, "{}.{}", schema, self.name),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// An access requested by a statement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access {
+    /// Operation, as the uppercase keyword of the statement, e.g. `SELECT`.
+    pub operation: String,
+
+    /// Table accessed, if any, e.g. none for `SELECT 1` or `SET`.
+    pub table: Option<Table>,
+
+    /// Function called, if any, named as tables are, e.g. `pg_read_file`.
+    pub function: Option<Table>,
+
+    /// Columns involved, sorted, or `None` when all or unknown.
+    pub columns: Option<Vec<String>>,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.table, &self.function) {
+            (Some(table), _) => write!(f, "{} on {}", self.operation, table)?,
+            (None, Some(function)) => write!(f, "{} on function:{}", self.operation, function)?,
+            (None, None) => f.write_str(&self.operation)?,
+        }
+        match &self.columns {
+            Some(columns) if self.table.is_some() => write!(f, "({})", columns.join(", ")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the normalized name of `ident`, as unquoted identifiers are
+/// case-insensitive.
+pub(crate) fn normalize(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+/// Returns the table named `name`, ignoring any database qualifier.
+pub(crate) fn table(name: &ObjectName) -> Table {
+    let mut parts = name.0.iter().rev().map(normalize);
+    let name = parts.next().unwrap_or_default();
+    Table {
+        schema: parts.next(),
+        name,
+    }
+}
+
+/// Parses the table named `name`, e.g. in settings.
+pub(crate) fn parse_table(name: &str) -> Result<Table, String> {
+    Parser::new(&PostgreSqlDialect {})
+        .try_with_sql(name)
+        .and_then(|mut parser| parser.parse_object_name(false))
+        .map(|name| table(&name))
+        .map_err(|err| err.to_string())
+}
+
+/// Collects tables, aliases, and columns referenced in a statement.
+#[derive(Debug, Default)]
+struct Collector {
+    /// Tables referenced, in order, including names of CTEs.
+    relations: Vec<Table>,
+
+    /// Names of CTEs, which are not tables.
+    ctes: HashSet<String>,
+
+    /// Tables by alias.
+    aliases: HashMap<String, Table>,
+
+    /// Columns referenced by a qualifier, either a table name or an alias.
+    qualified: Vec<(String, String)>,
+
+    /// Columns referenced without qualifier.
+    unqualified: BTreeSet<String>,
+
+    /// Qualifiers of wildcards, `None` for unqualified ones.
+    wildcards: Vec<Option<String>>,
+
+    /// References which may be to whole rows of a table, by name or alias.
+    whole_rows: Vec<Table>,
+
+    /// Statements nested in queries, i.e. `INSERT` and `UPDATE` of CTEs.
+    writes: Vec<Statement>,
+
+    /// Tables created by `SELECT INTO`.
+    created: Vec<Table>,
+
+    /// Functions called, including table functions.
+    functions: Vec<Table>,
+}
+
+impl Collector {
+    /// Collects wildcards of `projection`.
+    fn projection(&mut self, projection: &[SelectItem]) {
+        for item in projection {
+            match item {
+                SelectItem::Wildcard(_) => self.wildcards.push(None),
+                SelectItem::QualifiedWildcard(name, _) => {
+                    let qualifier = name.0.last().map(normalize);
+                    self.wildcards.push(qualifier);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Collects wildcards of the projections of `body`, and the tables it
+    /// writes or creates.
+    fn set_expr(&mut self, body: &SetExpr) {
+        match body {
+            SetExpr::Select(select) => {
+                self.projection(&select.projection);
+                if let Some(into) = &select.into {
+                    self.created.push(table(&into.name));
+                }
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                self.set_expr(left);
+                self.set_expr(right);
+            }
+            SetExpr::Insert(statement) | SetExpr::Update(statement) => {
+                self.writes.push(statement.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns whether `qualifier` designates `table`.
+    fn designates(&self, qualifier: &str, table: &Table) -> bool {
+        match self.aliases.get(qualifier) {
+            Some(aliased) => aliased == table,
+            None => qualifier == table.name,
+        }
+    }
+
+    /// Returns the columns of `table` referenced, or `None` for all.
+    fn columns(&self, table: &Table) -> Option<Vec<String>> {
+        let wildcard = self.wildcards.iter().any(|qualifier| match qualifier {
+            Some(qualifier) => self.designates(qualifier, table),
+            None => true,
+        });
+        let whole_row = self
+            .whole_rows
+            .iter()
+            .any(|reference| match &reference.schema {
+                Some(_) => table.matches(reference),
+                None => self.designates(&reference.name, table),
+            });
+        if wildcard || whole_row {
+            return None;
+        }
+
+        let mut columns = self.unqualified.clone();
+        for (qualifier, column) in &self.qualified {
+            if self.designates(qualifier, table) {
+                columns.insert(column.clone());
+            }
+        }
+        Some(columns.into_iter().collect())
+    }
+
+    /// Returns tables referenced, other than CTEs, without duplicates.
+    fn tables(&self) -> Vec<Table> {
+        let mut tables: Vec<Table> = vec![];
+        for table in &self.relations {
+            let cte = table.schema.is_none() && self.ctes.contains(&table.name);
+            if !cte && !tables.contains(table) {
+                tables.push(table.clone());
+            }
+        }
+        tables
+    }
+}
+
+impl Visitor for Collector {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<Self::Break> {
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                self.ctes.insert(normalize(&cte.alias.name));
+            }
+        }
+        self.set_expr(&query.body);
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<Self::Break> {
+        self.relations.push(table(relation));
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<Self::Break> {
+        match table_factor {
+            TableFactor::Table {
+                name,
+                args: Some(_),
+                ..
+            }
+            | TableFactor::Function { name, .. } => self.functions.push(table(name)),
+            _ => {}
+        }
+        if let TableFactor::Table {
+            name,
+            alias: Some(alias),
+            ..
+        } = table_factor
+        {
+            self.aliases.insert(normalize(&alias.name), table(name));
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<Self::Break> {
+        match expr {
+            Expr::Identifier(ident) => {
+                self.unqualified.insert(normalize(ident));
+                self.whole_rows
+                    .push(table(&ObjectName(vec![ident.clone()])));
+            }
+            Expr::CompoundIdentifier(idents) if idents.len() >= 2 => {
+                let column = normalize(&idents[idents.len() - 1]);
+                let qualifier = normalize(&idents[idents.len() - 2]);
+                self.qualified.push((qualifier, column));
+                // Note: `schema.table` may be a whole row, as `table.column`.
+                self.whole_rows.push(table(&ObjectName(idents.clone())));
+            }
+            Expr::QualifiedWildcard(name, _) => {
+                self.wildcards.push(name.0.last().map(normalize));
+            }
+            Expr::Function(function) => {
+                self.functions.push(table(&function.name));
+                if let FunctionArguments::List(list) = &function.args {
+                    for arg in &list.args {
+                        let arg = match arg {
+                            FunctionArg::Named { arg, .. }
+                            | FunctionArg::ExprNamed { arg, .. }
+                            | FunctionArg::Unnamed(arg) => arg,
+                        };
+                        if let FunctionArgExpr::QualifiedWildcard(name) = arg {
+                            self.wildcards.push(name.0.last().map(normalize));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Returns tables of `from` clauses.
+fn from_tables(from: &[TableWithJoins]) -> Vec<Table> {
+    from.iter()
+        .filter_map(|table_with_joins| match &table_with_joins.relation {
+            TableFactor::Table { name, .. } => Some(table(name)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns names of `columns`, or `None` for all if empty.
+fn named(columns: &[Ident]) -> Option<Vec<String>> {
+    let columns: BTreeSet<String> = columns.iter().map(normalize).collect();
+    match columns.is_empty() {
+        true => None,
+        false => Some(columns.into_iter().collect()),
+    }
+}
+
+/// Returns the operation of `statement`, as its first keyword.
+pub(crate) fn operation(statement: &Statement) -> String {
+    match statement {
+        Statement::Query(_) => "SELECT".to_string(),
+        Statement::Copy { to: true, .. } => "SELECT".to_string(),
+        Statement::Copy { to: false, .. } => "INSERT".to_string(),
+        _ => statement
+            .to_string()
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase(),
+    }
+}
+
+/// Returns the accesses requested by `statement`.
+fn accesses(statement: &Statement) -> Vec<Access> {
+    match statement {
+        // Transaction control requests no access.
+        Statement::StartTransaction { .. }
+        | Statement::Commit { .. }
+        | Statement::Rollback { .. }
+        | Statement::Savepoint { .. }
+        | Statement::ReleaseSavepoint { .. } => return vec![],
+        // The explained statement is executed by `EXPLAIN ANALYZE`, and
+        // its plan discloses data anyway.
+        Statement::Explain { statement, .. } => return accesses(statement),
+        _ => {}
+    }
+
+    let mut collector = Collector::default();
+    let _ = statement.visit(&mut collector);
+    let returning = match statement {
+        Statement::Insert(insert) => insert.returning.as_ref(),
+        Statement::Update { returning, .. } => returning.as_ref(),
+        Statement::Delete(delete) => delete.returning.as_ref(),
+        _ => None,
+    };
+    if let Some(returning) = returning {
+        collector.projection(returning);
+    }
+    let operation = operation(statement);
+
+    // Tables accessed with the statement operation, and their columns if known.
+    let targets: Vec<(Table, Option<Vec<String>>)> = match statement {
+        Statement::Insert(insert) => {
+            vec![(table(&insert.table_name), named(&insert.columns))]
+        }
+        Statement::Update {
+            table: target,
+            assignments,
+            ..
+        } => {
+            let mut columns: Vec<Ident> = vec![];
+            for assignment in assignments {
+                if let AssignmentTarget::ColumnName(name) = &assignment.target {
+                    columns.extend(name.0.last().cloned());
+                }
+            }
+            // Columns of updated rows read, e.g. in `WHERE` clauses, are involved too.
+            from_tables(std::slice::from_ref(target))
+                .into_iter()
+                .map(|target| {
+                    let columns = match (named(&columns), collector.columns(&target)) {
+                        (Some(mut written), Some(read)) => {
+                            written.extend(read);
+                            written.sort();
+                            written.dedup();
+                            Some(written)
+                        }
+                        _ => None,
+                    };
+                    (target, columns)
+                })
+                .collect()
+        }
+        Statement::Delete(delete) => {
+            let from = match &delete.from {
+                FromTable::WithFromKeyword(from) | FromTable::WithoutKeyword(from) => from,
+            };
+            let mut tables: Vec<Table> = delete.tables.iter().map(table).collect();
+            tables.extend(from_tables(from));
+            tables.into_iter().map(|target| (target, None)).collect()
+        }
+        Statement::Copy {
+            source:
+                CopySource::Table {
+                    table_name,
+                    columns,
+                },
+            ..
+        } => vec![(table(table_name), named(columns))],
+        Statement::Drop {
+            object_type: ObjectType::Table | ObjectType::View,
+            names,
+            ..
+        } => names.iter().map(|name| (table(name), None)).collect(),
+        Statement::Query(_) | Statement::Copy { .. } => vec![],
+        // Other statements access all tables they reference.
+        _ => collector
+            .tables()
+            .into_iter()
+            .map(|target| (target, None))
+            .collect(),
+    };
+
+    let mut accesses: Vec<Access> = targets
+        .into_iter()
+        .map(|(target, columns)| Access {
+            operation: operation.clone(),
+            table: Some(target),
+            function: None,
+            columns,
+        })
+        .collect();
+
+    // Tables created by `SELECT INTO`, and written by data-modifying CTEs.
+    let created = collector.created.iter().map(|target| Access {
+        operation: "CREATE".to_string(),
+        table: Some(target.clone()),
+        function: None,
+        columns: None,
+    });
+    let written = collector.writes.iter().flat_map(self::accesses);
+    for access in created.chain(written).collect::<Vec<_>>() {
+        if !accesses.contains(&access) {
+            accesses.push(access);
+        }
+    }
+
+    // Tables read by the statement.
+    for table in collector.tables() {
+        if accesses
+            .iter()
+            .any(|access| access.table.as_ref() == Some(&table))
+        {
+            continue;
+        }
+        accesses.push(Access {
+            operation: "SELECT".to_string(),
+            columns: collector.columns(&table),
+            table: Some(table),
+            function: None,
+        });
+    }
+
+    for function in collector.functions {
+        let access = Access {
+            operation: "EXECUTE".to_string(),
+            table: None,
+            function: Some(function),
+            columns: None,
+        };
+        if !accesses.contains(&access) {
+            accesses.push(access);
+        }
+    }
+
+    if accesses.is_empty() {
+        accesses.push(Access {
+            operation,
+            table: None,
+            function: None,
+            columns: None,
+        });
+    }
+    accesses
+}
+
+/// Parses statements of `query`, or returns why it cannot be analyzed.
+pub(crate) fn parse(query: &str) -> Result<Vec<Statement>, String> {
+    // `COPY ... FROM` is only parsed when terminated, after which any text
+    // is taken as inline data, and would hide subsequent statements.
+    let terminated;
+    let query = match query.trim_end() {
+        trimmed if trimmed.ends_with(';') => trimmed,
+        trimmed => {
+            terminated = format!("{};", trimmed);
+            &terminated
+        }
+    };
+    let statements =
+        Parser::parse_sql(&PostgreSqlDialect {}, query).map_err(|err| err.to_string())?;
+    if statements
+        .iter()
+        .any(|statement| matches!(statement, Statement::Copy { to: false, .. }))
+    {
+        let tokens = Tokenizer::new(&PostgreSqlDialect {}, query)
+            .tokenize()
+            .map_err(|err| err.to_string())?;
+        if tokens
+            .iter()
+            .filter(|token| **token == Token::SemiColon)
+            .count()
+            > 1
+        {
+            return Err("COPY FROM must be the only statement of a query".to_string());
+        }
+    }
+    // Note: `ONLY` is parsed as a table aliased as the table it applies to,
+    // e.g. `FROM ONLY users` as `FROM only AS users`, hiding `users`.
+    let only = visit_relations(&statements, |relation| match relation.0.as_slice() {
+        [ident] if ident.quote_style.is_none() && ident.value.eq_ignore_ascii_case("only") => {
+            ControlFlow::Break(())
+        }
+        _ => ControlFlow::Continue(()),
+    });
+    if only.is_break() {
+        return Err("ONLY is not supported".to_string());
+    }
+    Ok(statements)
+}
+
+/// Returns the accesses requested by statements of `query`, or why it
+/// cannot be analyzed.
+pub fn analyze(query: &str) -> Result<Vec<Access>, String> {
+    Ok(parse(query)?.iter().flat_map(accesses).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function returning accesses of `query`, as strings.
+    fn accesses(query: &str) -> Vec<String> {
+        analyze(query)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid_select() {
+        assert_eq!(
+            vec!["SELECT on users(email, id)"],
+            accesses("SELECT id, email FROM users")
+        );
+        assert_eq!(
+            vec!["SELECT on public.users"],
+            accesses("SELECT * FROM public.users")
+        );
+        assert_eq!(vec!["SELECT"], accesses("SELECT 1"));
+        assert_eq!(
+            vec!["EXECUTE on function:pg_read_file"],
+            accesses("SELECT pg_read_file('/etc/passwd')")
+        );
+        assert_eq!(
+            vec![
+                "SELECT on users(email)",
+                "EXECUTE on function:count",
+                "EXECUTE on function:lower"
+            ],
+            accesses("SELECT count(*) FROM users WHERE lower(email) = lower($1)")
+        );
+        assert_eq!(
+            vec!["SELECT on users(id)"],
+            accesses("SELECT ID FROM Users")
+        );
+        assert_eq!(
+            vec!["SELECT on Users(ID)"],
+            accesses(r#"SELECT "ID" FROM "Users""#),
+            "quoted identifiers"
+        );
+
+        let query = "SELECT u.email, o.* FROM app.users u JOIN app.orders o ON o.user_id = u.id";
+        assert_eq!(
+            vec!["SELECT on app.users(email, id)", "SELECT on app.orders"],
+            accesses(query)
+        );
+        let query = "WITH recent AS (SELECT * FROM orders) SELECT id FROM recent";
+        assert_eq!(vec!["SELECT on orders"], accesses(query), "CTE");
+        let query = "SELECT name FROM users WHERE id IN (SELECT user_id FROM payments.cards)";
+        assert_eq!(
+            vec![
+                "SELECT on users(id, name, user_id)",
+                "SELECT on payments.cards(id, name, user_id)"
+            ],
+            accesses(query),
+            "unqualified columns"
+        );
+    }
+
+    #[test]
+    fn valid_writes() {
+        let query = "INSERT INTO users (id, email) SELECT id, email FROM staging";
+        assert_eq!(
+            vec!["INSERT on users(email, id)", "SELECT on staging(email, id)"],
+            accesses(query)
+        );
+        let query = "UPDATE users SET email = $1 WHERE id = $2 RETURNING *";
+        assert_eq!(vec!["UPDATE on users"], accesses(query));
+        let query = "UPDATE users SET email = $1 WHERE id = $2";
+        assert_eq!(vec!["UPDATE on users(email, id)"], accesses(query));
+        let query = "DELETE FROM users WHERE id IN (SELECT user_id FROM bans)";
+        assert_eq!(
+            vec!["DELETE on users", "SELECT on bans(id, user_id)"],
+            accesses(query)
+        );
+        assert_eq!(
+            vec!["SELECT on users(email)"],
+            accesses("COPY users (email) TO STDOUT")
+        );
+        assert_eq!(vec!["INSERT on users"], accesses("COPY users FROM STDIN"));
+
+        let query = "WITH d AS (UPDATE public.users SET email = 'x' RETURNING 1) SELECT * FROM d";
+        assert_eq!(
+            vec!["UPDATE on public.users(email)"],
+            accesses(query),
+            "CTE"
+        );
+        let query = "WITH i AS (INSERT INTO logs (id) VALUES ($1) RETURNING id) SELECT * FROM i";
+        assert_eq!(vec!["INSERT on logs(id)"], accesses(query), "CTE");
+        assert_eq!(
+            vec!["CREATE on public.copy", "SELECT on public.users"],
+            accesses("SELECT * INTO public.copy FROM public.users")
+        );
+    }
+
+    #[test]
+    fn valid_other_statements() {
+        assert!(accesses("BEGIN; COMMIT").is_empty(), "transaction control");
+        assert_eq!(
+            vec!["DROP on payments.cards"],
+            accesses("DROP TABLE payments.cards")
+        );
+        assert_eq!(vec!["TRUNCATE on users"], accesses("TRUNCATE users"));
+        assert_eq!(vec!["SET"], accesses("SET search_path = app"));
+        assert_eq!(
+            vec!["DELETE on users"],
+            accesses("EXPLAIN ANALYZE DELETE FROM users")
+        );
+        assert_eq!(
+            vec!["SELECT on users", "DELETE on orders"],
+            accesses("SELECT * FROM users; DELETE FROM orders"),
+            "several statements"
+        );
+    }
+
+    #[test]
+    fn invalid_analyze() {
+        assert!(
+            analyze("COPY users FROM STDIN; DROP TABLE users").is_err(),
+            "statements hidden as inline COPY data"
+        );
+        assert!(analyze("SELEC * FROM users").is_err());
+        for query in [
+            "SELECT * FROM ONLY orders",
+            "SELECT * FROM ONLY (orders)",
+            "SELECT * FROM users JOIN ONLY orders ON true",
+            "UPDATE ONLY orders SET id = 1",
+            "DELETE FROM ONLY orders",
+        ] {
+            assert!(analyze(query).is_err(), "{}", query);
+        }
+        assert!(analyze(r#"SELECT * FROM "only""#).is_ok(), "quoted");
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Caching of policy decisions by input document, for a while.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::client::Decision;
+
+/// Decisions of recent input documents, expiring after a time to live.
+#[derive(Debug)]
+pub struct DecisionCache {
+    /// Decisions, with their expiration, by serialized input document.
+    entries: HashMap<String, (Instant, Decision)>,
+
+    ttl: Duration,
+
+    /// Maximum amount of decisions kept.
+    capacity: usize,
+}
+
+impl DecisionCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// Returns the decision for `input`, unless expired.
+    pub fn get(&self, input: &str) -> Option<&Decision> {
+        match self.entries.get(input) {
+            Some((expiration, decision)) if *expiration > Instant::now() => Some(decision),
+            _ => None,
+        }
+    }
+
+    /// Keeps `decision` for `input`, evicting expired decisions, or the
+    /// closest to expire, when full.
+    pub fn insert(&mut self, input: String, decision: Decision) {
+        if self.capacity == 0 || self.ttl.is_zero() {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&input) {
+            let now = Instant::now();
+            self.entries.retain(|_, (expiration, _)| *expiration > now);
+            if self.entries.len() >= self.capacity {
+                let closest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (expiration, _))| *expiration)
+                    .map(|(input, _)| input.clone());
+                if let Some(closest) = closest {
+                    self.entries.remove(&closest);
+                }
+            }
+        }
+        self.entries
+            .insert(input, (Instant::now() + self.ttl, decision));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DecisionCache;
+    use crate::client::Decision;
+
+    #[test]
+    fn valid_cache() {
+        let mut cache = DecisionCache::new(Duration::from_secs(60), 2);
+        cache.insert("a".to_string(), Decision::allow());
+        cache.insert("b".to_string(), Decision::deny());
+        assert_eq!(Some(&Decision::allow()), cache.get("a"));
+        assert_eq!(Some(&Decision::deny()), cache.get("b"));
+
+        cache.insert("c".to_string(), Decision::allow());
+        assert_eq!(None, cache.get("a"), "closest to expire evicted");
+        assert_eq!(Some(&Decision::allow()), cache.get("c"));
+    }
+
+    #[test]
+    fn expired_cache() {
+        let mut cache = DecisionCache::new(Duration::from_millis(10), 2);
+        cache.insert("a".to_string(), Decision::allow());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, cache.get("a"));
+
+        let mut cache = DecisionCache::new(Duration::ZERO, 2);
+        cache.insert("a".to_string(), Decision::allow());
+        assert_eq!(None, cache.get("a"), "caching disabled");
+    }
+}
@@ -0,0 +1,198 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTP client of the OPA Data API, querying a policy decision for an input
+//! document with `POST /v1/data/<path>`.
+
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+/// Address of OPA, unless defined otherwise.
+const DEFAULT_ADDRESS: &str = "http://127.0.0.1:8181";
+
+/// How long to wait for OPA responses, in milliseconds, unless defined otherwise.
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Maps an error to an `io::Error`.
+fn to_io_error(kind: io::ErrorKind, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(kind, format!("opa - {}", reason))
+}
+
+/// A policy decision.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Decision {
+    pub allow: bool,
+
+    /// Why the statement is denied, if given by the policy.
+    pub reason: Option<String>,
+
+    /// Names of columns to mask in results, i.e. masking obligations.
+    pub mask: Vec<String>,
+}
+
+impl Decision {
+    /// Returns a decision allowing a statement without obligations.
+    pub fn allow() -> Self {
+        Self {
+            allow: true,
+            ..Self::default()
+        }
+    }
+
+    /// Returns a decision denying a statement.
+    pub fn deny() -> Self {
+        Self::default()
+    }
+
+    /// Reads a decision from the `result` of a policy, either a boolean,
+    /// or an object with `allow`, `reason`, and `mask` members.
+    ///
+    /// An undefined `result`, e.g. when no policy exists at the queried
+    /// path, or an object without `allow`, denies the statement.
+    pub fn from_result(result: Option<&Value>) -> io::Result<Self> {
+        match result {
+            None | Some(Value::Null) => Ok(Self::deny()),
+            Some(Value::Bool(allow)) => Ok(Self {
+                allow: *allow,
+                ..Self::default()
+            }),
+            Some(Value::Object(members)) => {
+                let mask = match members.get("mask") {
+                    None | Some(Value::Null) => vec![],
+                    Some(Value::Array(columns)) => columns
+                        .iter()
+                        .map(|column| column.as_str().map(ToString::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| {
+                            to_io_error(io::ErrorKind::InvalidData, "invalid 'mask' columns")
+                        })?,
+                    Some(_) => {
+                        return Err(to_io_error(io::ErrorKind::InvalidData, "invalid 'mask'"))
+                    }
+                };
+                Ok(Self {
+                    allow: members.get("allow").and_then(Value::as_bool) == Some(true),
+                    reason: members
+                        .get("reason")
+                        .and_then(Value::as_str)
+                        .map(ToString::to_string),
+                    mask,
+                })
+            }
+            Some(_) => Err(to_io_error(
+                io::ErrorKind::InvalidData,
+                "decision is neither a boolean nor an object",
+            )),
+        }
+    }
+}
+
+/// An OPA Data API client.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+
+    /// URL of the policy decision, e.g. `http://opa:8181/v1/data/fern/authz`.
+    url: String,
+}
+
+impl Client {
+    /// Creates a client of OPA at `address`, querying the decision of
+    /// document `path`, e.g. `fern/authz`, within `timeout`.
+    pub fn new(address: &str, path: &str, timeout: Duration) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("valid HTTP client settings"),
+            url: format!(
+                "{}/v1/data/{}",
+                address.trim_end_matches('/'),
+                path.trim_matches('/')
+            ),
+        }
+    }
+
+    /// Creates a client from settings found in `config`:
+    /// - `authorization.opa.address`: base URL of OPA, `http://127.0.0.1:8181`
+    ///   by default,
+    /// - `authorization.opa.path`: path of the policy decision document,
+    ///   e.g. `fern/authz`,
+    /// - `authorization.opa.timeout_ms`: how long to wait for a decision,
+    ///   1000 milliseconds by default.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let address = config
+            .get::<String>("authorization.opa.address")
+            .unwrap_or_else(|_| DEFAULT_ADDRESS.to_string());
+        let path = config
+            .get::<String>("authorization.opa.path")
+            .map_err(|err| to_io_error(io::ErrorKind::NotFound, err))?;
+        let timeout = config
+            .get::<u64>("authorization.opa.timeout_ms")
+            .unwrap_or(DEFAULT_TIMEOUT_MS);
+        Ok(Self::new(&address, &path, Duration::from_millis(timeout)))
+    }
+
+    /// Queries the decision for `input` document.
+    pub async fn decide(&self, input: &Value) -> io::Result<Decision> {
+        let response = self
+            .http
+            .post(&self.url)
+            .json(&json!({ "input": input }))
+            .send()
+            .await
+            .map_err(|err| to_io_error(io::ErrorKind::ConnectionRefused, err))?;
+        let status = response.status();
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|err| to_io_error(io::ErrorKind::InvalidData, err))?;
+        if !status.is_success() {
+            let message = body["message"].as_str().unwrap_or_default();
+            return Err(to_io_error(
+                io::ErrorKind::Other,
+                format!("{} {}", status, message),
+            ));
+        }
+        Decision::from_result(body.get("result"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Decision;
+
+    #[test]
+    fn valid_decisions() {
+        assert_eq!(Decision::deny(), Decision::from_result(None).unwrap());
+        let result = json!(true);
+        assert_eq!(
+            Decision::allow(),
+            Decision::from_result(Some(&result)).unwrap()
+        );
+        let result = json!({ "allow": false, "reason": "outside business hours" });
+        let decision = Decision::from_result(Some(&result)).unwrap();
+        assert!(!decision.allow);
+        assert_eq!(Some("outside business hours"), decision.reason.as_deref());
+        let result = json!({ "allow": true, "mask": ["email", "phone"] });
+        let decision = Decision::from_result(Some(&result)).unwrap();
+        assert!(decision.allow);
+        assert_eq!(vec!["email", "phone"], decision.mask);
+        let result = json!({ "mask": ["email"] });
+        assert!(!Decision::from_result(Some(&result)).unwrap().allow);
+    }
+
+    #[test]
+    fn invalid_decisions() {
+        for result in [json!("allow"), json!({ "allow": true, "mask": "email" })] {
+            assert!(Decision::from_result(Some(&result)).is_err());
+        }
+        let result = json!({ "allow": true, "mask": [1] });
+        assert!(Decision::from_result(Some(&result)).is_err());
+    }
+}
@@ -1,8 +1,387 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
+//! Delegated authorization with Open Policy Agent for Fern proxy.
+//!
+//! Statements of `Query` and `Parse` messages are analyzed by an
+//! [`OpaAuthorizationHandler`], and a decision on the accesses they
+//! request is queried from OPA with an input document describing the
+//! session and the statement:
+//!
+//! ```json
+//! {
+//!   "user": "alice",
+//!   "database": "shop",
+//!   "application_name": "psql",
+//!   "client_address": "10.0.0.7:51234",
+//!   "operations": ["SELECT"],
+//!   "tables": ["public.users"],
+//!   "accesses": [
+//!     {"operation": "SELECT", "schema": "public", "table": "users", "columns": ["email", "id"]}
+//!   ]
+//! }
+//! ```
+//!
+//! A decision (see [`Decision`]) either denies the statement, which is then
+//! answered with an `ErrorResponse` of SQLSTATE `42501` as with embedded
+//! authorization, or allows it, possibly with obligations to mask columns
+//! in its results, applied by an [`ObligationHandler`]. Decisions are cached
+//! for a while, and when OPA cannot be reached, statements are either all
+//! denied (fail-closed, the default) or all allowed (fail-open).
+//!
+//! Settings are found in the `authorization.opa` table of `SQLHandlerConfig`.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use fern_authorization::{analyze, Access, Denials};
+use fern_protocol_postgresql::codec::frontend;
+use fern_proxy_interfaces::SQLMessageHandler;
+
+// Re-export.
+pub use cache::DecisionCache;
+pub use client::{Client, Decision};
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use obligations::{ObligationHandler, Obligations};
+
+mod cache;
+mod client;
+mod obligations;
+
+/// Seconds during which decisions are cached, unless defined otherwise.
+const DEFAULT_CACHE_TTL: u64 = 60;
+
+/// Maximum amount of decisions cached, unless defined otherwise.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Returns whether OPA authorization is defined in `config`.
+pub fn is_enabled(config: &SQLHandlerConfig) -> bool {
+    config.get::<String>("authorization.opa.path").is_ok()
+}
+
+/// Returns the input document of a decision on `accesses`, in `session`.
+fn input(session: &Value, accesses: &[Access]) -> Value {
+    let operations: BTreeSet<&str> = accesses
+        .iter()
+        .map(|access| access.operation.as_str())
+        .collect();
+    let tables: BTreeSet<String> = accesses
+        .iter()
+        .filter_map(|access| access.table.as_ref().map(ToString::to_string))
+        .collect();
+    let accesses: Vec<Value> = accesses
+        .iter()
+        .map(|access| {
+            json!({
+                "operation": access.operation,
+                "schema": access.table.as_ref().and_then(|table| table.schema.as_ref()),
+                "table": access.table.as_ref().map(|table| &table.name),
+                "function": access.function.as_ref().map(ToString::to_string),
+                "columns": access.columns,
+            })
+        })
+        .collect();
+
+    let mut input = session.clone();
+    input["operations"] = json!(operations);
+    input["tables"] = json!(tables);
+    input["accesses"] = json!(accesses);
+    input
+}
+
+/// An `SQLMessageHandler` denying statements of `Query` and `Parse`
+/// messages as decided by OPA, and queuing masking obligations of
+/// allowed ones for an `ObligationHandler`.
+///
+/// Statements requesting no access, e.g. transaction control, are allowed
+/// without querying OPA, while statements which cannot be analyzed are
+/// denied.
+#[derive(Debug)]
+pub struct OpaAuthorizationHandler {
+    /// Client of OPA, unless its settings are invalid.
+    client: Option<Client>,
+
+    cache: DecisionCache,
+
+    /// Whether statements are allowed when no decision can be obtained.
+    fail_open: bool,
+
+    /// Session part of input documents.
+    session: Value,
+
+    /// Columns to mask in results of prepared statements, by name.
+    statements: HashMap<Bytes, Vec<String>>,
+
+    /// Denials, shared with a `DenialHandler`.
+    denials: Denials,
+
+    /// Obligations, shared with an `ObligationHandler`.
+    obligations: Obligations,
+}
+
+impl OpaAuthorizationHandler {
+    /// Describes the session as coming from `address`.
+    #[must_use]
+    pub fn with_client_address(mut self, address: SocketAddr) -> Self {
+        self.session["client_address"] = json!(address.to_string());
+        self
+    }
+
+    /// Records denials in `denials`, e.g. shared with other handlers.
+    #[must_use]
+    pub fn with_denials(mut self, denials: Denials) -> Self {
+        self.denials = denials;
+        self
+    }
+
+    /// Returns the denials, to be shared with a `DenialHandler`.
+    pub fn denials(&self) -> Denials {
+        self.denials.clone()
+    }
+
+    /// Returns the obligations, to be shared with an `ObligationHandler`.
+    pub fn obligations(&self) -> Obligations {
+        self.obligations.clone()
+    }
+
+    /// Returns the decision on `query`.
+    async fn decide(&mut self, query: &[u8]) -> Decision {
+        let accesses = match analyze(&String::from_utf8_lossy(query)) {
+            Ok(accesses) => accesses,
+            Err(err) => {
+                log::warn!("denying statement which cannot be analyzed - {}", err);
+                return Decision {
+                    reason: Some("statement cannot be analyzed".to_string()),
+                    ..Decision::deny()
+                };
+            }
+        };
+        if accesses.is_empty() {
+            return Decision::allow();
+        }
+
+        let input = input(&self.session, &accesses);
+        let key = input.to_string();
+        if let Some(decision) = self.cache.get(&key) {
+            return decision.clone();
+        }
+
+        let result = match &self.client {
+            Some(client) => client.decide(&input).await,
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "opa - invalid settings",
+            )),
+        };
+        match result {
+            Ok(decision) => {
+                log::debug!("decision {:?} for {}", decision, key);
+                self.cache.insert(key, decision.clone());
+                decision
+            }
+            Err(err) if self.fail_open => {
+                log::error!("allowing statement without decision - {}", err);
+                Decision::allow()
+            }
+            Err(err) => {
+                log::error!("denying statement without decision - {}", err);
+                Decision {
+                    reason: Some("no policy decision available".to_string()),
+                    ..Decision::deny()
+                }
+            }
+        }
+    }
+
+    /// Returns the decision on `query`, and why it is denied, if it is.
+    async fn authorize(&mut self, query: &Bytes) -> (Decision, Option<String>) {
+        let decision = self.decide(query).await;
+        if decision.allow {
+            return (decision, None);
+        }
+
+        let user = self.session["user"].as_str().unwrap_or_default();
+        let message = match &decision.reason {
+            Some(reason) => {
+                log::warn!("denying statement to user '{}', {}", user, reason);
+                format!("permission denied by Fern, {}", reason)
+            }
+            None => {
+                log::warn!("denying statement to user '{}'", user);
+                "permission denied by Fern".to_string()
+            }
+        };
+        (decision, Some(message))
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for OpaAuthorizationHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        let client = Client::from_config(config)
+            .map_err(|err| log::error!("denying all statements - {}", err))
+            .ok();
+        let fail_open = match config
+            .get::<String>("authorization.opa.on_error")
+            .as_deref()
+        {
+            Ok("deny") | Err(_) => false,
+            Ok("allow") => true,
+            Ok(other) => {
+                log::warn!("unknown OPA error handling '{}', using 'deny'", other);
+                false
+            }
+        };
+        let ttl = config
+            .get::<u64>("authorization.opa.cache.ttl")
+            .unwrap_or(DEFAULT_CACHE_TTL);
+        let capacity = config
+            .get::<usize>("authorization.opa.cache.capacity")
+            .unwrap_or(DEFAULT_CACHE_CAPACITY);
+
+        Self {
+            client,
+            cache: DecisionCache::new(Duration::from_secs(ttl), capacity),
+            fail_open,
+            session: json!({
+                "user": null,
+                "database": null,
+                "application_name": null,
+                "client_address": null,
+            }),
+            statements: HashMap::new(),
+            denials: Denials::new(),
+            obligations: Obligations::new(),
+        }
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::StartupMessage { ref parameters, .. } => {
+                for parameter in parameters {
+                    let name = String::from_utf8_lossy(&parameter.name);
+                    if ["user", "database", "application_name"].contains(&name.as_ref()) {
+                        let value = String::from_utf8_lossy(&parameter.value);
+                        self.session[name.as_ref()] = json!(value);
+                    }
+                }
+                // The database defaults to the user name.
+                if self.session["database"].is_null() {
+                    self.session["database"] = self.session["user"].clone();
+                }
+                self.obligations.request(&[]);
+                Some(msg)
+            }
+            frontend::Message::Query(ref query) => {
+                let (decision, denied) = self.authorize(query).await;
+                self.obligations.request(&decision.mask);
+                match denied {
+                    Some(message) => {
+                        self.denials.deny_query(message);
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+            frontend::Message::Parse {
+                ref stmt_name,
+                ref query,
+                ..
+            } => {
+                let (decision, denied) = self.authorize(query).await;
+                self.obligations.extend(&[]);
+                self.statements.insert(stmt_name.clone(), decision.mask);
+                match denied {
+                    Some(message) => {
+                        self.denials.deny_parse(message);
+                        None
+                    }
+                    None => Some(msg),
+                }
+            }
+            frontend::Message::Bind { ref stmt_name, .. } => {
+                let mask = self.statements.get(stmt_name).cloned().unwrap_or_default();
+                self.obligations.extend(&mask);
+                Some(msg)
+            }
+            frontend::Message::Close { kind, ref name } => {
+                if kind == b'S' {
+                    self.statements.remove(name);
+                }
+                self.obligations.extend(&[]);
+                Some(msg)
+            }
+            frontend::Message::Describe { .. }
+            | frontend::Message::Execute { .. }
+            | frontend::Message::Flush() => {
+                self.obligations.extend(&[]);
+                Some(msg)
+            }
+            frontend::Message::Sync() => {
+                self.obligations.sync();
+                Some(msg)
+            }
+            frontend::Message::FunctionCall { .. } => {
+                self.obligations.request(&[]);
+                Some(msg)
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use fern_authorization::analyze;
+    use serde_json::json;
+
+    use super::input;
+
     #[test]
-    fn it_works() {}
+    fn valid_input() {
+        let session = json!({ "user": "alice", "database": "shop" });
+        let query = "INSERT INTO audit (id) SELECT u.id FROM app.users u; SELECT 1; SELECT now()";
+        let accesses = analyze(query).unwrap();
+        let expected = json!({
+            "user": "alice",
+            "database": "shop",
+            "operations": ["EXECUTE", "INSERT", "SELECT"],
+            "tables": ["app.users", "audit"],
+            "accesses": [
+                {
+                    "operation": "INSERT",
+                    "schema": null,
+                    "table": "audit",
+                    "function": null,
+                    "columns": ["id"],
+                },
+                {
+                    "operation": "SELECT",
+                    "schema": "app",
+                    "table": "users",
+                    "function": null,
+                    "columns": ["id"],
+                },
+                {
+                    "operation": "SELECT",
+                    "schema": null,
+                    "table": null,
+                    "function": null,
+                    "columns": null,
+                },
+                {
+                    "operation": "EXECUTE",
+                    "schema": null,
+                    "table": null,
+                    "function": "now",
+                    "columns": null,
+                },
+            ],
+        });
+        assert_eq!(expected, input(&session, &accesses));
+    }
 }
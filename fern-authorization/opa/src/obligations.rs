@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Masking obligations of policy decisions, applied to query results.
+//!
+//! Each `Query`, `Sync`, `FunctionCall`, and `StartupMessage` is answered
+//! with exactly one `ReadyForQuery`: obligations are queued by requests in
+//! that order, and applied to results until the matching `ReadyForQuery`.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use fern_protocol_postgresql::codec::backend;
+use fern_protocol_postgresql::tracker::DescriptionTracker;
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+/// Value replacing masked fields.
+const MASK: &[u8] = b"******";
+
+/// Obligations state, behind the shared `Obligations`.
+#[derive(Debug, Default)]
+struct ObligationsState {
+    /// Columns to mask, by request awaiting its `ReadyForQuery`.
+    queue: VecDeque<BTreeSet<String>>,
+
+    /// Whether the last request is an extended query, until `Sync`.
+    open: bool,
+}
+
+/// Masking obligations, shared by handlers of both directions.
+///
+/// Cloning `Obligations` gives access to the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Obligations {
+    state: Arc<Mutex<ObligationsState>>,
+}
+
+impl Obligations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks the state, regardless of poisoning as it is always consistent.
+    fn state(&self) -> MutexGuard<'_, ObligationsState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues a request answered on its own, with columns to `mask`.
+    pub(crate) fn request(&self, mask: &[String]) {
+        let mut state = self.state();
+        state.open = false;
+        state.queue.push_back(mask.iter().cloned().collect());
+    }
+
+    /// Adds columns to `mask` to the ongoing extended query.
+    pub(crate) fn extend(&self, mask: &[String]) {
+        let mut state = self.state();
+        if !state.open {
+            state.open = true;
+            state.queue.push_back(BTreeSet::new());
+        }
+        if let Some(columns) = state.queue.back_mut() {
+            columns.extend(mask.iter().cloned());
+        }
+    }
+
+    /// Terminates the ongoing extended query, if any.
+    pub(crate) fn sync(&self) {
+        let open = std::mem::replace(&mut self.state().open, false);
+        if !open {
+            self.request(&[]);
+        }
+    }
+
+    /// Returns columns to mask in current results.
+    fn current(&self) -> BTreeSet<String> {
+        self.state().queue.front().cloned().unwrap_or_default()
+    }
+
+    /// Forgets obligations of the request answered with a `ReadyForQuery`.
+    fn complete(&self) {
+        self.state().queue.pop_front();
+    }
+}
+
+/// An `SQLMessageHandler` masking columns of `DataRow`s which policy
+/// decisions oblige to mask.
+///
+/// When the description of a `DataRow` is unknown, all its fields are
+/// masked. `COPY` data comes without column names: policies should deny
+/// `COPY` of columns to mask instead.
+#[derive(Debug, Default)]
+pub struct ObligationHandler {
+    obligations: Obligations,
+
+    /// Tracker of descriptions applying to incoming `DataRow`s.
+    tracker: DescriptionTracker,
+
+    /// Whether backend Messages are tracked by this Handler, rather than
+    /// by a shared `DescriptionTracker` preceding it in a chain of handlers.
+    tracking: bool,
+}
+
+impl ObligationHandler {
+    /// Uses `obligations` of an `OpaAuthorizationHandler`.
+    #[must_use]
+    pub fn with_obligations(mut self, obligations: Obligations) -> Self {
+        self.obligations = obligations;
+        self
+    }
+
+    /// Uses a shared `tracker` of descriptions, which tracks backend Messages
+    /// before they reach this Handler.
+    #[must_use]
+    pub fn with_tracker(mut self, tracker: DescriptionTracker) -> Self {
+        self.tracker = tracker;
+        self.tracking = false;
+        self
+    }
+
+    /// Masks `fields` of columns in `mask`.
+    fn mask_fields(
+        &self,
+        fields: Vec<Option<Bytes>>,
+        mask: &BTreeSet<String>,
+    ) -> Vec<Option<Bytes>> {
+        let descriptions = self
+            .tracker
+            .row_description()
+            .filter(|descriptions| descriptions.len() == fields.len());
+        if descriptions.is_none() {
+            log::warn!("no matching description for `DataRow`, masking all fields");
+        }
+
+        fields
+            .into_iter()
+            .enumerate()
+            .map(|(idx, field)| {
+                let masked = match &descriptions {
+                    Some(descriptions) => {
+                        mask.contains(String::from_utf8_lossy(&descriptions[idx].name).as_ref())
+                    }
+                    None => true,
+                };
+                match field {
+                    Some(_) if masked => Some(Bytes::from_static(MASK)),
+                    field => field,
+                }
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for ObligationHandler {
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self {
+            tracking: true,
+            ..Self::default()
+        }
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        if self.tracking {
+            self.tracker.track_backend(&msg);
+        }
+
+        match msg {
+            backend::Message::DataRow(fields) => {
+                let mask = self.obligations.current();
+                if mask.is_empty() {
+                    return Some(backend::Message::DataRow(fields));
+                }
+                Some(backend::Message::DataRow(self.mask_fields(fields, &mask)))
+            }
+            backend::Message::ReadyForQuery(_) => {
+                self.obligations.complete();
+                Some(msg)
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Obligations;
+
+    #[test]
+    fn valid_obligations() {
+        let obligations = Obligations::new();
+        obligations.request(&[]);
+        obligations.request(&["email".to_string()]);
+        obligations.extend(&[]);
+        obligations.extend(&["phone".to_string()]);
+        obligations.sync();
+        obligations.sync();
+
+        assert!(obligations.current().is_empty(), "startup");
+        obligations.complete();
+        assert_eq!(vec!["email"], Vec::from_iter(obligations.current()));
+        obligations.complete();
+        assert_eq!(vec!["phone"], Vec::from_iter(obligations.current()));
+        obligations.complete();
+        assert!(obligations.current().is_empty(), "lone `Sync`");
+        obligations.complete();
+        assert!(obligations.current().is_empty(), "no request");
+    }
+}
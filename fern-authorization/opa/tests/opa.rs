@@ -0,0 +1,308 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tests against a mock OPA server, whose policy denies accesses to
+//! `payments` tables, and obliges to mask `email` columns.
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use fern_authorization::Denials;
+use fern_protocol_postgresql::codec::backend::{self, RowDescription};
+use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+use fern_authorization_opa::{ObligationHandler, OpaAuthorizationHandler};
+
+/// Mock policy, at `fern/authz`.
+struct MockPolicy;
+
+impl Respond for MockPolicy {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = request.body_json().unwrap();
+        let input = &body["input"];
+        let tables: Vec<&str> = input["tables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        let result = if tables.iter().any(|table| table.starts_with("payments.")) {
+            json!({ "allow": false, "reason": "payments are off limits" })
+        } else {
+            json!({ "allow": true, "mask": ["email"] })
+        };
+        ResponseTemplate::new(200).set_body_json(json!({ "result": result }))
+    }
+}
+
+async fn server() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/data/fern/authz"))
+        .respond_with(MockPolicy)
+        .mount(&server)
+        .await;
+    server
+}
+
+/// Helper function building handlers sharing state, with `settings`, and
+/// the channel of answers to denied statements.
+fn handlers(
+    address: &str,
+    settings: &[(&str, &str)],
+) -> (
+    OpaAuthorizationHandler,
+    mpsc::UnboundedReceiver<backend::Message>,
+    ObligationHandler,
+) {
+    let mut builder = SQLHandlerConfig::builder()
+        .set_override("authorization.opa.address", address)
+        .unwrap()
+        .set_override("authorization.opa.path", "fern/authz")
+        .unwrap();
+    for (key, value) in settings {
+        builder = builder.set_override(*key, *value).unwrap();
+    }
+    let config = builder.build().unwrap();
+    assert!(fern_authorization_opa::is_enabled(&config));
+
+    let (replies, answers) = mpsc::unbounded_channel();
+    let authorization = OpaAuthorizationHandler::new(&config)
+        .with_client_address("10.0.0.7:51234".parse().unwrap())
+        .with_denials(Denials::new().with_replies(replies));
+    let obligation = ObligationHandler::new(&config).with_obligations(authorization.obligations());
+    (authorization, answers, obligation)
+}
+
+/// Helper function building a `StartupMessage` of "alice" with `psql`.
+fn startup() -> frontend::Message {
+    let parameter = |name: &'static str, value: &'static str| Parameter {
+        name: Bytes::from_static(name.as_bytes()),
+        value: Bytes::from_static(value.as_bytes()),
+    };
+    frontend::Message::StartupMessage {
+        frame_length: 0,
+        parameters: vec![
+            parameter("user", "alice"),
+            parameter("database", "shop"),
+            parameter("application_name", "psql"),
+        ],
+    }
+}
+
+/// Helper function returning the query forwarded for `query`, if any.
+async fn forwarded(handler: &mut OpaAuthorizationHandler, query: &'static str) -> Option<Bytes> {
+    let msg = frontend::Message::Query(Bytes::from_static(query.as_bytes()));
+    match handler.process(msg).await {
+        Some(frontend::Message::Query(query)) => Some(query),
+        None => None,
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+/// Helper function building the `ErrorResponse` of the server to `query`.
+fn syntax_error(query: &Bytes) -> backend::Message {
+    let message = format!(
+        "syntax error at or near \"{}\"",
+        String::from_utf8_lossy(query)
+    );
+    backend::Message::error_response("42601", &message)
+}
+
+/// Helper function building a `RowDescription` of text columns.
+fn description(columns: &[&'static str]) -> backend::Message {
+    backend::Message::RowDescription(
+        columns
+            .iter()
+            .map(|name| RowDescription {
+                name: Bytes::from_static(name.as_bytes()),
+                table_oid: 0,
+                column_attr: 0,
+                data_type_oid: 25,
+                data_type_size: -1,
+                type_modifier: -1,
+                format: 0,
+            })
+            .collect(),
+    )
+}
+
+/// Helper function building a row of text fields.
+fn row(fields: &[&'static str]) -> backend::Message {
+    backend::Message::DataRow(
+        fields
+            .iter()
+            .map(|field| Some(Bytes::from_static(field.as_bytes())))
+            .collect(),
+    )
+}
+
+async fn requests(server: &MockServer) -> Vec<Value> {
+    server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| request.body_json::<Value>().unwrap()["input"].clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn statements_decided() {
+    let server = server().await;
+    let (mut handler, mut answers, _) = handlers(&server.uri(), &[]);
+    handler.process(startup()).await;
+
+    for query in ["SELECT id FROM users", "BEGIN"] {
+        let forwarded = forwarded(&mut handler, query).await;
+        assert_eq!(Some(query.as_bytes()), forwarded.as_deref());
+    }
+    let query = "SELECT number FROM payments.cards";
+    assert_eq!(None, forwarded(&mut handler, query).await);
+    let expected = backend::Message::error_response(
+        "42501",
+        "permission denied by Fern, payments are off limits",
+    );
+    assert_eq!(Ok(expected), answers.try_recv());
+
+    let inputs = requests(&server).await;
+    assert_eq!(2, inputs.len(), "no decision for transaction control");
+    let expected = json!({
+        "user": "alice",
+        "database": "shop",
+        "application_name": "psql",
+        "client_address": "10.0.0.7:51234",
+        "operations": ["SELECT"],
+        "tables": ["users"],
+        "accesses": [
+            {
+                "operation": "SELECT",
+                "schema": null,
+                "table": "users",
+                "function": null,
+                "columns": ["id"],
+            },
+        ],
+    });
+    assert_eq!(expected, inputs[0]);
+}
+
+#[tokio::test]
+async fn parse_denied() {
+    let server = server().await;
+    let (mut handler, _, _) = handlers(&server.uri(), &[]);
+    handler.process(startup()).await;
+
+    let msg = frontend::Message::Parse {
+        stmt_name: Bytes::from_static(b"s"),
+        query: Bytes::from_static(b"DELETE FROM payments.cards WHERE id = $1"),
+        parameters_types: vec![23],
+    };
+    assert_eq!(None, handler.process(msg).await);
+}
+
+#[tokio::test]
+async fn decisions_cached() {
+    let server = server().await;
+    let (mut handler, _, _) = handlers(&server.uri(), &[]);
+    handler.process(startup()).await;
+    for _ in 0..3 {
+        forwarded(&mut handler, "SELECT id FROM users").await;
+    }
+    forwarded(&mut handler, "SELECT email FROM users").await;
+    assert_eq!(2, requests(&server).await.len());
+
+    let (mut handler, _, _) = handlers(&server.uri(), &[("authorization.opa.cache.ttl", "0")]);
+    handler.process(startup()).await;
+    for _ in 0..3 {
+        forwarded(&mut handler, "SELECT id FROM users").await;
+    }
+    assert_eq!(5, requests(&server).await.len(), "caching disabled");
+}
+
+#[tokio::test]
+async fn obligations_applied() {
+    let server = server().await;
+    let (mut handler, _, mut obligation) = handlers(&server.uri(), &[]);
+    handler.process(startup()).await;
+    obligation
+        .process(backend::Message::ReadyForQuery(b'I'))
+        .await;
+
+    // Simple query.
+    forwarded(&mut handler, "SELECT id, email FROM users").await;
+    obligation.process(description(&["id", "email"])).await;
+    let masked = obligation.process(row(&["1", "alice@example.com"])).await;
+    assert_eq!(Some(row(&["1", "******"])), masked);
+    obligation
+        .process(backend::Message::ReadyForQuery(b'I'))
+        .await;
+
+    // Extended query, with the statement prepared beforehand.
+    let parse = frontend::Message::Parse {
+        stmt_name: Bytes::from_static(b"s"),
+        query: Bytes::from_static(b"SELECT id, email FROM users WHERE id = $1"),
+        parameters_types: vec![],
+    };
+    handler.process(parse).await;
+    handler.process(frontend::Message::Sync()).await;
+    obligation
+        .process(backend::Message::ReadyForQuery(b'I'))
+        .await;
+    let messages = vec![
+        frontend::Message::Bind {
+            portal: Bytes::new(),
+            stmt_name: Bytes::from_static(b"s"),
+            parameters: vec![],
+            results_formats: vec![],
+        },
+        frontend::Message::Execute {
+            portal: Bytes::new(),
+            max_rows: 0,
+        },
+        frontend::Message::Sync(),
+    ];
+    for msg in messages {
+        handler.process(msg).await;
+    }
+    // Without description tracking of the `Bind`, all fields are masked.
+    let masked = obligation.process(row(&["1", "alice@example.com"])).await;
+    assert_eq!(Some(row(&["******", "******"])), masked);
+    obligation
+        .process(backend::Message::ReadyForQuery(b'I'))
+        .await;
+
+    // Statements without obligations.
+    forwarded(&mut handler, "SELECT 1").await;
+    let server_error = syntax_error(&Bytes::from_static(b"SELECT 1"));
+    obligation.process(server_error).await;
+    obligation
+        .process(backend::Message::ReadyForQuery(b'I'))
+        .await;
+    let unmasked = obligation.process(row(&["1", "alice@example.com"])).await;
+    assert_eq!(Some(row(&["1", "alice@example.com"])), unmasked);
+}
+
+#[tokio::test]
+async fn unreachable_policy() {
+    // Nothing listens on the discard port.
+    let address = "http://127.0.0.1:9";
+    let (mut handler, mut answers, _) = handlers(address, &[]);
+    handler.process(startup()).await;
+    let query = "SELECT id FROM users";
+    assert_eq!(None, forwarded(&mut handler, query).await, "fail-closed");
+    let expected = backend::Message::error_response(
+        "42501",
+        "permission denied by Fern, no policy decision available",
+    );
+    assert_eq!(Ok(expected), answers.try_recv());
+
+    let (mut handler, _, _) = handlers(address, &[("authorization.opa.on_error", "allow")]);
+    handler.process(startup()).await;
+    let forwarded = forwarded(&mut handler, query).await;
+    assert_eq!(Some(query.as_bytes()), forwarded.as_deref());
+}
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Blind indexes of encrypted values, allowing equality search on columns
+//! encrypted with randomized envelopes.
+//!
+//! A blind index is the hex-encoded HMAC-SHA256 of a value, keyed with a
+//! dedicated key which is not versioned: as indexes are compared by the
+//! server, they must remain the same across rotations of encryption keys.
+//! The column name is authenticated along with the value, so that equal
+//! values of different columns get different indexes.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::keyring::KEY_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Environment variable holding the key, unless defined otherwise.
+const DEFAULT_ENV: &str = "FERN_BLIND_INDEX_KEY";
+
+/// Maps a loading error to an `io::Error`.
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("blind index key - {}", reason),
+    )
+}
+
+/// A blind index key.
+#[derive(Clone)]
+pub struct BlindIndex {
+    mac: HmacSha256,
+}
+
+impl fmt::Debug for BlindIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: key material is not disclosed.
+        f.write_str("BlindIndex")
+    }
+}
+
+impl BlindIndex {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            mac: <HmacSha256 as Mac>::new_from_slice(key).expect("any key size"),
+        }
+    }
+
+    /// Parses a key, as 32 hex-encoded bytes.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        hex::decode(text.trim())
+            .ok()
+            .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+            .map(|key| Self::new(&key))
+            .ok_or_else(|| invalid("expected 32 hex-encoded bytes"))
+    }
+
+    /// Loads the key defined by settings found in `config`:
+    /// - `encryption.blind_index.file`: path of a keyfile,
+    /// - `encryption.blind_index.env`: environment variable, if no keyfile
+    ///   is defined, `FERN_BLIND_INDEX_KEY` by default.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        if let Ok(path) = config.get::<String>("encryption.blind_index.file") {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|err| io::Error::new(err.kind(), format!("'{}' - {}", path, err)))?;
+            return Self::parse(&text);
+        }
+
+        let env = config
+            .get::<String>("encryption.blind_index.env")
+            .unwrap_or_else(|_| DEFAULT_ENV.to_string());
+        match std::env::var(&env) {
+            Ok(text) => Self::parse(&text),
+            Err(_) => Err(invalid(&format!(
+                "'{}' environment variable undefined",
+                env
+            ))),
+        }
+    }
+
+    /// Returns the blind index of `value` of `column`.
+    pub fn compute(&self, column: &[u8], value: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(column);
+        mac.update(&[0]);
+        mac.update(value);
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn valid_compute() {
+        let index = BlindIndex::parse(KEY).unwrap();
+        let value = index.compute(b"email", b"jane@example.com");
+        assert_eq!(64, value.len());
+        assert_eq!(value, index.compute(b"email", b"jane@example.com"));
+        assert_ne!(value, index.compute(b"contact", b"jane@example.com"));
+        assert_ne!(value, index.compute(b"email", b"john@example.com"));
+
+        let other = BlindIndex::new(&[0xff; KEY_SIZE]);
+        assert_ne!(value, other.compute(b"email", b"jane@example.com"));
+    }
+
+    #[test]
+    fn invalid_parse() {
+        assert!(BlindIndex::parse("").is_err());
+        assert!(BlindIndex::parse("0011").is_err());
+        assert!(BlindIndex::parse(&format!("1:{}", KEY)).is_err());
+    }
+}
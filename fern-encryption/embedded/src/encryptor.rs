@@ -0,0 +1,28 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of values by a key management service, rather than with keys
+//! of a local [`Keyring`](crate::Keyring), e.g. by HashiCorp Vault.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::fmt;
+use std::io;
+
+/// An encryptor of column values, processing many values at once, e.g. all
+/// parameters of a `Bind`, or all fields of a `DataRow`, so that a remote
+/// service is called as few times as possible.
+///
+/// Envelopes are stored as defined by the column `Storage`, the same as
+/// envelopes of local keys.
+#[async_trait]
+pub trait Encryptor: fmt::Debug + Send + Sync {
+    /// Returns whether `envelope` was sealed by this encryptor.
+    fn is_sealed(&self, envelope: &[u8]) -> bool;
+
+    /// Encrypts `plaintexts`, failing if any of them cannot be encrypted.
+    async fn encrypt(&self, plaintexts: &[&[u8]]) -> io::Result<Vec<Bytes>>;
+
+    /// Decrypts `envelopes`, returning a result for each of them.
+    async fn decrypt(&self, envelopes: &[&[u8]]) -> io::Result<Vec<io::Result<Bytes>>>;
+}
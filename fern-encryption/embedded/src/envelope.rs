@@ -0,0 +1,292 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Envelope of encrypted values, as stored by the server.
+//!
+//! An envelope starts with a format version, followed by the version of the
+//! key used, a random nonce, and the AES-256-GCM ciphertext with its tag:
+//!
+//! ```text
+//! | format (1) | key version (4, big endian) | nonce (12) | ciphertext + tag |
+//! ```
+//!
+//! Deterministic envelopes, allowing equality search, rather hold the AES-SIV
+//! synthetic IV and ciphertext, so that the same value of a column always
+//! gives the same envelope for a given key:
+//!
+//! ```text
+//! | format (2) | key version (4, big endian) | synthetic IV (16) | ciphertext |
+//! ```
+//!
+//! The column name is authenticated along with the value, so that an envelope
+//! cannot be moved to another column unnoticed. Envelopes are stored either
+//! in `bytea` columns, or base64-encoded in text columns.
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Nonce};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::rngs::OsRng;
+use std::io;
+
+use crate::keyring::Keyring;
+use crate::siv::SIV_SIZE;
+
+/// Format version of randomized envelopes.
+const FORMAT_V1: u8 = 1;
+
+/// Format version of deterministic envelopes.
+const FORMAT_SIV: u8 = 2;
+
+/// Size in bytes of the deterministic envelope header, before the ciphertext.
+const SIV_HEADER_SIZE: usize = 1 + 4 + SIV_SIZE;
+
+/// Size in bytes of the envelope header, before the ciphertext.
+const HEADER_SIZE: usize = 1 + 4 + NONCE_SIZE;
+
+/// Size in bytes of AES-GCM nonces.
+const NONCE_SIZE: usize = 12;
+
+/// Size in bytes of AES-GCM authentication tags.
+const TAG_SIZE: usize = 16;
+
+/// Maps an error to an `io::Error`.
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("encryption envelope - {}", reason),
+    )
+}
+
+/// Encrypts `plaintext` of `column` with the current key of `keyring`.
+pub fn seal(keyring: &Keyring, column: &[u8], plaintext: &[u8]) -> io::Result<Bytes> {
+    let version = keyring.current_version();
+    let cipher = &keyring.get(version).expect("current key").randomized;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: plaintext,
+        aad: column,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| invalid("encryption failure"))?;
+
+    let mut envelope = BytesMut::with_capacity(HEADER_SIZE + ciphertext.len());
+    envelope.put_u8(FORMAT_V1);
+    envelope.put_u32(version);
+    envelope.put_slice(&nonce);
+    envelope.put_slice(&ciphertext);
+    Ok(envelope.freeze())
+}
+
+/// Encrypts `plaintext` of `column` deterministically, with the current key
+/// of `keyring`.
+pub fn seal_deterministic(keyring: &Keyring, column: &[u8], plaintext: &[u8]) -> Bytes {
+    let version = keyring.current_version();
+    let cipher = &keyring.get(version).expect("current key").deterministic;
+    let ciphertext = cipher.encrypt(column, plaintext);
+
+    let mut envelope = BytesMut::with_capacity(1 + 4 + ciphertext.len());
+    envelope.put_u8(FORMAT_SIV);
+    envelope.put_u32(version);
+    envelope.put_slice(&ciphertext);
+    envelope.freeze()
+}
+
+/// Returns the version of the key `envelope` was sealed with.
+pub fn key_version(envelope: &[u8]) -> io::Result<u32> {
+    match envelope {
+        [FORMAT_V1, a, b, c, d, ..] if envelope.len() >= HEADER_SIZE + TAG_SIZE => {
+            Ok(u32::from_be_bytes([*a, *b, *c, *d]))
+        }
+        [FORMAT_SIV, a, b, c, d, ..] if envelope.len() >= SIV_HEADER_SIZE => {
+            Ok(u32::from_be_bytes([*a, *b, *c, *d]))
+        }
+        [FORMAT_V1 | FORMAT_SIV, ..] => Err(invalid("truncated envelope")),
+        _ => Err(invalid("unknown envelope format")),
+    }
+}
+
+/// Decrypts `envelope` of `column` with the matching key of `keyring`.
+pub fn open(keyring: &Keyring, column: &[u8], envelope: &[u8]) -> io::Result<Bytes> {
+    let version = key_version(envelope)?;
+    let key = keyring
+        .get(version)
+        .ok_or_else(|| invalid(&format!("unknown key version {}", version)))?;
+
+    if envelope[0] == FORMAT_SIV {
+        return key
+            .deterministic
+            .decrypt(column, &envelope[5..])
+            .map(Bytes::from)
+            .ok_or_else(|| invalid("authentication failure"));
+    }
+
+    let cipher = &key.randomized;
+    let nonce = Nonce::from_slice(&envelope[5..HEADER_SIZE]);
+    let payload = Payload {
+        msg: &envelope[HEADER_SIZE..],
+        aad: column,
+    };
+    cipher
+        .decrypt(nonce, payload)
+        .map(Bytes::from)
+        .map_err(|_| invalid("authentication failure"))
+}
+
+/// Encrypts again `envelope` of `column` with the current key of `keyring`,
+/// keeping its format, unless it already is sealed with that key.
+pub fn reseal(keyring: &Keyring, column: &[u8], envelope: &[u8]) -> io::Result<Option<Bytes>> {
+    if key_version(envelope)? == keyring.current_version() {
+        return Ok(None);
+    }
+
+    let plaintext = open(keyring, column, envelope)?;
+    match envelope[0] {
+        FORMAT_SIV => Ok(Some(seal_deterministic(keyring, column, &plaintext))),
+        _ => seal(keyring, column, &plaintext).map(Some),
+    }
+}
+
+/// How envelopes are stored by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Base64-encoded, in a text column.
+    Base64,
+
+    /// As is, in a `bytea` column.
+    Bytea,
+}
+
+impl Storage {
+    /// Encodes `envelope` as a value in text `format` if zero, or binary format.
+    pub fn encode(&self, envelope: &[u8], format: u16) -> Bytes {
+        match (self, format) {
+            (Self::Base64, _) => Bytes::from(base64::encode(envelope)),
+            (Self::Bytea, 0) => Bytes::from(format!("\\x{}", hex::encode(envelope))),
+            (Self::Bytea, _) => Bytes::copy_from_slice(envelope),
+        }
+    }
+
+    /// Decodes an envelope from a value in text `format` if zero, or binary format.
+    pub fn decode(&self, value: &[u8], format: u16) -> io::Result<Vec<u8>> {
+        match (self, format) {
+            (Self::Base64, _) => base64::decode(value).map_err(|_| invalid("invalid base64")),
+            (Self::Bytea, 0) => match value.strip_prefix(b"\\x") {
+                Some(value) => hex::decode(value).map_err(|_| invalid("invalid bytea")),
+                None => Err(invalid("unsupported bytea output format")),
+            },
+            (Self::Bytea, _) => Ok(value.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn valid_seal_open() {
+        let keyring = Keyring::new(3, KEY);
+        let envelope = seal(&keyring, b"email", b"jane@example.com").unwrap();
+        assert_eq!(FORMAT_V1, envelope[0], "format version");
+        assert_eq!(3, key_version(&envelope).unwrap(), "key version");
+        assert_eq!(HEADER_SIZE + 16 + TAG_SIZE, envelope.len());
+
+        let other = seal(&keyring, b"email", b"jane@example.com").unwrap();
+        assert_ne!(envelope, other, "random nonces");
+
+        let plaintext = open(&keyring, b"email", &envelope).unwrap();
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext);
+    }
+
+    #[test]
+    fn valid_seal_open_deterministic() {
+        let keyring = Keyring::new(3, KEY);
+        let envelope = seal_deterministic(&keyring, b"email", b"jane@example.com");
+        assert_eq!(FORMAT_SIV, envelope[0], "format version");
+        assert_eq!(3, key_version(&envelope).unwrap(), "key version");
+        assert_eq!(SIV_HEADER_SIZE + 16, envelope.len());
+
+        let other = seal_deterministic(&keyring, b"email", b"jane@example.com");
+        assert_eq!(envelope, other, "same value, same envelope");
+        let other = seal_deterministic(&keyring, b"contact", b"jane@example.com");
+        assert_ne!(envelope, other, "other column, other envelope");
+
+        let plaintext = open(&keyring, b"email", &envelope).unwrap();
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext);
+        assert!(
+            open(&keyring, b"contact", &envelope).is_err(),
+            "other column"
+        );
+        assert!(
+            open(&keyring, b"email", &envelope[..20]).is_err(),
+            "truncated"
+        );
+    }
+
+    #[test]
+    fn invalid_open() {
+        let keyring = Keyring::new(1, KEY);
+        let envelope = seal(&keyring, b"email", b"secret").unwrap();
+        assert!(open(&keyring, b"phone", &envelope).is_err(), "other column");
+
+        let mut tampered = envelope.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&keyring, b"email", &tampered).is_err(), "tampered");
+
+        assert!(
+            open(&Keyring::new(2, KEY), b"email", &envelope).is_err(),
+            "unknown key"
+        );
+        assert!(
+            open(&keyring, b"email", b"plaintext").is_err(),
+            "not an envelope"
+        );
+        assert!(
+            open(&keyring, b"email", &envelope[..20]).is_err(),
+            "truncated"
+        );
+    }
+
+    #[test]
+    fn valid_reseal() {
+        let former = Keyring::new(1, KEY);
+        let envelope = seal(&former, b"email", b"jane@example.com").unwrap();
+        let deterministic = seal_deterministic(&former, b"email", b"jane@example.com");
+
+        let keyring = former.with_key(2, b"fedcba9876543210fedcba9876543210");
+        let resealed = reseal(&keyring, b"email", &envelope).unwrap().unwrap();
+        assert_eq!(FORMAT_V1, resealed[0], "format kept");
+        assert_eq!(2, key_version(&resealed).unwrap(), "current key");
+        let plaintext = open(&keyring, b"email", &resealed).unwrap();
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext);
+
+        let resealed = reseal(&keyring, b"email", &deterministic).unwrap();
+        let expected = seal_deterministic(&keyring, b"email", b"jane@example.com");
+        assert_eq!(Some(expected.clone()), resealed, "still searchable");
+        assert_eq!(None, reseal(&keyring, b"email", &expected).unwrap());
+
+        assert!(
+            reseal(&keyring, b"phone", &envelope).is_err(),
+            "other column"
+        );
+        assert!(reseal(&keyring, b"email", b"plaintext").is_err());
+    }
+
+    #[test]
+    fn valid_storage_encoding() {
+        let envelope = [FORMAT_V1, 0, 0, 0, 1];
+        let cases = [
+            (Storage::Base64, 0, &b"AQAAAAE="[..]),
+            (Storage::Base64, 1, &b"AQAAAAE="[..]),
+            (Storage::Bytea, 0, &b"\\x0100000001"[..]),
+            (Storage::Bytea, 1, &envelope[..]),
+        ];
+        for (storage, format, encoded) in cases {
+            assert_eq!(encoded, storage.encode(&envelope, format), "{:?}", storage);
+            assert_eq!(envelope.to_vec(), storage.decode(encoded, format).unwrap());
+        }
+    }
+}
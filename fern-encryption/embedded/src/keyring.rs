@@ -0,0 +1,259 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioned data encryption keys, loaded from a local keyfile or from an
+//! environment variable.
+//!
+//! Keys are listed one per line (or separated by commas), as `<version>:<key>`
+//! where `<key>` is 32 hex-encoded bytes, or as a bare key of version 1.
+//! Empty lines and lines starting with `#` are ignored. The highest version
+//! is used for encryption unless another active version is chosen, e.g. to
+//! distribute a new key before using it, while all versions can be used for
+//! decryption.
+//!
+//! Keys of deterministic encryption are derived from those with HKDF-SHA256.
+
+use aes_gcm::{Aes256Gcm, KeyInit};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::siv::{self, Siv};
+
+/// Size in bytes of keys.
+pub const KEY_SIZE: usize = 32;
+
+/// Environment variable holding keys, unless defined otherwise.
+const DEFAULT_ENV: &str = "FERN_ENCRYPTION_KEYS";
+
+/// Maps a parsing error to an `io::Error`.
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("encryption keys - {}", reason),
+    )
+}
+
+/// Context of the derivation of deterministic encryption keys.
+const SIV_INFO: &[u8] = b"fern-encryption aes-siv";
+
+/// A key of a given version, for randomized and deterministic encryption.
+#[derive(Clone)]
+pub struct Key {
+    /// AES-256-GCM key, of randomized encryption.
+    pub(crate) randomized: Aes256Gcm,
+
+    /// AES-SIV key, of deterministic encryption.
+    pub(crate) deterministic: Siv,
+}
+
+impl Key {
+    fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let mut derived = [0; siv::KEY_SIZE];
+        Hkdf::<Sha256>::new(None, key)
+            .expand(SIV_INFO, &mut derived)
+            .expect("valid derived key size");
+        Self {
+            randomized: Aes256Gcm::new(key.into()),
+            deterministic: Siv::new(&derived),
+        }
+    }
+}
+
+/// A set of versioned keys.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<u32, Key>,
+
+    /// Version of the key used for encryption, the highest if undefined.
+    active: Option<u32>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: key material is not disclosed.
+        f.debug_struct("Keyring")
+            .field("versions", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.current_version())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Creates a keyring holding a single `key` of `version`.
+    pub fn new(version: u32, key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            keys: BTreeMap::from([(version, Key::new(key))]),
+            active: None,
+        }
+    }
+
+    /// Adds `key` of `version`, replacing any key of the same version.
+    #[must_use]
+    pub fn with_key(mut self, version: u32, key: &[u8; KEY_SIZE]) -> Self {
+        self.keys.insert(version, Key::new(key));
+        self
+    }
+
+    /// Uses the key of `version` for encryption, rather than the highest one.
+    pub fn with_active(mut self, version: u32) -> io::Result<Self> {
+        if !self.keys.contains_key(&version) {
+            return Err(invalid(&format!("unknown active key version {}", version)));
+        }
+        self.active = Some(version);
+        Ok(self)
+    }
+
+    /// Parses keys listed in `text`.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut keys = BTreeMap::new();
+        for entry in text.split(['\n', ',']) {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let (version, key) = match entry.split_once(':') {
+                Some((version, key)) => (
+                    version
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|_| invalid("invalid key version"))?,
+                    key.trim(),
+                ),
+                None => (1, entry),
+            };
+            let key = hex::decode(key)
+                .ok()
+                .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+                .ok_or_else(|| invalid("expected 32 hex-encoded bytes"))?;
+            if keys.insert(version, Key::new(&key)).is_some() {
+                return Err(invalid("duplicate key version"));
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(invalid("no key defined"));
+        }
+        Ok(Self { keys, active: None })
+    }
+
+    /// Loads keys defined by settings found in `config`:
+    /// - `encryption.keys.file`: path of a keyfile,
+    /// - `encryption.keys.env`: environment variable, if no keyfile is
+    ///   defined, `FERN_ENCRYPTION_KEYS` by default,
+    /// - `encryption.keys.active`: version of the key used for encryption,
+    ///   the highest by default.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let keyring = if let Ok(path) = config.get::<String>("encryption.keys.file") {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|err| io::Error::new(err.kind(), format!("'{}' - {}", path, err)))?;
+            Self::parse(&text)?
+        } else {
+            let env = config
+                .get::<String>("encryption.keys.env")
+                .unwrap_or_else(|_| DEFAULT_ENV.to_string());
+            match std::env::var(&env) {
+                Ok(text) => Self::parse(&text)?,
+                Err(_) => {
+                    return Err(invalid(&format!(
+                        "'{}' environment variable undefined",
+                        env
+                    )))
+                }
+            }
+        };
+
+        match config.get::<u32>("encryption.keys.active") {
+            Ok(version) => keyring.with_active(version),
+            Err(_) => Ok(keyring),
+        }
+    }
+
+    /// Returns the version of the key used for encryption.
+    pub fn current_version(&self) -> u32 {
+        self.active
+            .unwrap_or_else(|| *self.keys.keys().next_back().expect("at least one key"))
+    }
+
+    /// Returns all known key versions, in ascending order.
+    pub fn versions(&self) -> Vec<u32> {
+        self.keys.keys().copied().collect()
+    }
+
+    /// Returns the key of `version`, if known.
+    pub fn get(&self, version: u32) -> Option<&Key> {
+        self.keys.get(&version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[test]
+    fn valid_parse() {
+        let keyring = Keyring::parse(KEY_1).unwrap();
+        assert_eq!(1, keyring.current_version(), "bare key");
+
+        let text = format!("# rotated keys\n1:{}\n\n 2 : {} \n", KEY_1, KEY_2);
+        let keyring = Keyring::parse(&text).unwrap();
+        assert_eq!(2, keyring.current_version(), "highest version");
+        assert!(keyring.get(1).is_some(), "former key");
+
+        let text = format!("1:{},2:{}", KEY_1, KEY_2);
+        assert_eq!(2, Keyring::parse(&text).unwrap().current_version());
+    }
+
+    #[test]
+    fn invalid_parse() {
+        assert!(Keyring::parse("").is_err(), "no key");
+        assert!(Keyring::parse("0011").is_err(), "short key");
+        assert!(Keyring::parse(&format!("x:{}", KEY_1)).is_err(), "version");
+        let text = format!("1:{}\n1:{}", KEY_1, KEY_2);
+        assert!(Keyring::parse(&text).is_err(), "duplicate version");
+    }
+
+    #[test]
+    fn valid_from_config_keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        std::fs::write(&path, format!("2:{}\n", KEY_2)).unwrap();
+
+        let config = SQLHandlerConfig::builder()
+            .set_override("encryption.keys.file", path.to_str().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let keyring = Keyring::from_config(&config).unwrap();
+        assert_eq!(2, keyring.current_version());
+    }
+
+    #[test]
+    fn valid_active_version() {
+        let text = format!("1:{}\n2:{}", KEY_1, KEY_2);
+        let keyring = Keyring::parse(&text).unwrap().with_active(1).unwrap();
+        assert_eq!(1, keyring.current_version(), "key not used yet");
+        assert_eq!(vec![1, 2], keyring.versions());
+        assert!(Keyring::parse(&text).unwrap().with_active(3).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        std::fs::write(&path, &text).unwrap();
+        let config = SQLHandlerConfig::builder()
+            .set_override("encryption.keys.file", path.to_str().unwrap())
+            .unwrap()
+            .set_override("encryption.keys.active", 1)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(1, Keyring::from_config(&config).unwrap().current_version());
+    }
+}
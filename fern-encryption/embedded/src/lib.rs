@@ -1,8 +1,1158 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
+//! Embedded transparent column encryption for Fern proxy.
+//!
+//! Values written to selected columns through `Bind` parameters of `INSERT`
+//! and `UPDATE` prepared statements are encrypted by an [`EncryptionHandler`],
+//! so that the server only stores envelopes (see [`Storage`]). Envelopes found
+//! in `DataRow`s are decrypted by a [`DecryptionHandler`], for sessions of
+//! authorized users only: other sessions get envelopes as stored.
+//!
+//! Columns are encrypted either with random nonces (the default), or
+//! deterministically, so that parameters compared for equality to them, e.g.
+//! in `WHERE email = $1`, can be encrypted as well and still match. Columns
+//! may also have a blind index column, holding a keyed hash of values: the
+//! [`EncryptionHandler`] then writes it along with values, and rewrites
+//! equality predicates to compare blind indexes instead.
+//!
+//! Values written as literals in queries are not encrypted, and a warning is
+//! logged. Should a parameter fail to be encrypted, the `Bind` is made to fail
+//! on the server side, rather than writing the value in clear.
+//!
+//! Columns may also be designated by `encrypt` rules of the policy document
+//! (see `fern_policy_engine`), with the same settings in their rule.
+//!
+//! Once keys rotated, values stored with former keys are still decrypted, and
+//! can be encrypted again with the current key by a [`Reencryptor`].
+//!
+//! Values may also be encrypted by a key management service, through an
+//! [`Encryptor`] given to handlers, rather than with local keys. It then
+//! encrypts values of randomized columns, while deterministic encryption and
+//! blind indexes still use local keys. Values encrypted with local keys are
+//! still decrypted.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use fern_policy_engine::{Action, Policy};
+use fern_protocol_postgresql::codec::{backend, frontend};
+use fern_protocol_postgresql::tracker::DescriptionTracker;
+use fern_proxy_interfaces::SQLMessageHandler;
+
+// Re-export.
+pub use blind::BlindIndex;
+pub use encryptor::Encryptor;
+pub use envelope::Storage;
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use keyring::Keyring;
+pub use rotation::Reencryptor;
+
+mod blind;
+mod encryptor;
+mod envelope;
+mod keyring;
+mod rotation;
+mod siv;
+mod statements;
+
+use statements::{Analysis, Watched};
+
+/// Name of the prepared statement bound instead of the one requested, when
+/// parameters cannot be encrypted. As it does not exist, the server answers
+/// with an error, and skips following extended query messages until `Sync`.
+const UNENCRYPTABLE_STATEMENT: &[u8] = b"fern: parameters cannot be encrypted";
+
+/// OID of the `bytea` data type.
+const BYTEA_OID: u32 = 17;
+
+/// OID of the `text` data type.
+const TEXT_OID: u32 = 25;
+
+/// Returns whether column encryption is defined in `config`, or by `encrypt`
+/// rules of the compiled `policy` document, if any.
+pub fn is_enabled(config: &SQLHandlerConfig, policy: Option<&Policy>) -> bool {
+    config
+        .get_table("encryption.columns")
+        .map_or(false, |columns| !columns.is_empty())
+        || policy.map_or(false, |policy| !policy.columns(Action::Encrypt).is_empty())
+}
+
+/// Settings of an encrypted column.
+#[derive(Debug, Clone)]
+struct Column {
+    name: Bytes,
+
+    /// How envelopes are stored by the server.
+    storage: Storage,
+
+    /// Whether values are encrypted deterministically.
+    deterministic: bool,
+
+    /// Name of the column holding blind indexes of values, if any.
+    blind_index: Option<String>,
+}
+
+/// Gets encrypted columns defined in `encryption.columns` table of `config`,
+/// and by `encrypt` rules of the compiled `policy` document, with their
+/// settings found in `config`:
+/// - `storage`, either `base64` (the default), or `bytea`,
+/// - `mode`, either `randomized` (the default), or `deterministic`,
+/// - `blind_index`, the name of a column holding blind indexes.
+fn columns_from_config(config: &SQLHandlerConfig, policy: Option<&Policy>) -> Vec<Column> {
+    let mut columns = vec![];
+    if let Ok(table) = config.get_table("encryption.columns") {
+        for column_name in table.keys() {
+            let key = format!("encryption.columns.{}", column_name);
+            columns.push(column_from_config(config, column_name, &key));
+        }
+    }
+    let policy_columns = policy.map_or_else(Vec::new, |policy| policy.columns(Action::Encrypt));
+    for (column_name, idx) in policy_columns {
+        // Note: settings of `encryption.columns` prevail.
+        if !columns.iter().any(|column| column.name == column_name) {
+            let key = format!("policy.rules[{}]", idx);
+            columns.push(column_from_config(config, &column_name, &key));
+        }
+    }
+    columns
+}
+
+/// Gets settings of encrypted column `column_name` under `key` of `config`.
+fn column_from_config(config: &SQLHandlerConfig, column_name: &str, key: &str) -> Column {
+    let storage = match config.get::<String>(&format!("{}.storage", key)).as_deref() {
+        Ok("base64") | Err(_) => Storage::Base64,
+        Ok("bytea") => Storage::Bytea,
+        Ok(other) => {
+            log::warn!("unknown encryption storage '{}', using 'base64'", other);
+            Storage::Base64
+        }
+    };
+    let deterministic = match config.get::<String>(&format!("{}.mode", key)).as_deref() {
+        Ok("randomized") | Err(_) => false,
+        Ok("deterministic") => true,
+        Ok(other) => {
+            log::warn!("unknown encryption mode '{}', using 'randomized'", other);
+            false
+        }
+    };
+    let blind_index = config.get::<String>(&format!("{}.blind_index", key)).ok();
+    log::debug!(
+        "column '{}' encrypted, stored as: {:?}, deterministic: {}, blind index: {:?}",
+        column_name,
+        storage,
+        deterministic,
+        blind_index
+    );
+    Column {
+        name: Bytes::from(column_name.to_string()),
+        storage,
+        deterministic,
+        blind_index,
+    }
+}
+
+/// Compiles the policy document defined in `config`, if any, for handlers
+/// created on their own.
+fn policy_from_config(config: &SQLHandlerConfig) -> Option<Policy> {
+    Policy::from_config(config)
+        .map_err(|err| log::error!("ignoring encryption rules of policy - {}", err))
+        .ok()
+        .flatten()
+}
+
+/// Loads keys defined in `config`, logging why if they cannot be.
+fn keyring_from_config(config: &SQLHandlerConfig) -> Option<Keyring> {
+    match Keyring::from_config(config) {
+        Ok(keyring) => Some(keyring),
+        Err(err) => {
+            log::error!("no encryption key available - {}", err);
+            None
+        }
+    }
+}
+
+/// Returns the settings of `column` among `columns`, if encrypted.
+fn find<'a>(columns: &'a [Column], column: &[u8]) -> Option<&'a Column> {
+    columns.iter().find(|settings| settings.name == column)
+}
+
+/// Records `value` of parameter `idx` among `values`, unless the parameter
+/// already has another value, e.g. when both written to and compared to
+/// `column`. Returns `false` in that case.
+fn assign(values: &mut HashMap<usize, Bytes>, idx: usize, column: &str, value: Bytes) -> bool {
+    if values
+        .insert(idx, value.clone())
+        .map_or(false, |other| other != value)
+    {
+        log::error!(
+            "cannot encrypt '{}' - parameter both written and compared",
+            column
+        );
+        return false;
+    }
+    true
+}
+
+/// State of a session, shared by handlers of both directions.
+///
+/// Cloning a `Session` gives access to the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    /// Whether the session user may get decrypted values.
+    authorized: Arc<AtomicBool>,
+
+    /// Number of parameters of prepared statements, by name, before
+    /// parameters were added to write blind indexes.
+    parameters: Arc<Mutex<HashMap<Bytes, usize>>>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether the session user may get decrypted values.
+    pub fn is_authorized(&self) -> bool {
+        self.authorized.load(Ordering::Acquire)
+    }
+
+    fn authorize(&self, authorized: bool) {
+        self.authorized.store(authorized, Ordering::Release);
+    }
+
+    /// Returns the number of parameters of statement `name` as defined by
+    /// the frontend, if parameters were added to it.
+    fn parameters(&self, name: &[u8]) -> Option<usize> {
+        let parameters = self
+            .parameters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        parameters.get(name).copied()
+    }
+
+    /// Records the number of `parameters` of statement `name` as defined by
+    /// the frontend, if parameters were added to it.
+    fn set_parameters(&self, name: &Bytes, parameters: Option<usize>) {
+        let mut statements = self
+            .parameters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match parameters {
+            Some(parameters) => statements.insert(name.clone(), parameters),
+            None => statements.remove(name),
+        };
+    }
+}
+
+/// An `SQLMessageHandler` encrypting values of selected columns in `Bind`
+/// parameters, and authorizing sessions for decryption.
+///
+/// Columns to encrypt are defined in `encryption.columns` table of
+/// `SQLHandlerConfig`, and users of sessions authorized for decryption
+/// in `encryption.authorized_users`, where `*` stands for any user.
+#[derive(Debug)]
+pub struct EncryptionHandler {
+    /// Keys, if available. Without keys, encryption always fails.
+    keyring: Option<Keyring>,
+
+    /// Encryptor of randomized columns, if not encrypted with `keyring`.
+    encryptor: Option<Arc<dyn Encryptor>>,
+
+    /// Blind index key, if available. Without it, blind indexes cannot
+    /// be computed.
+    blind_index: Option<BlindIndex>,
+
+    /// Columns to encrypt, with their settings.
+    columns: Vec<Column>,
+
+    /// Column names to encrypt, as analyzed in statements.
+    watched: Watched,
+
+    /// Users of sessions authorized for decryption.
+    authorized_users: Vec<String>,
+
+    /// Session state, shared with a `DecryptionHandler`.
+    session: Session,
+
+    /// Parameters of prepared statements involving encrypted columns, by name.
+    statements: HashMap<Bytes, Analysis>,
+}
+
+impl EncryptionHandler {
+    /// Creates a handler with settings of `config`, and columns of `encrypt`
+    /// rules of the already compiled `policy` document, if any.
+    pub fn from_policy(config: &SQLHandlerConfig, policy: Option<&Policy>) -> Self {
+        let authorized_users = config
+            .get::<Vec<String>>("encryption.authorized_users")
+            .unwrap_or_default();
+        if authorized_users.is_empty() {
+            log::warn!("no user authorized, encrypted values will not be decrypted");
+        }
+
+        let columns = columns_from_config(config, policy);
+        let watched = columns
+            .iter()
+            .map(|column| {
+                let name = String::from_utf8_lossy(&column.name).into_owned();
+                (name, column.blind_index.clone())
+            })
+            .collect();
+        let blind_index = match columns.iter().any(|column| column.blind_index.is_some()) {
+            true => BlindIndex::from_config(config)
+                .map_err(|err| log::error!("no blind index key available - {}", err))
+                .ok(),
+            false => None,
+        };
+
+        Self {
+            keyring: keyring_from_config(config),
+            encryptor: None,
+            blind_index,
+            columns,
+            watched,
+            authorized_users,
+            session: Session::new(),
+            statements: HashMap::new(),
+        }
+    }
+
+    /// Returns the session state, to be shared with a `DecryptionHandler`.
+    pub fn session(&self) -> Session {
+        self.session.clone()
+    }
+
+    /// Uses `keyring` rather than keys defined in `SQLHandlerConfig`.
+    #[must_use]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Encrypts values of randomized columns with `encryptor`, rather than
+    /// with keys.
+    #[must_use]
+    pub fn with_encryptor(mut self, encryptor: Arc<dyn Encryptor>) -> Self {
+        self.encryptor = Some(encryptor);
+        self
+    }
+
+    /// Uses `blind_index` rather than the key defined in `SQLHandlerConfig`.
+    #[must_use]
+    pub fn with_blind_index(mut self, blind_index: BlindIndex) -> Self {
+        self.blind_index = Some(blind_index);
+        self
+    }
+
+    /// Analyzes parameters of `query` involving encrypted columns, warning
+    /// about values which cannot be processed. `declared` parameters may
+    /// have been declared with their data types.
+    fn analyze(&self, query: &[u8], declared: usize) -> Analysis {
+        let query = String::from_utf8_lossy(query);
+        let lowercase = query.to_lowercase();
+        // Note: parsing is skipped for queries not mentioning encrypted columns.
+        if !self
+            .columns
+            .iter()
+            .any(|column| lowercase.contains(&*String::from_utf8_lossy(&column.name)))
+        {
+            return Analysis::default();
+        }
+
+        let analysis = statements::analyze(&query, &self.watched, declared);
+        for column in analysis.unprocessed.iter() {
+            log::warn!("value of '{}' is not a parameter, not encrypted", column);
+        }
+        for column in analysis.predicates.values() {
+            match find(&self.columns, column.as_bytes()) {
+                Some(settings) if !settings.deterministic && settings.blind_index.is_none() => {
+                    log::warn!(
+                        "'{}' values cannot match, as encrypted with random nonces",
+                        column
+                    );
+                }
+                _ => {}
+            }
+        }
+        analysis
+    }
+
+    /// Returns the parameter standing for `value` written to `column` in
+    /// `format`, or compared for equality to `column` if `predicate`.
+    fn protect(
+        &self,
+        column: &Column,
+        value: &[u8],
+        format: u16,
+        predicate: bool,
+    ) -> io::Result<Bytes> {
+        if predicate && column.blind_index.is_some() {
+            // Note: blind indexes are hex-encoded, the same in text and binary format.
+            return match &self.blind_index {
+                Some(blind_index) => Ok(Bytes::from(blind_index.compute(&column.name, value))),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no blind index key available",
+                )),
+            };
+        }
+
+        let keyring = self.keyring.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no encryption key available")
+        })?;
+        let envelope = match column.deterministic {
+            true => envelope::seal_deterministic(keyring, &column.name, value),
+            false => envelope::seal(keyring, &column.name, value)?,
+        };
+        Ok(column.storage.encode(&envelope, format))
+    }
+
+    /// Processes `parameters` involving encrypted columns as analyzed,
+    /// adding blind indexes parameters. Returns `false` if any of them
+    /// could not be processed.
+    ///
+    /// Values encrypted by the encryptor, if any, are encrypted at once.
+    async fn encrypt(
+        &self,
+        analysis: &Analysis,
+        parameters: &mut Vec<frontend::BindParameter>,
+    ) -> bool {
+        let mut added = Vec::with_capacity(analysis.indexes.len());
+        for (column, source) in analysis.indexes.iter() {
+            let value = match parameters.get(*source).and_then(|p| p.value.as_ref()) {
+                Some(value) => match &self.blind_index {
+                    Some(blind_index) => {
+                        Some(Bytes::from(blind_index.compute(column.as_bytes(), value)))
+                    }
+                    None => {
+                        log::error!("cannot index '{}' - no blind index key available", column);
+                        return false;
+                    }
+                },
+                None => None,
+            };
+            added.push(frontend::BindParameter { format: 0, value });
+        }
+
+        let mut values = HashMap::new();
+        let mut remote = vec![];
+        let writes = analysis.writes.iter().map(|write| (write, false));
+        let predicates = analysis
+            .predicates
+            .iter()
+            .map(|predicate| (predicate, true));
+        for ((idx, column), predicate) in writes.chain(predicates) {
+            let parameter = match parameters.get(*idx) {
+                Some(parameter) => parameter,
+                None => continue,
+            };
+            let (value, settings) = match (&parameter.value, find(&self.columns, column.as_bytes()))
+            {
+                (Some(value), Some(settings)) => (value, settings),
+                _ => continue,
+            };
+            if self.encryptor.is_some()
+                && !settings.deterministic
+                && !(predicate && settings.blind_index.is_some())
+            {
+                remote.push((
+                    *idx,
+                    column,
+                    value.clone(),
+                    parameter.format,
+                    settings.storage,
+                ));
+                continue;
+            }
+            let value = match self.protect(settings, value, parameter.format, predicate) {
+                Ok(value) => value,
+                Err(err) => {
+                    log::error!("cannot encrypt '{}' - {}", column, err);
+                    return false;
+                }
+            };
+            if !assign(&mut values, *idx, column, value) {
+                return false;
+            }
+        }
+
+        if let (Some(encryptor), false) = (&self.encryptor, remote.is_empty()) {
+            let plaintexts: Vec<&[u8]> =
+                remote.iter().map(|(_, _, value, ..)| &value[..]).collect();
+            let envelopes = match encryptor.encrypt(&plaintexts).await {
+                Ok(envelopes) => envelopes,
+                Err(err) => {
+                    log::error!("cannot encrypt parameters - {}", err);
+                    return false;
+                }
+            };
+            for ((idx, column, _, format, storage), envelope) in remote.iter().zip(envelopes) {
+                if !assign(
+                    &mut values,
+                    *idx,
+                    column,
+                    storage.encode(&envelope, *format),
+                ) {
+                    return false;
+                }
+            }
+        }
+
+        for (idx, value) in values {
+            parameters[idx].value = Some(value);
+        }
+        parameters.extend(added);
+        true
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for EncryptionHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_policy(config, policy_from_config(config).as_ref())
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::StartupMessage { ref parameters, .. } => {
+                let user = parameters
+                    .iter()
+                    .find(|parameter| parameter.name == "user")
+                    .map(|parameter| String::from_utf8_lossy(&parameter.value).into_owned());
+                let authorized = user.map_or(false, |user| {
+                    self.authorized_users
+                        .iter()
+                        .any(|authorized| *authorized == user || authorized == "*")
+                });
+                log::debug!("session authorized for decryption: {}", authorized);
+                self.session.authorize(authorized);
+                Some(msg)
+            }
+            frontend::Message::Parse {
+                stmt_name,
+                query,
+                parameters_types,
+            } => {
+                let mut analysis = self.analyze(&query, parameters_types.len());
+                let query = match analysis.rewritten.take() {
+                    Some(rewritten) => {
+                        log::debug!("statement rewritten to use blind indexes: {}", rewritten);
+                        Bytes::from(rewritten)
+                    }
+                    None => query,
+                };
+
+                // Parameters added for blind indexes are hidden from the frontend.
+                let added = !analysis.indexes.is_empty();
+                self.session
+                    .set_parameters(&stmt_name, added.then_some(analysis.parameters));
+                if analysis.is_empty() {
+                    self.statements.remove(&stmt_name);
+                } else {
+                    self.statements.insert(stmt_name.clone(), analysis);
+                }
+                Some(frontend::Message::Parse {
+                    stmt_name,
+                    query,
+                    parameters_types,
+                })
+            }
+            frontend::Message::Query(ref query) => {
+                // Note: simple queries have no parameters, only warning here.
+                self.analyze(query, 0);
+                Some(msg)
+            }
+            frontend::Message::Close {
+                kind: b'S',
+                ref name,
+            } => {
+                self.statements.remove(name);
+                self.session.set_parameters(name, None);
+                Some(msg)
+            }
+            frontend::Message::Bind {
+                portal,
+                stmt_name,
+                mut parameters,
+                results_formats,
+            } => {
+                let encrypted = match self.statements.get(&stmt_name) {
+                    Some(analysis) => self.encrypt(analysis, &mut parameters).await,
+                    None => true,
+                };
+                let stmt_name = match encrypted {
+                    true => stmt_name,
+                    false => {
+                        log::error!("parameters cannot be encrypted, failing `Bind`");
+                        parameters
+                            .iter_mut()
+                            .for_each(|parameter| parameter.value = None);
+                        Bytes::from_static(UNENCRYPTABLE_STATEMENT)
+                    }
+                };
+                Some(frontend::Message::Bind {
+                    portal,
+                    stmt_name,
+                    parameters,
+                    results_formats,
+                })
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
+/// An `SQLMessageHandler` decrypting values of selected columns in `DataRow`s,
+/// for sessions authorized by an `EncryptionHandler`.
+///
+/// Values which are not envelopes, or cannot be decrypted, are forwarded as is.
+/// Parameters added by the `EncryptionHandler` to write blind indexes are
+/// removed from `ParameterDescription`s, for any session.
+#[derive(Debug)]
+pub struct DecryptionHandler {
+    /// Keys, if available. Without keys, decryption always fails.
+    keyring: Option<Keyring>,
+
+    /// Encryptor of values not encrypted with `keyring`, if any.
+    encryptor: Option<Arc<dyn Encryptor>>,
+
+    /// Columns to decrypt, with their settings.
+    columns: Vec<Column>,
+
+    /// Session state, shared with an `EncryptionHandler`.
+    session: Session,
+
+    /// Tracker of descriptions applying to incoming `DataRow`s.
+    tracker: DescriptionTracker,
+
+    /// Whether backend Messages are tracked by this Handler, rather than
+    /// by a shared `DescriptionTracker` preceding it in a chain of handlers.
+    tracking: bool,
+}
+
+impl DecryptionHandler {
+    /// Creates a handler with settings of `config`, and columns of `encrypt`
+    /// rules of the already compiled `policy` document, if any.
+    pub fn from_policy(config: &SQLHandlerConfig, policy: Option<&Policy>) -> Self {
+        Self {
+            keyring: keyring_from_config(config),
+            encryptor: None,
+            columns: columns_from_config(config, policy),
+            session: Session::new(),
+            tracker: DescriptionTracker::new(),
+            tracking: true,
+        }
+    }
+
+    /// Uses `session` state shared with an `EncryptionHandler`.
+    #[must_use]
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Uses `keyring` rather than keys defined in `SQLHandlerConfig`.
+    #[must_use]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Decrypts values sealed by `encryptor` with it, rather than with keys.
+    #[must_use]
+    pub fn with_encryptor(mut self, encryptor: Arc<dyn Encryptor>) -> Self {
+        self.encryptor = Some(encryptor);
+        self
+    }
+
+    /// Uses a `tracker` shared with the frontend `Pipe`, to follow
+    /// descriptions of prepared statements and portals.
+    ///
+    /// Backend Messages are expected to be tracked by `tracker` itself, as an
+    /// `SQLMessageHandler` preceding this one.
+    #[must_use]
+    pub fn with_tracker(mut self, tracker: DescriptionTracker) -> Self {
+        self.tracker = tracker;
+        self.tracking = false;
+        self
+    }
+
+    /// Returns the storage of `column`, if encrypted.
+    fn storage(&self, column: &[u8]) -> Option<Storage> {
+        find(&self.columns, column).map(|settings| settings.storage)
+    }
+
+    /// Decrypts in place `envelopes` of `fields` by their position, sealed
+    /// by the encryptor, at once. Fields which cannot be decrypted are left
+    /// as is.
+    async fn decrypt_remote(&self, fields: &mut [Option<Bytes>], envelopes: Vec<(usize, Vec<u8>)>) {
+        let encryptor = match (&self.encryptor, envelopes.is_empty()) {
+            (Some(encryptor), false) => encryptor,
+            _ => return,
+        };
+        let values: Vec<&[u8]> = envelopes
+            .iter()
+            .map(|(_, envelope)| &envelope[..])
+            .collect();
+        let results = match encryptor.decrypt(&values).await {
+            Ok(results) => results,
+            Err(err) => {
+                log::error!("values forwarded as is - {}", err);
+                return;
+            }
+        };
+        for ((idx, _), result) in envelopes.iter().zip(results) {
+            match result {
+                Ok(plaintext) => fields[*idx] = Some(plaintext),
+                Err(err) => log::debug!("value forwarded as is - {}", err),
+            }
+        }
+    }
+
+    /// Decrypts `value` of `column`, in text `format` if zero, or binary format.
+    fn decrypt(&self, column: &[u8], storage: Storage, value: Bytes, format: u16) -> Bytes {
+        let keyring = match &self.keyring {
+            Some(keyring) => keyring,
+            None => return value,
+        };
+        let plaintext = storage
+            .decode(&value, format)
+            .and_then(|envelope| envelope::open(keyring, column, &envelope));
+        match plaintext {
+            Ok(plaintext) => plaintext,
+            Err(err) => {
+                log::debug!("value forwarded as is - {}", err);
+                value
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for DecryptionHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_policy(config, policy_from_config(config).as_ref())
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        if self.tracking {
+            self.tracker.track_backend(&msg);
+        }
+        if let backend::Message::ParameterDescription(mut data_type_oids) = msg {
+            let parameters = self
+                .tracker
+                .described_statement()
+                .and_then(|name| self.session.parameters(&name));
+            if let Some(parameters) = parameters {
+                data_type_oids.truncate(parameters);
+            }
+            return Some(backend::Message::ParameterDescription(data_type_oids));
+        }
+        if !self.session.is_authorized() {
+            return Some(msg);
+        }
+
+        match msg {
+            backend::Message::RowDescription(mut descriptions) => {
+                // Decrypted values of `bytea` columns are text values.
+                for description in descriptions.iter_mut() {
+                    if description.data_type_oid == BYTEA_OID
+                        && self.storage(&description.name) == Some(Storage::Bytea)
+                    {
+                        description.data_type_oid = TEXT_OID;
+                    }
+                }
+                Some(backend::Message::RowDescription(descriptions))
+            }
+            backend::Message::DataRow(mut fields) => {
+                let descriptions = match self.tracker.row_description() {
+                    Some(descriptions) if descriptions.len() == fields.len() => descriptions,
+                    _ => {
+                        log::debug!("no matching description for `DataRow`, not decrypting");
+                        return Some(backend::Message::DataRow(fields));
+                    }
+                };
+
+                // Values sealed by the encryptor are decrypted at once, once
+                // others are decrypted with keys.
+                let mut envelopes = vec![];
+                for (idx, description) in descriptions.iter().enumerate() {
+                    let (value, storage) = match (&fields[idx], self.storage(&description.name)) {
+                        (Some(value), Some(storage)) => (value.clone(), storage),
+                        _ => continue,
+                    };
+                    match (&self.encryptor, storage.decode(&value, description.format)) {
+                        (Some(encryptor), Ok(envelope)) if encryptor.is_sealed(&envelope) => {
+                            envelopes.push((idx, envelope));
+                        }
+                        _ => {
+                            fields[idx] = Some(self.decrypt(
+                                &description.name,
+                                storage,
+                                value,
+                                description.format,
+                            ));
+                        }
+                    }
+                }
+                self.decrypt_remote(&mut fields, envelopes).await;
+                Some(backend::Message::DataRow(fields))
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend::{self, RowDescription};
+    use fern_protocol_postgresql::codec::frontend::{self, BindParameter, Parameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+
+    use fern_protocol_postgresql::tracker::DescriptionTracker;
+
+    use super::{envelope, DecryptionHandler, EncryptionHandler, Keyring, SQLHandlerConfig};
+    use super::{BlindIndex, Storage, UNENCRYPTABLE_STATEMENT};
+
+    const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+    /// Helper function building handlers sharing a session, encrypting
+    /// column "email" as base64, and column "ssn" as bytea.
+    fn handlers() -> (EncryptionHandler, DecryptionHandler) {
+        let config = SQLHandlerConfig::builder()
+            .set_override("encryption.columns.email.storage", "base64")
+            .unwrap()
+            .set_override("encryption.columns.ssn.storage", "bytea")
+            .unwrap()
+            .set_override("encryption.authorized_users", vec!["app"])
+            .unwrap()
+            .set_override("encryption.keys.env", "FERN_TEST_UNDEFINED_KEYS")
+            .unwrap()
+            .build()
+            .unwrap();
+        let encryption = EncryptionHandler::new(&config).with_keyring(Keyring::new(1, KEY));
+        let decryption = DecryptionHandler::new(&config)
+            .with_keyring(Keyring::new(1, KEY))
+            .with_session(encryption.session());
+        (encryption, decryption)
+    }
+
+    /// Helper function building a `StartupMessage` of `user`.
+    fn startup(user: &'static str) -> frontend::Message {
+        frontend::Message::StartupMessage {
+            frame_length: 0,
+            parameters: vec![Parameter {
+                name: Bytes::from_static(b"user"),
+                value: Bytes::from_static(user.as_bytes()),
+            }],
+        }
+    }
+
+    /// Helper function building a `Bind` of text parameters to statement "s".
+    fn bind(values: &[&'static str]) -> frontend::Message {
+        frontend::Message::Bind {
+            portal: Bytes::new(),
+            stmt_name: Bytes::from_static(b"s"),
+            parameters: values
+                .iter()
+                .map(|value| BindParameter {
+                    format: 0,
+                    value: Some(Bytes::from_static(value.as_bytes())),
+                })
+                .collect(),
+            results_formats: vec![],
+        }
+    }
+
+    /// Helper function returning parameters of an encrypted `Bind`.
+    async fn encrypted(handler: &mut EncryptionHandler) -> Vec<BindParameter> {
+        let parse = frontend::Message::Parse {
+            stmt_name: Bytes::from_static(b"s"),
+            query: Bytes::from_static(b"INSERT INTO users (id, email, ssn) VALUES ($1, $2, $3)"),
+            parameters_types: vec![],
+        };
+        handler.process(parse).await;
+        match handler
+            .process(bind(&["1", "jane@example.com", "123-45-6789"]))
+            .await
+        {
+            Some(frontend::Message::Bind { parameters, .. }) => parameters,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    /// Helper function building a `RowDescription` of columns "id", "email", "ssn".
+    fn description() -> Vec<RowDescription> {
+        ["id", "email", "ssn"]
+            .iter()
+            .zip([25, 25, 17])
+            .map(|(name, data_type_oid)| RowDescription {
+                name: Bytes::from_static(name.as_bytes()),
+                table_oid: 0,
+                column_attr: 0,
+                data_type_oid,
+                data_type_size: -1,
+                type_modifier: -1,
+                format: 0,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn bind_parameters_encrypted() {
+        let (mut encryption, _) = handlers();
+        let parameters = encrypted(&mut encryption).await;
+        let keyring = Keyring::new(1, KEY);
+
+        assert_eq!(Some(Bytes::from_static(b"1")), parameters[0].value, "clear");
+
+        let email = parameters[1].value.as_ref().unwrap();
+        let envelope = Storage::Base64.decode(email, 0).unwrap();
+        let plaintext = envelope::open(&keyring, b"email", &envelope).unwrap();
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext);
+
+        let ssn = parameters[2].value.as_ref().unwrap();
+        assert!(ssn.starts_with(b"\\x"), "bytea hex format");
+        let envelope = Storage::Bytea.decode(ssn, 0).unwrap();
+        let plaintext = envelope::open(&keyring, b"ssn", &envelope).unwrap();
+        assert_eq!(Bytes::from_static(b"123-45-6789"), plaintext);
+
+        let unrelated = bind(&["jane@example.com"]);
+        let msg = frontend::Message::Close {
+            kind: b'S',
+            name: Bytes::from_static(b"s"),
+        };
+        encryption.process(msg).await;
+        let expected = unrelated.clone();
+        assert_eq!(
+            Some(expected),
+            encryption.process(unrelated).await,
+            "closed"
+        );
+    }
+
     #[test]
-    fn it_works() {}
+    fn policy_columns_encrypted() {
+        let document = r#"
+            [encryption.columns.ssn]
+            storage = "bytea"
+            [[policy.rules]]
+            resources = ["*.*.users.email", "*.*.users.ssn"]
+            action = "encrypt"
+            storage = "base64"
+            mode = "deterministic"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        let policy = super::policy_from_config(&config);
+        assert!(super::is_enabled(&config, policy.as_ref()));
+        let columns = super::columns_from_config(&config, policy.as_ref());
+        assert_eq!(2, columns.len());
+        assert_eq!(&b"ssn"[..], &columns[0].name[..]);
+        assert_eq!(
+            Storage::Bytea,
+            columns[0].storage,
+            "encryption settings prevail"
+        );
+        assert_eq!(&b"email"[..], &columns[1].name[..]);
+        assert_eq!(Storage::Base64, columns[1].storage);
+        assert!(columns[1].deterministic);
+    }
+
+    #[tokio::test]
+    async fn bind_failed_without_keys() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("encryption.columns.email.storage", "base64")
+            .unwrap()
+            .set_override("encryption.keys.env", "FERN_TEST_UNDEFINED_KEYS")
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut encryption = EncryptionHandler::new(&config);
+        let parse = frontend::Message::Parse {
+            stmt_name: Bytes::from_static(b"s"),
+            query: Bytes::from_static(b"INSERT INTO users (email) VALUES ($1)"),
+            parameters_types: vec![],
+        };
+        encryption.process(parse).await;
+        match encryption.process(bind(&["jane@example.com"])).await {
+            Some(frontend::Message::Bind {
+                stmt_name,
+                parameters,
+                ..
+            }) => {
+                assert_eq!(Bytes::from_static(UNENCRYPTABLE_STATEMENT), stmt_name);
+                assert_eq!(None, parameters[0].value, "no value leaked");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn data_row_decrypted_for_authorized_sessions() {
+        for (user, authorized) in [("app", true), ("analyst", false)] {
+            let (mut encryption, mut decryption) = handlers();
+            encryption.process(startup(user)).await;
+            let parameters = encrypted(&mut encryption).await;
+            let stored: Vec<Option<Bytes>> = parameters
+                .into_iter()
+                .map(|parameter| parameter.value)
+                .collect();
+
+            let msg = decryption
+                .process(backend::Message::RowDescription(description()))
+                .await;
+            let ssn_type = match msg {
+                Some(backend::Message::RowDescription(description)) => description[2].data_type_oid,
+                other => panic!("unexpected message: {:?}", other),
+            };
+
+            let msg = decryption
+                .process(backend::Message::DataRow(stored.clone()))
+                .await;
+            if authorized {
+                assert_eq!(25, ssn_type, "decrypted bytea is text");
+                let expected = backend::Message::DataRow(vec![
+                    Some(Bytes::from_static(b"1")),
+                    Some(Bytes::from_static(b"jane@example.com")),
+                    Some(Bytes::from_static(b"123-45-6789")),
+                ]);
+                assert_eq!(Some(expected), msg, "decrypted");
+            } else {
+                assert_eq!(17, ssn_type, "bytea kept");
+                assert_eq!(Some(backend::Message::DataRow(stored)), msg, "as stored");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn data_row_plaintext_forwarded() {
+        let (mut encryption, mut decryption) = handlers();
+        encryption.process(startup("app")).await;
+        decryption
+            .process(backend::Message::RowDescription(description()))
+            .await;
+        let row = backend::Message::DataRow(vec![
+            Some(Bytes::from_static(b"1")),
+            Some(Bytes::from_static(b"legacy@example.com")),
+            None,
+        ]);
+        let expected = row.clone();
+        assert_eq!(Some(expected), decryption.process(row).await);
+    }
+
+    /// Helper function building a handler encrypting column "email"
+    /// deterministically, and column "ssn" with blind index "ssn_bidx".
+    fn searchable() -> EncryptionHandler {
+        let config = SQLHandlerConfig::builder()
+            .set_override("encryption.columns.email.mode", "deterministic")
+            .unwrap()
+            .set_override("encryption.columns.ssn.blind_index", "ssn_bidx")
+            .unwrap()
+            .set_override("encryption.keys.env", "FERN_TEST_UNDEFINED_KEYS")
+            .unwrap()
+            .set_override("encryption.blind_index.env", "FERN_TEST_UNDEFINED_KEY")
+            .unwrap()
+            .build()
+            .unwrap();
+        EncryptionHandler::new(&config)
+            .with_keyring(Keyring::new(1, KEY))
+            .with_blind_index(BlindIndex::new(KEY))
+    }
+
+    /// Helper function building a `Parse` of `query` as statement "s".
+    fn parse(query: &'static str) -> frontend::Message {
+        frontend::Message::Parse {
+            stmt_name: Bytes::from_static(b"s"),
+            query: Bytes::from_static(query.as_bytes()),
+            parameters_types: vec![],
+        }
+    }
+
+    /// Helper function returning parameters values of a processed `Bind`.
+    async fn bound(handler: &mut EncryptionHandler, values: &[&'static str]) -> Vec<Option<Bytes>> {
+        match handler.process(bind(values)).await {
+            Some(frontend::Message::Bind { parameters, .. }) => parameters
+                .into_iter()
+                .map(|parameter| parameter.value)
+                .collect(),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn deterministic_predicates_encrypted() {
+        let mut encryption = searchable();
+        encryption
+            .process(parse("INSERT INTO users (email) VALUES ($1)"))
+            .await;
+        let written = bound(&mut encryption, &["jane@example.com"]).await;
+
+        let query = "SELECT id FROM users WHERE email = $1";
+        let msg = encryption.process(parse(query)).await;
+        assert_eq!(Some(parse(query)), msg, "not rewritten");
+        let compared = bound(&mut encryption, &["jane@example.com"]).await;
+        assert_eq!(written, compared, "matching envelopes");
+
+        let keyring = Keyring::new(1, KEY);
+        let envelope = Storage::Base64.decode(compared[0].as_ref().unwrap(), 0);
+        let plaintext = envelope::open(&keyring, b"email", &envelope.unwrap());
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext.unwrap());
+    }
+
+    #[tokio::test]
+    async fn blind_index_parameters_added() {
+        let mut encryption = searchable();
+        let tracker = DescriptionTracker::new();
+        let mut decryption = DecryptionHandler::new(&SQLHandlerConfig::default())
+            .with_session(encryption.session())
+            .with_tracker(tracker.clone());
+        let index = BlindIndex::new(KEY).compute(b"ssn", b"123-45-6789");
+        let msg = encryption
+            .process(parse("INSERT INTO users (id, ssn) VALUES ($1, $2)"))
+            .await;
+        let expected = frontend::Message::Parse {
+            stmt_name: Bytes::from_static(b"s"),
+            query: Bytes::from_static(b"INSERT INTO users (id, ssn, ssn_bidx) VALUES ($1, $2, $3)"),
+            parameters_types: vec![],
+        };
+        assert_eq!(Some(expected), msg, "rewritten");
+
+        let values = bound(&mut encryption, &["1", "123-45-6789"]).await;
+        assert_eq!(3, values.len(), "added parameter");
+        assert_ne!(Some(Bytes::from_static(b"123-45-6789")), values[1]);
+        assert_eq!(Some(Bytes::from(index.clone())), values[2]);
+
+        // Added parameters are not disclosed to the frontend.
+        tracker.track_frontend(&frontend::Message::Describe {
+            kind: b'S',
+            name: Bytes::from_static(b"s"),
+        });
+        let msg = backend::Message::ParameterDescription(vec![23, 25, 25]);
+        let expected = backend::Message::ParameterDescription(vec![23, 25]);
+        assert_eq!(Some(expected), decryption.process(msg).await);
+
+        let msg = encryption
+            .process(parse("SELECT id FROM users WHERE ssn = $1"))
+            .await;
+        let expected = frontend::Message::Parse {
+            stmt_name: Bytes::from_static(b"s"),
+            query: Bytes::from_static(b"SELECT id FROM users WHERE ssn_bidx = $1"),
+            parameters_types: vec![],
+        };
+        assert_eq!(Some(expected), msg, "rewritten");
+        let values = bound(&mut encryption, &["123-45-6789"]).await;
+        assert_eq!(vec![Some(Bytes::from(index))], values);
+
+        let msg = backend::Message::ParameterDescription(vec![25]);
+        let expected = msg.clone();
+        assert_eq!(Some(expected), decryption.process(msg).await, "as is");
+    }
 }
@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Re-encryption of stored values with the current key, once keys rotated.
+//!
+//! Values are taken as returned by the server in text format, i.e. as
+//! base64-encoded envelopes or hex-encoded `bytea`s, and given back in the
+//! same storage and encryption mode, so that deterministic values remain
+//! searchable with the current key. Blind indexes are not versioned, and do
+//! not need to be computed again.
+
+use bytes::Bytes;
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::envelope::{self, Storage};
+use crate::keyring::Keyring;
+use crate::{columns_from_config, find, policy_from_config, Column};
+
+/// Re-encrypts values of encrypted columns with the current key of a keyring.
+#[derive(Debug, Clone)]
+pub struct Reencryptor {
+    keyring: Keyring,
+    columns: Vec<Column>,
+}
+
+impl Reencryptor {
+    pub fn new(keyring: Keyring) -> Self {
+        Self {
+            keyring,
+            columns: vec![],
+        }
+    }
+
+    /// Adds encrypted `column`, whose envelopes are stored as `storage`.
+    #[must_use]
+    pub fn with_column(mut self, column: &str, storage: Storage) -> Self {
+        self.columns.push(Column {
+            name: Bytes::from(column.to_string()),
+            storage,
+            deterministic: false,
+            blind_index: None,
+        });
+        self
+    }
+
+    /// Creates a `Reencryptor` of keys and encrypted columns defined in
+    /// `config`, as used by the `EncryptionHandler`.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        Ok(Self {
+            keyring: Keyring::from_config(config)?,
+            columns: columns_from_config(config, policy_from_config(config).as_ref()),
+        })
+    }
+
+    /// Returns the keyring in use.
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Returns whether `column` is encrypted.
+    pub fn is_encrypted(&self, column: &str) -> bool {
+        find(&self.columns, column.as_bytes()).is_some()
+    }
+
+    /// Encrypts again `value` of `column`, in text format, with the current
+    /// key, returning `None` if it already is encrypted with that key.
+    pub fn reencrypt(&self, column: &str, value: &[u8]) -> io::Result<Option<Bytes>> {
+        let settings = find(&self.columns, column.as_bytes()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("column '{}' is not encrypted", column),
+            )
+        })?;
+
+        let envelope = settings.storage.decode(value, 0)?;
+        let resealed = envelope::reseal(&self.keyring, &settings.name, &envelope)?;
+        Ok(resealed.map(|envelope| settings.storage.encode(&envelope, 0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+    const KEY_2: &[u8; 32] = b"fedcba9876543210fedcba9876543210";
+
+    #[test]
+    fn valid_reencrypt() {
+        let former = Keyring::new(1, KEY_1);
+        let envelope = envelope::seal(&former, b"email", b"jane@example.com").unwrap();
+        let stored = Storage::Bytea.encode(&envelope, 0);
+
+        let keyring = former.with_key(2, KEY_2);
+        let reencryptor = Reencryptor::new(keyring.clone()).with_column("email", Storage::Bytea);
+        assert!(reencryptor.is_encrypted("email"));
+        assert!(!reencryptor.is_encrypted("phone"));
+
+        let reencrypted = reencryptor.reencrypt("email", &stored).unwrap().unwrap();
+        assert!(reencrypted.starts_with(b"\\x01"), "bytea kept");
+        let envelope = Storage::Bytea.decode(&reencrypted, 0).unwrap();
+        assert_eq!(2, envelope::key_version(&envelope).unwrap());
+        let plaintext = envelope::open(&keyring, b"email", &envelope).unwrap();
+        assert_eq!(Bytes::from_static(b"jane@example.com"), plaintext);
+
+        assert_eq!(None, reencryptor.reencrypt("email", &reencrypted).unwrap());
+    }
+
+    #[test]
+    fn invalid_reencrypt() {
+        let reencryptor =
+            Reencryptor::new(Keyring::new(2, KEY_2)).with_column("email", Storage::Base64);
+        let envelope = envelope::seal(&Keyring::new(1, KEY_1), b"email", b"jane").unwrap();
+        let stored = Storage::Base64.encode(&envelope, 0);
+        assert!(
+            reencryptor.reencrypt("email", &stored).is_err(),
+            "unknown key"
+        );
+        assert!(
+            reencryptor.reencrypt("email", b"jane").is_err(),
+            "plaintext"
+        );
+        assert!(reencryptor.reencrypt("phone", &stored).is_err(), "column");
+    }
+}
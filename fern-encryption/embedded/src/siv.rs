@@ -0,0 +1,168 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deterministic authenticated encryption with AES-SIV ([RFC 5297]), using
+//! AES-256 for both the S2V pseudo-random function (CMAC) and CTR mode.
+//!
+//! The same plaintext and associated data always give the same ciphertext,
+//! so that encrypted values can be compared for equality by the server.
+//!
+//! [RFC 5297]: https://www.rfc-editor.org/rfc/rfc5297
+
+use aes::{Aes256, Block};
+use cmac::{Cmac, Mac};
+use ctr::cipher::{InnerIvInit, KeyInit, StreamCipher, StreamCipherCoreWrapper};
+use ctr::flavors::Ctr128BE;
+use ctr::CtrCore;
+use dbl::Dbl;
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+/// Size in bytes of AES-SIV keys, half for S2V and half for CTR mode.
+pub const KEY_SIZE: usize = 64;
+
+/// Size in bytes of synthetic IVs, prepended to ciphertexts.
+pub const SIV_SIZE: usize = 16;
+
+/// Returns `a` XOR `b`.
+fn xor(mut a: Block, b: &Block) -> Block {
+    a.iter_mut().zip(b.iter()).for_each(|(a, b)| *a ^= b);
+    a
+}
+
+/// An AES-SIV key.
+#[derive(Clone)]
+pub struct Siv {
+    mac: Cmac<Aes256>,
+    ctr: Aes256,
+}
+
+impl fmt::Debug for Siv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: key material is not disclosed.
+        f.write_str("Siv")
+    }
+}
+
+impl Siv {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let (mac, ctr) = key.split_at(KEY_SIZE / 2);
+        Self {
+            mac: <Cmac<Aes256> as KeyInit>::new_from_slice(mac).expect("valid key size"),
+            ctr: Aes256::new_from_slice(ctr).expect("valid key size"),
+        }
+    }
+
+    /// Returns the CMAC of `data`.
+    fn cmac(&self, data: &[u8]) -> Block {
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.finalize().into_bytes()
+    }
+
+    /// Returns the synthetic IV of `plaintext` with `associated_data`.
+    fn s2v(&self, associated_data: &[u8], plaintext: &[u8]) -> Block {
+        let d = self.cmac(&[0; SIV_SIZE]);
+        let d = xor(d.dbl(), &self.cmac(associated_data));
+
+        if plaintext.len() >= SIV_SIZE {
+            let mut t = plaintext.to_vec();
+            let end = t.len() - SIV_SIZE;
+            t[end..].iter_mut().zip(d.iter()).for_each(|(t, d)| *t ^= d);
+            self.cmac(&t)
+        } else {
+            let mut padded = Block::default();
+            padded[..plaintext.len()].copy_from_slice(plaintext);
+            padded[plaintext.len()] = 0x80;
+            self.cmac(&xor(d.dbl(), &padded))
+        }
+    }
+
+    /// Applies the CTR keystream derived from `siv` to `data`.
+    fn ctr(&self, siv: &Block, data: &mut [u8]) {
+        // Clearing 31st and 63rd bits allows for 64-bit counter implementations.
+        let mut iv = *siv;
+        iv[8] &= 0x7f;
+        iv[12] &= 0x7f;
+        let core = CtrCore::<Aes256, Ctr128BE>::inner_iv_init(self.ctr.clone(), &iv);
+        StreamCipherCoreWrapper::from_core(core).apply_keystream(data);
+    }
+
+    /// Encrypts `plaintext`, authenticating `associated_data` along with it.
+    /// The synthetic IV is prepended to the ciphertext.
+    pub fn encrypt(&self, associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let siv = self.s2v(associated_data, plaintext);
+        let mut ciphertext = Vec::with_capacity(SIV_SIZE + plaintext.len());
+        ciphertext.extend_from_slice(&siv);
+        ciphertext.extend_from_slice(plaintext);
+        self.ctr(&siv, &mut ciphertext[SIV_SIZE..]);
+        ciphertext
+    }
+
+    /// Decrypts `ciphertext`, returning `None` unless it is authentic.
+    pub fn decrypt(&self, associated_data: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < SIV_SIZE {
+            return None;
+        }
+        let (siv, ciphertext) = ciphertext.split_at(SIV_SIZE);
+        let siv = Block::clone_from_slice(siv);
+        let mut plaintext = ciphertext.to_vec();
+        self.ctr(&siv, &mut plaintext);
+
+        let expected = self.s2v(associated_data, &plaintext);
+        match bool::from(expected.ct_eq(&siv)) {
+            true => Some(plaintext),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function returning the key made of bytes 0 to 63.
+    fn key() -> [u8; KEY_SIZE] {
+        let mut key = [0; KEY_SIZE];
+        key.iter_mut()
+            .enumerate()
+            .for_each(|(idx, byte)| *byte = idx as u8);
+        key
+    }
+
+    #[test]
+    fn valid_encrypt_decrypt() {
+        // Reference values computed with the `cryptography` Python package.
+        let siv = Siv::new(&key());
+        let cases = [
+            (
+                &b"jane@example.com"[..],
+                "f6e4ed6c125f7ca6da3e3c8582ac3cc7d81afa97a1d35c4fa23327fa873f251b",
+            ),
+            (&b"jane"[..], "4b54abfdabdde42c1262e447122f18c7e0fcb1ff"),
+            (&b""[..], "6429f14e15a52bc2641b34204f419e3e"),
+        ];
+        for (plaintext, expected) in cases {
+            let ciphertext = siv.encrypt(b"email", plaintext);
+            assert_eq!(expected, hex::encode(&ciphertext));
+            assert_eq!(
+                ciphertext,
+                siv.encrypt(b"email", plaintext),
+                "deterministic"
+            );
+            assert_eq!(Some(plaintext.to_vec()), siv.decrypt(b"email", &ciphertext));
+        }
+    }
+
+    #[test]
+    fn invalid_decrypt() {
+        let siv = Siv::new(&key());
+        let ciphertext = siv.encrypt(b"email", b"jane@example.com");
+        assert_eq!(None, siv.decrypt(b"phone", &ciphertext), "other data");
+
+        let mut tampered = ciphertext.clone();
+        tampered[SIV_SIZE] ^= 1;
+        assert_eq!(None, siv.decrypt(b"email", &tampered), "tampered");
+        assert_eq!(None, siv.decrypt(b"email", &ciphertext[..8]), "truncated");
+    }
+}
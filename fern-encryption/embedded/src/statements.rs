@@ -0,0 +1,484 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Analysis of prepared statements, finding which parameters are written
+//! to encrypted columns in `INSERT` and `UPDATE` statements, and which are
+//! compared for equality to encrypted columns, e.g. in `WHERE` clauses.
+//!
+//! Only parameters directly written to or compared to a column are found,
+//! possibly with a cast, e.g. `INSERT INTO t (a) VALUES ($1)`,
+//! `UPDATE t SET a = $1::text`, `a = $1`, `a <> $1`, or `a IN ($1, $2)`.
+//!
+//! Statements involving columns with a blind index are rewritten:
+//! - values written to such columns are also written to their blind index,
+//!   as parameters added after the existing ones,
+//! - comparisons of such columns are made to their blind index instead.
+
+use sqlparser::ast::{
+    visit_expressions, visit_expressions_mut, Assignment, AssignmentTarget, BinaryOperator, Expr,
+    Ident, ObjectName, OnConflictAction, OnInsert, SetExpr, Statement, Value,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+/// Encrypted columns, by parameter index.
+pub type ParameterColumns = HashMap<usize, String>;
+
+/// Encrypted column names, with the name of their blind index column, if any.
+pub type Watched = HashMap<String, Option<String>>;
+
+/// Parameters of a statement involving encrypted columns.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Analysis {
+    /// Encrypted columns written by parameters.
+    pub writes: ParameterColumns,
+
+    /// Encrypted columns compared for equality to parameters.
+    pub predicates: ParameterColumns,
+
+    /// Parameters added to write blind indexes, in order, as the encrypted
+    /// column and the index of the parameter holding its value.
+    pub indexes: Vec<(String, usize)>,
+
+    /// Number of parameters of the statement, before any is added.
+    pub parameters: usize,
+
+    /// Encrypted columns written or compared to something else than a
+    /// parameter, thus not processed.
+    pub unprocessed: Vec<String>,
+
+    /// Statement rewritten to use blind indexes, if needed.
+    pub rewritten: Option<String>,
+}
+
+impl Analysis {
+    /// Returns whether the statement involves encrypted columns.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty() && self.predicates.is_empty() && self.indexes.is_empty()
+    }
+
+    /// Adds a parameter writing the blind index of `column`, whose value is
+    /// held by parameter `source`, and returns it.
+    fn add_index(&mut self, column: &str, source: usize) -> Expr {
+        self.indexes.push((column.to_string(), source));
+        placeholder(self.parameters + self.indexes.len())
+    }
+}
+
+/// Returns the name `ident` stands for, folding unquoted identifiers
+/// to lower case as PostgreSQL does.
+fn name(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+/// Returns an identifier standing for `name`, quoted if needed.
+fn ident(name: &str) -> Ident {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    match plain {
+        true => Ident::new(name),
+        false => Ident::with_quote('"', name),
+    }
+}
+
+/// Returns the column name of a possibly qualified `object`.
+fn column(object: &ObjectName) -> Option<String> {
+    object.0.last().map(name)
+}
+
+/// Returns the placeholder of the parameter of (one-based) `number`.
+fn placeholder(number: usize) -> Expr {
+    Expr::Value(Value::Placeholder(format!("${}", number)))
+}
+
+/// Returns the index (zero-based) of the parameter `expr` stands for, if any.
+fn parameter(expr: &Expr) -> Option<usize> {
+    match expr {
+        Expr::Value(Value::Placeholder(placeholder)) => placeholder
+            .strip_prefix('$')
+            .and_then(|idx| idx.parse::<usize>().ok())
+            .and_then(|idx| idx.checked_sub(1)),
+        Expr::Cast { expr, .. } | Expr::Nested(expr) => parameter(expr),
+        _ => None,
+    }
+}
+
+/// Returns the column of the row proposed for insertion `expr` stands for,
+/// in an `ON CONFLICT DO UPDATE` clause, if any.
+fn excluded(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 && name(&idents[0]) == "excluded" => {
+            Some(name(&idents[1]))
+        }
+        _ => None,
+    }
+}
+
+/// Records the parameter written to `column` by `expr`, if any, into `found`,
+/// and returns its index. Values not given by a parameter are recorded
+/// into `unprocessed`.
+fn record(
+    column: &str,
+    expr: &Expr,
+    found: &mut ParameterColumns,
+    unprocessed: &mut Vec<String>,
+) -> Option<usize> {
+    match parameter(expr) {
+        Some(idx) => {
+            found.insert(idx, column.to_string());
+            Some(idx)
+        }
+        None => {
+            if !matches!(expr, Expr::Value(Value::Null)) {
+                unprocessed.push(column.to_string());
+            }
+            None
+        }
+    }
+}
+
+/// Records parameters written to encrypted columns by `statement`, writing
+/// blind indexes as well. Returns whether `statement` was rewritten.
+fn writes(statement: &mut Statement, watched: &Watched, analysis: &mut Analysis) -> bool {
+    let mut rewritten = false;
+    let assignments = match statement {
+        Statement::Insert(insert) => {
+            let columns: Vec<String> = insert.columns.iter().map(name).collect();
+            match insert.source.as_deref_mut().map(|q| &mut *q.body) {
+                Some(SetExpr::Values(values)) => {
+                    for (position, column) in columns.iter().enumerate() {
+                        let index = match watched.get(column) {
+                            Some(index) => index.as_ref().filter(|index| !columns.contains(index)),
+                            None => continue,
+                        };
+                        if let Some(index) = index {
+                            insert.columns.push(ident(index));
+                            rewritten = true;
+                        }
+                        for row in values.rows.iter_mut() {
+                            let source = match row.get(position) {
+                                Some(expr) => record(
+                                    column,
+                                    expr,
+                                    &mut analysis.writes,
+                                    &mut analysis.unprocessed,
+                                ),
+                                None => continue,
+                            };
+                            if index.is_some() {
+                                row.push(match source {
+                                    Some(source) => analysis.add_index(column, source),
+                                    None => Expr::Value(Value::Null),
+                                });
+                            }
+                        }
+                    }
+                }
+                _ => analysis.unprocessed.extend(
+                    columns
+                        .into_iter()
+                        .filter(|column| watched.contains_key(column)),
+                ),
+            }
+            match &mut insert.on {
+                Some(OnInsert::OnConflict(conflict)) => match &mut conflict.action {
+                    OnConflictAction::DoUpdate(update) => &mut update.assignments,
+                    OnConflictAction::DoNothing => return rewritten,
+                },
+                _ => return rewritten,
+            }
+        }
+        Statement::Update { assignments, .. } => assignments,
+        _ => return rewritten,
+    };
+
+    let mut added = vec![];
+    for assignment in assignments.iter() {
+        let column = match &assignment.target {
+            AssignmentTarget::ColumnName(object) => column(object),
+            _ => None,
+        };
+        let (column, index) = match column.and_then(|column| {
+            watched
+                .get(&column)
+                .map(|index| (column.clone(), index.as_ref()))
+        }) {
+            Some(found) => found,
+            None => continue,
+        };
+
+        let value = match excluded(&assignment.value) {
+            // Values of the row proposed for insertion are already processed.
+            Some(excluded) if excluded == column => index
+                .map(|index| Expr::CompoundIdentifier(vec![Ident::new("excluded"), ident(index)])),
+            _ => {
+                let source = record(
+                    &column,
+                    &assignment.value,
+                    &mut analysis.writes,
+                    &mut analysis.unprocessed,
+                );
+                index.map(|_| match source {
+                    Some(source) => analysis.add_index(&column, source),
+                    None => Expr::Value(Value::Null),
+                })
+            }
+        };
+        if let (Some(index), Some(value)) = (index, value) {
+            added.push(Assignment {
+                target: AssignmentTarget::ColumnName(ObjectName(vec![ident(index)])),
+                value,
+            });
+        }
+    }
+    rewritten |= !added.is_empty();
+    assignments.extend(added);
+    rewritten
+}
+
+/// Returns the encrypted column `expr` stands for, if any.
+fn encrypted_column(expr: &Expr, watched: &Watched) -> Option<String> {
+    let column = match expr {
+        Expr::Identifier(ident) => name(ident),
+        Expr::CompoundIdentifier(idents) => idents.last().map(name)?,
+        _ => return None,
+    };
+    watched.contains_key(&column).then_some(column)
+}
+
+/// Records the parameter `value` compared to encrypted `column`, if any.
+/// Returns whether `value` is a parameter.
+fn compared(column: &str, value: &Expr, analysis: &mut Analysis) -> bool {
+    match (parameter(value), value) {
+        (Some(idx), _) => {
+            analysis.predicates.insert(idx, column.to_string());
+            true
+        }
+        (None, Expr::Value(Value::Null)) => false,
+        (None, Expr::Value(_)) => {
+            analysis.unprocessed.push(column.to_string());
+            false
+        }
+        // Note: comparisons to other columns or expressions are left as is.
+        _ => false,
+    }
+}
+
+/// Makes `expr` standing for `column` stand for its blind index, if any.
+/// Returns whether `expr` was rewritten.
+fn use_blind_index(expr: &mut Expr, column: &str, watched: &Watched) -> bool {
+    let index = match watched.get(column) {
+        Some(Some(index)) => ident(index),
+        _ => return false,
+    };
+    match expr {
+        Expr::Identifier(ident) => *ident = index,
+        Expr::CompoundIdentifier(idents) => *idents.last_mut().expect("column") = index,
+        _ => return false,
+    }
+    true
+}
+
+/// Records parameters compared for equality to encrypted columns by
+/// `statement`, comparing blind indexes instead if any. Returns whether
+/// `statement` was rewritten.
+fn predicates(statement: &mut Statement, watched: &Watched, analysis: &mut Analysis) -> bool {
+    let mut rewritten = false;
+    let _ = visit_expressions_mut(statement, |expr| {
+        match expr {
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq | BinaryOperator::NotEq,
+                right,
+            } => {
+                if let Some(column) = encrypted_column(left, watched) {
+                    if compared(&column, right, analysis) {
+                        rewritten |= use_blind_index(left, &column, watched);
+                    }
+                } else if let Some(column) = encrypted_column(right, watched) {
+                    if compared(&column, left, analysis) {
+                        rewritten |= use_blind_index(right, &column, watched);
+                    }
+                }
+            }
+            Expr::InList { expr, list, .. } => {
+                if let Some(column) = encrypted_column(expr, watched) {
+                    let mut found = false;
+                    for value in list.iter() {
+                        found |= compared(&column, value, analysis);
+                    }
+                    if found {
+                        rewritten |= use_blind_index(expr, &column, watched);
+                    }
+                }
+            }
+            _ => {}
+        }
+        ControlFlow::<()>::Continue(())
+    });
+    rewritten
+}
+
+/// Analyzes parameters of `query` involving `watched` columns, where
+/// `declared` parameters may have been declared with their data types.
+pub fn analyze(query: &str, watched: &Watched, declared: usize) -> Analysis {
+    let mut analysis = Analysis::default();
+    let mut statements = match Parser::parse_sql(&PostgreSqlDialect {}, query) {
+        Ok(statements) => statements,
+        Err(err) => {
+            log::debug!("cannot parse statement, skipping analysis - {}", err);
+            return analysis;
+        }
+    };
+
+    // Parameters added for blind indexes are numbered after existing ones.
+    analysis.parameters = declared;
+    let _ = visit_expressions(&statements, |expr| {
+        if let Some(idx) = parameter(expr) {
+            analysis.parameters = analysis.parameters.max(idx + 1);
+        }
+        ControlFlow::<()>::Continue(())
+    });
+
+    let mut rewritten = false;
+    for statement in statements.iter_mut() {
+        rewritten |= writes(statement, watched, &mut analysis);
+        rewritten |= predicates(statement, watched, &mut analysis);
+    }
+    if rewritten {
+        let statements: Vec<String> = statements.iter().map(ToString::to_string).collect();
+        analysis.rewritten = Some(statements.join("; "));
+    }
+    analysis
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watched() -> Watched {
+        Watched::from([
+            ("email".to_string(), None),
+            ("ssn".to_string(), Some("ssn_bidx".to_string())),
+        ])
+    }
+
+    #[test]
+    fn valid_insert() {
+        let query = "INSERT INTO users (id, Email, ssn) VALUES ($1, $2, $3::text), ($4, $5, NULL)";
+        let analysis = analyze(query, &watched(), 0);
+        let expected = HashMap::from([
+            (1, "email".to_string()),
+            (2, "ssn".to_string()),
+            (4, "email".to_string()),
+        ]);
+        assert_eq!(expected, analysis.writes);
+        assert!(analysis.unprocessed.is_empty(), "NULL needs no encryption");
+        assert_eq!(vec![("ssn".to_string(), 2)], analysis.indexes);
+
+        let query = "INSERT INTO users (id, email) VALUES ($1, $2)";
+        assert_eq!(
+            None,
+            analyze(query, &watched(), 0).rewritten,
+            "no blind index"
+        );
+    }
+
+    #[test]
+    fn valid_update_and_upsert() {
+        let query = "UPDATE users SET email = $2, name = $3 WHERE id = $1";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(HashMap::from([(1, "email".to_string())]), analysis.writes);
+
+        let query = "INSERT INTO users (id, email) VALUES ($1, $2) \
+                     ON CONFLICT (id) DO UPDATE SET email = $3, name = excluded.name";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(2, analysis.writes.len());
+        assert_eq!(Some(&"email".to_string()), analysis.writes.get(&2));
+    }
+
+    #[test]
+    fn unprocessed_values() {
+        let query = "INSERT INTO users (email) VALUES ('jane@example.com')";
+        let analysis = analyze(query, &watched(), 0);
+        assert!(analysis.writes.is_empty());
+        assert_eq!(vec!["email".to_string()], analysis.unprocessed);
+
+        let query = "INSERT INTO users (email) SELECT email FROM old_users";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(vec!["email".to_string()], analysis.unprocessed);
+
+        let query = "SELECT * FROM users WHERE email = 'jane@example.com'";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(vec!["email".to_string()], analysis.unprocessed);
+
+        let query = "SELECT * FROM users u JOIN old_users o ON u.email = o.email";
+        assert_eq!(Analysis::default(), analyze(query, &watched(), 0));
+    }
+
+    #[test]
+    fn valid_predicates() {
+        let query = "SELECT id FROM users u WHERE u.email = $1 OR ($2::text <> email) \
+                     OR email IN ($3, NULL) OR name = $4";
+        let analysis = analyze(query, &watched(), 0);
+        let expected = HashMap::from([
+            (0, "email".to_string()),
+            (1, "email".to_string()),
+            (2, "email".to_string()),
+        ]);
+        assert_eq!(expected, analysis.predicates);
+        assert_eq!(4, analysis.parameters);
+        assert!(analysis.writes.is_empty());
+
+        let query = "DELETE FROM users WHERE email = $1";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(Some(&"email".to_string()), analysis.predicates.get(&0));
+    }
+
+    #[test]
+    fn blind_index_rewritten() {
+        let query = "INSERT INTO users (id, ssn) VALUES ($1, $2), ($3, NULL)";
+        let analysis = analyze(query, &watched(), 4);
+        assert_eq!(
+            Some("INSERT INTO users (id, ssn, ssn_bidx) VALUES ($1, $2, $5), ($3, NULL, NULL)"),
+            analysis.rewritten.as_deref()
+        );
+        assert_eq!(4, analysis.parameters, "declared parameters");
+        assert_eq!(vec![("ssn".to_string(), 1)], analysis.indexes);
+
+        let query = "UPDATE users SET ssn = $1 WHERE ssn = $2 AND email = $3";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(
+            Some("UPDATE users SET ssn = $1, ssn_bidx = $4 WHERE ssn_bidx = $2 AND email = $3"),
+            analysis.rewritten.as_deref()
+        );
+        assert_eq!(vec![("ssn".to_string(), 0)], analysis.indexes);
+        assert_eq!(Some(&"ssn".to_string()), analysis.predicates.get(&1));
+
+        let query = "INSERT INTO users (id, ssn) VALUES ($1, $2) \
+                     ON CONFLICT (id) DO UPDATE SET ssn = excluded.ssn";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(
+            Some(
+                "INSERT INTO users (id, ssn, ssn_bidx) VALUES ($1, $2, $3) \
+                 ON CONFLICT(id) DO UPDATE SET ssn = excluded.ssn, ssn_bidx = excluded.ssn_bidx"
+            ),
+            analysis.rewritten.as_deref()
+        );
+        assert!(analysis.unprocessed.is_empty());
+
+        let query = "SELECT * FROM users WHERE \"ssn\" IN ($1, $2)";
+        let analysis = analyze(query, &watched(), 0);
+        assert_eq!(
+            Some("SELECT * FROM users WHERE ssn_bidx IN ($1, $2)"),
+            analysis.rewritten.as_deref()
+        );
+    }
+}
@@ -0,0 +1,268 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTP client of the Vault API, authenticated either with a token, or with
+//! an [AppRole] whose token is obtained on first use, and again whenever it
+//! is rejected, e.g. once expired.
+//!
+//! [AppRole]: https://developer.hashicorp.com/vault/docs/auth/approle
+
+use serde_json::{json, Value};
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+/// Address of Vault, unless defined otherwise.
+const DEFAULT_ADDRESS: &str = "http://127.0.0.1:8200";
+
+/// Environment variable holding the token, unless defined otherwise.
+const DEFAULT_TOKEN_ENV: &str = "VAULT_TOKEN";
+
+/// Environment variable holding the AppRole secret ID, unless defined otherwise.
+const DEFAULT_SECRET_ID_ENV: &str = "VAULT_SECRET_ID";
+
+/// How long to wait for Vault responses, unless defined otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maps an error to an `io::Error`.
+pub(crate) fn to_io_error(kind: io::ErrorKind, reason: impl std::fmt::Display) -> io::Error {
+    io::Error::new(kind, format!("vault - {}", reason))
+}
+
+/// Reads the value of environment variable `env`.
+fn from_env(env: &str) -> io::Result<String> {
+    std::env::var(env).map_err(|_| {
+        to_io_error(
+            io::ErrorKind::NotFound,
+            format!("'{}' environment variable undefined", env),
+        )
+    })
+}
+
+/// How the client authenticates to Vault.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    /// A token, used as is.
+    Token(String),
+
+    /// An AppRole, exchanging its credentials for a token.
+    AppRole {
+        /// Path where the AppRole auth method is enabled.
+        mount: String,
+        role_id: String,
+        secret_id: String,
+    },
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Note: secrets are not disclosed.
+        match self {
+            Self::Token(_) => f.write_str("Token"),
+            Self::AppRole { mount, role_id, .. } => f
+                .debug_struct("AppRole")
+                .field("mount", mount)
+                .field("role_id", role_id)
+                .finish(),
+        }
+    }
+}
+
+impl Auth {
+    /// Loads authentication settings found in `config`:
+    /// - `encryption.vault.auth.method`: either `token` (the default), or `approle`,
+    /// - `encryption.vault.auth.token_env`: environment variable holding the
+    ///   token, `VAULT_TOKEN` by default,
+    /// - `encryption.vault.auth.mount`: path of the AppRole auth method,
+    ///   `approle` by default,
+    /// - `encryption.vault.auth.role_id`: AppRole role ID,
+    /// - `encryption.vault.auth.secret_id_env`: environment variable holding
+    ///   the AppRole secret ID, `VAULT_SECRET_ID` by default.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let method = config
+            .get::<String>("encryption.vault.auth.method")
+            .unwrap_or_else(|_| "token".to_string());
+        match method.as_str() {
+            "token" => {
+                let env = config
+                    .get::<String>("encryption.vault.auth.token_env")
+                    .unwrap_or_else(|_| DEFAULT_TOKEN_ENV.to_string());
+                Ok(Self::Token(from_env(&env)?))
+            }
+            "approle" => {
+                let role_id = config
+                    .get::<String>("encryption.vault.auth.role_id")
+                    .map_err(|err| to_io_error(io::ErrorKind::NotFound, err))?;
+                let env = config
+                    .get::<String>("encryption.vault.auth.secret_id_env")
+                    .unwrap_or_else(|_| DEFAULT_SECRET_ID_ENV.to_string());
+                Ok(Self::AppRole {
+                    mount: config
+                        .get::<String>("encryption.vault.auth.mount")
+                        .unwrap_or_else(|_| "approle".to_string()),
+                    role_id,
+                    secret_id: from_env(&env)?,
+                })
+            }
+            other => Err(to_io_error(
+                io::ErrorKind::InvalidInput,
+                format!("unknown auth method '{}'", other),
+            )),
+        }
+    }
+}
+
+/// A Vault API client.
+#[derive(Debug)]
+pub struct Client {
+    http: reqwest::Client,
+
+    /// Base URL of Vault, e.g. `https://vault.example.com:8200`.
+    address: String,
+
+    /// Vault Enterprise namespace, if any.
+    namespace: Option<String>,
+
+    auth: Auth,
+
+    /// Token in use, once authenticated.
+    token: Mutex<Option<String>>,
+}
+
+impl Client {
+    pub fn new(address: &str, auth: Auth) -> Self {
+        let token = match &auth {
+            Auth::Token(token) => Some(token.clone()),
+            Auth::AppRole { .. } => None,
+        };
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(DEFAULT_TIMEOUT)
+                .build()
+                .expect("valid HTTP client settings"),
+            address: address.trim_end_matches('/').to_string(),
+            namespace: None,
+            auth,
+            token: Mutex::new(token),
+        }
+    }
+
+    /// Sends requests in Vault Enterprise `namespace`.
+    #[must_use]
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Creates a client from settings found in `config`:
+    /// - `encryption.vault.address`: base URL of Vault, `VAULT_ADDR`
+    ///   environment variable by default, or `http://127.0.0.1:8200`,
+    /// - `encryption.vault.namespace`: Vault Enterprise namespace,
+    /// - `encryption.vault.auth`: authentication settings (see [`Auth`]).
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let address = config
+            .get::<String>("encryption.vault.address")
+            .or_else(|_| std::env::var("VAULT_ADDR"))
+            .unwrap_or_else(|_| DEFAULT_ADDRESS.to_string());
+        let client = Self::new(&address, Auth::from_config(config)?);
+        match config.get::<String>("encryption.vault.namespace") {
+            Ok(namespace) => Ok(client.with_namespace(&namespace)),
+            Err(_) => Ok(client),
+        }
+    }
+
+    /// Locks the token in use, regardless of poisoning as it is always consistent.
+    fn token(&self) -> MutexGuard<'_, Option<String>> {
+        self.token.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sends `body` to API `path`, returning the JSON response, if successful.
+    async fn send(&self, path: &str, token: Option<&str>, body: &Value) -> io::Result<Value> {
+        let mut request = self
+            .http
+            .post(format!("{}/v1/{}", self.address, path))
+            .json(body);
+        if let Some(token) = token {
+            request = request.header("X-Vault-Token", token);
+        }
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|err| to_io_error(io::ErrorKind::ConnectionRefused, err))?;
+        let status = response.status();
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|err| to_io_error(io::ErrorKind::InvalidData, err))?;
+        if status.is_success() {
+            return Ok(body);
+        }
+
+        let errors = body["errors"]
+            .as_array()
+            .map(|errors| {
+                errors
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        let kind = match status.as_u16() {
+            403 => io::ErrorKind::PermissionDenied,
+            400 => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        Err(to_io_error(kind, format!("{} {}", status, errors)))
+    }
+
+    /// Exchanges AppRole credentials for a token.
+    async fn login(&self) -> io::Result<String> {
+        let (mount, role_id, secret_id) = match &self.auth {
+            Auth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => (mount, role_id, secret_id),
+            Auth::Token(token) => return Ok(token.clone()),
+        };
+
+        let path = format!("auth/{}/login", mount);
+        let body = json!({ "role_id": role_id, "secret_id": secret_id });
+        let response = self.send(&path, None, &body).await?;
+        let token = response["auth"]["client_token"]
+            .as_str()
+            .ok_or_else(|| to_io_error(io::ErrorKind::InvalidData, "no token in login response"))?;
+        log::debug!("logged in to vault with AppRole '{}'", role_id);
+        *self.token() = Some(token.to_string());
+        Ok(token.to_string())
+    }
+
+    /// Sends `body` to API `path`, authenticated, returning the JSON response.
+    pub async fn post(&self, path: &str, body: &Value) -> io::Result<Value> {
+        let token = self.token().clone();
+        let token = match token {
+            Some(token) => token,
+            None => self.login().await?,
+        };
+
+        match self.send(path, Some(&token), body).await {
+            // AppRole tokens expire, logging in again once.
+            Err(err)
+                if err.kind() == io::ErrorKind::PermissionDenied
+                    && matches!(self.auth, Auth::AppRole { .. }) =>
+            {
+                log::debug!("token rejected, logging in again - {}", err);
+                let token = self.login().await?;
+                self.send(path, Some(&token), body).await
+            }
+            result => result,
+        }
+    }
+}
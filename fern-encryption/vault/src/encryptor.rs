@@ -0,0 +1,478 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of values with a Vault transit key, in one of two modes:
+//! - `transit`: values are sent to Vault, and stored as transit ciphertexts,
+//! - `datakey`: values are encrypted locally with AES-256-GCM, under data
+//!   keys generated by Vault and stored wrapped along with values, so that
+//!   Vault is only called when a data key is generated, or first unwrapped.
+//!
+//! Data keys envelopes are made of the wrapped data key, and of the random
+//! nonce followed by the ciphertext and its tag, base64-encoded:
+//!
+//! ```text
+//! fern:dk:<wrapped data key, e.g. vault:v1:...>:<base64(nonce | ciphertext + tag)>
+//! ```
+//!
+//! Data keys are cached, in clear and in memory only: the current data key
+//! is renewed once expired or used for too many values, and unwrapped data
+//! keys are kept until expired, or evicted as least recently used.
+
+use aes_gcm::aead::Aead;
+use aes_gcm::{AeadCore, Aes256Gcm, KeyInit, Nonce};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::rngs::OsRng;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use fern_encryption::Encryptor;
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::client::to_io_error;
+use crate::transit::{self, BatchResults, DataKey, Transit};
+
+/// Prefix of data keys envelopes.
+const ENVELOPE_PREFIX: &[u8] = b"fern:dk:";
+
+/// Size in bytes of AES-GCM nonces.
+const NONCE_SIZE: usize = 12;
+
+/// How long data keys are cached, unless defined otherwise.
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Amount of values encrypted with a data key before it is renewed,
+/// unless defined otherwise.
+const DEFAULT_MAX_USES: u64 = 1_000_000;
+
+/// Maximum amount of unwrapped data keys cached, unless defined otherwise.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Returns whether `value` is a data key envelope.
+pub fn is_envelope(value: &[u8]) -> bool {
+    value.starts_with(ENVELOPE_PREFIX)
+}
+
+/// Splits data key `envelope` into the wrapped data key and encrypted value.
+fn split(envelope: &[u8]) -> io::Result<(&str, &[u8])> {
+    std::str::from_utf8(envelope)
+        .ok()
+        .and_then(|envelope| envelope.strip_prefix("fern:dk:"))
+        .and_then(|envelope| envelope.rsplit_once(':'))
+        .filter(|(wrapped, _)| transit::is_ciphertext(wrapped.as_bytes()))
+        .map(|(wrapped, sealed)| (wrapped, sealed.as_bytes()))
+        .ok_or_else(|| to_io_error(io::ErrorKind::InvalidData, "invalid data key envelope"))
+}
+
+/// A wrapped ciphertext to rewrap, and the sealed value of its envelope, if any.
+type Wrapped<'a> = (&'a str, Option<&'a [u8]>);
+
+/// How values are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// By Vault.
+    Transit,
+
+    /// Locally, under data keys generated by Vault.
+    DataKey,
+}
+
+/// The data key used for encryption.
+struct CurrentKey {
+    ciphertext: String,
+    cipher: Aes256Gcm,
+    created: Instant,
+    uses: u64,
+}
+
+/// Cache of data keys.
+struct DataKeyCache {
+    current: Option<CurrentKey>,
+
+    /// Unwrapped data keys, by ciphertext, with their creation and last use.
+    keys: HashMap<String, (Aes256Gcm, Instant, Instant)>,
+
+    ttl: Duration,
+    max_uses: u64,
+    capacity: usize,
+}
+
+impl DataKeyCache {
+    /// Returns the current data key, unless it must be renewed, counting a use.
+    fn current(&mut self) -> Option<(String, Aes256Gcm)> {
+        match &mut self.current {
+            Some(current)
+                if current.created.elapsed() < self.ttl && current.uses < self.max_uses =>
+            {
+                current.uses += 1;
+                Some((current.ciphertext.clone(), current.cipher.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the unwrapped data key of `ciphertext`, if cached and not expired.
+    fn get(&mut self, ciphertext: &str) -> Option<Aes256Gcm> {
+        let ttl = self.ttl;
+        match self.keys.get_mut(ciphertext) {
+            Some((cipher, created, used)) if created.elapsed() < ttl => {
+                *used = Instant::now();
+                Some(cipher.clone())
+            }
+            Some(_) => {
+                self.keys.remove(ciphertext);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches unwrapped data key `cipher` of `ciphertext`.
+    fn insert(&mut self, ciphertext: &str, cipher: Aes256Gcm) {
+        if self.keys.len() >= self.capacity && !self.keys.contains_key(ciphertext) {
+            let least_recently_used = self
+                .keys
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(ciphertext, _)| ciphertext.clone());
+            if let Some(ciphertext) = least_recently_used {
+                self.keys.remove(&ciphertext);
+            }
+        }
+        let now = Instant::now();
+        self.keys.insert(ciphertext.to_string(), (cipher, now, now));
+    }
+}
+
+/// An encryptor of values with a Vault transit key.
+pub struct VaultEncryptor {
+    transit: Transit,
+    mode: Mode,
+    cache: Mutex<DataKeyCache>,
+}
+
+impl std::fmt::Debug for VaultEncryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Note: cached data keys are not disclosed.
+        f.debug_struct("VaultEncryptor")
+            .field("transit", &self.transit)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl VaultEncryptor {
+    pub fn new(transit: Transit, mode: Mode) -> Self {
+        Self {
+            transit,
+            mode,
+            cache: Mutex::new(DataKeyCache {
+                current: None,
+                keys: HashMap::new(),
+                ttl: DEFAULT_TTL,
+                max_uses: DEFAULT_MAX_USES,
+                capacity: DEFAULT_CAPACITY,
+            }),
+        }
+    }
+
+    /// Caches data keys for `ttl`, renewing the current one after `max_uses`,
+    /// and keeping at most `capacity` unwrapped data keys.
+    #[must_use]
+    pub fn with_cache(self, ttl: Duration, max_uses: u64, capacity: usize) -> Self {
+        {
+            let mut cache = self.cache();
+            cache.ttl = ttl;
+            cache.max_uses = max_uses.max(1);
+            cache.capacity = capacity.max(1);
+        }
+        self
+    }
+
+    /// Creates an encryptor from settings found in `config`:
+    /// - `encryption.vault.mode`: either `transit` (the default), or `datakey`,
+    /// - `encryption.vault.datakey.ttl`: how long data keys are cached, in
+    ///   seconds, 300 by default,
+    /// - `encryption.vault.datakey.max_uses`: amount of values encrypted with
+    ///   a data key before it is renewed, 1000000 by default,
+    /// - `encryption.vault.datakey.capacity`: maximum amount of unwrapped data
+    ///   keys cached, 1024 by default,
+    /// - settings of the [`Transit`] key.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let mode = match config.get::<String>("encryption.vault.mode").as_deref() {
+            Ok("transit") | Err(_) => Mode::Transit,
+            Ok("datakey") => Mode::DataKey,
+            Ok(other) => {
+                return Err(to_io_error(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown encryption mode '{}'", other),
+                ))
+            }
+        };
+        let ttl = config
+            .get::<u64>("encryption.vault.datakey.ttl")
+            .map_or(DEFAULT_TTL, Duration::from_secs);
+        let max_uses = config
+            .get::<u64>("encryption.vault.datakey.max_uses")
+            .unwrap_or(DEFAULT_MAX_USES);
+        let capacity = config
+            .get::<usize>("encryption.vault.datakey.capacity")
+            .unwrap_or(DEFAULT_CAPACITY);
+        Ok(Self::new(Transit::from_config(config)?, mode).with_cache(ttl, max_uses, capacity))
+    }
+
+    /// Returns the transit key used.
+    pub fn transit(&self) -> &Transit {
+        &self.transit
+    }
+
+    /// Locks the cache, regardless of poisoning as it is always consistent.
+    fn cache(&self) -> MutexGuard<'_, DataKeyCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns a cipher of `data_key`.
+    fn cipher(data_key: &[u8]) -> io::Result<Aes256Gcm> {
+        Aes256Gcm::new_from_slice(data_key)
+            .map_err(|_| to_io_error(io::ErrorKind::InvalidData, "invalid data key size"))
+    }
+
+    /// Returns the data key to encrypt with, generating a new one if needed.
+    async fn current_key(&self) -> io::Result<(String, Aes256Gcm)> {
+        if let Some(current) = self.cache().current() {
+            return Ok(current);
+        }
+
+        let DataKey {
+            plaintext,
+            ciphertext,
+        } = self.transit.datakey().await?;
+        let cipher = Self::cipher(&plaintext)?;
+        log::debug!("new data key generated");
+        let mut cache = self.cache();
+        cache.insert(&ciphertext, cipher.clone());
+        cache.current = Some(CurrentKey {
+            ciphertext: ciphertext.clone(),
+            cipher: cipher.clone(),
+            created: Instant::now(),
+            uses: 1,
+        });
+        Ok((ciphertext, cipher))
+    }
+
+    /// Returns ciphers of wrapped data keys found in `envelopes`, by wrapped
+    /// data key, unwrapping those not cached with a single batch request.
+    async fn unwrap(&self, envelopes: &[&[u8]]) -> io::Result<HashMap<String, Aes256Gcm>> {
+        let mut ciphers = HashMap::new();
+        let mut missing = vec![];
+        {
+            let mut cache = self.cache();
+            for (wrapped, _) in envelopes.iter().filter_map(|envelope| split(envelope).ok()) {
+                if ciphers.contains_key(wrapped) || missing.contains(&wrapped) {
+                    continue;
+                }
+                match cache.get(wrapped) {
+                    Some(cipher) => {
+                        ciphers.insert(wrapped.to_string(), cipher);
+                    }
+                    None => missing.push(wrapped),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(ciphers);
+        }
+
+        let results = self.transit.decrypt(&missing).await?;
+        let mut cache = self.cache();
+        for (wrapped, result) in missing.into_iter().zip(results) {
+            match result.and_then(|data_key| Self::cipher(&data_key)) {
+                Ok(cipher) => {
+                    cache.insert(wrapped, cipher.clone());
+                    ciphers.insert(wrapped.to_string(), cipher);
+                }
+                Err(err) => log::debug!("cannot unwrap data key - {}", err),
+            }
+        }
+        Ok(ciphers)
+    }
+
+    /// Encrypts `plaintexts`, failing if any of them cannot be encrypted.
+    pub async fn encrypt(&self, plaintexts: &[&[u8]]) -> io::Result<Vec<Bytes>> {
+        if self.mode == Mode::Transit {
+            return self
+                .transit
+                .encrypt(plaintexts)
+                .await?
+                .into_iter()
+                .map(|result| result.map(Bytes::from))
+                .collect();
+        }
+
+        let (wrapped, cipher) = self.current_key().await?;
+        plaintexts
+            .iter()
+            .map(|plaintext| {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .encrypt(&nonce, *plaintext)
+                    .map_err(|_| to_io_error(io::ErrorKind::Other, "encryption failure"))?;
+                let mut sealed = nonce.to_vec();
+                sealed.extend_from_slice(&ciphertext);
+                let envelope = format!("fern:dk:{}:{}", wrapped, base64::encode(sealed));
+                Ok(Bytes::from(envelope))
+            })
+            .collect()
+    }
+
+    /// Decrypts `values`, transit ciphertexts or data key envelopes, with as
+    /// few requests to Vault as possible.
+    pub async fn decrypt(&self, values: &[&[u8]]) -> io::Result<BatchResults<Bytes>> {
+        let mut results: Vec<Option<io::Result<Bytes>>> = values.iter().map(|_| None).collect();
+
+        // Transit ciphertexts, decrypted with a single batch request.
+        let (positions, ciphertexts): (Vec<usize>, Vec<&str>) = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| transit::is_ciphertext(value))
+            .filter_map(|(idx, value)| std::str::from_utf8(value).ok().map(|value| (idx, value)))
+            .unzip();
+        if !ciphertexts.is_empty() {
+            let decrypted = self.transit.decrypt(&ciphertexts).await?;
+            for (idx, result) in positions.into_iter().zip(decrypted) {
+                results[idx] = Some(result);
+            }
+        }
+
+        // Data key envelopes, whose data keys are unwrapped with a single batch request.
+        let envelopes: Vec<&[u8]> = values
+            .iter()
+            .copied()
+            .filter(|value| is_envelope(value))
+            .collect();
+        if !envelopes.is_empty() {
+            let ciphers = self.unwrap(&envelopes).await?;
+            for (idx, value) in values.iter().enumerate() {
+                if is_envelope(value) {
+                    results[idx] = Some(Self::open(&ciphers, value));
+                }
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(to_io_error(
+                        io::ErrorKind::InvalidData,
+                        "not encrypted by vault",
+                    ))
+                })
+            })
+            .collect())
+    }
+
+    /// Decrypts data key `envelope` with unwrapped data keys `ciphers`.
+    fn open(ciphers: &HashMap<String, Aes256Gcm>, envelope: &[u8]) -> io::Result<Bytes> {
+        let (wrapped, sealed) = split(envelope)?;
+        let cipher = ciphers
+            .get(wrapped)
+            .ok_or_else(|| to_io_error(io::ErrorKind::NotFound, "data key unavailable"))?;
+        let sealed = base64::decode(sealed)
+            .ok()
+            .filter(|sealed| sealed.len() > NONCE_SIZE)
+            .ok_or_else(|| to_io_error(io::ErrorKind::InvalidData, "invalid data key envelope"))?;
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map(Bytes::from)
+            .map_err(|_| to_io_error(io::ErrorKind::InvalidData, "authentication failure"))
+    }
+
+    /// Decrypts in place `fields` of a `DataRow` encrypted by Vault, with as
+    /// few requests to Vault as possible. Other fields, and those which
+    /// cannot be decrypted, are left as is.
+    pub async fn decrypt_fields(&self, fields: &mut [Option<Bytes>]) -> io::Result<()> {
+        let positions: Vec<usize> = fields
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, field)| match field {
+                Some(value) if transit::is_ciphertext(value) || is_envelope(value) => Some(idx),
+                _ => None,
+            })
+            .collect();
+        if positions.is_empty() {
+            return Ok(());
+        }
+
+        let values: Vec<&[u8]> = positions
+            .iter()
+            .filter_map(|idx| fields[*idx].as_deref())
+            .collect();
+        let results = self.decrypt(&values).await?;
+        for (idx, result) in positions.into_iter().zip(results) {
+            match result {
+                Ok(plaintext) => fields[idx] = Some(plaintext),
+                Err(err) => log::debug!("field forwarded as is - {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewraps `values` with the latest version of the transit key, without
+    /// disclosing them: transit ciphertexts are rewrapped as a whole, while
+    /// data key envelopes only get their data key rewrapped.
+    pub async fn rewrap(&self, values: &[&[u8]]) -> io::Result<BatchResults<Bytes>> {
+        let wrapped: BatchResults<Wrapped<'_>> = values
+            .iter()
+            .map(|value| match is_envelope(value) {
+                true => split(value).map(|(wrapped, sealed)| (wrapped, Some(sealed))),
+                false => std::str::from_utf8(value)
+                    .ok()
+                    .filter(|value| transit::is_ciphertext(value.as_bytes()))
+                    .map(|value| (value, None))
+                    .ok_or_else(|| {
+                        to_io_error(io::ErrorKind::InvalidData, "not encrypted by vault")
+                    }),
+            })
+            .collect();
+
+        let ciphertexts: Vec<&str> = wrapped
+            .iter()
+            .filter_map(|wrapped| wrapped.as_ref().ok().map(|(ciphertext, _)| *ciphertext))
+            .collect();
+        let mut rewrapped = self.transit.rewrap(&ciphertexts).await?.into_iter();
+
+        Ok(wrapped
+            .into_iter()
+            .map(|wrapped| {
+                let (_, sealed) = wrapped?;
+                let ciphertext = rewrapped.next().expect("one result per ciphertext")?;
+                Ok(match sealed {
+                    Some(sealed) => {
+                        let sealed = String::from_utf8_lossy(sealed);
+                        Bytes::from(format!("fern:dk:{}:{}", ciphertext, sealed))
+                    }
+                    None => Bytes::from(ciphertext),
+                })
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Encryptor for VaultEncryptor {
+    fn is_sealed(&self, envelope: &[u8]) -> bool {
+        transit::is_ciphertext(envelope) || is_envelope(envelope)
+    }
+
+    async fn encrypt(&self, plaintexts: &[&[u8]]) -> io::Result<Vec<Bytes>> {
+        VaultEncryptor::encrypt(self, plaintexts).await
+    }
+
+    async fn decrypt(&self, envelopes: &[&[u8]]) -> io::Result<BatchResults<Bytes>> {
+        VaultEncryptor::decrypt(self, envelopes).await
+    }
+}
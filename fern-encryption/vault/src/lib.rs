@@ -1,8 +1,29 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {}
+//! Remote encryption of column values with the HashiCorp Vault transit
+//! secrets engine, for Fern proxy.
+//!
+//! A [`VaultEncryptor`] is an [`Encryptor`](fern_encryption::Encryptor) of
+//! encryption handlers, encrypting values either by Vault itself, or locally
+//! under data keys generated and wrapped by Vault (envelope encryption),
+//! and decrypting many values, e.g. all fields of a `DataRow`, with a single
+//! `batch_input` request. Vault is reached with a [`Client`] authenticated
+//! with a token or an AppRole.
+//!
+//! Settings are found in the `encryption.vault` table of `SQLHandlerConfig`.
+
+// Re-export.
+pub use client::{Auth, Client};
+pub use encryptor::{is_envelope, Mode, VaultEncryptor};
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use transit::{is_ciphertext, BatchResults, DataKey, Transit};
+
+mod client;
+mod encryptor;
+mod transit;
+
+/// Returns whether Vault encryption is defined in `config`.
+pub fn is_enabled(config: &SQLHandlerConfig) -> bool {
+    config.get::<String>("encryption.vault.key").is_ok()
 }
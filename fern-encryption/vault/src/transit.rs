@@ -0,0 +1,204 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Operations of the Vault [transit secrets engine] with a named key:
+//! encryption, decryption, and rewrapping of many values with a single
+//! `batch_input` request, and generation of data keys.
+//!
+//! Batch operations fail as a whole when Vault cannot be reached, while
+//! each value gets its own result otherwise, as Vault reports errors of
+//! batch items separately.
+//!
+//! [transit secrets engine]: https://developer.hashicorp.com/vault/api-docs/secret/transit
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::client::{to_io_error, Client};
+
+/// Maximum amount of items of a single batch request, unless defined otherwise.
+const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// Size in bits of data keys.
+const DATA_KEY_BITS: usize = 256;
+
+/// Results of a batch operation, one per item.
+pub type BatchResults<T> = Vec<io::Result<T>>;
+
+/// A data key generated by Vault, in clear and wrapped by the transit key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    pub plaintext: Vec<u8>,
+
+    /// Ciphertext of the data key, as returned by Vault, e.g. `vault:v1:...`.
+    pub ciphertext: String,
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Note: key material is not disclosed.
+        f.debug_struct("DataKey")
+            .field("ciphertext", &self.ciphertext)
+            .finish()
+    }
+}
+
+/// Returns whether `value` is a transit ciphertext, e.g. `vault:v1:...`.
+pub fn is_ciphertext(value: &[u8]) -> bool {
+    value.starts_with(b"vault:v")
+}
+
+/// A transit key of a Vault server.
+#[derive(Debug)]
+pub struct Transit {
+    client: Client,
+
+    /// Path where the transit secrets engine is enabled.
+    mount: String,
+
+    /// Name of the transit key.
+    key: String,
+
+    /// Maximum amount of items of a single batch request.
+    batch_size: usize,
+}
+
+impl Transit {
+    pub fn new(client: Client, mount: &str, key: &str) -> Self {
+        Self {
+            client,
+            mount: mount.trim_matches('/').to_string(),
+            key: key.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    /// Sends at most `batch_size` items per batch request.
+    #[must_use]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Creates a transit key from settings found in `config`:
+    /// - `encryption.vault.mount`: path of the transit secrets engine,
+    ///   `transit` by default,
+    /// - `encryption.vault.key`: name of the transit key,
+    /// - `encryption.vault.batch_size`: maximum amount of items of a single
+    ///   batch request, 256 by default,
+    /// - settings of the [`Client`].
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let key = config
+            .get::<String>("encryption.vault.key")
+            .map_err(|err| to_io_error(io::ErrorKind::NotFound, err))?;
+        let mount = config
+            .get::<String>("encryption.vault.mount")
+            .unwrap_or_else(|_| "transit".to_string());
+        let transit = Self::new(Client::from_config(config)?, &mount, &key);
+        match config.get::<usize>("encryption.vault.batch_size") {
+            Ok(batch_size) => Ok(transit.with_batch_size(batch_size)),
+            Err(_) => Ok(transit),
+        }
+    }
+
+    /// Sends `items` to `operation` endpoint with as few batch requests as
+    /// possible, returning the results of items, in order.
+    async fn batch(&self, operation: &str, items: Vec<Value>) -> io::Result<Vec<Value>> {
+        let path = format!("{}/{}/{}", self.mount, operation, self.key);
+        let mut results = Vec::with_capacity(items.len());
+        for chunk in items.chunks(self.batch_size) {
+            let response = self
+                .client
+                .post(&path, &json!({ "batch_input": chunk }))
+                .await?;
+            match response["data"]["batch_results"].as_array() {
+                Some(batch) if batch.len() == chunk.len() => results.extend(batch.iter().cloned()),
+                _ => {
+                    return Err(to_io_error(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected {} batch results", operation),
+                    ))
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    /// Returns field `name` of a batch item `result`, or its error.
+    fn field<'a>(result: &'a Value, name: &str) -> io::Result<&'a str> {
+        match (result[name].as_str(), result["error"].as_str()) {
+            (Some(value), None | Some("")) => Ok(value),
+            (_, Some(error)) => Err(to_io_error(io::ErrorKind::InvalidData, error)),
+            (None, None) => Err(to_io_error(
+                io::ErrorKind::InvalidData,
+                format!("no {} in batch result", name),
+            )),
+        }
+    }
+
+    /// Encrypts `plaintexts`, returning transit ciphertexts.
+    pub async fn encrypt(&self, plaintexts: &[&[u8]]) -> io::Result<BatchResults<String>> {
+        let items = plaintexts
+            .iter()
+            .map(|plaintext| json!({ "plaintext": base64::encode(plaintext) }))
+            .collect();
+        let results = self.batch("encrypt", items).await?;
+        Ok(results
+            .iter()
+            .map(|result| Self::field(result, "ciphertext").map(str::to_string))
+            .collect())
+    }
+
+    /// Decrypts transit `ciphertexts`.
+    pub async fn decrypt(&self, ciphertexts: &[&str]) -> io::Result<BatchResults<Bytes>> {
+        let items = ciphertexts
+            .iter()
+            .map(|ciphertext| json!({ "ciphertext": ciphertext }))
+            .collect();
+        let results = self.batch("decrypt", items).await?;
+        Ok(results
+            .iter()
+            .map(|result| {
+                let plaintext = Self::field(result, "plaintext")?;
+                base64::decode(plaintext)
+                    .map(Bytes::from)
+                    .map_err(|err| to_io_error(io::ErrorKind::InvalidData, err))
+            })
+            .collect())
+    }
+
+    /// Rewraps transit `ciphertexts` with the latest version of the key,
+    /// without disclosing plaintexts.
+    pub async fn rewrap(&self, ciphertexts: &[&str]) -> io::Result<BatchResults<String>> {
+        let items = ciphertexts
+            .iter()
+            .map(|ciphertext| json!({ "ciphertext": ciphertext }))
+            .collect();
+        let results = self.batch("rewrap", items).await?;
+        Ok(results
+            .iter()
+            .map(|result| Self::field(result, "ciphertext").map(str::to_string))
+            .collect())
+    }
+
+    /// Generates a new data key, wrapped by the latest version of the key.
+    pub async fn datakey(&self) -> io::Result<DataKey> {
+        let path = format!("{}/datakey/plaintext/{}", self.mount, self.key);
+        let response = self
+            .client
+            .post(&path, &json!({ "bits": DATA_KEY_BITS }))
+            .await?;
+        let data = &response["data"];
+        let plaintext = Self::field(data, "plaintext").and_then(|plaintext| {
+            base64::decode(plaintext).map_err(|err| to_io_error(io::ErrorKind::InvalidData, err))
+        })?;
+        let ciphertext = Self::field(data, "ciphertext")?.to_string();
+        Ok(DataKey {
+            plaintext,
+            ciphertext,
+        })
+    }
+}
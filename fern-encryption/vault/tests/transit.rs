@@ -0,0 +1,360 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tests against a mock Vault server, speaking the transit API with a toy
+//! cipher: ciphertexts are `vault:v<version>:` followed by the base64 of
+//! plaintexts XORed with a constant.
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use wiremock::matchers::{header, method, path, path_regex};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use fern_encryption::{DecryptionHandler, EncryptionHandler};
+use fern_encryption_vault::{Auth, Client, Mode, SQLHandlerConfig, Transit, VaultEncryptor};
+use fern_protocol_postgresql::codec::backend::{self, RowDescription};
+use fern_protocol_postgresql::codec::frontend::{self, BindParameter, Parameter};
+use fern_proxy_interfaces::SQLMessageHandler;
+
+/// Mock transit secrets engine, mounted at `transit`.
+#[derive(Default)]
+struct MockTransit {
+    /// Amount of data keys generated.
+    datakeys: AtomicU8,
+}
+
+impl MockTransit {
+    fn seal(plaintext: &[u8], version: u8) -> String {
+        let xored: Vec<u8> = plaintext.iter().map(|byte| byte ^ 0x5a).collect();
+        format!("vault:v{}:{}", version, base64::encode(xored))
+    }
+
+    fn open(ciphertext: &str) -> Option<Vec<u8>> {
+        let (_, encoded) = ciphertext.strip_prefix("vault:v")?.split_once(':')?;
+        let xored = base64::decode(encoded).ok()?;
+        Some(xored.iter().map(|byte| byte ^ 0x5a).collect())
+    }
+
+    fn item(&self, operation: &str, input: &Value) -> Value {
+        let plaintext = input["plaintext"]
+            .as_str()
+            .and_then(|p| base64::decode(p).ok());
+        let opened = input["ciphertext"].as_str().and_then(Self::open);
+        match (operation, plaintext, opened) {
+            ("encrypt", Some(plaintext), _) => json!({ "ciphertext": Self::seal(&plaintext, 1) }),
+            ("decrypt", _, Some(plaintext)) => json!({ "plaintext": base64::encode(plaintext) }),
+            ("rewrap", _, Some(plaintext)) => json!({ "ciphertext": Self::seal(&plaintext, 2) }),
+            _ => json!({ "error": "invalid input" }),
+        }
+    }
+}
+
+impl Respond for MockTransit {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = request.body_json().unwrap();
+        let operation = request.url.path().split('/').nth(3).unwrap();
+        if operation == "datakey" {
+            let n = self.datakeys.fetch_add(1, Ordering::SeqCst) + 1;
+            let data_key = [n; 32];
+            return ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "plaintext": base64::encode(data_key),
+                    "ciphertext": Self::seal(&data_key, 1),
+                }
+            }));
+        }
+
+        let results: Vec<Value> = body["batch_input"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|input| self.item(operation, input))
+            .collect();
+        ResponseTemplate::new(200).set_body_json(json!({ "data": { "batch_results": results } }))
+    }
+}
+
+/// Helper function starting a mock Vault server, accepting token "s.test".
+async fn server() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path_regex(
+            "^/v1/transit/(encrypt|decrypt|rewrap|datakey/plaintext)/fern$",
+        ))
+        .and(header("X-Vault-Token", "s.test"))
+        .respond_with(MockTransit::default())
+        .mount(&server)
+        .await;
+    server
+}
+
+/// Helper function returning the transit key "fern" of mock `server`.
+fn transit(server: &MockServer) -> Transit {
+    let client = Client::new(&server.uri(), Auth::Token("s.test".to_string()));
+    Transit::new(client, "transit", "fern")
+}
+
+/// Helper function returning the amount of requests received by `server`.
+async fn requests(server: &MockServer) -> usize {
+    server.received_requests().await.unwrap().len()
+}
+
+#[tokio::test]
+async fn transit_batch_operations() {
+    let server = server().await;
+    let transit = transit(&server).with_batch_size(2);
+
+    let ciphertexts = transit.encrypt(&[b"jane", b"john", b"jack"]).await.unwrap();
+    assert_eq!(2, requests(&server).await, "batches of 2 items");
+    let ciphertexts: Vec<String> = ciphertexts.into_iter().map(Result::unwrap).collect();
+    assert!(ciphertexts[0].starts_with("vault:v1:"));
+
+    let ciphertexts: Vec<&str> = ciphertexts.iter().map(String::as_str).collect();
+    let rewrapped = transit.rewrap(&ciphertexts).await.unwrap();
+    let rewrapped: Vec<String> = rewrapped.into_iter().map(Result::unwrap).collect();
+    assert!(rewrapped[2].starts_with("vault:v2:"), "latest key version");
+
+    let mut ciphertexts: Vec<&str> = rewrapped.iter().map(String::as_str).collect();
+    ciphertexts.push("vault:v1:not base64");
+    let plaintexts = transit.decrypt(&ciphertexts).await.unwrap();
+    assert_eq!(
+        Bytes::from_static(b"john"),
+        *plaintexts[1].as_ref().unwrap()
+    );
+    assert!(plaintexts[3].is_err(), "item error");
+}
+
+#[tokio::test]
+async fn transit_errors() {
+    let server = server().await;
+    let client = Client::new(&server.uri(), Auth::Token("s.invalid".to_string()));
+    let transit = Transit::new(client, "transit", "fern");
+    assert!(transit.encrypt(&[b"jane"]).await.is_err(), "unknown token");
+
+    let client = Client::new("http://127.0.0.1:1", Auth::Token("s.test".to_string()));
+    let transit = Transit::new(client, "transit", "fern");
+    assert!(transit.datakey().await.is_err(), "unreachable");
+}
+
+#[tokio::test]
+async fn approle_login_renewed() {
+    let server = server().await;
+    let logins = Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.test" }
+        })));
+    // First token given is expired.
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.expired" }
+        })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    logins.mount(&server).await;
+    Mock::given(method("POST"))
+        .and(header("X-Vault-Token", "s.expired"))
+        .respond_with(
+            ResponseTemplate::new(403).set_body_json(json!({ "errors": ["permission denied"] })),
+        )
+        .mount(&server)
+        .await;
+
+    let auth = Auth::AppRole {
+        mount: "approle".to_string(),
+        role_id: "fern".to_string(),
+        secret_id: "secret".to_string(),
+    };
+    let transit = Transit::new(Client::new(&server.uri(), auth), "transit", "fern");
+    let plaintexts = transit.decrypt(&["vault:v1:MDQ/"]).await.unwrap();
+    assert_eq!(Bytes::from_static(b"jne"), *plaintexts[0].as_ref().unwrap());
+
+    let received = server.received_requests().await.unwrap();
+    let logins = received
+        .iter()
+        .filter(|request| request.url.path() == "/v1/auth/approle/login")
+        .count();
+    assert_eq!(2, logins, "logged in again once token rejected");
+    assert_eq!(4, received.len());
+}
+
+#[tokio::test]
+async fn datakey_envelopes_cached() {
+    let server = server().await;
+    let encryptor = VaultEncryptor::new(transit(&server), Mode::DataKey);
+
+    let envelopes = encryptor.encrypt(&[b"jane", b"john"]).await.unwrap();
+    assert!(envelopes[0].starts_with(b"fern:dk:vault:v1:"));
+    assert_ne!(envelopes[0], envelopes[1]);
+    encryptor.encrypt(&[b"jack"]).await.unwrap();
+    assert_eq!(1, requests(&server).await, "single data key generated");
+
+    let values: Vec<&[u8]> = envelopes.iter().map(|envelope| &envelope[..]).collect();
+    let plaintexts = encryptor.decrypt(&values).await.unwrap();
+    assert_eq!(
+        Bytes::from_static(b"jane"),
+        *plaintexts[0].as_ref().unwrap()
+    );
+    assert_eq!(1, requests(&server).await, "cached data key");
+
+    // Another instance has to unwrap the data key, once.
+    let other = VaultEncryptor::new(transit(&server), Mode::DataKey);
+    let plaintexts = other.decrypt(&values).await.unwrap();
+    assert_eq!(
+        Bytes::from_static(b"john"),
+        *plaintexts[1].as_ref().unwrap()
+    );
+    assert_eq!(2, requests(&server).await, "data key unwrapped in batch");
+
+    let rewrapped = other.rewrap(&values).await.unwrap();
+    let rewrapped = rewrapped[0].as_ref().unwrap();
+    assert!(
+        rewrapped.starts_with(b"fern:dk:vault:v2:"),
+        "rewrapped data key"
+    );
+    let plaintexts = other.decrypt(&[rewrapped]).await.unwrap();
+    assert_eq!(
+        Bytes::from_static(b"jane"),
+        *plaintexts[0].as_ref().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn datakey_renewed() {
+    let server = server().await;
+    let encryptor = VaultEncryptor::new(transit(&server), Mode::DataKey).with_cache(
+        Duration::from_secs(60),
+        2,
+        1,
+    );
+    let first = encryptor.encrypt(&[b"jane"]).await.unwrap();
+    encryptor.encrypt(&[b"john"]).await.unwrap();
+    let third = encryptor.encrypt(&[b"jack"]).await.unwrap();
+    assert_eq!(2, requests(&server).await, "data key used twice at most");
+    assert_ne!(first[0][..40], third[0][..40], "other data key");
+
+    // Evicted from a cache of a single data key.
+    let plaintexts = encryptor.decrypt(&[&first[0]]).await.unwrap();
+    assert_eq!(
+        Bytes::from_static(b"jane"),
+        *plaintexts[0].as_ref().unwrap()
+    );
+    assert_eq!(3, requests(&server).await, "evicted data key unwrapped");
+}
+
+#[tokio::test]
+async fn data_row_fields_decrypted_in_batch() {
+    let server = server().await;
+    let encryptor = VaultEncryptor::new(transit(&server), Mode::Transit);
+    let ciphertexts = encryptor.encrypt(&[b"jane", b"555-0100"]).await.unwrap();
+
+    let mut fields = vec![
+        Some(Bytes::from_static(b"1")),
+        Some(ciphertexts[0].clone()),
+        None,
+        Some(ciphertexts[1].clone()),
+        Some(Bytes::from_static(b"vault:v1:not base64")),
+    ];
+    encryptor.decrypt_fields(&mut fields).await.unwrap();
+    let expected = vec![
+        Some(Bytes::from_static(b"1")),
+        Some(Bytes::from_static(b"jane")),
+        None,
+        Some(Bytes::from_static(b"555-0100")),
+        Some(Bytes::from_static(b"vault:v1:not base64")),
+    ];
+    assert_eq!(expected, fields);
+    assert_eq!(2, requests(&server).await, "single decryption request");
+
+    let mut clear = vec![Some(Bytes::from_static(b"1")), None];
+    encryptor.decrypt_fields(&mut clear).await.unwrap();
+    assert_eq!(2, requests(&server).await, "nothing to decrypt");
+}
+
+#[tokio::test]
+async fn handlers_encrypt_with_vault() {
+    let server = server().await;
+    let config = SQLHandlerConfig::builder()
+        .set_override("encryption.columns.email.storage", "base64")
+        .unwrap()
+        .set_override("encryption.columns.phone.storage", "base64")
+        .unwrap()
+        .set_override("encryption.authorized_users", vec!["app"])
+        .unwrap()
+        .set_override("encryption.keys.env", "FERN_TEST_UNDEFINED_KEYS")
+        .unwrap()
+        .build()
+        .unwrap();
+    let encryptor = Arc::new(VaultEncryptor::new(transit(&server), Mode::Transit));
+    let mut encryption = EncryptionHandler::new(&config).with_encryptor(encryptor.clone());
+    let mut decryption = DecryptionHandler::new(&config)
+        .with_encryptor(encryptor)
+        .with_session(encryption.session());
+
+    let startup = frontend::Message::StartupMessage {
+        frame_length: 0,
+        parameters: vec![Parameter {
+            name: Bytes::from_static(b"user"),
+            value: Bytes::from_static(b"app"),
+        }],
+    };
+    encryption.process(startup).await;
+    let parse = frontend::Message::Parse {
+        stmt_name: Bytes::new(),
+        query: Bytes::from_static(b"INSERT INTO users (id, email, phone) VALUES ($1, $2, $3)"),
+        parameters_types: vec![],
+    };
+    encryption.process(parse).await;
+    let bind = frontend::Message::Bind {
+        portal: Bytes::new(),
+        stmt_name: Bytes::new(),
+        parameters: ["1", "jane@example.com", "555-0100"]
+            .iter()
+            .map(|value| BindParameter {
+                format: 0,
+                value: Some(Bytes::from_static(value.as_bytes())),
+            })
+            .collect(),
+        results_formats: vec![],
+    };
+    let stored: Vec<Option<Bytes>> = match encryption.process(bind).await {
+        Some(frontend::Message::Bind { parameters, .. }) => parameters
+            .into_iter()
+            .map(|parameter| parameter.value)
+            .collect(),
+        other => panic!("unexpected message: {:?}", other),
+    };
+    let email = base64::decode(stored[1].as_ref().unwrap()).unwrap();
+    assert!(email.starts_with(b"vault:v1:"), "stored as base64");
+    assert_eq!(1, requests(&server).await, "single encryption request");
+
+    let description = ["id", "email", "phone"]
+        .iter()
+        .map(|name| RowDescription {
+            name: Bytes::from_static(name.as_bytes()),
+            table_oid: 0,
+            column_attr: 0,
+            data_type_oid: 25,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        })
+        .collect();
+    decryption
+        .process(backend::Message::RowDescription(description))
+        .await;
+    let expected = backend::Message::DataRow(vec![
+        Some(Bytes::from_static(b"1")),
+        Some(Bytes::from_static(b"jane@example.com")),
+        Some(Bytes::from_static(b"555-0100")),
+    ]);
+    let msg = decryption.process(backend::Message::DataRow(stored)).await;
+    assert_eq!(Some(expected), msg);
+    assert_eq!(2, requests(&server).await, "single decryption request");
+}
@@ -0,0 +1,320 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Classification of columns with tags, e.g. `pii.email` or `pci.pan`,
+//! driving masking rules rather than column names.
+//!
+//! Tags are defined in a local classification file, by patterns of
+//! `schema.table.column`, and may be completed with columns known from the
+//! database catalog, i.e. tags in `COMMENT ON COLUMN` or `SECURITY LABEL`.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use fern_policy_engine::glob;
+use fern_protocol_postgresql::codec::backend;
+
+use crate::SQLHandlerConfig;
+
+/// Marker of tags in column comments, e.g. `Contact address. tags: pii.email`.
+const COMMENT_MARKER: &str = "tags:";
+
+/// A column known from the database catalog, with its tags.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Column {
+    pub schema: String,
+    pub table: String,
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+/// A pattern of columns, as `schema.table.column`, where parts may have
+/// `*` wildcards, and omitted leading parts match anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    parts: Vec<String>,
+}
+
+impl Pattern {
+    fn parse(text: &str) -> io::Result<Self> {
+        let parts: Vec<String> = text.split('.').map(str::to_string).collect();
+        if parts.len() > 3 || parts.iter().any(String::is_empty) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid column pattern '{}'", text),
+            ));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Returns whether `column` of `table` in `schema` matches, where unknown
+    /// parts are matched conservatively, as masking is due in doubt.
+    fn matches(&self, schema: Option<&str>, table: Option<&str>, column: &str) -> bool {
+        let parts = [schema, table, Some(column)];
+        self.parts
+            .iter()
+            .rev()
+            .zip(parts.iter().rev())
+            .all(|(pattern, part)| part.map_or(true, |part| glob(pattern, part)))
+    }
+}
+
+/// Tags of columns, from a classification file and the database catalog.
+///
+/// Columns of `RowDescription`s are known by their table OID and attribute
+/// number when found in the catalog. Otherwise, only by name, and then
+/// match patterns of any table.
+#[derive(Debug, Clone, Default)]
+pub struct Classification {
+    /// Tags of patterns of columns, from the classification file.
+    patterns: Vec<(Pattern, Vec<String>)>,
+
+    /// Columns from the catalog, by table OID and attribute number.
+    columns: HashMap<(u32, u16), Column>,
+}
+
+impl Classification {
+    /// Loads the classification file defined in `masking.classification.file`
+    /// setting of `config`, if any.
+    ///
+    /// The file, in TOML or YAML format depending on its extension, has a
+    /// `columns` table of tags by pattern:
+    ///
+    /// ```toml
+    /// [columns]
+    /// "public.users.email" = ["pii.email"]
+    /// "*.phone" = ["pii.phone"]
+    /// ```
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Self> {
+        let path = match config.get::<String>("masking.classification.file") {
+            Ok(path) => path,
+            Err(_) => return Ok(Self::default()),
+        };
+        let invalid = |err: config::ConfigError| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("classification file '{}' - {}", path, err),
+            )
+        };
+        let file = SQLHandlerConfig::builder()
+            .add_source(config::File::from(Path::new(&path)))
+            .build()
+            .map_err(invalid)?;
+
+        let mut classification = Self::default();
+        for (pattern, tags) in file.get_table("columns").map_err(invalid)? {
+            // Note: a single tag may be defined without an array.
+            let tags = match tags.clone().into_array() {
+                Ok(tags) => tags,
+                Err(_) => vec![tags],
+            };
+            let tags = tags
+                .into_iter()
+                .map(|tag| tag.into_string())
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(invalid)?;
+            classification
+                .patterns
+                .push((Pattern::parse(&pattern)?, tags));
+        }
+        log::debug!(
+            "loaded tags of {} column patterns from '{}'",
+            classification.patterns.len(),
+            path
+        );
+        Ok(classification)
+    }
+
+    /// Adds `column`, known from the catalog as attribute `column_attr`
+    /// of table `table_oid`.
+    pub fn add_column(&mut self, table_oid: u32, column_attr: u16, column: Column) {
+        self.columns.insert((table_oid, column_attr), column);
+    }
+
+    /// Returns whether no tags are defined at all.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.columns.values().all(|column| column.tags.is_empty())
+    }
+
+    /// Returns tags of the column of `description`.
+    pub fn tags(&self, description: &backend::RowDescription) -> Vec<&str> {
+        let mut tags = vec![];
+        let key = (description.table_oid, description.column_attr);
+        match self.columns.get(&key) {
+            Some(column) => {
+                tags.extend(column.tags.iter().map(String::as_str));
+                for (pattern, pattern_tags) in &self.patterns {
+                    if pattern.matches(Some(&column.schema), Some(&column.table), &column.name) {
+                        tags.extend(pattern_tags.iter().map(String::as_str));
+                    }
+                }
+            }
+            None => {
+                let name = String::from_utf8_lossy(&description.name);
+                for (pattern, pattern_tags) in &self.patterns {
+                    if pattern.matches(None, None, &name) {
+                        tags.extend(pattern_tags.iter().map(String::as_str));
+                    }
+                }
+            }
+        }
+        let mut unique = vec![];
+        for tag in tags {
+            if !unique.contains(&tag) {
+                unique.push(tag);
+            }
+        }
+        unique
+    }
+}
+
+/// Parses tags from a column `comment`, following a `tags:` marker up to
+/// the end of its line, separated by commas or spaces.
+pub fn comment_tags(comment: &str) -> Vec<String> {
+    comment
+        .lines()
+        .filter_map(|line| line.find(COMMENT_MARKER).map(|idx| &line[idx..]))
+        .flat_map(|line| label_tags(&line[COMMENT_MARKER.len()..]))
+        .collect()
+}
+
+/// Parses tags from a security `label`, separated by commas or spaces.
+pub fn label_tags(label: &str) -> Vec<String> {
+    label
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend::RowDescription;
+
+    use super::{comment_tags, label_tags, Classification, Column, Pattern};
+
+    /// Helper function building a `RowDescription` of column `name`,
+    /// as attribute `column_attr` of table `table_oid`.
+    fn description(name: &'static str, table_oid: u32, column_attr: u16) -> RowDescription {
+        RowDescription {
+            name: Bytes::from_static(name.as_bytes()),
+            table_oid,
+            column_attr,
+            data_type_oid: 25,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+
+    #[test]
+    fn valid_patterns() {
+        let pattern = Pattern::parse("public.users.email").unwrap();
+        assert!(pattern.matches(Some("public"), Some("users"), "email"));
+        assert!(pattern.matches(None, None, "email"));
+        assert!(!pattern.matches(Some("public"), Some("orders"), "email"));
+        assert!(!pattern.matches(None, None, "phone"));
+
+        let pattern = Pattern::parse("*_phone").unwrap();
+        assert!(pattern.matches(Some("crm"), Some("contacts"), "mobile_phone"));
+
+        assert!(Pattern::parse("db.public.users.email").is_err());
+        assert!(Pattern::parse("users..email").is_err());
+    }
+
+    #[test]
+    fn classification_file() {
+        let path = std::env::temp_dir().join("fern-masking-classification.yaml");
+        let document = "columns:\n  public.users.email: [pii.email]\n  \"*.phone\": pii.phone\n";
+        std::fs::write(&path, document).unwrap();
+        let config = crate::SQLHandlerConfig::builder()
+            .set_override("masking.classification.file", path.to_str().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let classification = Classification::from_config(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            vec!["pii.email"],
+            classification.tags(&description("email", 0, 0))
+        );
+        assert_eq!(
+            vec!["pii.phone"],
+            classification.tags(&description("phone", 0, 0))
+        );
+
+        let config = crate::SQLHandlerConfig::builder().build().unwrap();
+        assert!(Classification::from_config(&config).unwrap().is_empty());
+        let config = crate::SQLHandlerConfig::builder()
+            .set_override("masking.classification.file", "missing.toml")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(Classification::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn valid_tags() {
+        assert_eq!(
+            vec!["pii.email", "pii.contact"],
+            comment_tags("Contact address.\ntags: pii.email, pii.contact")
+        );
+        assert!(comment_tags("Contact address.").is_empty());
+        assert_eq!(vec!["pci.pan"], label_tags("pci.pan"));
+        assert_eq!(
+            vec!["pii.phone", "pii.contact"],
+            label_tags("pii.phone pii.contact")
+        );
+    }
+
+    #[test]
+    fn tags_of_descriptions() {
+        let mut classification = Classification {
+            patterns: vec![
+                (
+                    Pattern::parse("public.users.email").unwrap(),
+                    vec!["pii.email".into()],
+                ),
+                (Pattern::parse("*phone").unwrap(), vec!["pii.phone".into()]),
+            ],
+            ..Classification::default()
+        };
+        let column = |table: &str, name: &str, tags: &[&str]| Column {
+            schema: "public".into(),
+            table: table.into(),
+            name: name.into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        classification.add_column(16384, 2, column("users", "email", &[]));
+        classification.add_column(16384, 3, column("users", "phone", &["pii.contact"]));
+        classification.add_column(16390, 2, column("orders", "email", &[]));
+        classification.add_column(16390, 4, column("orders", "card", &["pci.pan"]));
+        assert!(!classification.is_empty());
+
+        // Columns known from the catalog, whatever their name in results.
+        assert_eq!(
+            vec!["pii.email"],
+            classification.tags(&description("e", 16384, 2))
+        );
+        assert_eq!(
+            vec!["pii.contact", "pii.phone"],
+            classification.tags(&description("phone", 16384, 3))
+        );
+        assert!(classification
+            .tags(&description("email", 16390, 2))
+            .is_empty());
+        assert_eq!(
+            vec!["pci.pan"],
+            classification.tags(&description("c", 16390, 4))
+        );
+
+        // Other columns, only known by name.
+        assert_eq!(
+            vec!["pii.email"],
+            classification.tags(&description("email", 0, 0))
+        );
+        assert!(classification.tags(&description("card", 0, 0)).is_empty());
+    }
+}
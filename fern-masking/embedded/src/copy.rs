@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for masking `CopyData` payloads of a `COPY ... TO STDOUT`
+//! operation in text format, where each line is a row, with fields
+//! separated by tabs, and `\N` standing for SQL `NULL`.
+//!
+//! CSV output is also textual, but not split on commas: each line is
+//! then considered as a single field, and masked as a whole.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Representation of SQL `NULL` in text format.
+const NULL: &[u8] = b"\\N";
+
+/// Applies `mask` to each field of `data`, keeping rows and fields layout.
+pub(crate) fn mask_rows<F>(data: &Bytes, mut mask: F) -> Bytes
+where
+    F: FnMut(&Option<Bytes>) -> Option<Bytes>,
+{
+    let mut res = BytesMut::with_capacity(data.len());
+
+    for (idx, line) in data.split(|c| *c == b'\n').enumerate() {
+        if idx > 0 {
+            res.put_u8(b'\n');
+        }
+        // Note: data ending with a newline yields a last empty line.
+        if line.is_empty() {
+            continue;
+        }
+
+        for (idx, field) in line.split(|c| *c == b'\t').enumerate() {
+            if idx > 0 {
+                res.put_u8(b'\t');
+            }
+
+            let field = match field {
+                NULL => None,
+                field => Some(unescape(field)),
+            };
+            match mask(&field) {
+                Some(value) => escape(&value, &mut res),
+                None => res.put_slice(NULL),
+            }
+        }
+    }
+
+    res.freeze()
+}
+
+/// Decodes backslash escape sequences of text format `field`.
+fn unescape(field: &[u8]) -> Bytes {
+    let mut res = BytesMut::with_capacity(field.len());
+
+    let mut chars = field.iter();
+    while let Some(c) = chars.next() {
+        if *c != b'\\' {
+            res.put_u8(*c);
+            continue;
+        }
+        match chars.next() {
+            Some(b't') => res.put_u8(b'\t'),
+            Some(b'n') => res.put_u8(b'\n'),
+            Some(b'r') => res.put_u8(b'\r'),
+            Some(b'b') => res.put_u8(0x08),
+            Some(b'f') => res.put_u8(0x0c),
+            Some(b'v') => res.put_u8(0x0b),
+            Some(other) => res.put_u8(*other),
+            None => res.put_u8(b'\\'),
+        }
+    }
+
+    res.freeze()
+}
+
+/// Writes `value` to `dst`, escaping characters with a meaning in text format.
+fn escape(value: &[u8], dst: &mut BytesMut) {
+    for c in value {
+        match c {
+            b'\\' => dst.put_slice(b"\\\\"),
+            b'\t' => dst.put_slice(b"\\t"),
+            b'\n' => dst.put_slice(b"\\n"),
+            b'\r' => dst.put_slice(b"\\r"),
+            _ => dst.put_u8(*c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_mask_rows_layout() {
+        let data = Bytes::from_static(b"1\tbob\t\\N\n2\talice\tx\n");
+        let masked = mask_rows(&data, |field| {
+            field.as_ref().map(|_| Bytes::from_static(b"*"))
+        });
+        assert_eq!(Bytes::from_static(b"*\t*\t\\N\n*\t*\t*\n"), masked);
+    }
+
+    #[test]
+    fn valid_mask_rows_escapes() {
+        let data = Bytes::from_static(b"a\\tb\\\\c");
+        let masked = mask_rows(&data, |field| {
+            assert_eq!(
+                Some(Bytes::from_static(b"a\tb\\c")),
+                *field,
+                "unescaped field"
+            );
+            field.clone()
+        });
+        assert_eq!(data, masked, "escaped field");
+    }
+}
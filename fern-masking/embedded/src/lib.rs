@@ -3,13 +3,17 @@
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use std::sync::Arc;
 
+use fern_policy_engine::{Resource, Session};
 use fern_protocol_postgresql::codec::backend;
+use fern_protocol_postgresql::tracker::{DescriptionTracker, Descriptions};
 use fern_proxy_interfaces::{SQLMessage, SQLMessageHandler};
 
 use crate::strategies::MaskingStrategy;
 
 // Re-export.
+pub use classification::{comment_tags, label_tags, Classification, Column};
 pub use fern_proxy_interfaces::SQLHandlerConfig;
 
 /// An `SQLMessageHandler` applying a data masking strategy.
@@ -23,6 +27,13 @@ pub use fern_proxy_interfaces::SQLHandlerConfig;
 pub struct DataMaskingHandler {
     state: QueryState,
 
+    /// Tracker of descriptions applying to incoming `DataRow`s.
+    tracker: DescriptionTracker,
+
+    /// Whether backend Messages are tracked by this Handler, rather than
+    /// by a shared `DescriptionTracker` preceding it in a chain of handlers.
+    tracking: bool,
+
     /// Masking strategy applied by this Handler.
     //TODO(ppiotr3k): investigate if `Box`-ing can be avoided
     strategy: Box<dyn MaskingStrategy>,
@@ -33,34 +44,349 @@ pub struct DataMaskingHandler {
     /// Column names where masking will be applied, in any case.
     /// This allows using a wildcard in exclusions, and progressively mask.
     columns_forced: Vec<Bytes>,
+
+    /// Column names with a dedicated masking strategy, applied in any case.
+    columns_strategies: Vec<(Bytes, Box<dyn MaskingStrategy>)>,
+
+    /// Tags of columns, from classification settings.
+    classification: Arc<Classification>,
+
+    /// Patterns of tags with a dedicated masking strategy, applied in any case
+    /// but to columns with a dedicated strategy.
+    tags_strategies: Vec<(Vec<String>, Box<dyn MaskingStrategy>)>,
+
+    /// Notification channels whose payloads are not masked.
+    channels_excluded: Vec<Bytes>,
+
+    /// Notification channels with a dedicated masking strategy for payloads.
+    channels_strategies: Vec<(Bytes, Box<dyn MaskingStrategy>)>,
+
+    /// Function OIDs whose function call results are not masked.
+    functions_excluded: Vec<u32>,
+
+    /// Function OIDs with a dedicated masking strategy for function call results.
+    functions_strategies: Vec<(u32, Box<dyn MaskingStrategy>)>,
+
+    /// Session of the policy document, if any.
+    policy: Option<Session>,
+
+    /// Indexes of `mask` rules of the policy document, with their strategy.
+    policy_strategies: Vec<(usize, Box<dyn MaskingStrategy>)>,
+
+    /// How SQL `NULL` values are handled in masked fields.
+    nulls: NullHandling,
+
+    /// How data which cannot be masked with certainty is handled.
+    on_uncertain: UncertainPolicy,
+
+    /// Whether Messages are dropped until the next `ReadyForQuery`,
+    /// after a query has been terminated with an error.
+    blocking: bool,
+
+    /// Whether data of the ongoing `COPY` operation is in binary format.
+    copy_binary: bool,
 }
 
-///TODO(ppiotr3k): write description
+/// Handling of user data which cannot be masked with certainty, such as a
+/// `DataRow` not matching a known description, or a `CopyData` payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UncertainPolicy {
+    /// Query is terminated with an error, and its remaining results dropped.
+    /// Asynchronous notifications are dropped.
+    Block,
+
+    /// All fields are masked with the default strategy, exclusions ignored.
+    MaskAll,
+
+    /// Data is forwarded unmasked.
+    Pass,
+}
+
+/// Handling of SQL `NULL` values in fields subject to masking.
+///
+/// Fields not subject to masking are always forwarded untouched,
+/// `NULL` values included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NullHandling {
+    /// `NULL` values are masked as any other value, not leaking nullness.
+    Mask,
+
+    /// `NULL` values are forwarded as `NULL`, only other values are masked.
+    Preserve,
+
+    /// Any masked value is replaced by a `NULL` value.
+    Nullify,
+}
+
+impl NullHandling {
+    /// Applies `strategy` to `field` of type `data_type_oid`,
+    /// according to `NULL` handling mode.
+    fn apply(
+        &self,
+        strategy: &dyn MaskingStrategy,
+        field: &Option<Bytes>,
+        data_type_oid: u32,
+    ) -> Option<Bytes> {
+        match (self, field) {
+            (Self::Nullify, _) => None,
+            (Self::Preserve, None) => None,
+            (Self::Preserve, Some(value)) => Some(strategy.mask_typed(value, data_type_oid)),
+            (Self::Mask, value) => {
+                let value = value.as_ref().unwrap_or(&Bytes::new()).clone();
+                Some(strategy.mask_typed(&value, data_type_oid))
+            }
+        }
+    }
+}
+
+/// Masking state for incoming `DataRow` Messages.
 #[derive(Debug)]
 enum QueryState {
-    /// Awaiting for a `RowDescription` Message.
-    Description,
+    /// No description is known for incoming `DataRow`s.
+    Unknown,
+
+    /// Processing `DataRow` Messages described by `descriptions`, with
+    /// masking to apply to each field, and the data type OID of each field.
+    Data {
+        descriptions: Descriptions,
+        plan: Vec<(FieldMasking, u32)>,
+    },
+}
+
+/// Masking to apply to a `DataRow` field, as defined by its `RowDescription`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldMasking {
+    /// Field is excluded from masking.
+    Excluded,
+
+    /// Field is masked with the default strategy of the Handler.
+    Default,
+
+    /// Field is masked with the strategy at this index in `columns_strategies`.
+    Column(usize),
+
+    /// Field is masked with the strategy at this index in `tags_strategies`.
+    Tag(usize),
+
+    /// Field is masked with the strategy at this index in `policy_strategies`.
+    Policy(usize),
+}
+
+impl DataMaskingHandler {
+    /// Uses a `tracker` shared with the frontend `Pipe`, to follow
+    /// descriptions of prepared statements and portals.
+    ///
+    /// Backend Messages are expected to be tracked by `tracker` itself, as an
+    /// `SQLMessageHandler` preceding this one. Without a shared tracker, only
+    /// descriptions of simple queries are known.
+    #[must_use]
+    pub fn with_tracker(mut self, tracker: DescriptionTracker) -> Self {
+        self.tracker = tracker;
+        self.tracking = false;
+        self
+    }
+
+    /// Masks columns as decided by `mask` rules of the policy document of
+    /// `session`, whatever masking settings.
+    ///
+    /// Columns are only known by name, and match rules of any table.
+    #[must_use]
+    pub fn with_policy(mut self, session: Session) -> Self {
+        self.policy = Some(session);
+        self
+    }
+
+    /// Masks columns with tags matching rules of `masking.tags` settings,
+    /// as classified by `classification`.
+    #[must_use]
+    pub fn with_classification(mut self, classification: Arc<Classification>) -> Self {
+        self.classification = classification;
+        self
+    }
+
+    /// Returns the masking of the first rule matching tags of the column
+    /// of `description`, if any.
+    fn tag_masking(&self, description: &backend::RowDescription) -> Option<FieldMasking> {
+        if self.tags_strategies.is_empty() {
+            return None;
+        }
+        let tags = self.classification.tags(description);
+        let rule = self.tags_strategies.iter().position(|(patterns, _)| {
+            patterns.iter().any(|pattern| {
+                tags.iter()
+                    .any(|tag| fern_policy_engine::glob(pattern, tag))
+            })
+        })?;
+        log::debug!("column {:?} tagged {:?}", description.name, tags);
+        Some(FieldMasking::Tag(rule))
+    }
+
+    /// Returns the masking decided by the policy for `column`, if any.
+    fn policy_masking(&self, column: &[u8]) -> Option<FieldMasking> {
+        let session = self.policy.as_ref()?;
+        let rule = session.mask(&Resource::column(&String::from_utf8_lossy(column)))?;
+        match self
+            .policy_strategies
+            .iter()
+            .position(|(idx, _)| *idx == rule)
+        {
+            Some(idx) => Some(FieldMasking::Policy(idx)),
+            // Rules of the policy are always known, but masking is still due.
+            None => Some(FieldMasking::Default),
+        }
+    }
+
+    /// Updates masking state for incoming `DataRow`s, from tracked descriptions.
+    fn update_state(&mut self) {
+        let descriptions = match self.tracker.row_description() {
+            Some(descriptions) => descriptions,
+            None => {
+                self.state = QueryState::Unknown;
+                return;
+            }
+        };
+
+        // Masking plan only changes along with descriptions.
+        if let QueryState::Data {
+            descriptions: current,
+            ..
+        } = &self.state
+        {
+            if std::sync::Arc::ptr_eq(current, &descriptions) {
+                return;
+            }
+        }
+
+        let plan = self.masking_plan(&descriptions);
+        self.state = QueryState::Data { descriptions, plan };
+        log::debug!("new masking state: {:?}", self.state);
+    }
+
+    /// Terminates the ongoing query with an error, dropping Messages
+    /// until the next `ReadyForQuery`.
+    fn block(&mut self) -> Option<backend::Message> {
+        log::warn!("data cannot be masked with certainty, terminating query");
+        self.blocking = true;
+        Some(backend::Message::error_response(
+            "42501",
+            "data withheld by Fern, masking cannot be applied with certainty",
+        ))
+    }
+
+    /// Masks all `fields` with the default strategy.
+    fn mask_all(&self, fields: &[Option<Bytes>]) -> Vec<Option<Bytes>> {
+        fields
+            .iter()
+            .map(|field| self.nulls.apply(self.strategy.as_ref(), field, 0))
+            .collect()
+    }
+
+    /// Masks `fields` of a `DataRow` according to masking `plan`.
+    fn mask_fields(
+        &self,
+        fields: &[Option<Bytes>],
+        plan: &[(FieldMasking, u32)],
+    ) -> Vec<Option<Bytes>> {
+        let mut replaced_fields = vec![];
+        for (idx, (field, (masking, data_type_oid))) in fields.iter().zip(plan).enumerate() {
+            let strategy = match masking {
+                FieldMasking::Excluded => {
+                    replaced_fields.push(field.clone());
+                    continue;
+                }
+                FieldMasking::Column(column) => &self.columns_strategies[*column].1,
+                FieldMasking::Tag(rule) => &self.tags_strategies[*rule].1,
+                FieldMasking::Policy(rule) => &self.policy_strategies[*rule].1,
+                FieldMasking::Default => &self.strategy,
+            };
+
+            log::debug!("applying masking to field #{}", idx);
+            let rewritten = self.nulls.apply(strategy.as_ref(), field, *data_type_oid);
+            replaced_fields.push(rewritten);
+        }
+        replaced_fields
+    }
+
+    /// Defines masking to apply to each field of upcoming `DataRow`s.
+    fn masking_plan(&self, descriptions: &[backend::RowDescription]) -> Vec<(FieldMasking, u32)> {
+        // Wildcard `*` in exclusions translates to all columns.
+        let exclude_all = self.columns_excluded.len() == 1 && self.columns_excluded[0] == "*";
 
-    /// Processing `DataRow` Messages.
-    Data(Vec<usize>),
+        descriptions
+            .iter()
+            .map(|description| {
+                // Note: policy decisions prevail on any masking settings.
+                if let Some(masking) = self.policy_masking(&description.name) {
+                    return (masking, description.data_type_oid);
+                }
+
+                // Note: columns with a dedicated strategy prevail on exclusions.
+                let column_strategy = self
+                    .columns_strategies
+                    .iter()
+                    .position(|(name, _)| *name == description.name);
+                if let Some(idx) = column_strategy {
+                    return (FieldMasking::Column(idx), description.data_type_oid);
+                }
+
+                // Note: tagged columns prevail on exclusions, as named ones.
+                if let Some(masking) = self.tag_masking(description) {
+                    return (masking, description.data_type_oid);
+                }
+
+                // Note: `forced` columns prevail on exclusions anyway.
+                let excluded = exclude_all || self.columns_excluded.contains(&description.name);
+                if excluded && !self.columns_forced.contains(&description.name) {
+                    (FieldMasking::Excluded, description.data_type_oid)
+                } else {
+                    (FieldMasking::Default, description.data_type_oid)
+                }
+            })
+            .collect()
+    }
+
+    /// Applies the uncertain data policy to a function call `result`.
+    fn uncertain_function_result(&mut self, result: Option<Bytes>) -> Option<backend::Message> {
+        match self.on_uncertain {
+            UncertainPolicy::Block => self.block(),
+            UncertainPolicy::MaskAll => {
+                let masked = self.nulls.apply(self.strategy.as_ref(), &result, 0);
+                Some(backend::Message::FunctionCallResponse(masked))
+            }
+            UncertainPolicy::Pass => Some(backend::Message::FunctionCallResponse(result)),
+        }
+    }
+
+    /// Applies the uncertain data policy to a notification `payload`.
+    ///
+    /// Note: notifications are not part of a query, and cannot terminate one.
+    fn uncertain_notification(
+        &self,
+        process: u32,
+        channel: Bytes,
+        payload: Bytes,
+    ) -> Option<backend::Message> {
+        let payload = match self.on_uncertain {
+            UncertainPolicy::Block => {
+                log::warn!("dropping notification on channel {:?}", channel);
+                return None;
+            }
+            UncertainPolicy::MaskAll => self.strategy.mask(&payload),
+            UncertainPolicy::Pass => payload,
+        };
+        Some(backend::Message::NotificationResponse {
+            process,
+            channel,
+            payload,
+        })
+    }
 }
 
 //TODO(ppiotr3k): this crate should only process abstracted types
 #[async_trait]
 impl SQLMessageHandler<backend::Message> for DataMaskingHandler {
     fn new(config: &SQLHandlerConfig) -> Self {
-        //TODO(ppiotr3k): make length configurable
-        let strategy: Box<dyn MaskingStrategy> =
-            if let Ok(strategy) = config.get::<String>("masking.strategy") {
-                match strategy.as_str() {
-                    "caviar" => Box::new(strategies::CaviarMask::new(6)),
-                    "caviar-preserve-shape" => Box::new(strategies::CaviarShapeMask::new()),
-                    _ => Box::new(strategies::CaviarMask::new(6)),
-                }
-            } else {
-                // Default strategy, if nothing is defined in `config`.
-                Box::new(strategies::CaviarMask::new(6))
-            };
+        let strategy = strategies::from_config(config, "masking");
 
         let mut columns_excluded = vec![];
         if let Ok(columns) = config.get::<Vec<String>>("masking.exclude.columns") {
@@ -76,75 +402,251 @@ impl SQLMessageHandler<backend::Message> for DataMaskingHandler {
             }
         }
 
+        let columns_strategies = strategies_table(config, "masking.columns")
+            .into_iter()
+            .map(|(column_name, strategy)| (Bytes::from(column_name), strategy))
+            .collect();
+
+        let tags_strategies = tags_strategies(config);
+
+        let channels_excluded = config
+            .get::<Vec<String>>("masking.exclude.channels")
+            .unwrap_or_default()
+            .into_iter()
+            .map(Bytes::from)
+            .collect();
+        let channels_strategies = strategies_table(config, "masking.channels")
+            .into_iter()
+            .map(|(channel, strategy)| (Bytes::from(channel), strategy))
+            .collect();
+
+        let functions_excluded = config
+            .get::<Vec<u32>>("masking.exclude.functions")
+            .unwrap_or_default();
+        let functions_strategies = strategies_table(config, "masking.functions")
+            .into_iter()
+            .filter_map(
+                |(function_oid, strategy)| match function_oid.parse::<u32>() {
+                    Ok(function_oid) => Some((function_oid, strategy)),
+                    Err(_) => {
+                        log::warn!("ignoring rule of invalid function OID '{}'", function_oid);
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        let policy_strategies = policy_strategies(config);
+
+        let nulls = match config.get::<String>("masking.nulls").as_deref() {
+            Ok("preserve") => NullHandling::Preserve,
+            Ok("nullify") => NullHandling::Nullify,
+            // Default mode, if nothing or something unknown is defined in `config`.
+            _ => NullHandling::Mask,
+        };
+
+        let on_uncertain = match config.get::<String>("masking.on_uncertain").as_deref() {
+            Ok("block") => UncertainPolicy::Block,
+            Ok("pass") => {
+                log::warn!("data which cannot be masked with certainty will be forwarded unmasked");
+                UncertainPolicy::Pass
+            }
+            // Default mode, if nothing or something unknown is defined in `config`.
+            _ => UncertainPolicy::MaskAll,
+        };
+
         Self {
-            state: QueryState::Description,
+            state: QueryState::Unknown,
+            tracker: DescriptionTracker::new(),
+            tracking: true,
             strategy,
             columns_excluded,
             columns_forced,
+            columns_strategies,
+            classification: Arc::new(Classification::default()),
+            tags_strategies,
+            channels_excluded,
+            channels_strategies,
+            functions_excluded,
+            functions_strategies,
+            policy: None,
+            policy_strategies,
+            nulls,
+            on_uncertain,
+            blocking: false,
+            copy_binary: false,
         }
     }
 
-    async fn process(&mut self, msg: backend::Message) -> backend::Message {
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        if self.tracking {
+            self.tracker.track_backend(&msg);
+        }
+
+        // Remaining results of a terminated query are dropped,
+        // while asynchronous Messages are still processed.
+        if self.blocking {
+            match msg {
+                backend::Message::ReadyForQuery(_) => self.blocking = false,
+                backend::Message::NoticeResponse(_)
+                | backend::Message::NotificationResponse { .. }
+                | backend::Message::ParameterStatus { .. } => (),
+                _ => {
+                    log::trace!("dropping message of terminated query: {:?}", msg);
+                    return None;
+                }
+            }
+        }
+
         match msg {
-            backend::Message::RowDescription(descriptions) => {
-                // Define indexes of columns to exclude from masking.
-                let mut no_mask = vec![];
-                if self.columns_excluded.len() == 1 && self.columns_excluded[0] == "*" {
-                    // Wildcard `*` translates to all indexes.
-                    // Note: `forced` columns prevail on exclusions anyway.
-                    for (idx, description) in descriptions.iter().enumerate() {
-                        if !self.columns_forced.contains(&description.name) {
-                            no_mask.push(idx);
-                        }
+            backend::Message::DataRow(fields) => {
+                log::trace!("processing fields: {:?}", fields);
+                self.update_state();
+                match &self.state {
+                    QueryState::Data { plan, .. } if plan.len() == fields.len() => {
+                        Some(backend::Message::DataRow(self.mask_fields(&fields, plan)))
                     }
-                } else {
-                    // If no wildcard, capture columns to exclude from masking.
-                    // Note: `forced` columns prevail on exclusions anyway.
-                    for (idx, description) in descriptions.iter().enumerate() {
-                        if self.columns_excluded.contains(&description.name)
-                            && !self.columns_forced.contains(&description.name)
-                        {
-                            no_mask.push(idx);
+                    _ => {
+                        log::warn!("no matching description for `DataRow`");
+                        match self.on_uncertain {
+                            UncertainPolicy::Block => self.block(),
+                            UncertainPolicy::MaskAll => {
+                                Some(backend::Message::DataRow(self.mask_all(&fields)))
+                            }
+                            UncertainPolicy::Pass => Some(backend::Message::DataRow(fields)),
                         }
                     }
                 }
-
-                // Store indexes to exclude from masking in upcoming `DataRow`s.
-                self.state = QueryState::Data(no_mask);
-                log::debug!("new masking exclusion state: {:?}", self.state);
-                backend::Message::RowDescription(descriptions)
             }
-            backend::Message::CommandComplete(command) => {
-                // No more `DataRow`s to process, reset state.
-                self.state = QueryState::Description;
-                log::debug!("resetting masking state, awaiting next query");
-                backend::Message::CommandComplete(command)
+            backend::Message::CopyOutResponse(ref response)
+            | backend::Message::CopyBothResponse(ref response) => {
+                self.copy_binary = response.format == 1;
+                Some(msg)
             }
-            backend::Message::DataRow(fields) => {
-                log::trace!("processing fields: {:?}", fields);
-                let mask = if let QueryState::Data(mask) = &self.state {
-                    mask
-                } else {
-                    panic!("unexpected state for `QueryState`");
-                };
-
-                let mut replaced_fields = vec![];
-                for (idx, field) in fields.iter().enumerate() {
-                    if !mask.contains(&idx) {
-                        log::debug!("applying masking to field #{}", idx);
-                        let rewritten = self.strategy.mask(field);
-                        replaced_fields.push(rewritten);
-                    } else {
-                        replaced_fields.push(field.clone());
+            // Note: `COPY` data comes without column names, masking is uncertain.
+            backend::Message::CopyData(data) => match self.on_uncertain {
+                UncertainPolicy::Block => self.block(),
+                UncertainPolicy::MaskAll if self.copy_binary => {
+                    log::warn!("binary `COPY` data cannot be masked");
+                    self.block()
+                }
+                UncertainPolicy::MaskAll => {
+                    let masked = copy::mask_rows(&data, |field| {
+                        self.nulls.apply(self.strategy.as_ref(), field, 0)
+                    });
+                    Some(backend::Message::CopyData(masked))
+                }
+                UncertainPolicy::Pass => Some(backend::Message::CopyData(data)),
+            },
+            backend::Message::FunctionCallResponse(result) => {
+                let function_oid = self.tracker.function_call();
+                if let Some(function_oid) = function_oid {
+                    if self.functions_excluded.contains(&function_oid) {
+                        return Some(backend::Message::FunctionCallResponse(result));
+                    }
+                    let rule = self
+                        .functions_strategies
+                        .iter()
+                        .find(|(oid, _)| *oid == function_oid);
+                    if let Some((_, strategy)) = rule {
+                        let masked = self.nulls.apply(strategy.as_ref(), &result, 0);
+                        return Some(backend::Message::FunctionCallResponse(masked));
                     }
                 }
-                backend::Message::DataRow(replaced_fields)
+
+                log::warn!("no masking rule for result of function {:?}", function_oid);
+                self.uncertain_function_result(result)
+            }
+            backend::Message::NotificationResponse {
+                process,
+                channel,
+                payload,
+            } => {
+                let rule = self
+                    .channels_strategies
+                    .iter()
+                    .find(|(name, _)| *name == channel);
+                let payload = if self.channels_excluded.contains(&channel) {
+                    payload
+                } else if let Some((_, strategy)) = rule {
+                    strategy.mask(&payload)
+                } else {
+                    log::warn!("no masking rule for notification channel {:?}", channel);
+                    return self.uncertain_notification(process, channel, payload);
+                };
+                Some(backend::Message::NotificationResponse {
+                    process,
+                    channel,
+                    payload,
+                })
             }
-            _ => msg,
+            _ => Some(msg),
         }
     }
 }
 
+/// Builds the [`MaskingStrategy`] of each rule defined in the `key` table of `config`,
+/// as pairs of rule name and strategy.
+fn strategies_table(
+    config: &SQLHandlerConfig,
+    key: &str,
+) -> Vec<(String, Box<dyn MaskingStrategy>)> {
+    let mut rules = vec![];
+    if let Ok(table) = config.get_table(key) {
+        for name in table.keys() {
+            let strategy = strategies::from_config(config, &format!("{}.{}", key, name));
+            log::debug!("'{}' in '{}' masked with: {:?}", name, key, strategy);
+            rules.push((name.clone(), strategy));
+        }
+    }
+    rules
+}
+
+/// Builds the [`MaskingStrategy`] of each rule defined in the `masking.tags`
+/// array of `config`, as pairs of tag patterns and strategy.
+fn tags_strategies(config: &SQLHandlerConfig) -> Vec<(Vec<String>, Box<dyn MaskingStrategy>)> {
+    let count = config
+        .get_array("masking.tags")
+        .map_or(0, |rules| rules.len());
+    (0..count)
+        .filter_map(|idx| {
+            let key = format!("masking.tags[{}]", idx);
+            match config.get::<Vec<String>>(&format!("{}.tags", key)) {
+                Ok(tags) if !tags.is_empty() => {
+                    let strategy = strategies::from_config(config, &key);
+                    log::debug!("columns tagged {:?} masked with: {:?}", tags, strategy);
+                    Some((tags, strategy))
+                }
+                _ => {
+                    log::warn!("ignoring masking rule #{} without tags", idx);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Builds the [`MaskingStrategy`] of each `mask` rule of the policy document
+/// defined in `config`, as pairs of rule index and strategy.
+fn policy_strategies(config: &SQLHandlerConfig) -> Vec<(usize, Box<dyn MaskingStrategy>)> {
+    let count = config
+        .get_array("policy.rules")
+        .map_or(0, |rules| rules.len());
+    (0..count)
+        .filter_map(|idx| {
+            let key = format!("policy.rules[{}]", idx);
+            match config.get::<String>(&format!("{}.action", key)).as_deref() {
+                Ok("mask") => {
+                    let strategy = strategies::from_config(config, &key);
+                    log::debug!("policy rule #{} masked with: {:?}", idx, strategy);
+                    Some((idx, strategy))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
 /// Handler used currently for PostgreSQL frontend Messages.
 /// Does nothing but passthrough.
 #[derive(Debug)]
@@ -164,10 +666,535 @@ where
     }
 }
 
+mod classification;
+mod copy;
 mod strategies;
 
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend::{CopyResponse, Message, RowDescription};
+    use fern_protocol_postgresql::codec::frontend;
+    use fern_protocol_postgresql::tracker::DescriptionTracker;
+    use fern_proxy_interfaces::SQLMessageHandler;
+
+    use super::{DataMaskingHandler, SQLHandlerConfig};
+
+    /// Helper function processing `msg` with `handler` preceded by `tracker`,
+    /// as chained in a `Pipe`.
+    async fn process(
+        tracker: &DescriptionTracker,
+        handler: &mut DataMaskingHandler,
+        msg: Message,
+    ) -> Option<Message> {
+        tracker.track_backend(&msg);
+        handler.process(msg).await
+    }
+
+    /// Helper function building a handler excluding column "clear" from masking.
+    fn handler() -> DataMaskingHandler {
+        handler_with(&[])
+    }
+
+    /// Helper function building a handler excluding column "clear" from masking,
+    /// with additional `settings`.
+    fn handler_with(settings: &[(&str, &str)]) -> DataMaskingHandler {
+        let mut builder = SQLHandlerConfig::builder()
+            .set_default("masking.exclude.columns", vec!["clear"])
+            .unwrap();
+        for (key, value) in settings {
+            builder = builder.set_override(*key, *value).unwrap();
+        }
+        DataMaskingHandler::new(&builder.build().unwrap())
+    }
+
+    /// Helper function building a row of text fields.
+    fn row(fields: &[&'static str]) -> Message {
+        Message::DataRow(
+            fields
+                .iter()
+                .map(|field| Some(Bytes::from_static(field.as_bytes())))
+                .collect(),
+        )
+    }
+
+    /// Helper function building a `RowDescription` for a text column.
+    fn text_column(name: &'static str) -> RowDescription {
+        RowDescription {
+            name: Bytes::from_static(name.as_bytes()),
+            table_oid: 0,
+            column_attr: 0,
+            data_type_oid: 25,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+
+    /// Helper function processing a single `DataRow` with a two columns description.
+    async fn assert_masked_row(
+        settings: &[(&str, &str)],
+        row: Vec<Option<Bytes>>,
+        expected: Vec<Option<Bytes>>,
+    ) {
+        let mut builder = SQLHandlerConfig::builder()
+            .set_default("masking.exclude.columns", vec!["clear"])
+            .unwrap();
+        for (key, value) in settings {
+            builder = builder.set_override(*key, *value).unwrap();
+        }
+        let config = builder.build().unwrap();
+
+        let mut handler = DataMaskingHandler::new(&config);
+        let description = vec![text_column("clear"), text_column("secret")];
+        handler.process(Message::RowDescription(description)).await;
+        let masked = handler.process(Message::DataRow(row)).await;
+        assert_eq!(Some(Message::DataRow(expected)), masked, "masked row");
+    }
+
+    #[tokio::test]
+    async fn null_excluded_column_is_forwarded_as_null() {
+        assert_masked_row(
+            &[],
+            vec![None, Some(Bytes::from_static(b"secret"))],
+            vec![None, Some(Bytes::from_static(b"******"))],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn null_masked_by_default() {
+        assert_masked_row(
+            &[],
+            vec![Some(Bytes::from_static(b"")), None],
+            vec![
+                Some(Bytes::from_static(b"")),
+                Some(Bytes::from_static(b"******")),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn null_preserved() {
+        assert_masked_row(
+            &[("masking.nulls", "preserve")],
+            vec![Some(Bytes::from_static(b"a")), None],
+            vec![Some(Bytes::from_static(b"a")), None],
+        )
+        .await;
+        assert_masked_row(
+            &[("masking.nulls", "preserve")],
+            vec![None, Some(Bytes::from_static(b"b"))],
+            vec![None, Some(Bytes::from_static(b"******"))],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn masked_values_nullified() {
+        assert_masked_row(
+            &[("masking.nulls", "nullify")],
+            vec![
+                Some(Bytes::from_static(b"a")),
+                Some(Bytes::from_static(b"b")),
+            ],
+            vec![Some(Bytes::from_static(b"a")), None],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn column_strategy_prevails_on_exclusion() {
+        assert_masked_row(
+            &[("masking.columns.clear.strategy", "caviar-preserve-shape")],
+            vec![
+                Some(Bytes::from_static(b"ab-c")),
+                Some(Bytes::from_static(b"secret")),
+            ],
+            vec![
+                Some(Bytes::from_static(b"**-*")),
+                Some(Bytes::from_static(b"******")),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn data_row_without_description_is_masked() {
+        let mut handler = handler();
+        let masked = handler.process(row(&["a", "b"])).await;
+        assert_eq!(Some(row(&["******", "******"])), masked, "masked row");
+    }
+
+    #[tokio::test]
+    async fn multiple_result_sets_in_simple_query() {
+        let mut handler = handler();
+        handler
+            .process(Message::RowDescription(vec![text_column("clear")]))
+            .await;
+        let masked = handler.process(row(&["a"])).await;
+        assert_eq!(Some(row(&["a"])), masked, "first result set");
+        handler
+            .process(Message::CommandComplete(Bytes::new()))
+            .await;
+
+        handler
+            .process(Message::RowDescription(vec![text_column("secret")]))
+            .await;
+        let masked = handler.process(row(&["b"])).await;
+        assert_eq!(Some(row(&["******"])), masked, "second result set");
+    }
+
+    #[tokio::test]
+    async fn error_response_resets_state() {
+        let mut handler = handler();
+        handler
+            .process(Message::RowDescription(vec![text_column("clear")]))
+            .await;
+        handler.process(Message::ErrorResponse(Bytes::new())).await;
+        let masked = handler.process(row(&["a"])).await;
+        assert_eq!(Some(row(&["******"])), masked, "stale description not used");
+    }
+
+    #[tokio::test]
+    async fn extended_query_with_prepared_statement() {
+        let tracker = DescriptionTracker::new();
+        let mut handler = handler().with_tracker(tracker.clone());
+        let stmt_name = Bytes::from_static(b"s1");
+
+        // Statement described once, executed twice.
+        for msg in [
+            frontend::Message::Parse {
+                stmt_name: stmt_name.clone(),
+                query: Bytes::from_static(b"SELECT clear, secret FROM t"),
+                parameters_types: vec![],
+            },
+            frontend::Message::Describe {
+                kind: b'S',
+                name: stmt_name.clone(),
+            },
+            frontend::Message::Sync(),
+        ] {
+            tracker.track_frontend(&msg);
+        }
+        for _ in 0..2 {
+            for msg in [
+                frontend::Message::Bind {
+                    portal: Bytes::new(),
+                    stmt_name: stmt_name.clone(),
+                    parameters: vec![],
+                    results_formats: vec![],
+                },
+                frontend::Message::Execute {
+                    portal: Bytes::new(),
+                    max_rows: 0,
+                },
+                frontend::Message::Sync(),
+            ] {
+                tracker.track_frontend(&msg);
+            }
+        }
+
+        process(&tracker, &mut handler, Message::ParseComplete()).await;
+        process(
+            &tracker,
+            &mut handler,
+            Message::ParameterDescription(vec![]),
+        )
+        .await;
+        let description = vec![text_column("clear"), text_column("secret")];
+        process(&tracker, &mut handler, Message::RowDescription(description)).await;
+        process(&tracker, &mut handler, Message::ReadyForQuery(b'I')).await;
+
+        for _ in 0..2 {
+            process(&tracker, &mut handler, Message::BindComplete()).await;
+            let masked = process(&tracker, &mut handler, row(&["a", "b"])).await;
+            assert_eq!(Some(row(&["a", "******"])), masked, "masked row");
+            process(
+                &tracker,
+                &mut handler,
+                Message::CommandComplete(Bytes::new()),
+            )
+            .await;
+            process(&tracker, &mut handler, Message::ReadyForQuery(b'I')).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn uncertain_data_row_blocked() {
+        let mut handler = handler_with(&[("masking.on_uncertain", "block")]);
+        let blocked = handler.process(row(&["a"])).await;
+        assert!(
+            matches!(blocked, Some(Message::ErrorResponse(_))),
+            "query terminated"
+        );
+        assert_eq!(None, handler.process(row(&["b"])).await, "row dropped");
+        let complete = Message::CommandComplete(Bytes::from_static(b"SELECT 2"));
+        assert_eq!(None, handler.process(complete).await, "completion dropped");
+
+        let notice = Message::NoticeResponse(Bytes::from_static(b"SNOTICE\0\0"));
+        let forwarded = handler.process(notice.clone()).await;
+        assert_eq!(Some(notice), forwarded, "asynchronous message");
+
+        let ready = Message::ReadyForQuery(b'I');
+        assert_eq!(Some(ready.clone()), handler.process(ready).await);
+
+        // Next query is processed as usual.
+        handler
+            .process(Message::RowDescription(vec![text_column("clear")]))
+            .await;
+        let masked = handler.process(row(&["a"])).await;
+        assert_eq!(Some(row(&["a"])), masked, "next query");
+    }
+
+    #[tokio::test]
+    async fn uncertain_data_row_passed() {
+        let mut handler = handler_with(&[("masking.on_uncertain", "pass")]);
+        let forwarded = handler.process(row(&["a", "b"])).await;
+        assert_eq!(Some(row(&["a", "b"])), forwarded, "unmasked row");
+    }
+
+    #[tokio::test]
+    async fn data_row_not_matching_description_is_uncertain() {
+        let mut handler = handler();
+        handler
+            .process(Message::RowDescription(vec![text_column("clear")]))
+            .await;
+        let masked = handler.process(row(&["a", "b"])).await;
+        assert_eq!(Some(row(&["******", "******"])), masked, "masked row");
+    }
+
+    #[tokio::test]
+    async fn copy_data_masked_in_text_format() {
+        let mut handler = handler_with(&[("masking.nulls", "preserve")]);
+        let response = CopyResponse {
+            format: 0,
+            columns_formats: vec![0, 0],
+        };
+        handler.process(Message::CopyOutResponse(response)).await;
+        let data = Bytes::from_static(b"1\tbob\n");
+        let masked = handler.process(Message::CopyData(data)).await;
+        let expected = Bytes::from_static(b"******\t******\n");
+        assert_eq!(Some(Message::CopyData(expected)), masked);
+
+        let data = Bytes::from_static(b"2\t\\N\n");
+        let masked = handler.process(Message::CopyData(data)).await;
+        let expected = Bytes::from_static(b"******\t\\N\n");
+        assert_eq!(Some(Message::CopyData(expected)), masked, "NULL field");
+    }
+
+    #[tokio::test]
+    async fn copy_data_blocked_in_binary_format() {
+        let mut handler = handler();
+        let response = CopyResponse {
+            format: 1,
+            columns_formats: vec![1],
+        };
+        handler.process(Message::CopyOutResponse(response)).await;
+        let data = Bytes::from_static(b"PGCOPY\n\xff\r\n\0");
+        let blocked = handler.process(Message::CopyData(data)).await;
+        assert!(
+            matches!(blocked, Some(Message::ErrorResponse(_))),
+            "query terminated"
+        );
+        assert_eq!(None, handler.process(Message::CopyDone()).await);
+    }
+
+    #[tokio::test]
+    async fn function_call_response_masked() {
+        let mut handler = handler();
+        let result = Message::FunctionCallResponse(Some(Bytes::from_static(b"secret")));
+        let masked = handler.process(result).await;
+        let expected = Message::FunctionCallResponse(Some(Bytes::from_static(b"******")));
+        assert_eq!(Some(expected), masked);
+    }
+
+    #[tokio::test]
+    async fn notification_response_dropped_when_blocking() {
+        let mut handler = handler_with(&[("masking.on_uncertain", "block")]);
+        let notification = Message::NotificationResponse {
+            process: 42,
+            channel: Bytes::from_static(b"changes"),
+            payload: Bytes::from_static(b"{\"id\": 1}"),
+        };
+        assert_eq!(None, handler.process(notification).await);
+
+        // Notifications do not terminate any query.
+        let masked = handler.process(Message::ReadyForQuery(b'I')).await;
+        assert_eq!(Some(Message::ReadyForQuery(b'I')), masked);
+    }
+
+    /// Helper function building a `NotificationResponse` on `channel`.
+    fn notification(channel: &'static str, payload: &'static str) -> Message {
+        Message::NotificationResponse {
+            process: 42,
+            channel: Bytes::from_static(channel.as_bytes()),
+            payload: Bytes::from_static(payload.as_bytes()),
+        }
+    }
+
+    /// Helper function building a `FunctionCall` of `function_oid`.
+    fn function_call(function_oid: u32) -> frontend::Message {
+        frontend::Message::FunctionCall {
+            function_oid,
+            arguments: vec![],
+            result_format: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn notification_masked_with_channel_rule() {
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [masking]
+                on_uncertain = 'block'
+                exclude.channels = ['heartbeat']
+                [masking.channels.customers]
+                strategy = 'json-path'
+                json.paths = [{ path = '$.email', strategy = 'caviar' }]
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let mut handler = DataMaskingHandler::new(&config);
+
+        let msg = notification("customers", r#"{"id":1,"email":"bob@example.com"}"#);
+        let expected = notification("customers", r#"{"id":1,"email":"******"}"#);
+        assert_eq!(Some(expected), handler.process(msg).await, "channel rule");
+
+        let msg = notification("heartbeat", "alive");
+        let expected = msg.clone();
+        assert_eq!(
+            Some(expected),
+            handler.process(msg).await,
+            "excluded channel"
+        );
+
+        let msg = notification("orders", "{}");
+        assert_eq!(None, handler.process(msg).await, "no rule");
+    }
+
+    #[tokio::test]
+    async fn function_call_response_masked_with_function_rule() {
+        let tracker = DescriptionTracker::new();
+        let mut handler = handler_with(&[
+            ("masking.on_uncertain", "pass"),
+            ("masking.functions.16384.strategy", "caviar-preserve-shape"),
+        ])
+        .with_tracker(tracker.clone());
+
+        tracker.track_frontend(&function_call(16384));
+        let result = Message::FunctionCallResponse(Some(Bytes::from_static(b"ab-12")));
+        let expected = Message::FunctionCallResponse(Some(Bytes::from_static(b"**-**")));
+        assert_eq!(
+            Some(expected),
+            process(&tracker, &mut handler, result).await,
+            "function rule"
+        );
+        process(&tracker, &mut handler, Message::ReadyForQuery(b'I')).await;
+
+        tracker.track_frontend(&function_call(16385));
+        let result = Message::FunctionCallResponse(Some(Bytes::from_static(b"ab-12")));
+        let expected = result.clone();
+        assert_eq!(
+            Some(expected),
+            process(&tracker, &mut handler, result).await,
+            "no rule"
+        );
+    }
+
+    #[tokio::test]
+    async fn policy_masking_prevails_on_exclusion() {
+        let document = r#"
+            [masking.exclude]
+            columns = ["*"]
+            [policy.subjects.analysts]
+            users = ["alice"]
+            [[policy.rules]]
+            resources = ["*.*.users.email"]
+            action = "allow"
+            [[policy.rules]]
+            subjects = ["analysts"]
+            resources = ["*.*.users.email"]
+            action = "mask"
+            strategy = "caviar-preserve-shape"
+            [[policy.rules]]
+            subjects = ["analysts"]
+            resources = ["*.*.*.ssn"]
+            action = "mask"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        let mut session = fern_policy_engine::SessionHandler::new(&config);
+        let mut handler = DataMaskingHandler::new(&config).with_policy(session.session());
+        let startup = fern_protocol_postgresql::codec::frontend::Message::StartupMessage {
+            frame_length: 0,
+            parameters: vec![fern_protocol_postgresql::codec::frontend::Parameter {
+                name: Bytes::from_static(b"user"),
+                value: Bytes::from_static(b"alice"),
+            }],
+        };
+        session.process(startup).await;
+        let description = vec![text_column("id"), text_column("email"), text_column("ssn")];
+        handler.process(Message::RowDescription(description)).await;
+        let masked = handler.process(row(&["7", "a@b.c", "123"])).await;
+        assert_eq!(Some(row(&["7", "*@*.*", "******"])), masked, "masked row");
+    }
+
+    #[tokio::test]
+    async fn tag_strategy_prevails_on_exclusion() {
+        let document = r#"
+            [masking.exclude]
+            columns = ["*"]
+            [masking.columns.email]
+            strategy = "caviar"
+            [[masking.tags]]
+            tags = ["pci.*"]
+            strategy = "prefix"
+            [[masking.tags]]
+            tags = ["pii.email", "pii.phone"]
+            strategy = "caviar-preserve-shape"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        let mut classification = super::Classification::default();
+        let column = |name: &str, tags: &[&str]| super::Column {
+            schema: "public".into(),
+            table: "users".into(),
+            name: name.into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        classification.add_column(16384, 2, column("email", &["pii.email"]));
+        classification.add_column(16384, 3, column("phone", &["pii.phone"]));
+        classification.add_column(16384, 4, column("card", &["pii.card", "pci.pan"]));
+        let mut handler = DataMaskingHandler::new(&config)
+            .with_classification(std::sync::Arc::new(classification));
+        let tagged = |name, column_attr| RowDescription {
+            table_oid: 16384,
+            column_attr,
+            ..text_column(name)
+        };
+        let description = vec![
+            text_column("id"),
+            tagged("email", 2),
+            tagged("mobile", 3),
+            tagged("card", 4),
+        ];
+        handler.process(Message::RowDescription(description)).await;
+        let masked = handler
+            .process(row(&["7", "a@b.c", "06-12", "4111-1111"]))
+            .await;
+        let expected = row(&["7", "******", "**-**", "41*******"]);
+        assert_eq!(Some(expected), masked, "masked row");
+    }
+
     #[test]
     fn it_works() {}
 }
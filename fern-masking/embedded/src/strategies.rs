@@ -4,11 +4,66 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use std::fmt::Debug;
 
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+pub use content::ContentScanMask;
+pub use faker::FakerMask;
+pub use generalization::{AgeBucketMask, DateTruncateMask, PrefixMask, RoundMask};
+pub use json::JsonPathMask;
+pub use noise::NoiseMask;
+
+mod content;
+mod faker;
+mod generalization;
+mod json;
+mod noise;
+
 /// A trait defining an interface for data masking strategies.
 //TODO(ppiotr3k): consider moving to interfaces
 //TODO(ppiotr3k): refactor/closure to dedup logging code
 pub trait MaskingStrategy: Debug + Send + Sync {
     fn mask(&self, data: &Bytes) -> Bytes;
+
+    /// Masks `data` from a column of type `data_type_oid`, for strategies
+    /// whose result depends on the data type. Defaults to [`Self::mask`].
+    fn mask_typed(&self, data: &Bytes, _data_type_oid: u32) -> Bytes {
+        self.mask(data)
+    }
+}
+
+/// Builds the [`MaskingStrategy`] defined by the `strategy` setting under `key`.
+///
+/// Should no strategy be defined, a fixed-length caviar strategy is used.
+pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Box<dyn MaskingStrategy> {
+    match config.get::<String>(&format!("{}.strategy", key)) {
+        Ok(name) => build(&name, config, key),
+        // Default strategy, if nothing is defined in `config`.
+        Err(_) => Box::new(CaviarMask::new(6)),
+    }
+}
+
+/// Builds the [`MaskingStrategy`] called `name`, getting its
+/// strategy-specific settings from `config` under `key`.
+///
+/// Unknown strategy names fall back to a fixed-length caviar strategy.
+pub fn build(name: &str, config: &SQLHandlerConfig, key: &str) -> Box<dyn MaskingStrategy> {
+    //TODO(ppiotr3k): make length configurable
+    match name {
+        "caviar" => Box::new(CaviarMask::new(6)),
+        "caviar-preserve-shape" => Box::new(CaviarShapeMask::new()),
+        "content-scan" => Box::new(ContentScanMask::from_config(config, key)),
+        "json-path" => Box::new(JsonPathMask::from_config(config, key)),
+        "date-truncate" => Box::new(DateTruncateMask::from_config(config, key)),
+        "age-bucket" => Box::new(AgeBucketMask::from_config(config, key)),
+        "round" => Box::new(RoundMask::from_config(config, key)),
+        "prefix" => Box::new(PrefixMask::from_config(config, key)),
+        "noise" => Box::new(NoiseMask::from_config(config, key)),
+        "faker" => Box::new(FakerMask::from_config(config, key)),
+        _ => {
+            log::warn!("unknown masking strategy '{}', using 'caviar'", name);
+            Box::new(CaviarMask::new(6))
+        }
+    }
 }
 
 /// A simple and fast masking strategy where whatever the provided data, the
@@ -0,0 +1,441 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Content-based masking for unstructured text, rewriting only the spans of
+//! a field matched by personal data detectors.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use super::{CaviarShapeMask, MaskingStrategy};
+
+/// Names of the built-in detectors, all enabled unless configured otherwise.
+const BUILTIN_DETECTORS: [&str; 8] = [
+    "email",
+    "iban",
+    "credit-card",
+    "phone",
+    "ipv4",
+    "ipv6",
+    "fr-nir",
+    "us-ssn",
+];
+
+/// A function discarding false positives among spans matched by a `Detector`.
+type Validator = fn(&[u8]) -> bool;
+
+/// A personal data detector, matching spans with a regular expression,
+/// and optionally discarding false positives with a validation function.
+#[derive(Debug)]
+struct Detector {
+    name: String,
+    regex: Regex,
+    validator: Option<Validator>,
+}
+
+impl Detector {
+    /// Creates a built-in `Detector` by `name`, if such a detector exists.
+    fn builtin(name: &str) -> Option<Self> {
+        let (pattern, validator): (&str, Option<Validator>) = match name {
+            "email" => (
+                r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b",
+                None,
+            ),
+            "iban" => (
+                r"\b[A-Z]{2}[0-9]{2}(?: ?[A-Z0-9]){11,30}\b",
+                Some(is_valid_iban),
+            ),
+            "credit-card" => (r"\b[0-9](?:[ -]?[0-9]){12,18}\b", Some(is_valid_luhn)),
+            "phone" => (
+                concat!(
+                    r"(?:\+|\b00)[1-9][0-9]{0,2}(?:[ .-]?\(?[0-9]\)?){6,12}\b",
+                    r"|\b0[1-9](?:[ .-]?[0-9]{2}){4}\b",
+                    r"|(?:\([2-9][0-9]{2}\)|\b[2-9][0-9]{2})[ .-]?[2-9][0-9]{2}[ .-]?[0-9]{4}\b",
+                ),
+                None,
+            ),
+            "ipv4" => (r"\b(?:[0-9]{1,3}\.){3}[0-9]{1,3}\b", Some(is_valid_ipv4)),
+            "ipv6" => (
+                r"(?i)(?:[0-9a-f]{1,4}|:)?(?::[0-9a-f]{0,4}){2,7}(?:%[0-9a-z]+)?",
+                Some(is_valid_ipv6),
+            ),
+            "fr-nir" => (
+                r"\b[12] ?[0-9]{2} ?[0-9]{2} ?(?:[0-9]{2}|2[AB]) ?[0-9]{3} ?[0-9]{3} ?[0-9]{2}\b",
+                Some(is_valid_fr_nir),
+            ),
+            "us-ssn" => (
+                r"\b[0-9]{3}[- ][0-9]{2}[- ][0-9]{4}\b",
+                Some(is_valid_us_ssn),
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            name: name.to_string(),
+            // Built-in patterns are known to be valid.
+            regex: Regex::new(pattern).expect("invalid built-in detector pattern"),
+            validator,
+        })
+    }
+
+    /// Creates a user-defined `Detector`, without validation function.
+    fn custom(name: &str, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            name: name.to_string(),
+            regex: Regex::new(pattern)?,
+            validator: None,
+        })
+    }
+
+    /// Returns all validated spans matched in `data`, as `(start, end)` offsets.
+    fn find_spans(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        self.regex
+            .find_iter(data)
+            .filter(|found| !found.as_bytes().is_empty())
+            .filter(|found| self.validator.map_or(true, |valid| valid(found.as_bytes())))
+            .map(|found| {
+                log::trace!("detector '{}' matched {:?}", self.name, found.range());
+                (found.start(), found.end())
+            })
+            .collect()
+    }
+}
+
+/// A content-based masking strategy where only spans of data matched by
+/// personal data detectors are rewritten, using a span masking strategy.
+/// The rest of the data is kept as is.
+///
+/// Built-in detectors cover emails, IBANs (checksum validated), credit card
+/// numbers (Luhn validated), phone numbers, IPv4 and IPv6 addresses, French
+/// NIR (key validated), and US SSN. User-defined patterns can be added.
+#[derive(Debug)]
+pub struct ContentScanMask {
+    detectors: Vec<Detector>,
+
+    /// Masking strategy applied to each matched span.
+    span_strategy: Box<dyn MaskingStrategy>,
+}
+
+impl ContentScanMask {
+    /// Creates a `ContentScanMask` with provided `detectors` names, and no
+    /// user-defined patterns. Unknown detector names are ignored.
+    pub fn new(detectors: &[&str], span_strategy: Box<dyn MaskingStrategy>) -> Self {
+        let detectors = detectors
+            .iter()
+            .filter_map(|name| {
+                let detector = Detector::builtin(name);
+                if detector.is_none() {
+                    log::warn!("unknown content detector '{}', ignoring", name);
+                }
+                detector
+            })
+            .collect();
+
+        Self {
+            detectors,
+            span_strategy,
+        }
+    }
+
+    /// Creates a `ContentScanMask` from settings found in `config` under `key`:
+    /// - `content.detectors`: built-in detectors names, all by default,
+    /// - `content.replacement`: strategy applied to matched spans,
+    ///   `caviar-preserve-shape` by default,
+    /// - `content.patterns`: list of user-defined `{ name, regex }` tables.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        let span_strategy: Box<dyn MaskingStrategy> =
+            match config.get::<String>(&format!("{}.content.replacement", key)) {
+                // Nesting content scanning makes no sense, prevent it.
+                Ok(name) if name != "content-scan" => super::build(&name, config, key),
+                _ => Box::new(CaviarShapeMask::new()),
+            };
+
+        let mut scanner = match config.get::<Vec<String>>(&format!("{}.content.detectors", key)) {
+            Ok(names) => {
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                Self::new(&names, span_strategy)
+            }
+            Err(_) => Self::new(&BUILTIN_DETECTORS, span_strategy),
+        };
+
+        if let Ok(patterns) =
+            config.get::<Vec<HashMap<String, String>>>(&format!("{}.content.patterns", key))
+        {
+            for pattern in patterns.iter() {
+                let (name, regex) = match (pattern.get("name"), pattern.get("regex")) {
+                    (Some(name), Some(regex)) => (name, regex),
+                    _ => {
+                        log::error!("content pattern without 'name' or 'regex', ignoring");
+                        continue;
+                    }
+                };
+                match Detector::custom(name, regex) {
+                    Ok(detector) => scanner.detectors.push(detector),
+                    Err(err) => {
+                        log::error!("invalid content pattern '{}', ignoring: {}", name, err)
+                    }
+                }
+            }
+        }
+
+        scanner
+    }
+
+    /// Returns sorted and merged spans matched by all detectors in `data`.
+    fn find_spans(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        let mut spans: Vec<(usize, usize)> = self
+            .detectors
+            .iter()
+            .flat_map(|detector| detector.find_spans(data))
+            .collect();
+        spans.sort_unstable();
+
+        // Overlapping spans from different detectors are masked as one.
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+        for (start, end) in spans {
+            match merged.last_mut() {
+                Some(last) if start < last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+}
+
+impl MaskingStrategy for ContentScanMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let spans = self.find_spans(data);
+        if spans.is_empty() {
+            return data.clone();
+        }
+
+        let mut res = BytesMut::with_capacity(data.len());
+        let mut position = 0;
+        for (start, end) in spans {
+            res.put(&data[position..start]);
+            res.put(self.span_strategy.mask(&data.slice(start..end)));
+            position = end;
+        }
+        res.put(&data[position..]);
+
+        log::trace!("rewritten value: {:?}", res);
+        res.freeze()
+    }
+}
+
+/// Returns the decimal digits found in `data`, ignoring any separator.
+fn digits(data: &[u8]) -> Vec<u32> {
+    data.iter()
+        .filter(|c| c.is_ascii_digit())
+        .map(|c| u32::from(c - b'0'))
+        .collect()
+}
+
+/// Validates a number with the Luhn (mod 10) algorithm, as used for cards.
+fn is_valid_luhn(data: &[u8]) -> bool {
+    let digits = digits(data);
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, digit)| match (idx % 2, digit * 2) {
+            (1, doubled) if doubled > 9 => doubled - 9,
+            (1, doubled) => doubled,
+            _ => *digit,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Validates an IBAN with its ISO 7064 mod 97-10 checksum.
+fn is_valid_iban(data: &[u8]) -> bool {
+    let compact: Vec<u8> = data.iter().filter(|c| **c != b' ').copied().collect();
+    if !(15..=34).contains(&compact.len()) {
+        return false;
+    }
+
+    // Country code and check digits are moved to the end, letters become numbers.
+    let (head, tail) = compact.split_at(4);
+    let mut remainder = 0u32;
+    for c in tail.iter().chain(head.iter()) {
+        remainder = match c {
+            b'0'..=b'9' => (remainder * 10 + u32::from(c - b'0')) % 97,
+            b'A'..=b'Z' => (remainder * 100 + u32::from(c - b'A') + 10) % 97,
+            _ => return false,
+        };
+    }
+    remainder == 1
+}
+
+/// Validates an IPv4 address, rejecting octets out of range.
+fn is_valid_ipv4(data: &[u8]) -> bool {
+    std::str::from_utf8(data).map_or(false, |ip| ip.parse::<Ipv4Addr>().is_ok())
+}
+
+/// Validates an IPv6 address, possibly with a zone index.
+fn is_valid_ipv6(data: &[u8]) -> bool {
+    std::str::from_utf8(data).map_or(false, |ip| {
+        let address = ip.split('%').next().unwrap_or_default();
+        address.contains(':') && address.parse::<Ipv6Addr>().is_ok()
+    })
+}
+
+/// Validates a French NIR (social security number) with its control key.
+fn is_valid_fr_nir(data: &[u8]) -> bool {
+    let compact: Vec<u8> = data.iter().filter(|c| **c != b' ').copied().collect();
+    if compact.len() != 15 {
+        return false;
+    }
+
+    // Corsica departments `2A` and `2B` are substituted for the key computation.
+    let (number, key) = compact.split_at(13);
+    let (number, shift) = match &number[5..7] {
+        b"2A" => ([&number[..5], b"19", &number[7..]].concat(), 1_000_000),
+        b"2B" => ([&number[..5], b"18", &number[7..]].concat(), 2_000_000),
+        _ => (number.to_vec(), 0),
+    };
+    let number = match std::str::from_utf8(&number).map(str::parse::<u64>) {
+        Ok(Ok(number)) => number - shift,
+        _ => return false,
+    };
+    let key = match std::str::from_utf8(key).map(str::parse::<u64>) {
+        Ok(Ok(key)) => key,
+        _ => return false,
+    };
+    97 - (number % 97) == key
+}
+
+/// Validates a US SSN, rejecting never assigned area, group and serial numbers.
+fn is_valid_us_ssn(data: &[u8]) -> bool {
+    let digits = digits(data);
+    if digits.len() != 9 {
+        return false;
+    }
+
+    let area = digits[0] * 100 + digits[1] * 10 + digits[2];
+    let group = digits[3] * 10 + digits[4];
+    let serial = digits[5..].iter().fold(0, |acc, digit| acc * 10 + digit);
+    area != 0 && area != 666 && area < 900 && group != 0 && serial != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::CaviarMask;
+
+    /// Helper function masking `data` with all built-in detectors.
+    fn assert_scanned(data: &'static str, expected: &'static str) {
+        let strategy = ContentScanMask::new(&BUILTIN_DETECTORS, Box::new(CaviarShapeMask::new()));
+        let masked = strategy.mask(&Bytes::from_static(data.as_bytes()));
+        assert_eq!(
+            Bytes::from_static(expected.as_bytes()),
+            masked,
+            "masked data"
+        );
+    }
+
+    #[test]
+    fn valid_content_no_match() {
+        assert_scanned(
+            "Customer called at 10:30 about order 42.",
+            "Customer called at 10:30 about order 42.",
+        );
+    }
+
+    #[test]
+    fn valid_content_email() {
+        assert_scanned(
+            "Please reply to jane.doe@example.com asap",
+            "Please reply to ****.***@*******.*** asap",
+        );
+    }
+
+    #[test]
+    fn valid_content_iban_checksum() {
+        assert_scanned(
+            "IBAN FR76 3000 6000 0112 3456 7890 189 ok",
+            "IBAN **** **** **** **** **** **** *** ok",
+        );
+        // Invalid checksum, not an IBAN.
+        assert_scanned(
+            "IBAN FR76 3000 6000 0112 3456 7890 188 ok",
+            "IBAN FR76 3000 6000 0112 3456 7890 188 ok",
+        );
+    }
+
+    #[test]
+    fn valid_content_credit_card_luhn() {
+        assert_scanned("card: 4111-1111-1111-1111.", "card: ****-****-****-****.");
+        // Invalid Luhn checksum, not a card number.
+        assert_scanned("ref: 4111-1111-1111-1112.", "ref: 4111-1111-1111-1112.");
+    }
+
+    #[test]
+    fn valid_content_phone() {
+        assert_scanned("call +33 6 12 34 56 78 now", "call +** * ** ** ** ** now");
+        assert_scanned("call 06.12.34.56.78 now", "call **.**.**.**.** now");
+        assert_scanned("call (415) 555-2671 now", "call (***) ***-**** now");
+    }
+
+    #[test]
+    fn valid_content_ip_addresses() {
+        assert_scanned("from 192.168.1.20:5432", "from ***.***.*.**:5432");
+        assert_scanned("from 999.168.1.20 only", "from 999.168.1.20 only");
+        assert_scanned(
+            "from fe80::1ff:fe23:4567:890a!",
+            "from ****::***:****:****:****!",
+        );
+    }
+
+    #[test]
+    fn valid_content_national_ids() {
+        assert_scanned("NIR 1 84 12 76 451 089 46.", "NIR * ** ** ** *** *** **.");
+        assert_scanned("NIR 2 69 05 2A 123 456 18.", "NIR * ** ** ** *** *** **.");
+        assert_scanned("SSN 123-45-6789.", "SSN ***-**-****.");
+        assert_scanned("SSN 666-45-6789.", "SSN 666-45-6789.");
+    }
+
+    #[test]
+    fn valid_content_custom_pattern() {
+        let mut strategy = ContentScanMask::new(&[], Box::new(CaviarMask::new(3)));
+        strategy
+            .detectors
+            .push(Detector::custom("employee", r"EMP-[0-9]{6}").unwrap());
+
+        let masked = strategy.mask(&Bytes::from_static(b"by EMP-001234 and EMP-12"));
+        assert_eq!(
+            Bytes::from_static(b"by *** and EMP-12"),
+            masked,
+            "masked data"
+        );
+    }
+
+    #[test]
+    fn valid_content_from_config() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("masking.content.detectors", vec!["email"])
+            .unwrap()
+            .set_override("masking.content.replacement", "caviar")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let strategy = ContentScanMask::from_config(&config, "masking");
+        let masked = strategy.mask(&Bytes::from_static(b"a@b.io, 123-45-6789"));
+        assert_eq!(
+            Bytes::from_static(b"******, 123-45-6789"),
+            masked,
+            "masked data"
+        );
+    }
+}
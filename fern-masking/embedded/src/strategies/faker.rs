@@ -0,0 +1,369 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fake data substitution strategy, replacing values with realistic ones
+//! picked in embedded locale dictionaries.
+//!
+//! Substitution is consistent: fake values are derived from a keyed hash of
+//! original values, so that the same input always maps to the same output,
+//! across columns, queries, connections, and Fern instances sharing a key.
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use super::MaskingStrategy;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Reserved domains (RFC 2606), so that fake emails never reach anyone.
+const EMAIL_DOMAINS: [&str; 3] = ["example.com", "example.org", "example.net"];
+
+/// Word lists of a given locale, embedded at build time.
+#[derive(Debug)]
+struct Dictionary {
+    first_names: &'static str,
+    last_names: &'static str,
+    streets: &'static str,
+    cities: &'static str,
+    company_suffixes: &'static [&'static str],
+}
+
+const EN: Dictionary = Dictionary {
+    first_names: include_str!("../../dictionaries/en/first_names.txt"),
+    last_names: include_str!("../../dictionaries/en/last_names.txt"),
+    streets: include_str!("../../dictionaries/en/streets.txt"),
+    cities: include_str!("../../dictionaries/en/cities.txt"),
+    company_suffixes: &["Inc.", "LLC", "Group", "& Sons", "Ltd"],
+};
+
+const FR: Dictionary = Dictionary {
+    first_names: include_str!("../../dictionaries/fr/first_names.txt"),
+    last_names: include_str!("../../dictionaries/fr/last_names.txt"),
+    streets: include_str!("../../dictionaries/fr/streets.txt"),
+    cities: include_str!("../../dictionaries/fr/cities.txt"),
+    company_suffixes: &["SA", "SARL", "SAS", "et Fils", "Groupe"],
+};
+
+const DE: Dictionary = Dictionary {
+    first_names: include_str!("../../dictionaries/de/first_names.txt"),
+    last_names: include_str!("../../dictionaries/de/last_names.txt"),
+    streets: include_str!("../../dictionaries/de/streets.txt"),
+    cities: include_str!("../../dictionaries/de/cities.txt"),
+    company_suffixes: &["GmbH", "AG", "KG", "& Co.", "Gruppe"],
+};
+
+/// Supported locales of fake data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    Fr,
+    De,
+}
+
+impl Locale {
+    fn dictionary(self) -> &'static Dictionary {
+        match self {
+            Self::En => &EN,
+            Self::Fr => &FR,
+            Self::De => &DE,
+        }
+    }
+}
+
+/// Kinds of fake data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakeKind {
+    FirstName,
+    LastName,
+    FullName,
+    Email,
+    StreetAddress,
+    City,
+    Company,
+}
+
+impl FakeKind {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first-name" => Some(Self::FirstName),
+            "last-name" => Some(Self::LastName),
+            "full-name" => Some(Self::FullName),
+            "email" => Some(Self::Email),
+            "street-address" => Some(Self::StreetAddress),
+            "city" => Some(Self::City),
+            "company" => Some(Self::Company),
+            _ => None,
+        }
+    }
+}
+
+/// Deterministic source of choices, fed by a keyed hash of original data.
+struct Seed {
+    digest: [u8; 32],
+    offset: usize,
+}
+
+impl Seed {
+    /// Returns a number in `0..bound`, consuming 4 bytes of the digest.
+    fn next(&mut self, bound: usize) -> usize {
+        let idx = self.offset % 32;
+        let mut word = [0u8; 4];
+        for (i, byte) in word.iter_mut().enumerate() {
+            *byte = self.digest[(idx + i) % 32];
+        }
+        self.offset += 4;
+        u32::from_be_bytes(word) as usize % bound.max(1)
+    }
+
+    /// Picks a line from a dictionary word list.
+    fn pick(&mut self, words: &'static str) -> &'static str {
+        let lines: Vec<&str> = words.lines().filter(|line| !line.is_empty()).collect();
+        lines[self.next(lines.len())]
+    }
+}
+
+/// A strategy substituting data with consistent fake values of a given kind,
+/// such as names, emails, or addresses, in a given locale.
+///
+/// Fake values are seeded from an HMAC-SHA256 of original data. Without a
+/// configured key, a random key is generated, and consistency only holds
+/// for the lifetime of the process.
+pub struct FakerMask {
+    kind: FakeKind,
+    locale: Locale,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for FakerMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Key must never be leaked in logs.
+        f.debug_struct("FakerMask")
+            .field("kind", &self.kind)
+            .field("locale", &self.locale)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FakerMask {
+    pub fn new(kind: FakeKind, locale: Locale, key: &[u8]) -> Self {
+        Self {
+            kind,
+            locale,
+            key: key.to_vec(),
+        }
+    }
+
+    /// Creates a `FakerMask` from settings found in `config` under `key`:
+    /// - `faker.kind`: `first-name`, `last-name`, `full-name` (the default),
+    ///   `email`, `street-address`, `city`, or `company`,
+    /// - `faker.locale`: `en` (the default), `fr`, or `de`,
+    /// - `faker.key`: secret seeding fake values, random if undefined.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        let kind = match config.get::<String>(&format!("{}.faker.kind", key)) {
+            Ok(name) => FakeKind::from_name(&name).unwrap_or_else(|| {
+                log::warn!("unknown fake data kind '{}', using 'full-name'", name);
+                FakeKind::FullName
+            }),
+            Err(_) => FakeKind::FullName,
+        };
+
+        let locale = match config
+            .get::<String>(&format!("{}.faker.locale", key))
+            .as_deref()
+        {
+            Ok("fr") => Locale::Fr,
+            Ok("de") => Locale::De,
+            Ok("en") | Err(_) => Locale::En,
+            Ok(other) => {
+                log::warn!("unknown fake data locale '{}', using 'en'", other);
+                Locale::En
+            }
+        };
+
+        let secret = match config.get::<String>(&format!("{}.faker.key", key)) {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                log::warn!(
+                    "no key defined for '{}.faker', fake data will change on restart",
+                    key
+                );
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+
+        Self::new(kind, locale, &secret)
+    }
+
+    /// Derives a deterministic seed from `data`, scoped to the fake data kind.
+    fn seed(&self, data: &[u8]) -> Seed {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC can take key of any size");
+        mac.update(&[self.kind as u8]);
+        mac.update(data);
+
+        Seed {
+            digest: mac.finalize().into_bytes().into(),
+            offset: 0,
+        }
+    }
+
+    /// Generates a fake value, as a `String`.
+    fn fake(&self, data: &[u8]) -> String {
+        let dictionary = self.locale.dictionary();
+        let mut seed = self.seed(data);
+
+        match self.kind {
+            FakeKind::FirstName => seed.pick(dictionary.first_names).to_string(),
+            FakeKind::LastName => seed.pick(dictionary.last_names).to_string(),
+            FakeKind::FullName => {
+                let first_name = seed.pick(dictionary.first_names);
+                format!("{} {}", first_name, seed.pick(dictionary.last_names))
+            }
+            FakeKind::Email => {
+                let first_name = ascii_fold(seed.pick(dictionary.first_names));
+                let last_name = ascii_fold(seed.pick(dictionary.last_names));
+                let domain = EMAIL_DOMAINS[seed.next(EMAIL_DOMAINS.len())];
+                format!("{}.{}@{}", first_name, last_name, domain)
+            }
+            FakeKind::StreetAddress => {
+                let number = seed.next(199) + 1;
+                let street = seed.pick(dictionary.streets);
+                match self.locale {
+                    Locale::De => format!("{} {}", street, number),
+                    Locale::Fr => format!("{}, {}", number, street),
+                    Locale::En => format!("{} {}", number, street),
+                }
+            }
+            FakeKind::City => seed.pick(dictionary.cities).to_string(),
+            FakeKind::Company => {
+                let name = seed.pick(dictionary.last_names);
+                let suffixes = dictionary.company_suffixes;
+                format!("{} {}", name, suffixes[seed.next(suffixes.len())])
+            }
+        }
+    }
+}
+
+impl MaskingStrategy for FakerMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let res = self.fake(data);
+
+        log::trace!("rewritten value: {:?}", res);
+        Bytes::from(res)
+    }
+}
+
+/// Lowercases `word`, folding common diacritics to ASCII, and dropping
+/// any other non-alphanumeric character.
+fn ascii_fold(word: &str) -> String {
+    let mut res = String::with_capacity(word.len());
+    for c in word.to_lowercase().chars() {
+        match c {
+            'à' | 'â' | 'á' => res.push('a'),
+            'ä' => res.push_str("ae"),
+            'ç' => res.push('c'),
+            'é' | 'è' | 'ê' | 'ë' => res.push('e'),
+            'î' | 'ï' => res.push('i'),
+            'ô' => res.push('o'),
+            'ö' => res.push_str("oe"),
+            'ù' | 'û' => res.push('u'),
+            'ü' => res.push_str("ue"),
+            'ß' => res.push_str("ss"),
+            c if c.is_ascii_alphanumeric() => res.push(c),
+            _ => {}
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_faker_consistent() {
+        let strategy = FakerMask::new(FakeKind::FullName, Locale::En, b"secret");
+        let first = strategy.mask(&Bytes::from_static(b"Jane Doe"));
+        let again = strategy.mask(&Bytes::from_static(b"Jane Doe"));
+        assert_eq!(first, again, "same input, same fake value");
+
+        let other = FakerMask::new(FakeKind::FullName, Locale::En, b"secret");
+        assert_eq!(
+            first,
+            other.mask(&Bytes::from_static(b"Jane Doe")),
+            "same key"
+        );
+    }
+
+    #[test]
+    fn valid_faker_keyed() {
+        // Over a few values, different keys must yield different fake values.
+        let strategy = FakerMask::new(FakeKind::FullName, Locale::En, b"secret");
+        let other = FakerMask::new(FakeKind::FullName, Locale::En, b"another");
+        let differs = ["alice", "bob", "carol", "dave", "eve"].iter().any(|name| {
+            let data = Bytes::from_static(name.as_bytes());
+            strategy.mask(&data) != other.mask(&data)
+        });
+        assert!(differs, "keys must change fake values");
+    }
+
+    #[test]
+    fn valid_faker_from_dictionary() {
+        let strategy = FakerMask::new(FakeKind::City, Locale::Fr, b"secret");
+        let masked = strategy.mask(&Bytes::from_static(b"Paris"));
+        let city = std::str::from_utf8(&masked).unwrap();
+        assert!(FR.cities.lines().any(|line| line == city), "city: {}", city);
+    }
+
+    #[test]
+    fn valid_faker_email() {
+        let strategy = FakerMask::new(FakeKind::Email, Locale::De, b"secret");
+        for name in ["jane@corp.io", "john@corp.io", "jürgen@corp.io"] {
+            let masked = strategy.mask(&Bytes::from_static(name.as_bytes()));
+            let email = std::str::from_utf8(&masked).unwrap();
+            let (local, domain) = email.split_once('@').unwrap();
+            assert!(EMAIL_DOMAINS.contains(&domain), "domain: {}", domain);
+            assert!(
+                local.chars().all(|c| c.is_ascii_alphanumeric() || c == '.'),
+                "local part: {}",
+                local
+            );
+        }
+    }
+
+    #[test]
+    fn valid_faker_from_config() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("masking.faker.kind", "company")
+            .unwrap()
+            .set_override("masking.faker.locale", "de")
+            .unwrap()
+            .set_override("masking.faker.key", "secret")
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let strategy = FakerMask::from_config(&config, "masking");
+        assert_eq!(FakeKind::Company, strategy.kind);
+        assert_eq!(Locale::De, strategy.locale);
+        assert_eq!(b"secret".to_vec(), strategy.key);
+        assert!(
+            !format!("{:?}", strategy).contains("secret"),
+            "no key in logs"
+        );
+    }
+
+    #[test]
+    fn valid_ascii_fold() {
+        assert_eq!("francoise", ascii_fold("Françoise"));
+        assert_eq!("mueller", ascii_fold("Müller"));
+        assert_eq!("saintemarie", ascii_fold("Sainte-Marie"));
+    }
+}
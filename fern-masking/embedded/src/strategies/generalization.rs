@@ -0,0 +1,388 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generalization strategies, reducing the precision of data rather than
+//! hiding it, so that masked data remains useful for analytics.
+//!
+//! Values which cannot be generalized, because they do not have the expected
+//! shape, are masked with a fixed-length caviar strategy.
+
+use bytes::Bytes;
+
+use fern_protocol_postgresql::types;
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use super::{CaviarMask, MaskingStrategy};
+
+/// Masks `data` which cannot be generalized, failing closed.
+fn fallback(data: &Bytes) -> Bytes {
+    log::debug!("value cannot be generalized, using fallback");
+    CaviarMask::new(6).mask(data)
+}
+
+/// Returns the number of decimals in the textual representation of a number.
+pub(super) fn decimals(number: &str) -> usize {
+    number.split_once('.').map_or(0, |(_, decimals)| {
+        decimals.chars().take_while(char::is_ascii_digit).count()
+    })
+}
+
+/// Formats `value` with `decimals`, as an integer if `decimals` is zero.
+pub(super) fn format_number(value: f64, decimals: usize) -> Bytes {
+    if decimals == 0 {
+        Bytes::from((value.round() as i64).to_string())
+    } else {
+        Bytes::from(format!("{:.*}", decimals, value))
+    }
+}
+
+/// Unit to which dates and timestamps are truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateUnit {
+    Month,
+    Year,
+}
+
+/// A generalization strategy truncating dates and timestamps, in PostgreSQL
+/// ISO output format, to the first day of their month or year. Time of day
+/// is reset to midnight, while a time zone offset is preserved.
+#[derive(Debug)]
+pub struct DateTruncateMask {
+    unit: DateUnit,
+}
+
+impl DateTruncateMask {
+    pub const fn new(unit: DateUnit) -> Self {
+        Self { unit }
+    }
+
+    /// Creates a `DateTruncateMask` from `date.unit` setting found in `config`
+    /// under `key`, either `month` (the default), or `year`.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        match config
+            .get::<String>(&format!("{}.date.unit", key))
+            .as_deref()
+        {
+            Ok("year") => Self::new(DateUnit::Year),
+            _ => Self::new(DateUnit::Month),
+        }
+    }
+}
+
+impl MaskingStrategy for DateTruncateMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        // Without a known data type, the shape of data drives truncation.
+        self.mask_typed(data, types::TEXT)
+    }
+
+    fn mask_typed(&self, data: &Bytes, data_type_oid: u32) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return fallback(data),
+        };
+
+        // Expected shape is `YYYY-MM-DD`, possibly followed by a time part.
+        let bytes = text.as_bytes();
+        let is_date = bytes.len() >= 10
+            && bytes[..10].iter().enumerate().all(|(idx, c)| match idx {
+                4 | 7 => *c == b'-',
+                _ => c.is_ascii_digit(),
+            });
+        if !is_date {
+            return fallback(data);
+        }
+
+        let month = match self.unit {
+            DateUnit::Month => &text[5..7],
+            DateUnit::Year => "01",
+        };
+        let date = format!("{}-{}-01", &text[..4], month);
+
+        let time = &text[10..];
+        let res = if data_type_oid == types::DATE || time.is_empty() {
+            date
+        } else {
+            // Time zone offset, if any, follows the time of day.
+            let offset = time
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == '+' || *c == '-')
+                .map_or("", |(idx, _)| &time[idx..]);
+            format!("{} 00:00:00{}", date, offset)
+        };
+
+        log::trace!("rewritten value: {:?}", res);
+        Bytes::from(res)
+    }
+}
+
+/// A generalization strategy replacing numbers, such as ages, with the range
+/// of the bucket of `width` they belong to, e.g. `30-39` for `34`.
+#[derive(Debug)]
+pub struct AgeBucketMask {
+    width: u64,
+}
+
+impl AgeBucketMask {
+    /// Creates an `AgeBucketMask`, a zero `width` being considered as 1.
+    pub fn new(width: u64) -> Self {
+        Self {
+            width: width.max(1),
+        }
+    }
+
+    /// Creates an `AgeBucketMask` from `bucket.width` setting found in
+    /// `config` under `key`, 10 being the default.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        Self::new(
+            config
+                .get::<u64>(&format!("{}.bucket.width", key))
+                .unwrap_or(10),
+        )
+    }
+}
+
+impl MaskingStrategy for AgeBucketMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let value = match std::str::from_utf8(data).map(|text| text.trim().parse::<f64>()) {
+            Ok(Ok(value)) if value.is_finite() => value.floor() as i64,
+            _ => return fallback(data),
+        };
+
+        let width = self.width as i64;
+        let lower = value.div_euclid(width) * width;
+        let res = format!("{}-{}", lower, lower + width - 1);
+
+        log::trace!("rewritten value: {:?}", res);
+        Bytes::from(res)
+    }
+}
+
+/// A generalization strategy rounding numbers to the nearest multiple of `step`.
+#[derive(Debug)]
+pub struct RoundMask {
+    step: f64,
+
+    /// Decimals kept in results, as many as in `step`.
+    decimals: usize,
+}
+
+impl RoundMask {
+    /// Creates a `RoundMask`, a non-positive `step` being considered as 1.
+    pub fn new(step: f64) -> Self {
+        let step = if step > 0.0 && step.is_finite() {
+            step
+        } else {
+            1.0
+        };
+        Self {
+            step,
+            decimals: decimals(&step.to_string()),
+        }
+    }
+
+    /// Creates a `RoundMask` from `round.step` setting found in `config`
+    /// under `key`, 1 being the default.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        Self::new(
+            config
+                .get::<f64>(&format!("{}.round.step", key))
+                .unwrap_or(1.0),
+        )
+    }
+}
+
+impl MaskingStrategy for RoundMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        self.mask_typed(data, types::NUMERIC)
+    }
+
+    fn mask_typed(&self, data: &Bytes, data_type_oid: u32) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let value = match std::str::from_utf8(data).map(|text| text.trim().parse::<f64>()) {
+            Ok(Ok(value)) if value.is_finite() => value,
+            _ => return fallback(data),
+        };
+
+        let rounded = (value / self.step).round() * self.step;
+        let decimals = if types::is_integer(data_type_oid) {
+            0
+        } else {
+            self.decimals
+        };
+        let res = format_number(rounded, decimals);
+
+        log::trace!("rewritten value: {:?}", res);
+        res
+    }
+}
+
+/// A generalization strategy keeping only the first `length` characters of
+/// data, such as postcodes, with remaining characters replaced by `pad`,
+/// or removed if there is no `pad`.
+#[derive(Debug)]
+pub struct PrefixMask {
+    length: usize,
+    pad: Option<char>,
+}
+
+impl PrefixMask {
+    pub const fn new(length: usize, pad: Option<char>) -> Self {
+        Self { length, pad }
+    }
+
+    /// Creates a `PrefixMask` from settings found in `config` under `key`:
+    /// - `prefix.length`: characters kept, 2 by default,
+    /// - `prefix.pad`: replacement character, `*` by default, none if empty.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        let length = config
+            .get::<usize>(&format!("{}.prefix.length", key))
+            .unwrap_or(2);
+        let pad = match config.get::<String>(&format!("{}.prefix.pad", key)) {
+            Ok(pad) => pad.chars().next(),
+            Err(_) => Some('*'),
+        };
+        Self::new(length, pad)
+    }
+}
+
+impl MaskingStrategy for PrefixMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return fallback(data),
+        };
+
+        let mut res: String = text.chars().take(self.length).collect();
+        if let Some(pad) = self.pad {
+            let remaining = text.chars().count().saturating_sub(self.length);
+            res.extend(std::iter::repeat(pad).take(remaining));
+        }
+
+        log::trace!("rewritten value: {:?}", res);
+        Bytes::from(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_date_truncate_month() {
+        let strategy = DateTruncateMask::new(DateUnit::Month);
+
+        let masked = strategy.mask_typed(&Bytes::from_static(b"2022-09-24"), types::DATE);
+        assert_eq!(Bytes::from_static(b"2022-09-01"), masked, "date");
+
+        let data = Bytes::from_static(b"2022-09-24 13:45:12.123456");
+        let masked = strategy.mask_typed(&data, types::TIMESTAMP);
+        assert_eq!(
+            Bytes::from_static(b"2022-09-01 00:00:00"),
+            masked,
+            "timestamp"
+        );
+
+        let data = Bytes::from_static(b"2022-09-24 13:45:12+02");
+        let masked = strategy.mask_typed(&data, types::TIMESTAMPTZ);
+        assert_eq!(
+            Bytes::from_static(b"2022-09-01 00:00:00+02"),
+            masked,
+            "timestamptz"
+        );
+    }
+
+    #[test]
+    fn valid_date_truncate_year() {
+        let strategy = DateTruncateMask::new(DateUnit::Year);
+
+        let masked = strategy.mask(&Bytes::from_static(b"1984-12-31"));
+        assert_eq!(Bytes::from_static(b"1984-01-01"), masked, "date");
+
+        let masked = strategy.mask(&Bytes::from_static(b"1984-12-31 23:59:59-05:30"));
+        assert_eq!(Bytes::from_static(b"1984-01-01 00:00:00-05:30"), masked);
+    }
+
+    #[test]
+    fn invalid_date_truncate_fallback() {
+        let strategy = DateTruncateMask::new(DateUnit::Month);
+        let masked = strategy.mask(&Bytes::from_static(b"24/09/2022"));
+        assert_eq!(Bytes::from_static(b"******"), masked, "masked data");
+    }
+
+    #[test]
+    fn date_truncate_multibyte_suffix() {
+        let strategy = DateTruncateMask::new(DateUnit::Month);
+        let masked = strategy.mask(&Bytes::from("2024-01-15é+01".to_string()));
+        assert_eq!(Bytes::from_static(b"2024-01-01 00:00:00+01"), masked);
+        let masked = strategy.mask(&Bytes::from("2024-01-15é".to_string()));
+        assert_eq!(Bytes::from_static(b"2024-01-01 00:00:00"), masked);
+    }
+
+    #[test]
+    fn valid_age_bucket() {
+        let strategy = AgeBucketMask::new(10);
+        assert_eq!(
+            Bytes::from_static(b"30-39"),
+            strategy.mask(&Bytes::from_static(b"34"))
+        );
+        assert_eq!(
+            Bytes::from_static(b"40-49"),
+            strategy.mask(&Bytes::from_static(b"40"))
+        );
+        assert_eq!(
+            Bytes::from_static(b"0-9"),
+            strategy.mask(&Bytes::from_static(b"9.9"))
+        );
+        assert_eq!(
+            Bytes::from_static(b"-10--1"),
+            strategy.mask(&Bytes::from_static(b"-3"))
+        );
+        assert_eq!(
+            Bytes::from_static(b"******"),
+            strategy.mask(&Bytes::from_static(b"old"))
+        );
+    }
+
+    #[test]
+    fn valid_round() {
+        let strategy = RoundMask::new(1000.0);
+        let masked = strategy.mask_typed(&Bytes::from_static(b"52340"), types::INT4);
+        assert_eq!(Bytes::from_static(b"52000"), masked, "integer");
+
+        let strategy = RoundMask::new(0.5);
+        let masked = strategy.mask_typed(&Bytes::from_static(b"3.14159"), types::NUMERIC);
+        assert_eq!(Bytes::from_static(b"3.0"), masked, "numeric");
+
+        let masked = strategy.mask_typed(&Bytes::from_static(b"7"), types::INT8);
+        assert_eq!(
+            Bytes::from_static(b"7"),
+            masked,
+            "integer with decimal step"
+        );
+    }
+
+    #[test]
+    fn valid_prefix() {
+        let strategy = PrefixMask::new(2, Some('*'));
+        assert_eq!(
+            Bytes::from_static(b"75***"),
+            strategy.mask(&Bytes::from_static(b"75011"))
+        );
+        assert_eq!(
+            Bytes::from_static(b"S"),
+            strategy.mask(&Bytes::from_static(b"S"))
+        );
+
+        let strategy = PrefixMask::new(3, None);
+        let masked = strategy.mask(&Bytes::from_static("SW1A 2AA".as_bytes()));
+        assert_eq!(Bytes::from_static(b"SW1"), masked, "postcode");
+    }
+}
@@ -0,0 +1,388 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Masking of selected paths inside JSON documents, as stored in `json`
+//! and `jsonb` columns, leaving the rest of the document readable.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::Value;
+use std::collections::HashMap;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use super::{CaviarMask, MaskingStrategy};
+
+/// Version byte prefixing `jsonb` values in binary format.
+const JSONB_BINARY_VERSION: u8 = 1;
+
+/// A segment of a JSONPath expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// Member of an object, as in `$.name` or `$['name']`.
+    Key(String),
+
+    /// Element of an array, as in `$[0]`.
+    Index(usize),
+
+    /// All members of an object, or elements of an array, as in `$.*` or `$[*]`.
+    Wildcard,
+
+    /// Member of an object at any depth, as in `$..name`.
+    Descendant(String),
+}
+
+/// Parses a JSONPath expression, supporting the subset needed to select
+/// values to mask: `$`, `.name`, `['name']`, `[0]`, `.*`, `[*]` and `..name`.
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let mut rest = path
+        .trim()
+        .strip_prefix('$')
+        .ok_or_else(|| format!("path '{}' must start with '$'", path))?;
+
+    let mut segments = vec![];
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            let (name, tail) = split_name(tail);
+            if name.is_empty() {
+                return Err(format!("path '{}' has an empty descendant name", path));
+            }
+            segments.push(Segment::Descendant(name.to_string()));
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix(".*") {
+            segments.push(Segment::Wildcard);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('.') {
+            let (name, tail) = split_name(tail);
+            if name.is_empty() {
+                return Err(format!("path '{}' has an empty member name", path));
+            }
+            segments.push(Segment::Key(name.to_string()));
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail
+                .find(']')
+                .ok_or_else(|| format!("path '{}' has an unclosed bracket", path))?;
+            let selector = tail[..end].trim();
+            let segment = if selector == "*" {
+                Segment::Wildcard
+            } else if let Ok(index) = selector.parse::<usize>() {
+                Segment::Index(index)
+            } else if selector.len() >= 2
+                && (selector.starts_with('\'') && selector.ends_with('\'')
+                    || selector.starts_with('"') && selector.ends_with('"'))
+            {
+                Segment::Key(selector[1..selector.len() - 1].to_string())
+            } else {
+                return Err(format!("path '{}' has an invalid selector", path));
+            };
+            segments.push(segment);
+            rest = &tail[end + 1..];
+        } else {
+            return Err(format!("path '{}' is invalid near '{}'", path, rest));
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Splits a member name from the rest of a JSONPath expression.
+fn split_name(path: &str) -> (&str, &str) {
+    let end = path.find(['.', '[']).unwrap_or(path.len());
+    path.split_at(end)
+}
+
+/// Calls `action` on all values of `value` selected by `segments`.
+fn select(value: &mut Value, segments: &[Segment], action: &mut dyn FnMut(&mut Value)) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return action(value),
+    };
+
+    match (segment, value) {
+        (Segment::Key(name), Value::Object(members)) => {
+            if let Some(member) = members.get_mut(name) {
+                select(member, rest, action);
+            }
+        }
+        (Segment::Index(index), Value::Array(elements)) => {
+            if let Some(element) = elements.get_mut(*index) {
+                select(element, rest, action);
+            }
+        }
+        (Segment::Wildcard, Value::Object(members)) => {
+            for member in members.values_mut() {
+                select(member, rest, action);
+            }
+        }
+        (Segment::Wildcard, Value::Array(elements)) => {
+            for element in elements.iter_mut() {
+                select(element, rest, action);
+            }
+        }
+        (Segment::Descendant(name), Value::Object(members)) => {
+            for (member_name, member) in members.iter_mut() {
+                if member_name == name {
+                    select(member, rest, action);
+                } else {
+                    select(member, segments, action);
+                }
+            }
+        }
+        (Segment::Descendant(_), Value::Array(elements)) => {
+            for element in elements.iter_mut() {
+                select(element, segments, action);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rewriting applied to values selected by a JSONPath expression.
+#[derive(Debug)]
+enum PathAction {
+    /// Selected values are replaced by a JSON `null`.
+    Null,
+
+    /// Selected values are masked with a strategy. Containers have all their
+    /// scalar values masked, preserving the document structure.
+    Mask(Box<dyn MaskingStrategy>),
+}
+
+impl PathAction {
+    /// Applies this action to `value`.
+    fn apply(&self, value: &mut Value) {
+        let strategy = match self {
+            Self::Null => {
+                *value = Value::Null;
+                return;
+            }
+            Self::Mask(strategy) => strategy,
+        };
+
+        match value {
+            // Note: masking a `null` would reveal nullness anyway.
+            Value::Null => {}
+            Value::String(text) => {
+                let masked = strategy.mask(&Bytes::from(std::mem::take(text)));
+                *value = Value::String(String::from_utf8_lossy(&masked).into_owned());
+            }
+            // Note: masked numbers and booleans may not be valid anymore, hence strings.
+            Value::Bool(_) | Value::Number(_) => {
+                let masked = strategy.mask(&Bytes::from(value.to_string()));
+                *value = Value::String(String::from_utf8_lossy(&masked).into_owned());
+            }
+            Value::Array(elements) => elements.iter_mut().for_each(|element| self.apply(element)),
+            Value::Object(members) => members.values_mut().for_each(|member| self.apply(member)),
+        }
+    }
+}
+
+/// A masking strategy for JSON documents, where only values selected by
+/// JSONPath expressions are masked, each with its own strategy, or replaced
+/// by a JSON `null`. The document is then serialized back.
+///
+/// Values not being valid JSON are masked with a fixed-length caviar strategy.
+#[derive(Debug)]
+pub struct JsonPathMask {
+    rules: Vec<(Vec<Segment>, PathAction)>,
+
+    /// Masking strategy applied to values not being valid JSON.
+    fallback: Box<dyn MaskingStrategy>,
+}
+
+impl JsonPathMask {
+    /// Creates a `JsonPathMask` without any rule, leaving documents as is.
+    pub fn new() -> Self {
+        Self {
+            rules: vec![],
+            fallback: Box::new(CaviarMask::new(6)),
+        }
+    }
+
+    /// Adds a rule replacing values selected by `path` with a JSON `null`.
+    pub fn with_null(mut self, path: &str) -> Result<Self, String> {
+        self.rules.push((parse_path(path)?, PathAction::Null));
+        Ok(self)
+    }
+
+    /// Adds a rule masking values selected by `path` with `strategy`.
+    pub fn with_mask(
+        mut self,
+        path: &str,
+        strategy: Box<dyn MaskingStrategy>,
+    ) -> Result<Self, String> {
+        self.rules
+            .push((parse_path(path)?, PathAction::Mask(strategy)));
+        Ok(self)
+    }
+
+    /// Creates a `JsonPathMask` from settings found in `config` under `key`:
+    /// - `json.paths`: list of `{ path, strategy }` tables, where `strategy`
+    ///   is either the name of a masking strategy, or `null`.
+    ///
+    /// Strategies get their own settings from `config` under `key` as well.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        let mut mask = Self::new();
+
+        let paths = config
+            .get::<Vec<HashMap<String, String>>>(&format!("{}.json.paths", key))
+            .unwrap_or_default();
+        for rule in paths.iter() {
+            let (path, strategy) = match (rule.get("path"), rule.get("strategy")) {
+                (Some(path), Some(strategy)) => (path, strategy.as_str()),
+                _ => {
+                    log::error!("json path rule without 'path' or 'strategy', ignoring");
+                    continue;
+                }
+            };
+            let added = match strategy {
+                "null" => mask.with_null(path),
+                // Nesting JSON path masking makes no sense, prevent it.
+                "json-path" => mask.with_mask(path, Box::new(CaviarMask::new(6))),
+                name => mask.with_mask(path, super::build(name, config, key)),
+            };
+            mask = match added {
+                Ok(mask) => mask,
+                Err(err) => {
+                    // Fail closed: an invalid path cannot be trusted to select anything.
+                    log::error!("invalid json path rule, masking whole documents: {}", err);
+                    return Self::new()
+                        .with_mask("$", Box::new(CaviarMask::new(6)))
+                        .unwrap();
+                }
+            };
+        }
+
+        mask
+    }
+}
+
+impl Default for JsonPathMask {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MaskingStrategy for JsonPathMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        // `jsonb` values in binary format are prefixed with a version byte.
+        let (prefix, text) = match data.first() {
+            Some(&JSONB_BINARY_VERSION) => (Some(JSONB_BINARY_VERSION), &data[1..]),
+            _ => (None, &data[..]),
+        };
+
+        let mut document = match serde_json::from_slice::<Value>(text) {
+            Ok(document) => document,
+            Err(err) => {
+                log::debug!("not a valid json document, using fallback: {}", err);
+                return self.fallback.mask(data);
+            }
+        };
+
+        for (path, action) in self.rules.iter() {
+            select(&mut document, path, &mut |value| action.apply(value));
+        }
+
+        let mut res = BytesMut::with_capacity(data.len());
+        if let Some(version) = prefix {
+            res.put_u8(version);
+        }
+        // Serializing a `Value` cannot fail, all keys being strings.
+        res.put(&serde_json::to_vec(&document).expect("json serialization")[..]);
+
+        log::trace!("rewritten value: {:?}", res);
+        res.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::CaviarShapeMask;
+
+    const PROFILE: &[u8] = br#"{"name":"Jane","contact":{"email":"jane@example.com","phones":["+33 6 12 34 56 78","555-0100"]},"ssn":"123-45-6789","age":42}"#;
+
+    #[test]
+    fn valid_parse_path() {
+        let expected = vec![
+            Segment::Key("contact".to_string()),
+            Segment::Key("phones".to_string()),
+            Segment::Index(0),
+            Segment::Wildcard,
+            Segment::Descendant("email".to_string()),
+            Segment::Key("a b".to_string()),
+        ];
+        let parsed = parse_path("$.contact.phones[0][*]..email['a b']").unwrap();
+        assert_eq!(expected, parsed, "parsed path");
+    }
+
+    #[test]
+    fn invalid_parse_path() {
+        assert!(parse_path("contact.email").is_err(), "missing root");
+        assert!(parse_path("$.contact[0").is_err(), "unclosed bracket");
+        assert!(parse_path("$.").is_err(), "empty member name");
+        assert!(parse_path("$[abc]").is_err(), "invalid selector");
+    }
+
+    #[test]
+    fn valid_json_path_mask_and_null() {
+        let strategy = JsonPathMask::new()
+            .with_mask("$.contact.email", Box::new(CaviarShapeMask::new()))
+            .unwrap()
+            .with_null("$.ssn")
+            .unwrap();
+
+        let masked = strategy.mask(&Bytes::from_static(PROFILE));
+        let expected = br#"{"name":"Jane","contact":{"email":"****@*******.***","phones":["+33 6 12 34 56 78","555-0100"]},"ssn":null,"age":42}"#;
+        assert_eq!(Bytes::from_static(expected), masked, "masked data");
+    }
+
+    #[test]
+    fn valid_json_path_wildcard_descendant_and_numbers() {
+        let strategy = JsonPathMask::new()
+            .with_mask("$.contact.phones[*]", Box::new(CaviarMask::new(3)))
+            .unwrap()
+            .with_mask("$..age", Box::new(CaviarShapeMask::new()))
+            .unwrap();
+
+        let masked = strategy.mask(&Bytes::from_static(PROFILE));
+        let expected = br#"{"name":"Jane","contact":{"email":"jane@example.com","phones":["***","***"]},"ssn":"123-45-6789","age":"**"}"#;
+        assert_eq!(Bytes::from_static(expected), masked, "masked data");
+    }
+
+    #[test]
+    fn valid_json_path_jsonb_binary_and_invalid_documents() {
+        let strategy = JsonPathMask::new().with_null("$.a").unwrap();
+
+        let masked = strategy.mask(&Bytes::from_static(b"\x01{\"a\":1,\"b\":2}"));
+        assert_eq!(Bytes::from_static(b"\x01{\"a\":null,\"b\":2}"), masked);
+
+        let masked = strategy.mask(&Bytes::from_static(b"{not json"));
+        assert_eq!(Bytes::from_static(b"******"), masked, "fallback masking");
+    }
+
+    #[test]
+    fn valid_json_path_from_config() {
+        let settings = r#"
+            [masking.columns.profile]
+            strategy = 'json-path'
+            json.paths = [
+                { path = '$.contact.email', strategy = 'content-scan' },
+                { path = '$.ssn', strategy = 'null' },
+            ]
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(settings, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+
+        let strategy = JsonPathMask::from_config(&config, "masking.columns.profile");
+        let masked = strategy.mask(&Bytes::from_static(
+            br#"{"contact":{"email":"Mail: a@b.io"},"ssn":"1"}"#,
+        ));
+        let expected = br#"{"contact":{"email":"Mail: *@*.**"},"ssn":null}"#;
+        assert_eq!(Bytes::from_static(expected), masked, "masked data");
+    }
+}
@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Noise strategy, perturbing numbers with bounded random noise, so that
+//! aggregates over masked data remain statistically useful.
+
+use bytes::Bytes;
+use rand::Rng;
+
+use fern_protocol_postgresql::types;
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use super::generalization::{decimals, format_number};
+use super::{CaviarMask, MaskingStrategy};
+
+/// Probability distribution of the noise added to values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    /// Laplace distribution centered on zero, with scale `b`, as used
+    /// by the Laplace mechanism of differential privacy.
+    Laplace,
+
+    /// Uniform distribution in `[-scale, scale]`.
+    Uniform,
+}
+
+/// A strategy adding random noise to numbers. Integer columns get
+/// integer results, other columns keep the precision of their values.
+///
+/// For the Laplace distribution, the scale can be derived from a privacy
+/// budget `epsilon` and the `sensitivity` of the data, as `sensitivity / epsilon`.
+#[derive(Debug)]
+pub struct NoiseMask {
+    distribution: Distribution,
+    scale: f64,
+}
+
+impl NoiseMask {
+    /// Creates a `NoiseMask`, a negative `scale` being considered as zero.
+    pub fn new(distribution: Distribution, scale: f64) -> Self {
+        Self {
+            distribution,
+            scale: scale.max(0.0),
+        }
+    }
+
+    /// Creates a `NoiseMask` from settings found in `config` under `key`:
+    /// - `noise.distribution`: `laplace` (the default), or `uniform`,
+    /// - `noise.scale`: scale of the distribution, 1 by default,
+    /// - `noise.epsilon` and `noise.sensitivity` (1 by default): used
+    ///   to derive scale, unless `noise.scale` is defined.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        let distribution = match config
+            .get::<String>(&format!("{}.noise.distribution", key))
+            .as_deref()
+        {
+            Ok("uniform") => Distribution::Uniform,
+            _ => Distribution::Laplace,
+        };
+
+        let scale = match config.get::<f64>(&format!("{}.noise.scale", key)) {
+            Ok(scale) => scale,
+            Err(_) => match config.get::<f64>(&format!("{}.noise.epsilon", key)) {
+                Ok(epsilon) if epsilon > 0.0 => {
+                    let sensitivity = config
+                        .get::<f64>(&format!("{}.noise.sensitivity", key))
+                        .unwrap_or(1.0);
+                    sensitivity / epsilon
+                }
+                _ => 1.0,
+            },
+        };
+
+        Self::new(distribution, scale)
+    }
+
+    /// Draws a noise value from the configured distribution.
+    fn sample(&self) -> f64 {
+        let mut rng = rand::thread_rng();
+        match self.distribution {
+            Distribution::Uniform => {
+                if self.scale == 0.0 {
+                    return 0.0;
+                }
+                rng.gen_range(-self.scale..=self.scale)
+            }
+            Distribution::Laplace => {
+                // Inverse transform sampling, with `u` in `(-0.5, 0.5)`: `-0.5`
+                // is drawn again, as it would result in `ln(0)`.
+                let mut u: f64 = rng.gen_range(-0.5..0.5);
+                while u == -0.5 {
+                    u = rng.gen_range(-0.5..0.5);
+                }
+                -self.scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+            }
+        }
+    }
+}
+
+impl MaskingStrategy for NoiseMask {
+    fn mask(&self, data: &Bytes) -> Bytes {
+        self.mask_typed(data, types::NUMERIC)
+    }
+
+    fn mask_typed(&self, data: &Bytes, data_type_oid: u32) -> Bytes {
+        log::trace!(" original value: {:?}", data);
+
+        let text = std::str::from_utf8(data).unwrap_or_default().trim();
+        let value = match text.parse::<f64>() {
+            Ok(value) if value.is_finite() => value,
+            _ => {
+                log::debug!("value is not a number, using fallback");
+                return CaviarMask::new(6).mask(data);
+            }
+        };
+
+        let decimals = if types::is_integer(data_type_oid) {
+            0
+        } else {
+            decimals(text)
+        };
+        let res = format_number(value + self.sample(), decimals);
+
+        log::trace!("rewritten value: {:?}", res);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function parsing masked data as a number.
+    fn noisy(strategy: &NoiseMask, data: &'static [u8], data_type_oid: u32) -> f64 {
+        let masked = strategy.mask_typed(&Bytes::from_static(data), data_type_oid);
+        std::str::from_utf8(&masked).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn valid_noise_uniform_bounded() {
+        let strategy = NoiseMask::new(Distribution::Uniform, 5.0);
+        for _ in 0..1000 {
+            let value = noisy(&strategy, b"100", types::INT4);
+            assert!((95.0..=105.0).contains(&value), "bounded noise: {}", value);
+            assert_eq!(value, value.round(), "integer result");
+        }
+    }
+
+    #[test]
+    fn valid_noise_laplace_scale() {
+        // Mean absolute deviation of a Laplace distribution is its scale.
+        let strategy = NoiseMask::new(Distribution::Laplace, 2.0);
+        let samples = 20_000;
+        let deviation: f64 = (0..samples)
+            .map(|_| (noisy(&strategy, b"50.00", types::NUMERIC) - 50.0).abs())
+            .sum::<f64>()
+            / samples as f64;
+        assert!((1.8..2.2).contains(&deviation), "deviation: {}", deviation);
+    }
+
+    #[test]
+    fn valid_noise_keeps_precision() {
+        let strategy = NoiseMask::new(Distribution::Uniform, 0.0);
+        let masked = strategy.mask_typed(&Bytes::from_static(b"12.340"), types::NUMERIC);
+        assert_eq!(Bytes::from_static(b"12.340"), masked, "masked data");
+    }
+
+    #[test]
+    fn valid_noise_epsilon_from_config() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("masking.noise.epsilon", 0.5)
+            .unwrap()
+            .set_override("masking.noise.sensitivity", 2.0)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let strategy = NoiseMask::from_config(&config, "masking");
+        assert_eq!(Distribution::Laplace, strategy.distribution);
+        assert_eq!(4.0, strategy.scale, "scale");
+    }
+
+    #[test]
+    fn invalid_noise_fallback() {
+        let strategy = NoiseMask::new(Distribution::Laplace, 1.0);
+        let masked = strategy.mask(&Bytes::from_static(b"n/a"));
+        assert_eq!(Bytes::from_static(b"******"), masked, "masked data");
+    }
+}
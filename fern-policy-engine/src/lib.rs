@@ -1,8 +1,432 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
+//! Unified declarative policy language for Fern proxy.
+//!
+//! A single policy document, in the `policy` table of `SQLHandlerConfig`,
+//! describes:
+//! - subjects: sessions of users or roles, from network ranges, or of
+//!   applications (see [`Context`]),
+//! - resources: patterns of `database.schema.table.column`, with tags,
+//! - rules: actions of subjects on resources, either `allow`, `deny`,
+//!   `mask` with a strategy, `tokenize`, `encrypt`, or `audit`.
+//!
+//! ```toml
+//! [policy.subjects.analysts]
+//! roles = ["analyst"]
+//! networks = ["10.0.0.0/8"]
+//!
+//! [policy.resources]
+//! "*.public.users.email" = ["pii"]
+//!
+//! [[policy.rules]]
+//! subjects = ["analysts"]
+//! resources = ["tag:pii"]
+//! action = "mask"
+//! strategy = "caviar"
+//! ```
+//!
+//! The document is compiled once into a [`Policy`], shared by all sessions
+//! along with the candidate one as [`Policies`], and each session gets
+//! [`Decisions`] on rules applying to its subjects only, with verdicts
+//! cached. A [`SessionHandler`] starts decisions of sessions, shared with
+//! handlers applying them through a [`Session`].
+//!
+//! A candidate document may be defined in the `policy.shadow` table, with
+//! the same structure, to know what it would do before enforcing it: its
+//! decisions are evaluated along active ones, and divergences, e.g. accesses
+//! it would deny or columns it would mask, are logged with target
+//! `fern::shadow` and counted, while only active decisions are applied.
+
+use async_trait::async_trait;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use fern_protocol_postgresql::codec::frontend;
+use fern_proxy_interfaces::SQLMessageHandler;
+
+// Re-export.
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use policy::{Action, Decisions, Policy, Verdict};
+pub use resources::{glob, Resource};
+pub use subjects::Context;
+
+mod policy;
+mod resources;
+mod subjects;
+
+/// Returns whether a policy with rules is defined in `config`.
+pub fn is_enabled(config: &SQLHandlerConfig) -> bool {
+    config
+        .get_array("policy.rules")
+        .map_or(false, |rules| !rules.is_empty())
+}
+
+/// Returns whether a candidate policy with rules is defined in `config`.
+pub fn is_shadowed(config: &SQLHandlerConfig) -> bool {
+    config
+        .get_array("policy.shadow.rules")
+        .map_or(false, |rules| !rules.is_empty())
+}
+
+/// Active and candidate policy documents, compiled once and shared by
+/// handlers of all sessions.
+///
+/// Should the active policy be invalid, any access is denied, while an
+/// invalid candidate policy is ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    /// Active policy, if defined.
+    pub active: Option<Policy>,
+
+    /// Candidate policy, evaluated without being enforced.
+    pub shadow: Option<Policy>,
+}
+
+impl Policies {
+    /// Compiles policy documents defined in `config`.
+    pub fn from_config(config: &SQLHandlerConfig) -> Self {
+        let active = Policy::from_config(config).unwrap_or_else(|err| {
+            log::error!("denying all accesses - {}", err);
+            Some(Policy::default())
+        });
+        let shadow = Policy::shadow_from_config(config)
+            .map_err(|err| log::error!("ignoring candidate policy - {}", err))
+            .ok()
+            .flatten();
+        Self { active, shadow }
+    }
+}
+
+/// State of a session.
+#[derive(Debug, Default)]
+struct State {
+    /// Decisions of the active policy, once started.
+    decisions: Option<Decisions>,
+
+    /// Decisions of the candidate policy, if any.
+    shadow: Option<Decisions>,
+
+    /// Last statement received, for divergences to be logged with.
+    statement: Option<String>,
+}
+
+impl State {
+    /// Logs a divergence of the candidate policy, described by `what`.
+    fn diverge(&self, what: fmt::Arguments<'_>) {
+        let context = self.decisions.as_ref().map(Decisions::context);
+        log::info!(
+            target: "fern::shadow",
+            "candidate policy {} for {}, statement: {}",
+            what,
+            context.map_or_else(|| "?".to_string(), Context::to_string),
+            self.statement.as_deref().unwrap_or("?")
+        );
+    }
+}
+
+/// Decisions of a session, shared by handlers of both directions.
+///
+/// Cloning a `Session` gives access to the same decisions.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    state: Arc<Mutex<State>>,
+
+    /// Count of divergences of the candidate policy.
+    divergences: Arc<AtomicU64>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks the state, regardless of poisoning as it is always consistent.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts the session with `decisions`, and `shadow` ones of a candidate
+    /// policy, if any.
+    pub fn start(&self, decisions: Decisions, shadow: Option<Decisions>) {
+        let mut state = self.state();
+        state.decisions = Some(decisions);
+        state.shadow = shadow;
+    }
+
+    /// Records `statement` as the last one received.
+    fn set_statement(&self, statement: String) {
+        self.state().statement = Some(statement);
+    }
+
+    /// Returns the context of the session, once started.
+    pub fn context(&self) -> Option<Context> {
+        self.state()
+            .decisions
+            .as_ref()
+            .map(|decisions| decisions.context().clone())
+    }
+
+    /// Returns the count of divergences of the candidate policy so far.
+    pub fn divergences(&self) -> u64 {
+        self.divergences.load(Ordering::Relaxed)
+    }
+
+    /// Returns the verdict on `operation` on `resource`, denied until the
+    /// session is started.
+    pub fn authorize(&self, operation: &str, resource: &Resource) -> Verdict {
+        let mut state = self.state();
+        let verdict = match state.decisions.as_mut() {
+            Some(decisions) => decisions.authorize(operation, resource),
+            None => return Verdict::default(),
+        };
+        let shadow = state
+            .shadow
+            .as_mut()
+            .map(|shadow| shadow.authorize(operation, resource));
+        if let Some(shadow) = shadow.filter(|shadow| shadow.allowed != verdict.allowed) {
+            self.divergences.fetch_add(1, Ordering::Relaxed);
+            let effect = if shadow.allowed { "allow" } else { "deny" };
+            state.diverge(format_args!(
+                "would {} {} on {}",
+                effect, operation, resource
+            ));
+        }
+        verdict
+    }
+
+    /// Returns the index of the first rule masking `resource`, if any.
+    pub fn mask(&self, resource: &Resource) -> Option<usize> {
+        let state = self.state();
+        let rule = state
+            .decisions
+            .as_ref()
+            .and_then(|decisions| decisions.mask(resource));
+        let shadow = state.shadow.as_ref().map(|shadow| shadow.mask(resource));
+        if let Some(shadow) = shadow.filter(|shadow| shadow.is_some() != rule.is_some()) {
+            self.divergences.fetch_add(1, Ordering::Relaxed);
+            let effect = if shadow.is_some() { "mask" } else { "not mask" };
+            state.diverge(format_args!("would {} column {}", effect, resource));
+        }
+        rule
+    }
+
+    /// Returns whether a statement whose accesses are unknown, e.g. as it
+    /// cannot be analyzed for `reason`, is denied: it is by any policy which
+    /// may deny or mask anything, and until the session is started.
+    pub fn deny_unverifiable(&self, reason: &str) -> bool {
+        let state = self.state();
+        let enforcing = state
+            .decisions
+            .as_ref()
+            .map_or(true, Decisions::is_enforcing);
+        let shadow = state.shadow.as_ref().map(Decisions::is_enforcing);
+        if shadow.map_or(false, |shadow| shadow != enforcing) {
+            self.divergences.fetch_add(1, Ordering::Relaxed);
+            let effect = if enforcing { "allow" } else { "deny" };
+            state.diverge(format_args!("would {} statement, {}", effect, reason));
+        }
+        enforcing
+    }
+}
+
+/// An `SQLMessageHandler` starting decisions of the session, once its
+/// `StartupMessage` known.
+///
+/// Should the policy be invalid, any access is denied, while an invalid
+/// candidate policy is ignored.
+#[derive(Debug)]
+pub struct SessionHandler {
+    policy: Policy,
+
+    /// Candidate policy, evaluated without being enforced.
+    shadow: Option<Policy>,
+
+    /// Address of the client, if known.
+    address: Option<SocketAddr>,
+
+    /// Session, shared with handlers applying decisions.
+    session: Session,
+}
+
+impl SessionHandler {
+    /// Creates a handler starting decisions of already compiled `policies`,
+    /// where a missing active policy denies any access.
+    pub fn from_policies(policies: &Policies) -> Self {
+        Self {
+            policy: policies.active.clone().unwrap_or_default(),
+            shadow: policies.shadow.clone(),
+            address: None,
+            session: Session::new(),
+        }
+    }
+
+    /// Describes the session as coming from `address`.
+    #[must_use]
+    pub fn with_client_address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Returns the session, to be shared with handlers applying decisions.
+    pub fn session(&self) -> Session {
+        self.session.clone()
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for SessionHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_policies(&Policies::from_config(config))
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::Query(ref query) | frontend::Message::Parse { ref query, .. }
+                if self.shadow.is_some() =>
+            {
+                self.session
+                    .set_statement(String::from_utf8_lossy(query).into_owned());
+            }
+            frontend::Message::Terminate() if self.shadow.is_some() => {
+                log::info!(
+                    target: "fern::shadow",
+                    "{} divergences of candidate policy in session",
+                    self.session.divergences()
+                );
+            }
+            _ => (),
+        }
+
+        if let frontend::Message::StartupMessage { ref parameters, .. } = msg {
+            let parameter = |name: &str| {
+                parameters
+                    .iter()
+                    .find(|parameter| parameter.name == name)
+                    .map(|parameter| String::from_utf8_lossy(&parameter.value).into_owned())
+            };
+            let user = parameter("user");
+            let context = Context {
+                // The database defaults to the user name.
+                database: parameter("database").or_else(|| user.clone()),
+                user,
+                application: parameter("application_name"),
+                address: self.address.map(|address| address.ip()),
+            };
+            let shadow = self
+                .shadow
+                .as_ref()
+                .map(|shadow| shadow.decisions(context.clone()));
+            self.session.start(self.policy.decisions(context), shadow);
+        }
+        Some(msg)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn it_works() {}
+    use bytes::Bytes;
+    use config::{File, FileFormat};
+    use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+
+    use super::{Context, Resource, SQLHandlerConfig, SessionHandler};
+
+    #[tokio::test]
+    async fn started_session() {
+        let document = r#"
+            [policy]
+            default = "deny"
+            [policy.subjects.office]
+            networks = ["10.0.0.0/8"]
+            [[policy.rules]]
+            subjects = ["office"]
+            resources = ["shop"]
+            action = "allow"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(File::from_str(document, FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(super::is_enabled(&config));
+        let mut handler =
+            SessionHandler::new(&config).with_client_address("10.0.0.7:51234".parse().unwrap());
+        let session = handler.session();
+        let resource = Resource::column("email");
+        assert!(
+            !session.authorize("SELECT", &resource).allowed,
+            "not started"
+        );
+
+        let parameter = |name: &'static str, value: &'static str| Parameter {
+            name: Bytes::from_static(name.as_bytes()),
+            value: Bytes::from_static(value.as_bytes()),
+        };
+        let msg = frontend::Message::StartupMessage {
+            frame_length: 0,
+            parameters: vec![
+                parameter("user", "shop"),
+                parameter("application_name", "psql"),
+            ],
+        };
+        handler.process(msg).await;
+        let expected = Context {
+            user: Some("shop".to_string()),
+            database: Some("shop".to_string()),
+            application: Some("psql".to_string()),
+            address: Some("10.0.0.7".parse().unwrap()),
+        };
+        assert_eq!(Some(expected), session.context());
+        assert!(session.authorize("SELECT", &resource).allowed);
+    }
+
+    #[tokio::test]
+    async fn shadowed_session() {
+        let document = r#"
+            [[policy.rules]]
+            resources = ["*.*.users.ssn"]
+            action = "mask"
+            [[policy.shadow.rules]]
+            resources = ["*.*.users.ssn"]
+            action = "deny"
+            [[policy.shadow.rules]]
+            resources = ["*.*.users.email"]
+            action = "mask"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(File::from_str(document, FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(super::is_shadowed(&config));
+        let mut handler = SessionHandler::new(&config);
+        let session = handler.session();
+        let msg = frontend::Message::StartupMessage {
+            frame_length: 0,
+            parameters: vec![Parameter {
+                name: Bytes::from_static(b"user"),
+                value: Bytes::from_static(b"alice"),
+            }],
+        };
+        handler.process(msg).await;
+        let msg = frontend::Message::Query(Bytes::from_static(b"SELECT ssn FROM users"));
+        handler.process(msg).await;
+
+        // Active decisions only are applied, divergences are counted.
+        let ssn = Resource {
+            table: Some("users".to_string()),
+            column: Some("ssn".to_string()),
+            ..Resource::default()
+        };
+        assert!(session.authorize("SELECT", &ssn).allowed);
+        assert_eq!(1, session.divergences());
+        assert!(session.mask(&Resource::column("ssn")).is_some());
+        assert_eq!(2, session.divergences(), "not masked by candidate");
+        assert!(session.mask(&Resource::column("email")).is_none());
+        assert_eq!(3, session.divergences(), "masked by candidate");
+        assert!(session.mask(&Resource::column("id")).is_none());
+        assert!(session.deny_unverifiable("cannot be analyzed"));
+        assert_eq!(3, session.divergences());
+    }
 }
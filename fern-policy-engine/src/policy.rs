@@ -0,0 +1,613 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Policy documents, compiled into decisions of sessions.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+use crate::resources::{Pattern, Resource};
+use crate::subjects::{Context, Subject};
+
+/// Maximum amount of verdicts cached by session.
+const MAX_CACHED_VERDICTS: usize = 1024;
+
+/// Prefix of tags in resources of rules.
+const TAG_PREFIX: &str = "tag:";
+
+/// Returns an `io::Error` of invalid data, with `message`.
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Action of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Accesses to resources are allowed, unless denied by another rule.
+    Allow,
+
+    /// Accesses to resources are denied, whatever other rules.
+    Deny,
+
+    /// Values of columns are masked in results, with the strategy of the rule.
+    Mask,
+
+    /// Values of columns are tokenized in results, for all subjects.
+    Tokenize,
+
+    /// Values of columns are stored encrypted, for all subjects.
+    Encrypt,
+
+    /// Accesses to resources are logged.
+    Audit,
+}
+
+impl Action {
+    fn parse(text: &str) -> io::Result<Self> {
+        match text {
+            "allow" => Ok(Self::Allow),
+            "deny" => Ok(Self::Deny),
+            "mask" => Ok(Self::Mask),
+            "tokenize" => Ok(Self::Tokenize),
+            "encrypt" => Ok(Self::Encrypt),
+            "audit" => Ok(Self::Audit),
+            other => Err(invalid(format!("unknown action '{}'", other))),
+        }
+    }
+}
+
+/// A policy document, as defined in the `policy` table of `SQLHandlerConfig`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Document {
+    /// Effect of accesses matching no rule, either `allow` or `deny`.
+    default: Option<String>,
+
+    /// Users of roles, by role name.
+    roles: BTreeMap<String, Vec<String>>,
+
+    subjects: BTreeMap<String, SubjectDocument>,
+
+    /// Tags of resources, by pattern.
+    resources: BTreeMap<String, Vec<String>>,
+
+    rules: Vec<RuleDocument>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SubjectDocument {
+    users: Vec<String>,
+    roles: Vec<String>,
+    networks: Vec<String>,
+    applications: Vec<String>,
+}
+
+/// A rule, where settings of actions, e.g. the masking `strategy`, are
+/// left to handlers applying them.
+#[derive(Debug, Deserialize)]
+struct RuleDocument {
+    #[serde(default)]
+    subjects: Vec<String>,
+
+    resources: Vec<String>,
+
+    #[serde(default)]
+    operations: Vec<String>,
+
+    action: String,
+}
+
+/// A compiled rule.
+#[derive(Debug)]
+struct Rule {
+    /// Subjects, by index, or `None` for any session.
+    subjects: Option<Vec<usize>>,
+
+    patterns: Vec<Pattern>,
+
+    /// Uppercase operations, or none for any.
+    operations: Vec<String>,
+
+    action: Action,
+}
+
+impl Rule {
+    /// Returns whether the rule applies to `operation` on `resource`.
+    fn applies(&self, operation: &str, resource: &Resource, conservative: bool) -> bool {
+        (self.operations.is_empty() || self.operations.iter().any(|op| op == operation))
+            && self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.matches(resource, conservative))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Compiled {
+    /// Whether accesses matching no rule are allowed.
+    default_allow: bool,
+
+    /// Users of roles, by role name.
+    roles: BTreeMap<String, Vec<String>>,
+
+    subjects: Vec<Subject>,
+    rules: Vec<Rule>,
+}
+
+/// A compiled policy document.
+///
+/// The default policy has no rule, and denies any access. Cloning a `Policy`
+/// shares the same compiled document.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    compiled: Arc<Compiled>,
+}
+
+impl Policy {
+    /// Gets the policy document defined in the `policy` table of `config`,
+    /// if any.
+    pub fn from_config(config: &SQLHandlerConfig) -> io::Result<Option<Self>> {
+        Self::from_table(config, "policy")
+    }
+
+    /// Gets the candidate policy document defined in the `policy.shadow`
+    /// table of `config`, if any, to be evaluated without being enforced.
+    pub fn shadow_from_config(config: &SQLHandlerConfig) -> io::Result<Option<Self>> {
+        Self::from_table(config, "policy.shadow")
+    }
+
+    fn from_table(config: &SQLHandlerConfig, key: &str) -> io::Result<Option<Self>> {
+        if config.get_table(key).is_err() {
+            return Ok(None);
+        }
+        let document = config
+            .get::<Document>(key)
+            .map_err(|err| invalid(format!("invalid {} - {}", key, err)))?;
+        Self::compile(document).map(Some)
+    }
+
+    fn compile(document: Document) -> io::Result<Self> {
+        let default_allow = match document.default.as_deref() {
+            Some("allow") | None => true,
+            Some("deny") => false,
+            Some(other) => return Err(invalid(format!("unknown default '{}'", other))),
+        };
+
+        let mut names = HashMap::new();
+        let mut subjects = vec![];
+        for (name, subject) in document.subjects {
+            names.insert(name.to_lowercase(), subjects.len());
+            subjects.push(Subject::new(
+                subject.users,
+                subject.roles,
+                &subject.networks,
+                subject.applications,
+            )?);
+        }
+
+        let mut tagged: HashMap<String, Vec<Pattern>> = HashMap::new();
+        for (pattern, tags) in &document.resources {
+            let pattern = Pattern::parse(pattern)?;
+            for tag in tags {
+                tagged.entry(tag.clone()).or_default().push(pattern.clone());
+            }
+        }
+
+        let mut rules = vec![];
+        for (idx, rule) in document.rules.into_iter().enumerate() {
+            let action = Action::parse(&rule.action)?;
+            let subjects = match rule.subjects.iter().any(|name| name == "*") {
+                true => None,
+                false if rule.subjects.is_empty() => None,
+                false => Some(
+                    rule.subjects
+                        .iter()
+                        .map(|name| {
+                            names.get(&name.to_lowercase()).copied().ok_or_else(|| {
+                                invalid(format!("unknown subject '{}' in rule #{}", name, idx))
+                            })
+                        })
+                        .collect::<io::Result<_>>()?,
+                ),
+            };
+
+            let mut patterns = vec![];
+            for resource in &rule.resources {
+                match resource.strip_prefix(TAG_PREFIX) {
+                    Some(tag) => match tagged.get(tag) {
+                        Some(tagged) => patterns.extend(tagged.iter().cloned()),
+                        None => {
+                            return Err(invalid(format!("unknown tag '{}' in rule #{}", tag, idx)))
+                        }
+                    },
+                    None => patterns.push(Pattern::parse(resource)?),
+                }
+            }
+
+            // Stored and tokenized values are the same whoever reads them.
+            if let Action::Tokenize | Action::Encrypt = action {
+                if subjects.is_some() {
+                    return Err(invalid(format!(
+                        "rule #{} of action '{}' must apply to all subjects",
+                        idx, rule.action
+                    )));
+                }
+                if patterns.iter().any(|pattern| pattern.column().is_none()) {
+                    return Err(invalid(format!(
+                        "rule #{} of action '{}' must designate columns by name",
+                        idx, rule.action
+                    )));
+                }
+            }
+
+            rules.push(Rule {
+                subjects,
+                patterns,
+                operations: rule.operations.iter().map(|op| op.to_uppercase()).collect(),
+                action,
+            });
+        }
+
+        Ok(Self {
+            compiled: Arc::new(Compiled {
+                default_allow,
+                roles: document.roles,
+                subjects,
+                rules,
+            }),
+        })
+    }
+
+    /// Returns names of columns of rules of `action` applying to all
+    /// subjects, e.g. `encrypt`, with the index of their rule, whose
+    /// settings are found at `policy.rules[<index>]`.
+    pub fn columns(&self, action: Action) -> Vec<(String, usize)> {
+        let mut columns = vec![];
+        for (idx, rule) in self.compiled.rules.iter().enumerate() {
+            if rule.action != action || rule.subjects.is_some() {
+                continue;
+            }
+            for column in rule.patterns.iter().filter_map(Pattern::column) {
+                if !columns.iter().any(|(name, _)| name == column) {
+                    columns.push((column.to_string(), idx));
+                }
+            }
+        }
+        columns
+    }
+
+    /// Returns the decisions of a session in `context`.
+    pub fn decisions(&self, context: Context) -> Decisions {
+        let user = context.user.as_deref().unwrap_or_default();
+        let roles: Vec<&str> = self
+            .compiled
+            .roles
+            .iter()
+            .filter(|(_, users)| users.iter().any(|member| member == user))
+            .map(|(role, _)| role.as_str())
+            .collect();
+        let subjects: Vec<bool> = self
+            .compiled
+            .subjects
+            .iter()
+            .map(|subject| subject.matches(&context, &roles))
+            .collect();
+        let rules = self
+            .compiled
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| match &rule.subjects {
+                Some(indexes) => indexes.iter().any(|idx| subjects[*idx]),
+                None => true,
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        log::debug!("rules {:?} apply to session {:?}", rules, context);
+
+        Decisions {
+            policy: self.clone(),
+            context,
+            rules,
+            cache: HashMap::new(),
+        }
+    }
+}
+
+/// A verdict on an access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Verdict {
+    pub allowed: bool,
+
+    /// Whether the access is to be logged.
+    pub audited: bool,
+}
+
+/// Decisions of a session, on rules applying to its subjects only.
+#[derive(Debug)]
+pub struct Decisions {
+    policy: Policy,
+    context: Context,
+
+    /// Rules applying to the session, by index.
+    rules: Vec<usize>,
+
+    /// Verdicts, by operation and resource.
+    cache: HashMap<(String, Resource), Verdict>,
+}
+
+impl Decisions {
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Returns whether the policy may deny or mask anything at all.
+    pub fn is_enforcing(&self) -> bool {
+        !self.policy.compiled.default_allow || !self.policy.compiled.rules.is_empty()
+    }
+
+    /// Returns rules of `action` applying to the session.
+    fn rules(&self, action: Action) -> impl Iterator<Item = (usize, &Rule)> {
+        let rules = &self.policy.compiled.rules;
+        self.rules
+            .iter()
+            .map(move |idx| (*idx, &rules[*idx]))
+            .filter(move |(_, rule)| rule.action == action)
+    }
+
+    /// Returns `resource`, in the database of the session if unknown.
+    fn resolve(&self, resource: &Resource) -> Resource {
+        let mut resource = resource.clone();
+        if resource.database.is_none() {
+            resource.database = self.context.database.clone();
+        }
+        resource
+    }
+
+    /// Returns the verdict on `operation`, e.g. `SELECT`, on `resource`.
+    ///
+    /// Denials prevail on allowances, and apply to resources which may be
+    /// denied ones, e.g. columns of unknown tables, while allowances only
+    /// apply to resources which are certainly allowed.
+    pub fn authorize(&mut self, operation: &str, resource: &Resource) -> Verdict {
+        let key = (operation.to_uppercase(), self.resolve(resource));
+        if let Some(verdict) = self.cache.get(&key) {
+            return *verdict;
+        }
+
+        let (operation, resource) = (&key.0, &key.1);
+        let denied = self
+            .rules(Action::Deny)
+            .any(|(_, rule)| rule.applies(operation, resource, true));
+        let allowed = !denied
+            && (self.policy.compiled.default_allow
+                || self
+                    .rules(Action::Allow)
+                    .any(|(_, rule)| rule.applies(operation, resource, false)));
+        let audited = self
+            .rules(Action::Audit)
+            .any(|(_, rule)| rule.applies(operation, resource, true));
+        let verdict = Verdict { allowed, audited };
+
+        if self.cache.len() >= MAX_CACHED_VERDICTS {
+            self.cache.clear();
+        }
+        self.cache.insert(key, verdict);
+        verdict
+    }
+
+    /// Returns the index of the first rule masking `resource`, if any,
+    /// whose settings are found at `policy.rules[<index>]`.
+    pub fn mask(&self, resource: &Resource) -> Option<usize> {
+        let resource = self.resolve(resource);
+        self.rules(Action::Mask)
+            .find(|(_, rule)| {
+                rule.patterns
+                    .iter()
+                    .any(|pattern| pattern.matches(&resource, true))
+            })
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Policy, Resource};
+    use crate::{Context, SQLHandlerConfig};
+    use config::{File, FileFormat};
+
+    /// Helper function compiling the policy document `text`.
+    fn policy(text: &str) -> std::io::Result<Policy> {
+        let config = SQLHandlerConfig::builder()
+            .add_source(File::from_str(text, FileFormat::Toml))
+            .build()
+            .unwrap();
+        Policy::from_config(&config).map(Option::unwrap)
+    }
+
+    const DOCUMENT: &str = r#"
+        [policy]
+        default = "deny"
+
+        [policy.roles]
+        analyst = ["alice", "bob"]
+
+        [policy.subjects.analysts]
+        roles = ["analyst"]
+        networks = ["10.0.0.0/8"]
+
+        [policy.subjects.support]
+        users = ["carol"]
+        applications = ["backoffice"]
+
+        [policy.resources]
+        "*.public.users.email" = ["pii"]
+        "*.public.users.ssn" = ["pii", "restricted"]
+
+        [[policy.rules]]
+        resources = ["shop.public"]
+        action = "allow"
+
+        [[policy.rules]]
+        subjects = ["analysts"]
+        resources = ["tag:restricted"]
+        action = "deny"
+
+        [[policy.rules]]
+        subjects = ["analysts", "support"]
+        resources = ["tag:pii"]
+        action = "mask"
+        strategy = "caviar"
+
+        [[policy.rules]]
+        resources = ["*.public.users.ssn"]
+        action = "encrypt"
+
+        [[policy.rules]]
+        resources = ["*.*.payments"]
+        operations = ["delete"]
+        action = "audit"
+    "#;
+
+    fn resource(table: &str, column: Option<&str>) -> Resource {
+        Resource {
+            table: Some(table.to_string()),
+            column: column.map(str::to_string),
+            ..Resource::default()
+        }
+    }
+
+    #[test]
+    fn valid_decisions() {
+        let policy = policy(DOCUMENT).unwrap();
+        let context = Context {
+            user: Some("alice".to_string()),
+            database: Some("shop".to_string()),
+            application: Some("psql".to_string()),
+            address: Some("10.0.0.7".parse().unwrap()),
+        };
+        let mut analyst = policy.decisions(context.clone());
+        assert!(
+            analyst
+                .authorize("SELECT", &resource("users", Some("email")))
+                .allowed
+        );
+        assert!(
+            !analyst
+                .authorize("SELECT", &resource("users", Some("ssn")))
+                .allowed
+        );
+        assert!(
+            !analyst
+                .authorize("SELECT", &resource("users", None))
+                .allowed,
+            "all columns, ssn included"
+        );
+        let verdict = analyst.authorize("delete", &resource("payments", None));
+        assert!(verdict.allowed && verdict.audited);
+        assert_eq!(Some(2), analyst.mask(&Resource::column("email")));
+        assert_eq!(None, analyst.mask(&Resource::column("name")));
+
+        // Outside of the network of analysts.
+        let remote = Context {
+            address: Some("192.168.0.1".parse().unwrap()),
+            ..context.clone()
+        };
+        let mut remote = policy.decisions(remote);
+        assert!(
+            remote
+                .authorize("SELECT", &resource("users", Some("ssn")))
+                .allowed
+        );
+        assert_eq!(None, remote.mask(&Resource::column("email")));
+
+        // Outside of the allowed database.
+        let other = Context {
+            database: Some("crm".to_string()),
+            ..context
+        };
+        let mut other = policy.decisions(other);
+        assert!(
+            !other
+                .authorize("SELECT", &resource("users", Some("email")))
+                .allowed
+        );
+
+        assert_eq!(
+            vec![("ssn".to_string(), 3)],
+            policy.columns(Action::Encrypt)
+        );
+        assert!(policy.columns(Action::Tokenize).is_empty());
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let data = vec![
+            "[policy]\ndefault = 'maybe'",
+            "[[policy.rules]]\nresources = ['users']\naction = 'obfuscate'",
+            "[[policy.rules]]\nsubjects = ['nobody']\nresources = ['shop']\naction = 'deny'",
+            "[[policy.rules]]\nresources = ['tag:unknown']\naction = 'deny'",
+            "[[policy.rules]]\nresources = ['shop.public.users.*']\naction = 'encrypt'",
+            "[policy.subjects.a]\nusers = ['x']\n[[policy.rules]]\nsubjects = ['a']\n\
+             resources = ['*.*.users.ssn']\naction = 'tokenize'",
+            "[policy.subjects.a]\nnetworks = ['10.0.0.0/64']",
+            "[[policy.rules]]\naction = 'deny'",
+        ];
+        for document in data {
+            assert!(policy(document).is_err(), "{}", document);
+        }
+
+        let config = SQLHandlerConfig::builder().build().unwrap();
+        assert!(Policy::from_config(&config).unwrap().is_none());
+        assert!(Policy::shadow_from_config(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn shadow_policy() {
+        let document = "[[policy.shadow.rules]]\nresources = ['*.*.users']\naction = 'deny'";
+        let config = SQLHandlerConfig::builder()
+            .add_source(File::from_str(document, FileFormat::Toml))
+            .build()
+            .unwrap();
+        let active = Policy::from_config(&config).unwrap().unwrap();
+        let mut decisions = active.decisions(Context::default());
+        assert!(!decisions.is_enforcing(), "no active rule");
+        assert!(
+            decisions
+                .authorize("SELECT", &resource("users", None))
+                .allowed
+        );
+
+        let shadow = Policy::shadow_from_config(&config).unwrap().unwrap();
+        let mut decisions = shadow.decisions(Context::default());
+        assert!(decisions.is_enforcing());
+        assert!(
+            !decisions
+                .authorize("SELECT", &resource("users", None))
+                .allowed
+        );
+    }
+
+    #[test]
+    fn default_policy() {
+        let mut decisions = Policy::default().decisions(Context::default());
+        assert!(
+            !decisions
+                .authorize("SELECT", &resource("users", None))
+                .allowed
+        );
+
+        let policy = policy("[[policy.rules]]\nresources = ['*.*.users']\naction = 'deny'");
+        let mut decisions = policy.unwrap().decisions(Context::default());
+        assert!(
+            decisions
+                .authorize("SELECT", &resource("orders", None))
+                .allowed
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resources, from databases down to columns, and patterns matching them.
+
+use std::fmt;
+use std::io;
+
+/// A resource, from a database down to a column, where parts which are
+/// unknown, e.g. the schema of an unqualified table, are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Resource {
+    pub database: Option<String>,
+    pub schema: Option<String>,
+    pub table: Option<String>,
+    pub column: Option<String>,
+}
+
+impl Resource {
+    /// Returns the resource of `column`, of an unknown table.
+    pub fn column(column: &str) -> Self {
+        Self {
+            column: Some(column.to_string()),
+            ..Self::default()
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [&self.database, &self.schema, &self.table, &self.column];
+        let parts: Vec<&str> = parts
+            .iter()
+            .map(|part| part.as_deref().unwrap_or("?"))
+            .collect();
+        f.write_str(&parts.join("."))
+    }
+}
+
+/// Returns whether `text` matches `pattern`, where `*` matches any
+/// sequence of characters.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// A pattern of resources, as `database.schema.table.column`, where parts
+/// may have `*` wildcards, and omitted trailing parts match anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Pattern {
+    parts: Vec<String>,
+}
+
+impl Pattern {
+    pub(crate) fn parse(text: &str) -> io::Result<Self> {
+        let parts: Vec<String> = text.split('.').map(str::to_string).collect();
+        if parts.len() > 4 || parts.iter().any(String::is_empty) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid resource pattern '{}'", text),
+            ));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Returns the column name designated without wildcard, if any.
+    pub(crate) fn column(&self) -> Option<&str> {
+        self.parts
+            .get(3)
+            .map(String::as_str)
+            .filter(|column| !column.contains('*'))
+    }
+
+    /// Returns whether `resource` matches the pattern.
+    ///
+    /// Unknown databases and schemas match any, while unknown tables and
+    /// columns only match wildcards, unless `conservative`, where they may
+    /// be any.
+    pub(crate) fn matches(&self, resource: &Resource, conservative: bool) -> bool {
+        let parts = [
+            (&resource.database, true),
+            (&resource.schema, true),
+            (&resource.table, conservative),
+            (&resource.column, conservative),
+        ];
+        self.parts
+            .iter()
+            .zip(parts.iter())
+            .all(|(pattern, (part, any))| match part {
+                Some(part) => glob(pattern, part),
+                None => *any || pattern == "*",
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, Pattern, Resource};
+
+    #[test]
+    fn valid_glob() {
+        assert!(glob("*", ""));
+        assert!(glob("pii_*", "pii_email"));
+        assert!(glob("*_at", "created_at"));
+        assert!(glob("a*b*c", "aXbYc"));
+        assert!(!glob("a*b*c", "aXbY"));
+        assert!(!glob("users", "users2"));
+    }
+
+    #[test]
+    fn valid_pattern() {
+        let resource = Resource {
+            database: Some("shop".to_string()),
+            schema: Some("public".to_string()),
+            table: Some("users".to_string()),
+            column: Some("email".to_string()),
+        };
+        let data = vec![
+            ("shop", true),
+            ("*.public.users.email", true),
+            ("shop.*.users", true),
+            ("shop.public.users.e*", true),
+            ("crm", false),
+            ("shop.public.orders", false),
+            ("shop.public.users.ssn", false),
+        ];
+        for (pattern, expected) in data {
+            let pattern = Pattern::parse(pattern).unwrap();
+            assert_eq!(expected, pattern.matches(&resource, false), "{:?}", pattern);
+        }
+
+        // Unqualified tables, and accesses to all columns.
+        let resource = Resource {
+            database: Some("shop".to_string()),
+            table: Some("users".to_string()),
+            ..Resource::default()
+        };
+        let pattern = Pattern::parse("shop.public.users.email").unwrap();
+        assert!(!pattern.matches(&resource, false));
+        assert!(pattern.matches(&resource, true));
+        assert!(Pattern::parse("shop.public.users.*")
+            .unwrap()
+            .matches(&resource, false));
+
+        assert_eq!(
+            Some("email"),
+            Pattern::parse("*.*.users.email").unwrap().column()
+        );
+        assert_eq!(None, Pattern::parse("*.*.users.e*").unwrap().column());
+        assert!(Pattern::parse("a.b.c.d.e").is_err());
+        assert!(Pattern::parse("shop..users").is_err());
+    }
+}
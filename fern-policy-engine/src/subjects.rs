@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Subjects of rules, matched against contexts of sessions.
+
+use ipnet::IpNet;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+use crate::resources::glob;
+
+/// Context of a session, as known from its `StartupMessage` and socket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub user: Option<String>,
+    pub database: Option<String>,
+    pub application: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |part: &Option<String>| part.clone().unwrap_or_else(|| "?".to_string());
+        write!(
+            f,
+            "user '{}' on '{}' from {} with '{}'",
+            unknown(&self.user),
+            unknown(&self.database),
+            self.address
+                .map_or_else(|| "?".to_string(), |address| address.to_string()),
+            unknown(&self.application)
+        )
+    }
+}
+
+/// A subject, i.e. sessions matching all its criteria defined: any of its
+/// `users` or `roles`, any of its `networks`, and any of its `applications`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Subject {
+    /// User names, where `*` matches any characters.
+    users: Vec<String>,
+
+    roles: Vec<String>,
+    networks: Vec<IpNet>,
+
+    /// Application names, where `*` matches any characters.
+    applications: Vec<String>,
+}
+
+impl Subject {
+    pub(crate) fn new(
+        users: Vec<String>,
+        roles: Vec<String>,
+        networks: &[String],
+        applications: Vec<String>,
+    ) -> io::Result<Self> {
+        let networks = networks
+            .iter()
+            .map(|network| {
+                // Addresses are networks of their own.
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid network '{}'", network),
+                        )
+                    })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            users,
+            roles,
+            networks,
+            applications,
+        })
+    }
+
+    /// Returns whether the session of `context`, whose user has `roles`,
+    /// is one of the subject.
+    pub(crate) fn matches(&self, context: &Context, roles: &[&str]) -> bool {
+        let user = context.user.as_deref().unwrap_or_default();
+        let identified = (self.users.is_empty() && self.roles.is_empty())
+            || self.users.iter().any(|pattern| glob(pattern, user))
+            || self.roles.iter().any(|role| roles.contains(&role.as_str()));
+        let located = self.networks.is_empty()
+            || context.address.map_or(false, |address| {
+                self.networks
+                    .iter()
+                    .any(|network| network.contains(&address))
+            });
+        let application = context.application.as_deref().unwrap_or_default();
+        let applied = self.applications.is_empty()
+            || self
+                .applications
+                .iter()
+                .any(|pattern| glob(pattern, application));
+        identified && located && applied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, Subject};
+
+    #[test]
+    fn valid_subject() {
+        let subject = Subject::new(
+            vec!["alice".to_string(), "svc_*".to_string()],
+            vec!["analyst".to_string()],
+            &["10.0.0.0/8".to_string(), "192.168.1.7".to_string()],
+            vec!["metabase*".to_string()],
+        )
+        .unwrap();
+        let context = Context {
+            user: Some("bob".to_string()),
+            database: Some("shop".to_string()),
+            application: Some("metabase-prod".to_string()),
+            address: Some("10.1.2.3".parse().unwrap()),
+        };
+        assert!(!subject.matches(&context, &[]));
+        assert!(subject.matches(&context, &["analyst"]));
+
+        let context = Context {
+            user: Some("svc_reports".to_string()),
+            ..context
+        };
+        assert!(subject.matches(&context, &[]));
+        let outside = Context {
+            address: Some("172.16.0.1".parse().unwrap()),
+            ..context.clone()
+        };
+        assert!(!subject.matches(&outside, &[]));
+        let unknown = Context {
+            address: None,
+            ..context.clone()
+        };
+        assert!(!subject.matches(&unknown, &[]));
+        let other = Context {
+            application: Some("psql".to_string()),
+            ..context
+        };
+        assert!(!subject.matches(&other, &[]));
+
+        assert!(Subject::default().matches(&Context::default(), &[]));
+        assert!(Subject::new(vec![], vec![], &["10.0.0.0/33".to_string()], vec![]).is_err());
+    }
+}
@@ -24,11 +24,13 @@ where
     M: Send + Sync,
 {
     /// Applies a transformation to an `SQLMessage`.
-    async fn process(&mut self, msg: M) -> M
+    ///
+    /// Returning `None` drops the `SQLMessage`, which is then not forwarded.
+    async fn process(&mut self, msg: M) -> Option<M>
     where
         M: SQLMessage + 'async_trait,
     {
-        msg
+        Some(msg)
     }
 
     fn new(config: &SQLHandlerConfig) -> Self
@@ -0,0 +1,257 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Chaining of `SQLMessageHandler`s, applied in order to Messages of a `Pipe`,
+//! and building of the chains of both directions of a connection.
+
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use fern_authorization::{
+    AuthorizationHandler, ColumnMaskHandler, DenialHandler, Denials, ReadOnlyHandler,
+    RowFilterHandler,
+};
+use fern_authorization_opa::{ObligationHandler, OpaAuthorizationHandler};
+use fern_encryption::{DecryptionHandler, EncryptionHandler, Encryptor};
+use fern_encryption_vault::VaultEncryptor;
+use fern_masking::{Classification, DataMaskingHandler};
+use fern_policy_engine::{Policies, SessionHandler};
+use fern_protocol_postgresql::codec::{backend, frontend};
+use fern_protocol_postgresql::tracker::DescriptionTracker;
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessage, SQLMessageHandler};
+use fern_tokenization::{DetokenizationHandler, TokenVault, TokenizationHandler};
+
+/// An `SQLMessageHandler` applying a sequence of handlers, each one
+/// processing the Message returned by the previous one.
+///
+/// Should a handler drop a Message, following handlers are not applied.
+#[derive(Debug)]
+pub struct HandlerChain<M> {
+    handlers: Vec<Box<dyn SQLMessageHandler<M>>>,
+}
+
+impl<M> HandlerChain<M>
+where
+    M: SQLMessage,
+{
+    /// Appends `handler` at the end of the chain.
+    #[must_use]
+    pub fn with<H>(mut self, handler: H) -> Self
+    where
+        H: SQLMessageHandler<M> + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+}
+
+#[async_trait]
+impl<M> SQLMessageHandler<M> for HandlerChain<M>
+where
+    M: SQLMessage,
+{
+    /// Creates an empty chain, passing Messages through.
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self { handlers: vec![] }
+    }
+
+    async fn process(&mut self, msg: M) -> Option<M>
+    where
+        M: 'async_trait,
+    {
+        let mut msg = msg;
+        for handler in self.handlers.iter_mut() {
+            msg = handler.process(msg).await?;
+        }
+        Some(msg)
+    }
+}
+
+/// State shared by handlers of all connections, built once at startup.
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    /// Compiled policy documents.
+    pub policies: Arc<Policies>,
+
+    /// Tags of columns.
+    pub classification: Arc<Classification>,
+
+    /// Vault where tokens are stored, if tokenization is enabled.
+    pub vault: Option<Arc<TokenVault>>,
+
+    /// Encryptor of column values, if not encrypted with local keys.
+    pub encryptor: Option<Arc<dyn Encryptor>>,
+}
+
+impl Shared {
+    /// Builds state defined in `config`, with tags of `classification`,
+    /// failing if the encryptor defined cannot be built.
+    pub fn new(config: &SQLHandlerConfig, classification: Classification) -> io::Result<Self> {
+        let policies = Policies::from_config(config);
+        let vault = fern_tokenization::is_enabled(config, policies.active.as_ref())
+            .then(|| Arc::new(TokenVault::from_config(config)));
+        let mut encryptor = None;
+        if fern_encryption::is_enabled(config, policies.active.as_ref())
+            && fern_encryption_vault::is_enabled(config)
+        {
+            let vault: Arc<dyn Encryptor> = Arc::new(VaultEncryptor::from_config(config)?);
+            encryptor = Some(vault);
+        }
+        Ok(Self {
+            policies: Arc::new(policies),
+            classification: Arc::new(classification),
+            vault,
+            encryptor,
+        })
+    }
+}
+
+/// Chains of handlers of both directions of a connection.
+#[derive(Debug)]
+pub struct Chains {
+    /// Handlers of Messages from Client to proxied Server.
+    pub forward: HandlerChain<frontend::Message>,
+
+    /// Handlers of Messages from proxied Server to Client.
+    pub backward: HandlerChain<backend::Message>,
+}
+
+impl Chains {
+    /// Chains handlers enabled in `config`, using `shared` state, for a
+    /// connection of a Client at `client_address`, if known, answering
+    /// denied statements through `replies`, and sending statements of the
+    /// proxy to the Server through `requests`.
+    #[rustfmt::skip]
+    pub fn new(
+        config: &SQLHandlerConfig,
+        shared: &Shared,
+        client_address: Option<SocketAddr>,
+        replies: mpsc::UnboundedSender<backend::Message>,
+        requests: mpsc::UnboundedSender<frontend::Message>,
+    ) -> Self {
+        let policies = &shared.policies;
+
+        // Share `RowDescription`s tracking between both flows, as the Client
+        // side knows which statement or portal Server results belong to.
+        let tracker = DescriptionTracker::new();
+
+        // Chain handlers, with the tracker first so that it is shared by
+        // all following ones, and masking last as it deals with uncertainty.
+        // On the Server side, the tracker follows the `DenialHandler`, which
+        // answers denied statements in place of the Server.
+        let mut forward = HandlerChain::new(config).with(tracker.clone());
+        let mut backward = HandlerChain::new(config);
+        let mut encryption = None;
+        let mut obligations = None;
+        let mut read_only = None;
+
+        // Start decisions of the policy document first, shared with handlers applying them.
+        let mut policy = None;
+        if fern_policy_engine::is_enabled(config) || fern_policy_engine::is_shadowed(config) {
+            let mut session = SessionHandler::from_policies(policies);
+            if let Some(address) = client_address {
+                session = session.with_client_address(address);
+            }
+            policy = Some(session.session());
+            forward = forward.with(session);
+        }
+
+        // Share denials between authorization handlers, answered by a single `DenialHandler`.
+        let denials = Denials::new().with_replies(replies);
+        if fern_authorization::is_read_only(config) {
+            let guard = ReadOnlyHandler::from_config(config)
+                .with_denials(denials.clone())
+                .with_requests(requests);
+            read_only = Some(guard.clone());
+            forward = forward.with(guard);
+        }
+        if fern_authorization::is_enabled(config) || policy.is_some() {
+            let mut authorizer = AuthorizationHandler::new(config).with_denials(denials.clone());
+            if let Some(session) = &policy {
+                authorizer = authorizer.with_policy(session.clone());
+            }
+            forward = forward.with(authorizer);
+        }
+        if fern_authorization_opa::is_enabled(config) {
+            let mut authorizer = OpaAuthorizationHandler::new(config).with_denials(denials.clone());
+            if let Some(address) = client_address {
+                authorizer = authorizer.with_client_address(address);
+            }
+            obligations = Some(authorizer.obligations());
+            forward = forward.with(authorizer);
+        }
+        // Rewrite statements once authorized, as written by the client,
+        // masking columns before tables are replaced by filtered subqueries.
+        if fern_authorization::has_column_masks(config) {
+            let masker = ColumnMaskHandler::new(config).with_denials(denials.clone());
+            forward = forward.with(masker);
+        }
+        if fern_authorization::has_row_filters(config) {
+            let filter = RowFilterHandler::new(config).with_denials(denials.clone());
+            forward = forward.with(filter);
+        }
+        if fern_authorization::is_read_only(config)
+            || fern_authorization::is_enabled(config)
+            || policy.is_some()
+            || fern_authorization_opa::is_enabled(config)
+            || fern_authorization::has_column_masks(config)
+            || fern_authorization::has_row_filters(config)
+        {
+            let denial = DenialHandler::default().with_denials(denials);
+            forward = forward.with(denial.clone());
+            backward = backward.with(denial);
+        }
+        backward = backward.with(tracker.clone());
+        if fern_encryption::is_enabled(config, policies.active.as_ref()) {
+            let mut encryptor = EncryptionHandler::from_policy(config, policies.active.as_ref());
+            let mut decryptor = DecryptionHandler::from_policy(config, policies.active.as_ref())
+                .with_session(encryptor.session())
+                .with_tracker(tracker.clone());
+            if let Some(vault) = &shared.encryptor {
+                encryptor = encryptor.with_encryptor(vault.clone());
+                decryptor = decryptor.with_encryptor(vault.clone());
+            }
+            encryption = Some(encryptor);
+            backward = backward.with(decryptor);
+        }
+        if fern_tokenization::is_enabled(config, policies.active.as_ref()) {
+            let mut tokenizer = TokenizationHandler::from_policy(config, policies.active.as_ref())
+                .with_tracker(tracker.clone());
+            let mut detokenizer = DetokenizationHandler::new(config);
+            if let Some(vault) = &shared.vault {
+                tokenizer = tokenizer.with_vault(vault.clone());
+                detokenizer = detokenizer.with_vault(vault.clone());
+            }
+            forward = forward.with(detokenizer);
+            backward = backward.with(tokenizer);
+        }
+        // Encrypt detokenized values, as tokenized values are decrypted ones.
+        if let Some(encryptor) = encryption {
+            forward = forward.with(encryptor);
+        }
+        // Mask columns as obliged by policy decisions, whatever masking settings.
+        if let Some(obligations) = obligations {
+            let obligation = ObligationHandler::new(config)
+                .with_obligations(obligations)
+                .with_tracker(tracker.clone());
+            backward = backward.with(obligation);
+        }
+        let mut masker = DataMaskingHandler::new(config)
+            .with_tracker(tracker)
+            .with_classification(shared.classification.clone());
+        if let Some(session) = policy {
+            masker = masker.with_policy(session);
+        }
+        let mut backward = backward.with(masker);
+        // Make read-only sessions read-only on the Server, once other
+        // handlers tracked its answers to the proxy.
+        if let Some(guard) = read_only {
+            backward = backward.with(guard);
+        }
+
+        Self { forward, backward }
+    }
+}
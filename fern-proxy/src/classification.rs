@@ -0,0 +1,104 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loading of the classification of columns with tags, shared by masking
+//! handlers of all connections.
+//!
+//! Tags of the classification file are completed, if `catalog` is set in
+//! `masking.classification` settings, with tags of columns found in the
+//! catalog of the server defined by the `DATABASE_URL` env variable, once
+//! at startup:
+//! - in comments, following a `tags:` marker, e.g.
+//!   `COMMENT ON COLUMN users.email IS 'Contact address. tags: pii.email'`,
+//! - in security labels, of the `label_provider` only if set, e.g.
+//!   `SECURITY LABEL FOR anon ON COLUMN users.email IS 'pii.email'`.
+
+use std::io;
+
+use crate::database::{self, to_io_error};
+use fern_masking::{comment_tags, label_tags, Classification, Column, SQLHandlerConfig};
+use tokio_postgres::SimpleQueryMessage;
+
+/// Returns the catalog query of user columns, with their comment and
+/// security labels of `provider`, or of any provider if `None`.
+fn catalog_query(provider: Option<&str>) -> String {
+    let provider = match provider {
+        Some(provider) => format!(" AND l.provider = '{}'", provider.replace('\'', "''")),
+        None => String::new(),
+    };
+    format!(
+        "SELECT c.oid, a.attnum, n.nspname, c.relname, a.attname, \
+        pg_catalog.col_description(c.oid, a.attnum), \
+        (SELECT pg_catalog.string_agg(l.label, ',') FROM pg_catalog.pg_seclabel l \
+        WHERE l.classoid = 'pg_catalog.pg_class'::pg_catalog.regclass \
+        AND l.objoid = c.oid AND l.objsubid = a.attnum{}) \
+        FROM pg_catalog.pg_attribute a \
+        JOIN pg_catalog.pg_class c ON c.oid = a.attrelid \
+        JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+        WHERE a.attnum > 0 AND NOT a.attisdropped \
+        AND c.relkind IN ('r', 'p', 'v', 'm', 'f') \
+        AND n.nspname NOT IN ('pg_catalog', 'information_schema') \
+        AND n.nspname NOT LIKE 'pg\\_toast%'",
+        provider
+    )
+}
+
+/// Loads the classification defined in `config`.
+pub async fn load(config: &SQLHandlerConfig) -> io::Result<Classification> {
+    let mut classification = Classification::from_config(config)?;
+    if !config
+        .get::<bool>("masking.classification.catalog")
+        .unwrap_or(false)
+    {
+        return Ok(classification);
+    }
+
+    let client = database::connect().await?;
+
+    let provider = config
+        .get::<String>("masking.classification.label_provider")
+        .ok();
+    let query = catalog_query(provider.as_deref());
+    let mut tagged = 0;
+    for message in client.simple_query(&query).await.map_err(to_io_error)? {
+        let row = match message {
+            SimpleQueryMessage::Row(row) => row,
+            _ => continue,
+        };
+        let table_oid = row.get(0).and_then(|value| value.parse::<u32>().ok());
+        let column_attr = row.get(1).and_then(|value| value.parse::<u16>().ok());
+        let (table_oid, column_attr) = match (table_oid, column_attr) {
+            (Some(table_oid), Some(column_attr)) => (table_oid, column_attr),
+            _ => continue,
+        };
+        let mut tags = row.get(5).map(comment_tags).unwrap_or_default();
+        tags.extend(row.get(6).map(label_tags).unwrap_or_default());
+        if !tags.is_empty() {
+            tagged += 1;
+        }
+        let column = Column {
+            schema: row.get(2).unwrap_or_default().to_string(),
+            table: row.get(3).unwrap_or_default().to_string(),
+            name: row.get(4).unwrap_or_default().to_string(),
+            tags,
+        };
+        classification.add_column(table_oid, column_attr, column);
+    }
+    log::info!("loaded tags of {} columns from catalog", tagged);
+    Ok(classification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::catalog_query;
+
+    #[test]
+    fn valid_catalog_query() {
+        let query = catalog_query(None);
+        assert!(query.contains("pg_catalog.col_description(c.oid, a.attnum)"));
+        assert!(!query.contains("l.provider"));
+
+        let query = catalog_query(Some("o'anon"));
+        assert!(query.contains("a.attnum AND l.provider = 'o''anon')"));
+    }
+}
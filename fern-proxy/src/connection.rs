@@ -5,9 +5,10 @@ use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
+use crate::chain::{Chains, HandlerChain, Shared};
 use crate::pipe::{Direction, Pipe, ShortCircuit};
-use fern_masking::{DataMaskingHandler, PassthroughHandler, SQLHandlerConfig};
 use fern_protocol_postgresql::codec::{backend, frontend};
+use fern_proxy_interfaces::SQLHandlerConfig;
 
 //TODO(ppiotr3k): write description
 #[derive(Debug)]
@@ -19,7 +20,7 @@ pub struct Connection {
         frontend::Codec,
         frontend::Message,
         backend::Message,
-        PassthroughHandler<frontend::Message>,
+        HandlerChain<frontend::Message>,
     >,
 
     /// `Pipe` instance processing Messages from proxied Server to Client.
@@ -29,31 +30,49 @@ pub struct Connection {
         backend::Codec,
         backend::Message,
         frontend::Message,
-        DataMaskingHandler,
+        HandlerChain<backend::Message>,
     >,
 }
 
 impl Connection {
-    /// Creates a new connection for proxying provided `client_socket` and `server_socket`.
+    /// Creates a new connection for proxying provided `client_socket` and `server_socket`,
+    /// with handlers using `shared` state.
     #[rustfmt::skip]
-    pub async fn new(client_socket: TcpStream, server_socket: TcpStream, config: &SQLHandlerConfig) -> Connection {
+    pub async fn new(
+        client_socket: TcpStream,
+        server_socket: TcpStream,
+        config: &SQLHandlerConfig,
+        shared: &Shared,
+    ) -> Connection {
+        let client_address = client_socket.peer_addr().ok();
+
         // Split the sockets to be able to `Pipe` them together.
         let (client_rx, client_tx) = client_socket.into_split();
         let (server_rx, server_tx) = server_socket.into_split();
 
         // Create channels to allow short-circuiting regular Message flows.
-        let (forward_tx, forward_rx) = mpsc::channel::<backend::Message>(128);
-        let (backward_tx, backward_rx) = mpsc::channel::<frontend::Message>(128);
+        let (forward_tx, forward_rx) = mpsc::unbounded_channel::<backend::Message>();
+        let (backward_tx, backward_rx) = mpsc::unbounded_channel::<frontend::Message>();
         let forward_short = ShortCircuit::new(forward_tx, backward_rx);
         let backward_short = ShortCircuit::new(backward_tx, forward_rx);
 
+        // Handlers of Client Messages may answer them, short-circuiting the Server,
+        // and handlers of Server Messages may send it requests of their own.
+        let chains = Chains::new(
+            config,
+            shared,
+            client_address,
+            forward_short.sender(),
+            backward_short.sender(),
+        );
+
         // Create `Pipe` instance for regular Client -> proxied Server Message flows.
         let forward_pipe = Pipe::new(
             Direction::ClientServer,
             client_rx,
             server_tx,
             forward_short,
-            config,
+            chains.forward,
         );
 
         // Create `Pipe` instance for regular proxied Server -> Client Message flows.
@@ -62,7 +81,7 @@ impl Connection {
             server_rx,
             client_tx,
             backward_short,
-            config,
+            chains.backward,
         );
 
         Connection {
@@ -0,0 +1,30 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Regular PostgreSQL connection to the server defined by the `DATABASE_URL`
+//! env variable, for work outside proxied sessions, e.g. reading the catalog.
+
+use std::io;
+
+use tokio_postgres::{Client, NoTls};
+
+/// Maps an error to an `io::Error`.
+pub fn to_io_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+/// Connects to the server defined by the `DATABASE_URL` env variable,
+/// driving the connection in a background task.
+pub async fn connect() -> io::Result<Client> {
+    let url = std::env::var("DATABASE_URL")
+        .map_err(|_| to_io_error("DATABASE_URL env variable is undefined"))?;
+    let (client, connection) = tokio_postgres::connect(&url, NoTls)
+        .await
+        .map_err(to_io_error)?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            log::error!("database connection - {}", err);
+        }
+    });
+    Ok(client)
+}
@@ -3,21 +3,37 @@
 
 use tokio::{io::Result, net::TcpListener};
 
+use crate::chain::Shared;
+
+mod chain;
+mod classification;
 mod connection;
+mod database;
 mod pipe;
+mod policy;
+mod reencrypt;
 mod server;
 mod shutdown;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
-    // Per "12 factors: III. Config", store config in the environment.
-    let own_addr = std::env::var("ADDRESS").unwrap_or_else(|_| "0.0.0.0:30000".into());
-    log::trace!("listener addr: {}", own_addr);
+    let mut args = std::env::args().skip(1);
+    let subcommand = args.next();
+    if subcommand.as_deref() == Some("reencrypt") {
+        // Report progress of maintenance tasks by default.
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    } else {
+        env_logger::init();
+    }
 
-    let srv_addr = std::env::var("SERVER").expect("SERVER env variable is undefinied");
-    log::trace!("proxied Server addr: {}", srv_addr);
+    // Policy tests run offline, with settings of their own policy file.
+    if subcommand.as_deref() == Some("policy") {
+        if let Err(err) = policy::run(args).await {
+            log::error!("aborting - {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     //TODO(ppiotr3k): refactor and move config initialization out of `main`
     // Get settings defined in `CONFIG_FILE`.
@@ -52,12 +68,53 @@ async fn main() -> Result<()> {
         .expect("conflict in config defaults - should not happen");
     log::trace!("using config: {:?}", config);
 
+    if subcommand.as_deref() == Some("reencrypt") {
+        if let Err(err) = reencrypt::run(args, &config).await {
+            log::error!("aborting - {}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Load tags of columns once, shared by all connections.
+    let classification = match classification::load(&config).await {
+        Ok(classification) => classification,
+        Err(err) => {
+            log::error!("aborting - {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Compile policy documents, open the token vault, and build the encryptor
+    // once, shared by all connections.
+    let shared = match Shared::new(&config, classification) {
+        Ok(shared) => shared,
+        Err(err) => {
+            log::error!("aborting - {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Per "12 factors: III. Config", store config in the environment.
+    let own_addr = std::env::var("ADDRESS").unwrap_or_else(|_| "0.0.0.0:30000".into());
+    log::trace!("listener addr: {}", own_addr);
+
+    let srv_addr = std::env::var("SERVER").expect("SERVER env variable is undefinied");
+    log::trace!("proxied Server addr: {}", srv_addr);
+
     //TODO(ppiotr3k): support instanciation of multiple listener tasks
     //TODO(ppiotr3k): consider multiple processes and CPU affinity
     let listener = TcpListener::bind(own_addr).await?;
 
     // Run until `<CTRL> + C` is hit - equivalent to SIGINT signal.
-    server::run(listener, &srv_addr, tokio::signal::ctrl_c(), &config).await;
+    server::run(
+        listener,
+        &srv_addr,
+        tokio::signal::ctrl_c(),
+        &config,
+        shared,
+    )
+    .await;
     log::info!("proxy shut down; exiting");
     Ok(())
 }
@@ -8,7 +8,7 @@ use tokio::io::{AsyncRead, AsyncWrite, Result};
 use tokio::sync::mpsc;
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
-use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessage, SQLMessageHandler};
+use fern_proxy_interfaces::{SQLMessage, SQLMessageHandler};
 
 /// Direction of Messages flow in a `Pipe`.
 #[derive(Debug)]
@@ -46,14 +46,14 @@ pub struct Pipe<R, W, C, I, S, H> {
     sink: FramedWrite<W, C>,
 
     /// Chain of `SQLMessageHandler`s applied to data flowing in the `Pipe`.
-    /// The chain is built before beeing passed to the `Pipe` constructor.
-    //TODO(ppiotr3k): implement a higher-level struct processing the chain automatically
+    /// The chain is built before beeing passed to the `Pipe` constructor,
+    /// as a `HandlerChain`.
     //TODO(ppiotr3k): settle on the naming: `frame`, `message`, `packet`, ...
     frame_handlers: H,
 
     /// Access to the `stream` and `sink` of the `Pipe` paired with this one.
     /// Used for "short-circuiting" regular Client <-> proxied Server flows.
-    _short_circuit: ShortCircuit<I, S>,
+    short_circuit: ShortCircuit<I, S>,
 }
 
 impl<R, W, C, I, S, H> Pipe<R, W, C, I, S, H>
@@ -71,15 +71,15 @@ where
         receiver: R,
         sender: W,
         short_circuit: ShortCircuit<I, S>,
-        config: &SQLHandlerConfig,
+        frame_handlers: H,
     ) -> Pipe<R, W, C, I, S, H> {
         // Adapt from `AsyncRead`/ `AsyncWrite` to `Stream`/`Sink`.
         Pipe {
             direction,
             stream: FramedRead::new(receiver, C::default()),
             sink: FramedWrite::new(sender, C::default()),
-            frame_handlers: H::new(config),
-            _short_circuit: short_circuit,
+            frame_handlers,
+            short_circuit,
         }
     }
 
@@ -96,7 +96,33 @@ where
         loop {
             // `select!` continuously runs all futures until one returns.
             // Read request frame, also listening for the shutdown signal.
-            let mut packet = tokio::select! {
+            let packet = tokio::select! {
+                // Short-circuit Messages come first, as they are sent in
+                // order with Messages already read from `Stream`.
+                biased;
+
+                // Process a short-circuit Message.
+                result = self.short_circuit.rx.recv() => {
+                    if let Some(packet) = result {
+                        log::trace!(
+                            "[{}] received short-circuit packet: {:?}",
+                            self.direction,
+                            packet,
+                        );
+                        packet
+                    } else {
+                        let err = std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!(
+                                "[{}] paired pipe prematurely closed",
+                                self.direction
+                            )
+                        );
+                        log::trace!("{}", err);
+                        return Err(err)
+                    }
+                },
+
                 // Await for a Message from `Stream`, or terminate if `Stream` dried.
                 result = self.stream.next() => {
                     if let Some(Ok(packet)) = result {
@@ -110,34 +136,18 @@ where
                         return Err(err);
                     }
                 },
-
-                // // Process a short-circuit Message.
-                // result = self.short_circuit.rx.recv() => {
-                //     if let Some(packet) = result {
-                //         log::trace!(
-                //             "[{}] received short-circuit packet: {:?}",
-                //             self.direction,
-                //             packet,
-                //         );
-                //         packet
-                //     } else {
-                //         let err = std::io::Error::new(
-                //             std::io::ErrorKind::UnexpectedEof,
-                //             format!(
-                //                 "[{}] paired pipe prematurely closed",
-                //                 self.direction
-                //             )
-                //         );
-                //         log::trace!("{}", err);
-                //         return Err(err)
-                //     }
-                // },
             };
 
             //TODO(ppiotr3k): check if `packet` should be short-circuited
             //TODO(ppiotr3k): process `packet` through "packet handlers"
 
-            packet = self.frame_handlers.process(packet).await;
+            let packet = match self.frame_handlers.process(packet).await {
+                Some(packet) => packet,
+                None => {
+                    log::trace!("[{}] packet dropped by handlers", self.direction);
+                    continue;
+                }
+            };
 
             //TODO(ppiotr3k): consider batching rather than `send`ing one-by-one
             // Write `packet` to `Sink`, and flush it.
@@ -149,15 +159,21 @@ where
     }
 }
 
-///TODO(ppiotr3k): write struct description
+/// Channels between paired `Pipe`s, where Messages sent are received by
+/// the paired `Pipe` as if read from its `Stream`.
 #[derive(Debug)]
 pub struct ShortCircuit<I, S> {
-    _tx: mpsc::Sender<S>,
-    _rx: mpsc::Receiver<I>,
+    tx: mpsc::UnboundedSender<S>,
+    rx: mpsc::UnboundedReceiver<I>,
 }
 
 impl<I, S> ShortCircuit<I, S> {
-    pub fn new(tx: mpsc::Sender<S>, rx: mpsc::Receiver<I>) -> ShortCircuit<I, S> {
-        ShortCircuit { _tx: tx, _rx: rx }
+    pub fn new(tx: mpsc::UnboundedSender<S>, rx: mpsc::UnboundedReceiver<I>) -> ShortCircuit<I, S> {
+        ShortCircuit { tx, rx }
+    }
+
+    /// Returns a sender of Messages to the paired `Pipe`, e.g. for handlers.
+    pub fn sender(&self) -> mpsc::UnboundedSender<S> {
+        self.tx.clone()
     }
 }
@@ -0,0 +1,493 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! `policy test` subcommand, running test cases of a policy through handlers
+//! chained as for a connection, in-process and without any database.
+//!
+//! The policy file is a configuration file, in TOML or YAML, with settings
+//! of `policy`, `authorization`, and `masking`. Test cases are defined in a
+//! file in TOML or YAML as well, in a `cases` array, each one describing:
+//! - `name` of the case,
+//! - `session` attributes: `user`, `database`, `application`, client
+//!   `address`, and other startup `parameters`, e.g. `fern.tenant`,
+//! - `statement` sent by the client, as a simple query,
+//! - `columns` and `rows` of its simulated result, as text,
+//! - `expect`ed outcome: whether `allowed`, the `statement` forwarded to
+//!   the server once rewritten, the `error` message of a denial, and `rows`
+//!   as forwarded to the client.
+//!
+//! ```yaml
+//! cases:
+//!   - name: analysts get masked emails
+//!     session: { user: alice, address: 10.0.0.7 }
+//!     statement: SELECT id, email FROM users
+//!     columns: [id, email]
+//!     rows: [["1", "jane@example.com"]]
+//!     expect:
+//!       allowed: true
+//!       rows: [["1", "******"]]
+//! ```
+//!
+//! Columns of simulated results are only known by name, and tags of the
+//! classification file, if any, apply to them whatever their table.
+//!
+//! Expected and actual outcomes of failed cases are reported, and the
+//! subcommand fails should any case fail.
+
+use bytes::{Buf, Bytes};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use tokio::sync::mpsc;
+
+use crate::chain::{Chains, Shared};
+use fern_masking::{Classification, SQLHandlerConfig};
+use fern_protocol_postgresql::codec::backend::{self, RowDescription};
+use fern_protocol_postgresql::codec::frontend::{self, Parameter};
+use fern_proxy_interfaces::SQLMessageHandler;
+
+const USAGE: &str = "usage: fern-proxy policy test <policy file> <cases file>";
+
+/// OID of the `text` data type.
+const TEXT_OID: u32 = 25;
+
+/// Fields of a row, where `None` is an SQL `NULL` value.
+type Row = Vec<Option<String>>;
+
+/// Test cases, as defined in a file.
+#[derive(Debug, Deserialize)]
+struct Cases {
+    cases: Vec<Case>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Case {
+    name: String,
+
+    #[serde(default)]
+    session: Attributes,
+
+    statement: String,
+
+    /// Names of columns of the simulated result, all of type `text`.
+    #[serde(default)]
+    columns: Vec<String>,
+
+    #[serde(default)]
+    rows: Vec<Row>,
+
+    #[serde(default)]
+    expect: Expected,
+}
+
+/// Attributes of a session, as sent in its `StartupMessage`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Attributes {
+    user: Option<String>,
+    database: Option<String>,
+    application: Option<String>,
+
+    /// IP address of the client.
+    address: Option<String>,
+
+    /// Other startup parameters, by name.
+    parameters: BTreeMap<String, String>,
+}
+
+/// Expected outcome of a case, where only defined parts are checked.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Expected {
+    allowed: Option<bool>,
+    statement: Option<String>,
+    error: Option<String>,
+    rows: Option<Vec<Row>>,
+}
+
+/// Actual outcome of a case.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcome {
+    allowed: bool,
+
+    /// Statement forwarded to the server, as rewritten by handlers.
+    statement: String,
+
+    /// Message of the error answered to the client, if any.
+    error: Option<String>,
+
+    /// Rows forwarded to the client.
+    rows: Vec<Row>,
+}
+
+impl Outcome {
+    /// Returns mismatches with `expected`, as descriptions of both.
+    fn mismatches(&self, expected: &Expected) -> Vec<String> {
+        let mut mismatches = vec![];
+        let mut check = |what: &str, expected: String, actual: String| {
+            if expected != actual {
+                mismatches.push(format!(
+                    "{}:\n      expected: {}\n      actual:   {}",
+                    what, expected, actual
+                ));
+            }
+        };
+        if let Some(allowed) = expected.allowed {
+            check("allowed", allowed.to_string(), self.allowed.to_string());
+        }
+        if let Some(statement) = &expected.statement {
+            check("statement", statement.clone(), self.statement.clone());
+        }
+        if let Some(error) = &expected.error {
+            let actual = self.error.clone().unwrap_or_else(|| "none".to_string());
+            check("error", error.clone(), actual);
+        }
+        if let Some(rows) = &expected.rows {
+            check("rows", format!("{:?}", rows), format!("{:?}", self.rows));
+        }
+        mismatches
+    }
+}
+
+/// Builds `config` from settings defined in file at `path`, whose format
+/// depends on its extension.
+fn load(path: &str) -> io::Result<config::Config> {
+    config::Config::builder()
+        .add_source(config::File::from(Path::new(path)))
+        .build()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("'{}' - {}", path, err)))
+}
+
+/// Builds the `StartupMessage` of a session with `attributes`.
+fn startup(attributes: &Attributes) -> frontend::Message {
+    let mut parameters = vec![];
+    let known = [
+        ("user", &attributes.user),
+        ("database", &attributes.database),
+        ("application_name", &attributes.application),
+    ];
+    for (name, value) in known {
+        if let Some(value) = value {
+            parameters.push((name.to_string(), value.clone()));
+        }
+    }
+    parameters.extend(attributes.parameters.clone());
+
+    // Length, protocol version, parameters as pairs of C strings, and terminator.
+    let length: usize = parameters
+        .iter()
+        .map(|(name, value)| name.len() + value.len() + 2)
+        .sum();
+    frontend::Message::StartupMessage {
+        frame_length: length + 9,
+        parameters: parameters
+            .into_iter()
+            .map(|(name, value)| Parameter {
+                name: Bytes::from(name),
+                value: Bytes::from(value),
+            })
+            .collect(),
+    }
+}
+
+/// Returns the human-readable message of an `ErrorResponse` with `fields`.
+fn error_message(mut fields: Bytes) -> String {
+    while fields.has_remaining() {
+        let field_type = fields.get_u8();
+        let end = match fields.iter().position(|byte| *byte == 0) {
+            Some(end) => end,
+            None => break,
+        };
+        let value = fields.split_to(end);
+        fields.advance(1);
+        if field_type == b'M' {
+            return String::from_utf8_lossy(&value).into_owned();
+        }
+    }
+    String::new()
+}
+
+/// Runs `case` through handlers built from `config` and `shared` state,
+/// as chained for a connection.
+async fn run_case(config: &SQLHandlerConfig, shared: &Shared, case: &Case) -> io::Result<Outcome> {
+    let address = match &case.session.address {
+        Some(address) => {
+            let address = address.parse::<IpAddr>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid address '{}'", address),
+                )
+            })?;
+            Some(SocketAddr::new(address, 0))
+        }
+        None => None,
+    };
+    // Cases are authorized statements only, without requests of the proxy.
+    let (replies, mut answers) = mpsc::unbounded_channel();
+    let (requests, _) = mpsc::unbounded_channel();
+    let Chains {
+        forward: mut forward_handlers,
+        backward: mut backward_handlers,
+    } = Chains::new(config, shared, address, replies, requests);
+
+    forward_handlers.process(startup(&case.session)).await;
+    let query = Bytes::from(case.statement.clone());
+    let statement = match forward_handlers
+        .process(frontend::Message::Query(query))
+        .await
+    {
+        Some(frontend::Message::Query(statement)) => statement,
+        // Denied statements are answered in place of the server.
+        None => {
+            let mut outcome = Outcome::default();
+            while let Ok(answer) = answers.try_recv() {
+                if let Some(backend::Message::ErrorResponse(fields)) =
+                    backward_handlers.process(answer).await
+                {
+                    outcome.error = Some(error_message(fields));
+                }
+            }
+            return Ok(outcome);
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected message forwarded: {:?}", other),
+            ))
+        }
+    };
+    let mut outcome = Outcome {
+        allowed: true,
+        statement: String::from_utf8_lossy(&statement).into_owned(),
+        ..Outcome::default()
+    };
+
+    let description = case
+        .columns
+        .iter()
+        .map(|name| RowDescription {
+            name: Bytes::from(name.clone()),
+            table_oid: 0,
+            column_attr: 0,
+            data_type_oid: TEXT_OID,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        })
+        .collect();
+    let description = backend::Message::RowDescription(description);
+    backward_handlers.process(description).await;
+    for row in &case.rows {
+        let fields = row
+            .iter()
+            .map(|field| field.clone().map(Bytes::from))
+            .collect();
+        match backward_handlers
+            .process(backend::Message::DataRow(fields))
+            .await
+        {
+            Some(backend::Message::DataRow(fields)) => outcome.rows.push(
+                fields
+                    .iter()
+                    .map(|field| {
+                        field
+                            .as_ref()
+                            .map(|field| String::from_utf8_lossy(field).into_owned())
+                    })
+                    .collect(),
+            ),
+            Some(backend::Message::ErrorResponse(fields)) => {
+                outcome.error = Some(error_message(fields));
+                break;
+            }
+            _ => (),
+        }
+    }
+    Ok(outcome)
+}
+
+/// Runs the `policy` subcommand with command line `args`.
+pub async fn run(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let usage = |message: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}\n{}", message, USAGE),
+        )
+    };
+    match args.next().as_deref() {
+        Some("test") => (),
+        Some(other) => return Err(usage(&format!("unknown policy command '{}'", other))),
+        None => return Err(usage("missing policy command")),
+    }
+    let policy = args.next().ok_or_else(|| usage("missing policy file"))?;
+    let cases = args.next().ok_or_else(|| usage("missing cases file"))?;
+
+    let config = load(&policy)?;
+    let cases = load(&cases)?
+        .try_deserialize::<Cases>()
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid cases - {}", err),
+            )
+        })?
+        .cases;
+
+    let shared = Shared::new(&config, Classification::from_config(&config)?)?;
+    let mut failed = 0;
+    for case in &cases {
+        let outcome = run_case(&config, &shared, case).await?;
+        let mismatches = outcome.mismatches(&case.expect);
+        if mismatches.is_empty() {
+            println!("case '{}' ... ok", case.name);
+        } else {
+            failed += 1;
+            println!("case '{}' ... FAILED", case.name);
+            for mismatch in mismatches {
+                println!("    {}", mismatch);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} of {} policy test cases failed", failed, cases.len()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+        [masking.exclude]
+        columns = ["*"]
+
+        [policy]
+        default = "deny"
+
+        [policy.subjects.analysts]
+        users = ["alice"]
+        networks = ["10.0.0.0/8"]
+
+        [[policy.rules]]
+        resources = ["*.public"]
+        operations = ["SELECT"]
+        action = "allow"
+
+        [[policy.rules]]
+        subjects = ["analysts"]
+        resources = ["*.*.users.email"]
+        action = "mask"
+    "#;
+
+    const CASES: &str = r#"
+        cases:
+          - name: analysts get masked emails
+            session: { user: alice, address: 10.0.0.7 }
+            statement: SELECT id, email FROM users
+            columns: [id, email]
+            rows: [["1", "jane@example.com"], ["2", ~]]
+            expect:
+              allowed: true
+              rows: [["1", "******"], ["2", "******"]]
+          - name: others get clear emails
+            session: { user: bob, address: 10.0.0.8 }
+            statement: SELECT email FROM users
+            columns: [email]
+            rows: [["jane@example.com"]]
+            expect:
+              rows: [["jane@example.com"]]
+          - name: deletes are denied
+            session: { user: alice }
+            statement: DELETE FROM users
+            expect:
+              allowed: false
+              error: permission denied by Fern for DELETE on users
+    "#;
+
+    fn config() -> SQLHandlerConfig {
+        config::Config::builder()
+            .add_source(config::File::from_str(POLICY, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    fn shared(config: &SQLHandlerConfig) -> Shared {
+        Shared::new(config, Classification::default()).unwrap()
+    }
+
+    fn cases() -> Vec<Case> {
+        config::Config::builder()
+            .add_source(config::File::from_str(CASES, config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+            .try_deserialize::<Cases>()
+            .unwrap()
+            .cases
+    }
+
+    #[tokio::test]
+    async fn valid_cases() {
+        let config = config();
+        for case in cases() {
+            let outcome = run_case(&config, &shared(&config), &case).await.unwrap();
+            assert_eq!(
+                Vec::<String>::new(),
+                outcome.mismatches(&case.expect),
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_case() {
+        let config = config();
+        let mut case = cases().remove(1);
+        case.expect.rows = Some(vec![vec![Some("******".to_string())]]);
+        case.expect.allowed = Some(false);
+        let outcome = run_case(&config, &shared(&config), &case).await.unwrap();
+        assert_eq!(
+            vec![
+                "allowed:\n      expected: false\n      actual:   true".to_string(),
+                "rows:\n      expected: [[Some(\"******\")]]\n      actual:   [[Some(\"jane@example.com\")]]"
+                    .to_string(),
+            ],
+            outcome.mismatches(&case.expect)
+        );
+    }
+
+    #[test]
+    fn valid_startup() {
+        let attributes = Attributes {
+            user: Some("alice".to_string()),
+            parameters: [("fern.tenant".to_string(), "7".to_string())].into(),
+            ..Attributes::default()
+        };
+        match startup(&attributes) {
+            frontend::Message::StartupMessage {
+                frame_length,
+                parameters,
+            } => {
+                // 4 + 4 + "user\0alice\0" + "fern.tenant\07\0" + 1
+                assert_eq!(9 + 11 + 14, frame_length);
+                assert_eq!(2, parameters.len());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        let error = backend::Message::error_response("42501", "permission denied");
+        match error {
+            backend::Message::ErrorResponse(fields) => {
+                assert_eq!("permission denied", error_message(fields))
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
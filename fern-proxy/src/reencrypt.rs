@@ -0,0 +1,414 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! `reencrypt` subcommand, encrypting again values of encrypted columns of
+//! a table with the current key, once keys rotated.
+//!
+//! Rows are read in batches ordered by a unique key column, over a regular
+//! PostgreSQL connection to the server defined by the `DATABASE_URL` env
+//! variable, and updated within a transaction per batch. A row is only
+//! updated if its values did not change meanwhile, so that applications can
+//! keep on writing through the proxy. After each batch, the last key value
+//! processed is recorded in a checkpoint file, so that an interrupted run
+//! resumes where it stopped.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::database::{self, to_io_error};
+use fern_encryption::Reencryptor;
+use fern_masking::SQLHandlerConfig;
+use tokio_postgres::{Client, SimpleQueryMessage, SimpleQueryRow};
+
+const USAGE: &str = "usage: fern-proxy reencrypt --table <table> --key <column> \
+    [--columns <column>,...] [--batch-size <rows>] [--checkpoint <file>]";
+
+/// Amount of rows processed per batch, unless defined otherwise.
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Options of the `reencrypt` subcommand.
+#[derive(Debug, PartialEq, Eq)]
+struct Options {
+    /// Table to process, optionally qualified by its schema.
+    table: String,
+
+    /// Unique column ordering rows, usually the primary key.
+    key: String,
+
+    /// Encrypted columns to process, all those of the table if empty.
+    columns: Vec<String>,
+
+    batch_size: usize,
+
+    /// File recording the last key value processed.
+    checkpoint: PathBuf,
+}
+
+impl Options {
+    /// Parses options from command line `args`, following the subcommand.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let (mut table, mut key, mut columns) = (None, None, vec![]);
+        let (mut batch_size, mut checkpoint) = (DEFAULT_BATCH_SIZE, None);
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of '{}'", arg))?;
+            match arg.as_str() {
+                "--table" => table = Some(value),
+                "--key" => key = Some(value),
+                "--columns" => columns = value.split(',').map(str::to_string).collect(),
+                "--batch-size" => {
+                    batch_size = match value.parse::<usize>() {
+                        Ok(size) if size > 0 => size,
+                        _ => return Err(format!("invalid batch size '{}'", value)),
+                    }
+                }
+                "--checkpoint" => checkpoint = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+
+        let table = table.ok_or("missing '--table'")?;
+        let checkpoint = checkpoint
+            .unwrap_or_else(|| PathBuf::from(format!("fern-reencrypt-{}.checkpoint", table)));
+        Ok(Self {
+            table,
+            key: key.ok_or("missing '--key'")?,
+            columns,
+            batch_size,
+            checkpoint,
+        })
+    }
+}
+
+/// Quotes an identifier, optionally qualified, e.g. `public.users`.
+fn quote_identifier(name: &str) -> String {
+    name.split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Quotes a literal value, of a type inferred by the server.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Returns the query selecting a batch of rows following key value `last`.
+fn select_batch(options: &Options, columns: &[String], last: Option<&str>) -> String {
+    let key = quote_identifier(&options.key);
+    let selected: Vec<String> = columns.iter().map(|c| quote_identifier(c)).collect();
+    let filter = match last {
+        Some(last) => format!(" WHERE {} > {}", key, quote_literal(last)),
+        None => String::new(),
+    };
+    format!(
+        "SELECT {}, {} FROM {}{} ORDER BY {} LIMIT {}",
+        key,
+        selected.join(", "),
+        quote_identifier(&options.table),
+        filter,
+        key,
+        options.batch_size
+    )
+}
+
+/// Returns the statement updating values of row with key value `key`, from
+/// `(column, former value, new value)` changes, provided they did not change.
+fn update_row(options: &Options, key: &str, changes: &[(&str, &str, String)]) -> String {
+    let assignments: Vec<String> = changes
+        .iter()
+        .map(|(column, _, value)| {
+            format!("{} = {}", quote_identifier(column), quote_literal(value))
+        })
+        .collect();
+    let conditions: Vec<String> = changes
+        .iter()
+        .map(|(column, former, _)| {
+            format!("{} = {}", quote_identifier(column), quote_literal(former))
+        })
+        .collect();
+    format!(
+        "UPDATE {} SET {} WHERE {} = {} AND {}",
+        quote_identifier(&options.table),
+        assignments.join(", "),
+        quote_identifier(&options.key),
+        quote_literal(key),
+        conditions.join(" AND ")
+    )
+}
+
+/// Returns the last key value recorded in `checkpoint`, if any.
+fn read_checkpoint(checkpoint: &Path) -> io::Result<Option<String>> {
+    match std::fs::read_to_string(checkpoint) {
+        Ok(last) => Ok(Some(last.trim_end_matches('\n').to_string())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Records key value `last` in `checkpoint`, atomically.
+fn write_checkpoint(checkpoint: &Path, last: &str) -> io::Result<()> {
+    let mut temporary = checkpoint.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, last)?;
+    std::fs::rename(&temporary, checkpoint)
+}
+
+/// Returns rows of simple query `messages`.
+fn rows(messages: Vec<SimpleQueryMessage>) -> Vec<SimpleQueryRow> {
+    messages
+        .into_iter()
+        .filter_map(|message| match message {
+            SimpleQueryMessage::Row(row) => Some(row),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns encrypted columns of `options.table`, or checks those requested.
+async fn encrypted_columns(
+    client: &Client,
+    options: &Options,
+    reencryptor: &Reencryptor,
+) -> io::Result<Vec<String>> {
+    if !options.columns.is_empty() {
+        return match options
+            .columns
+            .iter()
+            .find(|c| !reencryptor.is_encrypted(c))
+        {
+            Some(column) => Err(to_io_error(format!("column '{}' is not encrypted", column))),
+            None => Ok(options.columns.clone()),
+        };
+    }
+
+    let query = format!("SELECT * FROM {} LIMIT 0", quote_identifier(&options.table));
+    let statement = client.prepare(&query).await.map_err(to_io_error)?;
+    let columns: Vec<String> = statement
+        .columns()
+        .iter()
+        .map(|column| column.name().to_string())
+        .filter(|column| reencryptor.is_encrypted(column))
+        .collect();
+    if columns.is_empty() {
+        return Err(to_io_error(format!(
+            "no encrypted column in '{}'",
+            options.table
+        )));
+    }
+    Ok(columns)
+}
+
+/// Runs the `reencrypt` subcommand with command line `args`, and keys and
+/// encrypted columns defined in `config`.
+pub async fn run(args: impl Iterator<Item = String>, config: &SQLHandlerConfig) -> io::Result<()> {
+    let options = Options::parse(args).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n{}", err, USAGE))
+    })?;
+    let reencryptor = Reencryptor::from_config(config)?;
+    let version = reencryptor.keyring().current_version();
+
+    let client = database::connect().await?;
+
+    let columns = encrypted_columns(&client, &options, &reencryptor).await?;
+    let mut last = read_checkpoint(&options.checkpoint)?;
+    if let Some(last) = &last {
+        log::info!("resuming after {} = {}", options.key, last);
+    }
+
+    let filter = match &last {
+        Some(last) => format!(
+            " WHERE {} > {}",
+            quote_identifier(&options.key),
+            quote_literal(last)
+        ),
+        None => String::new(),
+    };
+    let query = format!(
+        "SELECT count(*) FROM {}{}",
+        quote_identifier(&options.table),
+        filter
+    );
+    let total: u64 = rows(client.simple_query(&query).await.map_err(to_io_error)?)
+        .first()
+        .and_then(|row| row.get(0))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_default();
+    log::info!(
+        "re-encrypting {:?} of {} rows of '{}' with key version {}",
+        columns,
+        total,
+        options.table,
+        version
+    );
+
+    let (mut processed, mut updated, mut skipped) = (0, 0, 0);
+    loop {
+        let query = select_batch(&options, &columns, last.as_deref());
+        let batch = rows(client.simple_query(&query).await.map_err(to_io_error)?);
+        let next = match batch.last() {
+            Some(row) => row.get(0),
+            None => break,
+        };
+        let next = next
+            .ok_or_else(|| to_io_error(format!("NULL value of key '{}'", options.key)))?
+            .to_string();
+
+        let mut statements = vec![];
+        for row in &batch {
+            let key = row.get(0).unwrap_or_default();
+            let mut changes = vec![];
+            for (idx, column) in columns.iter().enumerate() {
+                let value = match row.get(idx + 1) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let reencrypted =
+                    reencryptor
+                        .reencrypt(column, value.as_bytes())
+                        .map_err(|err| {
+                            to_io_error(format!(
+                                "'{}' of {} = {} - {}",
+                                column, options.key, key, err
+                            ))
+                        })?;
+                if let Some(reencrypted) = reencrypted {
+                    let reencrypted = String::from_utf8_lossy(&reencrypted).into_owned();
+                    changes.push((column.as_str(), value, reencrypted));
+                }
+            }
+            if !changes.is_empty() {
+                statements.push(update_row(&options, key, &changes));
+            }
+        }
+
+        // Statements of a single simple query run within a single transaction.
+        if !statements.is_empty() {
+            let messages = client
+                .simple_query(&statements.join(";\n"))
+                .await
+                .map_err(to_io_error)?;
+            let done: u64 = messages
+                .iter()
+                .map(|message| match message {
+                    SimpleQueryMessage::CommandComplete(rows) => *rows,
+                    _ => 0,
+                })
+                .sum();
+            updated += done;
+            skipped += statements.len() as u64 - done;
+        }
+
+        processed += batch.len() as u64;
+        write_checkpoint(&options.checkpoint, &next)?;
+        log::info!(
+            "{}/{} rows processed ({}%), {} re-encrypted, {} changed meanwhile",
+            processed,
+            total,
+            processed * 100 / total.max(processed).max(1),
+            updated,
+            skipped
+        );
+        last = Some(next);
+    }
+
+    std::fs::remove_file(&options.checkpoint).or_else(|err| match err.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(err),
+    })?;
+    log::info!(
+        "done - {} rows processed, {} re-encrypted with key version {}",
+        processed,
+        updated,
+        version
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn valid_options() {
+        let parsed = options(&["--table", "public.users", "--key", "id"]).unwrap();
+        let expected = Options {
+            table: "public.users".to_string(),
+            key: "id".to_string(),
+            columns: vec![],
+            batch_size: DEFAULT_BATCH_SIZE,
+            checkpoint: PathBuf::from("fern-reencrypt-public.users.checkpoint"),
+        };
+        assert_eq!(expected, parsed);
+
+        let args = [
+            "--columns",
+            "email,phone",
+            "--batch-size",
+            "10",
+            "--checkpoint",
+            "/tmp/users",
+            "--table",
+            "users",
+            "--key",
+            "id",
+        ];
+        let parsed = options(&args).unwrap();
+        assert_eq!(vec!["email", "phone"], parsed.columns);
+        assert_eq!(10, parsed.batch_size);
+        assert_eq!(PathBuf::from("/tmp/users"), parsed.checkpoint);
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(options(&["--table", "users"]).is_err(), "no key");
+        assert!(options(&["--key", "id"]).is_err(), "no table");
+        assert!(options(&["--table"]).is_err(), "no value");
+        assert!(options(&["--table", "t", "--key", "id", "--batch-size", "0"]).is_err());
+        assert!(options(&["--table", "t", "--key", "id", "--force", "yes"]).is_err());
+    }
+
+    #[test]
+    fn valid_statements() {
+        let options =
+            options(&["--table", "app.us\"ers", "--key", "id", "--batch-size", "2"]).unwrap();
+        let columns = vec!["email".to_string(), "phone".to_string()];
+        assert_eq!(
+            r#"SELECT "id", "email", "phone" FROM "app"."us""ers" ORDER BY "id" LIMIT 2"#,
+            select_batch(&options, &columns, None)
+        );
+        assert_eq!(
+            r#"SELECT "id", "email", "phone" FROM "app"."us""ers" WHERE "id" > 'o''k' ORDER BY "id" LIMIT 2"#,
+            select_batch(&options, &columns, Some("o'k"))
+        );
+
+        let changes = [("email", "AQ==", "Ag==".to_string())];
+        assert_eq!(
+            r#"UPDATE "app"."us""ers" SET "email" = 'Ag==' WHERE "id" = '7' AND "email" = 'AQ=='"#,
+            update_row(&options, "7", &changes)
+        );
+    }
+
+    #[test]
+    fn valid_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("fern-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let checkpoint = dir.join("users.checkpoint");
+        assert_eq!(None, read_checkpoint(&checkpoint).unwrap());
+        write_checkpoint(&checkpoint, "42").unwrap();
+        write_checkpoint(&checkpoint, "1000").unwrap();
+        std::fs::write(dir.join("edited"), "1000\n").unwrap();
+        let edited = read_checkpoint(&dir.join("edited")).unwrap();
+        assert_eq!(Some("1000".to_string()), edited, "edited by hand");
+        assert_eq!(
+            Some("1000".to_string()),
+            read_checkpoint(&checkpoint).unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,6 +6,7 @@ use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc, Semaphore};
 
+use crate::chain::Shared;
 use crate::connection::Connection;
 use crate::shutdown::Shutdown;
 
@@ -28,6 +29,9 @@ struct Listener {
     limit_connections: Arc<Semaphore>,
     shutdown_complete_rx: mpsc::Receiver<()>,
     shutdown_complete_tx: mpsc::Sender<()>,
+
+    /// State shared by handlers of all connections.
+    shared: Shared,
 }
 
 /// Per-connection handler.
@@ -146,7 +150,8 @@ impl Listener {
             // Initialize per-connection handler state.
             let mut handler = Handler {
                 // Initialize connection state (buffered wrapper for `TcpStream`).
-                connection: Connection::new(client_socket, server_socket, config).await,
+                connection: Connection::new(client_socket, server_socket, config, &self.shared)
+                    .await,
 
                 // Receive shutdown notification.
                 shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
@@ -217,6 +222,7 @@ pub async fn run(
     srv_addr: &str,
     shutdown: impl Future,
     config: &config::Config,
+    shared: Shared,
 ) {
     // When the provided `shutdown` future completes, i.e. shutdown signal is
     // received, the shutdown signal must be propagated to to all active connections.
@@ -233,6 +239,7 @@ pub async fn run(
         notify_shutdown,
         shutdown_complete_rx,
         shutdown_complete_tx,
+        shared,
     };
 
     // Infinite loop, unless a critical error or shutdown signal is encountered.
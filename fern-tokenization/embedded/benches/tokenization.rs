@@ -0,0 +1,61 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throughput of vault-based and vaultless tokenization modes.
+//!
+//! Run with `cargo bench -p fern-tokenization`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use fern_tokenization::{TokenVault, VaultlessTokenizer};
+
+const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+const VALUE: &[u8] = b"jane.doe@example.com";
+
+fn tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+
+    let dir = tempfile::tempdir().unwrap();
+    let vault = TokenVault::open(dir.path().join("tokens.db"), "tok_").unwrap();
+    vault.tokenize(VALUE).unwrap();
+    group.bench_function("vault/known", |b| b.iter(|| vault.tokenize(VALUE).unwrap()));
+
+    let mut idx = 0_u64;
+    group.bench_function("vault/new", |b| {
+        b.iter_batched(
+            || {
+                idx += 1;
+                format!("user{}@example.com", idx)
+            },
+            |value| vault.tokenize(value.as_bytes()).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let vaultless = VaultlessTokenizer::new("tkv_", 1, KEY);
+    group.bench_function("vaultless", |b| {
+        b.iter(|| vaultless.tokenize(VALUE).unwrap())
+    });
+
+    group.finish();
+}
+
+fn detokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("detokenize");
+
+    let dir = tempfile::tempdir().unwrap();
+    let vault = TokenVault::open(dir.path().join("tokens.db"), "tok_").unwrap();
+    let token = vault.tokenize(VALUE).unwrap();
+    group.bench_function("vault", |b| b.iter(|| vault.detokenize(&token).unwrap()));
+
+    let vaultless = VaultlessTokenizer::new("tkv_", 1, KEY);
+    let token = vaultless.tokenize(VALUE).unwrap();
+    group.bench_function("vaultless", |b| {
+        b.iter(|| vaultless.detokenize(&token).unwrap())
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, tokenize, detokenize);
+criterion_main!(benches);
@@ -1,8 +1,750 @@
 // SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
 // SPDX-License-Identifier: Apache-2.0
 
+//! Embedded data tokenization for Fern proxy.
+//!
+//! Values of selected columns are replaced by tokens in query results, with
+//! a [`TokenizationHandler`]. Tokens sent back by applications, in `Query`
+//! literals or `Bind` parameters, are replaced by original values before
+//! reaching the server, with a [`DetokenizationHandler`], so that tokens
+//! can be used transparently in following queries. Both handlers share a
+//! [`TokenVault`], opened once for all sessions.
+//!
+//! Columns whose tokens must keep the format of original values can rather
+//! be tokenized with a [`FormatPreservingTokenizer`], needing no vault. Where
+//! throughput matters, a [`VaultlessTokenizer`] derives reversible tokens
+//! from values and a versioned key, also needing no vault.
+//!
+//! Columns may also be designated by `tokenize` rules of the policy document
+//! (see `fern_policy_engine`), with the same settings in their rule.
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use fern_policy_engine::{Action, Policy};
+use fern_protocol_postgresql::codec::{backend, frontend};
+use fern_protocol_postgresql::tracker::DescriptionTracker;
+use fern_proxy_interfaces::SQLMessageHandler;
+
+// Re-export.
+pub use fern_proxy_interfaces::SQLHandlerConfig;
+pub use preserving::{Alphabet, FormatPreservingTokenizer};
+pub use vault::TokenVault;
+pub use vaultless::VaultlessTokenizer;
+
+mod literals;
+mod preserving;
+mod vault;
+mod vaultless;
+
+/// Tokenization mode of a column.
+#[derive(Debug)]
+enum Mode {
+    /// Random tokens, mapped to original values in a [`TokenVault`].
+    Vault,
+
+    /// Tokens keeping the format of original values, in text format only,
+    /// if the tokenizer could be set up.
+    FormatPreserving(Option<Box<FormatPreservingTokenizer>>),
+
+    /// Deterministic tokens, derived from values with a versioned key.
+    Vaultless,
+}
+
+impl Mode {
+    /// Gets the `mode` setting found under `key` of `config`, `vault` by default.
+    fn from_config(config: &SQLHandlerConfig, key: &str) -> Self {
+        match config.get::<String>(&format!("{}.mode", key)).as_deref() {
+            Ok("vault") | Err(_) => Self::Vault,
+            Ok("vaultless") => Self::Vaultless,
+            Ok("format-preserving") => match FormatPreservingTokenizer::from_config(config, key) {
+                Ok(tokenizer) => Self::FormatPreserving(Some(Box::new(tokenizer))),
+                Err(err) => {
+                    // Note: failing closed, values will be replaced by NULL.
+                    log::error!("cannot set up format-preserving tokenization - {}", err);
+                    Self::FormatPreserving(None)
+                }
+            },
+            Ok(other) => {
+                log::warn!("unknown tokenization mode '{}', using 'vault'", other);
+                Self::Vault
+            }
+        }
+    }
+}
+
+/// Returns whether tokenization is defined in `config`, or by `tokenize`
+/// rules of the compiled `policy` document, if any.
+pub fn is_enabled(config: &SQLHandlerConfig, policy: Option<&Policy>) -> bool {
+    config
+        .get_table("tokenization.columns")
+        .map_or(false, |columns| !columns.is_empty())
+        || policy.map_or(false, |policy| !policy.columns(Action::Tokenize).is_empty())
+}
+
+/// Compiles the policy document defined in `config`, if any, for handlers
+/// created on their own.
+fn policy_from_config(config: &SQLHandlerConfig) -> Option<Policy> {
+    Policy::from_config(config)
+        .map_err(|err| log::error!("ignoring tokenization rules of policy - {}", err))
+        .ok()
+        .flatten()
+}
+
+/// An `SQLMessageHandler` replacing values of selected columns
+/// in `DataRow`s with tokens.
+///
+/// Columns to tokenize are defined in `tokenization.columns` table of
+/// `SQLHandlerConfig`, and by `tokenize` rules of its policy document.
+/// `DataRow`s with no known description are forwarded untouched, for a
+/// following masking handler to deal with.
+///
+/// Tokens of `vault` columns are stored in the vault set with `with_vault`,
+/// without which their values are replaced by `NULL`.
+#[derive(Debug)]
+pub struct TokenizationHandler {
+    /// Vault where tokens are stored, if set.
+    vault: Option<Arc<TokenVault>>,
+
+    /// Tokenizer of vaultless tokens, if keys are defined.
+    vaultless: Option<VaultlessTokenizer>,
+
+    /// Tracker of descriptions applying to incoming `DataRow`s.
+    tracker: DescriptionTracker,
+
+    /// Whether backend Messages are tracked by this Handler, rather than
+    /// by a shared `DescriptionTracker` preceding it in a chain of handlers.
+    tracking: bool,
+
+    /// Column names to tokenize, with their tokenization mode.
+    columns: Vec<(Bytes, Mode)>,
+}
+
+impl TokenizationHandler {
+    /// Creates a handler with settings of `config`, and columns of `tokenize`
+    /// rules of the already compiled `policy` document, if any.
+    pub fn from_policy(config: &SQLHandlerConfig, policy: Option<&Policy>) -> Self {
+        let mut columns = vec![];
+        if let Ok(table) = config.get_table("tokenization.columns") {
+            for column_name in table.keys() {
+                let key = format!("tokenization.columns.{}", column_name);
+                let mode = Mode::from_config(config, &key);
+                log::debug!("column '{}' tokenized with: {:?}", column_name, mode);
+                columns.push((Bytes::from(column_name.clone()), mode));
+            }
+        }
+        let policy_columns =
+            policy.map_or_else(Vec::new, |policy| policy.columns(Action::Tokenize));
+        for (column_name, idx) in policy_columns {
+            // Note: settings of `tokenization.columns` prevail.
+            if !columns.iter().any(|(name, _)| *name == column_name) {
+                let mode = Mode::from_config(config, &format!("policy.rules[{}]", idx));
+                log::debug!("column '{}' tokenized with: {:?}", column_name, mode);
+                columns.push((Bytes::from(column_name), mode));
+            }
+        }
+
+        Self {
+            vault: None,
+            vaultless: VaultlessTokenizer::from_config(config),
+            tracker: DescriptionTracker::new(),
+            tracking: true,
+            columns,
+        }
+    }
+
+    /// Uses a `vault` shared with a `DetokenizationHandler`.
+    #[must_use]
+    pub fn with_vault(mut self, vault: Arc<TokenVault>) -> Self {
+        self.vault = Some(vault);
+        self
+    }
+
+    /// Uses a `tracker` shared with the frontend `Pipe`, to follow
+    /// descriptions of prepared statements and portals.
+    ///
+    /// Backend Messages are expected to be tracked by `tracker` itself, as an
+    /// `SQLMessageHandler` preceding this one.
+    #[must_use]
+    pub fn with_tracker(mut self, tracker: DescriptionTracker) -> Self {
+        self.tracker = tracker;
+        self.tracking = false;
+        self
+    }
+
+    /// Replaces `value` of a column tokenized with `mode` by its token,
+    /// where `value` is in text `format` if zero, or in binary format.
+    ///
+    /// Values of `vault` columns are rather tokenized by row, in `process`.
+    fn tokenize(&self, value: &Bytes, format: u16, mode: &Mode) -> Option<Bytes> {
+        let token = match mode {
+            Mode::Vault => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "vault tokens are only looked up by row",
+            )),
+            Mode::FormatPreserving(None) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no valid key for format-preserving tokenization",
+            )),
+            Mode::FormatPreserving(Some(tokenizer)) if format == 0 => tokenizer.tokenize(value),
+            Mode::FormatPreserving(Some(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "format-preserving tokenization of binary data",
+            )),
+            Mode::Vaultless => match &self.vaultless {
+                Some(tokenizer) => tokenizer.tokenize(value),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no key defined for vaultless tokenization",
+                )),
+            },
+        };
+        match token {
+            Ok(token) => Some(token),
+            Err(err) => {
+                // Note: failing closed, value is not forwarded.
+                log::error!("cannot tokenize value, replaced by NULL - {}", err);
+                None
+            }
+        }
+    }
+}
+
+//TODO(ppiotr3k): this crate should only process abstracted types
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for TokenizationHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self::from_policy(config, policy_from_config(config).as_ref())
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        if self.tracking {
+            self.tracker.track_backend(&msg);
+        }
+
+        let fields = match msg {
+            backend::Message::DataRow(fields) => fields,
+            _ => return Some(msg),
+        };
+
+        let descriptions = match self.tracker.row_description() {
+            Some(descriptions) if descriptions.len() == fields.len() => descriptions,
+            _ => {
+                log::debug!("no matching description for `DataRow`, not tokenizing");
+                return Some(backend::Message::DataRow(fields));
+            }
+        };
+
+        // Note: tokens of the vault are looked up at once, for all fields.
+        let mut vaulted = vec![];
+        let mut fields: Vec<Option<Bytes>> = fields
+            .into_iter()
+            .zip(descriptions.iter())
+            .enumerate()
+            .map(|(idx, (field, description))| {
+                let column = self
+                    .columns
+                    .iter()
+                    .find(|(name, _)| *name == description.name);
+                match (field, column) {
+                    (Some(value), Some((_, Mode::Vault))) => {
+                        vaulted.push(idx);
+                        Some(value)
+                    }
+                    (Some(value), Some((_, mode))) => {
+                        self.tokenize(&value, description.format, mode)
+                    }
+                    (field, _) => field,
+                }
+            })
+            .collect();
+
+        if !vaulted.is_empty() {
+            // Note: failing closed, values are not forwarded until tokenized.
+            let values = vaulted
+                .iter()
+                .filter_map(|idx| fields[*idx].take())
+                .collect();
+            let tokens = match &self.vault {
+                Some(vault) => vault.clone().tokenize_all(values).await,
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no vault set for vault tokenization",
+                )),
+            };
+            match tokens {
+                Ok(tokens) => {
+                    for (idx, token) in vaulted.into_iter().zip(tokens) {
+                        fields[idx] = Some(token);
+                    }
+                }
+                Err(err) => log::error!("cannot tokenize values, replaced by NULL - {}", err),
+            }
+        }
+        Some(backend::Message::DataRow(fields))
+    }
+}
+
+/// Writes `data` to `dst`, with tokens matching `pattern` replaced by values
+/// found with `lookup`, written with `put`. Returns whether any token was replaced.
+fn replace_tokens<L, F>(data: &[u8], pattern: &Regex, lookup: L, dst: &mut BytesMut, put: F) -> bool
+where
+    L: Fn(&[u8]) -> io::Result<Option<Bytes>>,
+    F: Fn(&[u8], &mut BytesMut),
+{
+    let mut replaced = false;
+
+    let mut last = 0;
+    for token in pattern.find_iter(data) {
+        let value = match lookup(token.as_bytes()) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(err) => {
+                log::error!("cannot detokenize, forwarding token - {}", err);
+                continue;
+            }
+        };
+        dst.put_slice(&data[last..token.start()]);
+        put(&value, dst);
+        last = token.end();
+        replaced = true;
+    }
+    dst.put_slice(&data[last..]);
+
+    replaced
+}
+
+/// An `SQLMessageHandler` replacing tokens found in `Query` and `Parse`
+/// string literals, and in text `Bind` parameters, with original values.
+///
+/// Unknown tokens are forwarded untouched, as well as tokens of the vault
+/// unless it is set with `with_vault`.
+#[derive(Debug)]
+pub struct DetokenizationHandler {
+    /// Vault where tokens are stored, if set.
+    vault: Option<Arc<TokenVault>>,
+
+    /// Tokenizer of vaultless tokens, if keys are defined.
+    vaultless: Option<VaultlessTokenizer>,
+}
+
+impl DetokenizationHandler {
+    /// Uses a `vault` shared with a `TokenizationHandler`.
+    #[must_use]
+    pub fn with_vault(mut self, vault: Arc<TokenVault>) -> Self {
+        self.vault = Some(vault);
+        self
+    }
+
+    /// Returns values of the vault tokens found in `data`, looked up at once.
+    async fn vault_values(&self, data: &[&Bytes]) -> HashMap<Bytes, Bytes> {
+        let vault = match &self.vault {
+            Some(vault) => vault,
+            None => return HashMap::new(),
+        };
+        let mut tokens = vec![];
+        for token in data.iter().flat_map(|data| vault.pattern().find_iter(data)) {
+            let token = Bytes::copy_from_slice(token.as_bytes());
+            if !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+        if tokens.is_empty() {
+            return HashMap::new();
+        }
+
+        match vault.clone().detokenize_all(tokens.clone()).await {
+            Ok(values) => tokens
+                .into_iter()
+                .zip(values)
+                .filter_map(|(token, value)| Some((token, value?)))
+                .collect(),
+            Err(err) => {
+                log::error!("cannot detokenize, forwarding tokens - {}", err);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Writes `data` to `dst`, with tokens replaced by original values, known
+    /// from `vault_values` for tokens of the vault, written with `put`.
+    /// Returns whether any token was replaced.
+    fn detokenize<F>(
+        &self,
+        data: &[u8],
+        vault_values: &HashMap<Bytes, Bytes>,
+        dst: &mut BytesMut,
+        put: F,
+    ) -> bool
+    where
+        F: Fn(&[u8], &mut BytesMut),
+    {
+        let mut rewritten = BytesMut::with_capacity(data.len());
+        let mut replaced = false;
+        if let Some(vault) = &self.vault {
+            replaced |= replace_tokens(
+                data,
+                vault.pattern(),
+                |token| Ok(vault_values.get(token).cloned()),
+                &mut rewritten,
+                &put,
+            );
+        } else {
+            rewritten.put_slice(data);
+        }
+        if let Some(vaultless) = &self.vaultless {
+            let data = rewritten.split().freeze();
+            replaced |= replace_tokens(
+                &data,
+                vaultless.pattern(),
+                |token| vaultless.detokenize(token),
+                &mut rewritten,
+                &put,
+            );
+        }
+        dst.put(rewritten);
+
+        replaced
+    }
+
+    /// Returns `query` with tokens in string literals replaced, if any.
+    async fn detokenize_query(&self, query: &Bytes) -> Option<Bytes> {
+        let vault_values = self.vault_values(&[query]).await;
+        literals::rewrite(query, |content, escape, dst| {
+            let mut rewritten = BytesMut::new();
+            let replaced = self.detokenize(content, &vault_values, &mut rewritten, |value, dst| {
+                literals::put_escaped(value, escape, dst)
+            });
+            if replaced {
+                dst.put(rewritten);
+            }
+            replaced
+        })
+    }
+}
+
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for DetokenizationHandler {
+    fn new(config: &SQLHandlerConfig) -> Self {
+        Self {
+            vault: None,
+            vaultless: VaultlessTokenizer::from_config(config),
+        }
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        match msg {
+            frontend::Message::Query(query) => {
+                let query = self.detokenize_query(&query).await.unwrap_or(query);
+                Some(frontend::Message::Query(query))
+            }
+            frontend::Message::Parse {
+                stmt_name,
+                query,
+                parameters_types,
+            } => {
+                let query = self.detokenize_query(&query).await.unwrap_or(query);
+                Some(frontend::Message::Parse {
+                    stmt_name,
+                    query,
+                    parameters_types,
+                })
+            }
+            frontend::Message::Bind {
+                portal,
+                stmt_name,
+                mut parameters,
+                results_formats,
+            } => {
+                // Note: binary parameters are not expected to hold tokens.
+                let texts: Vec<&Bytes> = parameters
+                    .iter()
+                    .filter(|p| p.format == 0)
+                    .filter_map(|p| p.value.as_ref())
+                    .collect();
+                let vault_values = self.vault_values(&texts).await;
+                for parameter in parameters.iter_mut().filter(|p| p.format == 0) {
+                    if let Some(value) = &parameter.value {
+                        let mut rewritten = BytesMut::new();
+                        let put = |value: &[u8], dst: &mut BytesMut| dst.put_slice(value);
+                        if self.detokenize(value, &vault_values, &mut rewritten, put) {
+                            parameter.value = Some(rewritten.freeze());
+                        }
+                    }
+                }
+                Some(frontend::Message::Bind {
+                    portal,
+                    stmt_name,
+                    parameters,
+                    results_formats,
+                })
+            }
+            _ => Some(msg),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn it_works() {}
+    use bytes::Bytes;
+    use fern_protocol_postgresql::codec::backend::{self, RowDescription};
+    use fern_protocol_postgresql::codec::frontend::{self, BindParameter};
+    use fern_proxy_interfaces::SQLMessageHandler;
+    use std::sync::Arc;
+
+    use super::{DetokenizationHandler, Policy, SQLHandlerConfig, TokenVault, TokenizationHandler};
+
+    /// Helper function building handlers sharing an in-memory vault,
+    /// tokenizing column "email".
+    fn handlers() -> (TokenizationHandler, DetokenizationHandler) {
+        let config = SQLHandlerConfig::builder()
+            .set_override("tokenization.columns.email.mode", "vault")
+            .unwrap()
+            .build()
+            .unwrap();
+        let vault = Arc::new(TokenVault::in_memory("tok_").unwrap());
+        (
+            TokenizationHandler::new(&config).with_vault(vault.clone()),
+            DetokenizationHandler::new(&config).with_vault(vault),
+        )
+    }
+
+    /// Helper function building a `RowDescription` for a text column.
+    fn text_column(name: &'static str) -> RowDescription {
+        RowDescription {
+            name: Bytes::from_static(name.as_bytes()),
+            table_oid: 0,
+            column_attr: 0,
+            data_type_oid: 25,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+
+    /// Helper function returning the token of `email` in a result row.
+    async fn tokenize(handler: &mut TokenizationHandler, email: &'static str) -> Bytes {
+        let description = vec![text_column("id"), text_column("email")];
+        handler
+            .process(backend::Message::RowDescription(description))
+            .await;
+        let row = backend::Message::DataRow(vec![
+            Some(Bytes::from_static(b"1")),
+            Some(Bytes::from_static(email.as_bytes())),
+        ]);
+        match handler.process(row).await {
+            Some(backend::Message::DataRow(fields)) => {
+                assert_eq!(Some(Bytes::from_static(b"1")), fields[0], "untouched");
+                fields[1].clone().unwrap()
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn policy_columns_tokenized() {
+        let document = r#"
+            [[policy.rules]]
+            resources = ["*.*.users.email"]
+            action = "tokenize"
+        "#;
+        let config = SQLHandlerConfig::builder()
+            .add_source(config::File::from_str(document, config::FileFormat::Toml))
+            .build()
+            .unwrap();
+        assert!(!super::is_enabled(&config, None));
+        let policy = Policy::from_config(&config).unwrap().unwrap();
+        assert!(super::is_enabled(&config, Some(&policy)));
+        let vault = Arc::new(TokenVault::in_memory("tok_").unwrap());
+        let mut tokenizer =
+            TokenizationHandler::from_policy(&config, Some(&policy)).with_vault(vault.clone());
+        let token = tokenize(&mut tokenizer, "jane@example.com").await;
+        assert!(token.starts_with(b"tok_"), "token: {:?}", token);
+
+        // Handlers created on their own compile the policy document themselves.
+        let mut tokenizer = TokenizationHandler::new(&config).with_vault(vault);
+        let token = tokenize(&mut tokenizer, "jane@example.com").await;
+        assert!(token.starts_with(b"tok_"), "token: {:?}", token);
+    }
+
+    #[tokio::test]
+    async fn data_row_tokenized() {
+        let (mut tokenizer, _) = handlers();
+        let token = tokenize(&mut tokenizer, "jane@example.com").await;
+        assert!(token.starts_with(b"tok_"), "token: {:?}", token);
+        let again = tokenize(&mut tokenizer, "jane@example.com").await;
+        assert_eq!(token, again, "consistent token");
+
+        let row = backend::Message::DataRow(vec![Some(Bytes::from_static(b"1")), None]);
+        let expected = row.clone();
+        assert_eq!(Some(expected), tokenizer.process(row).await, "NULL value");
+    }
+
+    #[tokio::test]
+    async fn data_row_format_preserving() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("tokenization.columns.card.mode", "format-preserving")
+            .unwrap()
+            .set_override("tokenization.fpe.key", "00".repeat(32))
+            .unwrap()
+            .set_override("tokenization.columns.card.fpe.keep_suffix", 4)
+            .unwrap()
+            .set_override("tokenization.columns.card.fpe.luhn", true)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut tokenizer = TokenizationHandler::new(&config);
+
+        let mut binary = text_column("card");
+        binary.format = 1;
+        let descriptions = [vec![text_column("card")], vec![binary]];
+        let mut results = vec![];
+        for description in descriptions {
+            tokenizer
+                .process(backend::Message::RowDescription(description))
+                .await;
+            let row =
+                backend::Message::DataRow(vec![Some(Bytes::from_static(b"4111-1111-1111-1111"))]);
+            results.push(tokenizer.process(row).await);
+        }
+
+        let token = match &results[0] {
+            Some(backend::Message::DataRow(fields)) => fields[0].clone().unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        assert_eq!(19, token.len(), "same length");
+        assert_ne!(&b"4111-1111-1111"[..], &token[..14], "tokenized");
+        assert_eq!(&b"-1111"[..], &token[14..], "suffix kept");
+
+        let expected = backend::Message::DataRow(vec![None]);
+        assert_eq!(Some(expected), results[1], "binary format");
+    }
+
+    #[tokio::test]
+    async fn data_row_format_preserving_without_key() {
+        for key in [None, Some("00")] {
+            let mut builder = SQLHandlerConfig::builder()
+                .set_override("tokenization.columns.card.mode", "format-preserving")
+                .unwrap();
+            if let Some(key) = key {
+                builder = builder.set_override("tokenization.fpe.key", key).unwrap();
+            }
+            let mut tokenizer = TokenizationHandler::new(&builder.build().unwrap());
+
+            let description = vec![text_column("card")];
+            tokenizer
+                .process(backend::Message::RowDescription(description))
+                .await;
+            let row = backend::Message::DataRow(vec![Some(Bytes::from_static(b"4111"))]);
+            let expected = backend::Message::DataRow(vec![None]);
+            assert_eq!(
+                Some(expected),
+                tokenizer.process(row).await,
+                "key {:?}",
+                key
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn vaultless_roundtrip() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("tokenization.columns.email.mode", "vaultless")
+            .unwrap()
+            .set_override("tokenization.vaultless.keys.1", "00".repeat(32))
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut tokenizer = TokenizationHandler::new(&config);
+        let mut detokenizer = DetokenizationHandler::new(&config);
+
+        let token = tokenize(&mut tokenizer, "jane@example.com").await;
+        assert!(token.starts_with(b"tkv_1_"), "token: {:?}", token);
+
+        let query = format!(
+            "SELECT 1 WHERE email = '{}'",
+            std::str::from_utf8(&token).unwrap()
+        );
+        let msg = frontend::Message::Query(Bytes::from(query));
+        let expected = "SELECT 1 WHERE email = 'jane@example.com'";
+        assert_eq!(
+            Some(frontend::Message::Query(Bytes::from_static(
+                expected.as_bytes()
+            ))),
+            detokenizer.process(msg).await
+        );
+    }
+
+    #[tokio::test]
+    async fn query_detokenized() {
+        let (mut tokenizer, mut detokenizer) = handlers();
+        let token = tokenize(&mut tokenizer, "o'hara@example.com").await;
+        let token = std::str::from_utf8(&token).unwrap();
+
+        let query = format!("SELECT * FROM t WHERE email = '{}' -- '{}'", token, token);
+        let msg = frontend::Message::Query(Bytes::from(query));
+        let expected = format!(
+            "SELECT * FROM t WHERE email = 'o''hara@example.com' -- '{}'",
+            token
+        );
+        assert_eq!(
+            Some(frontend::Message::Query(Bytes::from(expected))),
+            detokenizer.process(msg).await
+        );
+
+        let unknown = "SELECT 'tok_0123456789abcdef0123456789abcdef'";
+        let msg = frontend::Message::Query(Bytes::from_static(unknown.as_bytes()));
+        let expected = msg.clone();
+        assert_eq!(Some(expected), detokenizer.process(msg).await, "unknown");
+    }
+
+    #[tokio::test]
+    async fn bind_parameters_detokenized() {
+        let (mut tokenizer, mut detokenizer) = handlers();
+        let token = tokenize(&mut tokenizer, "jane@example.com").await;
+        let msg = frontend::Message::Bind {
+            portal: Bytes::new(),
+            stmt_name: Bytes::new(),
+            parameters: vec![
+                BindParameter {
+                    format: 0,
+                    value: Some(token.clone()),
+                },
+                BindParameter {
+                    format: 1,
+                    value: Some(token.clone()),
+                },
+                BindParameter {
+                    format: 0,
+                    value: None,
+                },
+            ],
+            results_formats: vec![],
+        };
+
+        let expected = frontend::Message::Bind {
+            portal: Bytes::new(),
+            stmt_name: Bytes::new(),
+            parameters: vec![
+                BindParameter {
+                    format: 0,
+                    value: Some(Bytes::from_static(b"jane@example.com")),
+                },
+                BindParameter {
+                    format: 1,
+                    value: Some(token),
+                },
+                BindParameter {
+                    format: 0,
+                    value: None,
+                },
+            ],
+            results_formats: vec![],
+        };
+        assert_eq!(Some(expected), detokenizer.process(msg).await);
+    }
 }
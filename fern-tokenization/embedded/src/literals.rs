@@ -0,0 +1,185 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Lightweight scanning of SQL queries for string literals, so that values
+//! are only substituted where the query holds constants.
+//!
+//! Standard (`'...'`) and escape (`E'...'`) string constants are recognized,
+//! skipping comments and quoted identifiers. Dollar-quoted string constants
+//! are left untouched.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use std::ops::Range;
+
+/// A string literal found in a query.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Literal {
+    /// Range of the literal content in the query, quotes excluded.
+    pub content: Range<usize>,
+
+    /// Whether backslash escapes apply, as in `E'...'` literals.
+    pub escape: bool,
+}
+
+/// Returns the string literals of `query`, in order.
+pub(crate) fn literals(query: &[u8]) -> Vec<Literal> {
+    let mut literals = vec![];
+
+    let mut idx = 0;
+    while idx < query.len() {
+        match query[idx] {
+            b'-' if query.get(idx + 1) == Some(&b'-') => {
+                // Comment until end of line.
+                idx = query[idx..]
+                    .iter()
+                    .position(|c| *c == b'\n')
+                    .map_or(query.len(), |end| idx + end);
+            }
+            b'/' if query.get(idx + 1) == Some(&b'*') => {
+                // Comment until closing, without nesting.
+                idx = query[idx + 2..]
+                    .windows(2)
+                    .position(|w| w == b"*/")
+                    .map_or(query.len(), |end| idx + 2 + end + 2);
+            }
+            b'"' => {
+                // Quoted identifier, where `""` is an escaped quote.
+                idx = closing(query, idx + 1, b'"', false).map_or(query.len(), |end| end + 1);
+            }
+            b'\'' => {
+                let escape = idx > 0
+                    && matches!(query[idx - 1], b'e' | b'E')
+                    && (idx == 1 || !is_identifier_char(query[idx - 2]));
+                let end = closing(query, idx + 1, b'\'', escape);
+                literals.push(Literal {
+                    content: idx + 1..end.unwrap_or(query.len()),
+                    escape,
+                });
+                idx = end.map_or(query.len(), |end| end + 1);
+            }
+            _ => idx += 1,
+        }
+    }
+
+    literals
+}
+
+/// Returns the position of the `quote` closing a sequence started at `start`,
+/// where a doubled `quote` or a backslash escape (if `escape`) do not close
+/// the sequence, or `None` if the sequence is unterminated.
+fn closing(query: &[u8], start: usize, quote: u8, escape: bool) -> Option<usize> {
+    let mut idx = start;
+    while idx < query.len() {
+        match query[idx] {
+            b'\\' if escape => idx += 2,
+            c if c == quote => {
+                if query.get(idx + 1) == Some(&quote) {
+                    idx += 2;
+                } else {
+                    return Some(idx);
+                }
+            }
+            _ => idx += 1,
+        }
+    }
+    None
+}
+
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$'
+}
+
+/// Writes `value` as the content of a string literal.
+pub(crate) fn put_escaped(value: &[u8], escape: bool, dst: &mut BytesMut) {
+    for c in value {
+        match c {
+            b'\'' => dst.put_slice(b"''"),
+            b'\\' if escape => dst.put_slice(b"\\\\"),
+            _ => dst.put_u8(*c),
+        }
+    }
+}
+
+/// Rewrites the content of string literals of `query` with `rewrite`,
+/// returning `None` if no literal was rewritten.
+///
+/// `rewrite` gets the content of a literal, and whether backslash escapes
+/// apply, and writes the new content to the buffer only if returning `true`.
+pub(crate) fn rewrite<F>(query: &[u8], mut rewrite: F) -> Option<Bytes>
+where
+    F: FnMut(&[u8], bool, &mut BytesMut) -> bool,
+{
+    let mut res = BytesMut::with_capacity(query.len());
+    let mut rewritten = false;
+
+    let mut last = 0;
+    for literal in literals(query) {
+        res.put_slice(&query[last..literal.content.start]);
+        let content = &query[literal.content.clone()];
+        if !rewrite(content, literal.escape, &mut res) {
+            res.put_slice(content);
+        } else {
+            rewritten = true;
+        }
+        last = literal.content.end;
+    }
+    res.put_slice(&query[last..]);
+
+    rewritten.then(|| res.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function returning literals contents of `query`.
+    fn contents(query: &str) -> Vec<&str> {
+        literals(query.as_bytes())
+            .into_iter()
+            .map(|literal| &query[literal.content])
+            .collect()
+    }
+
+    #[test]
+    fn valid_literals_simple() {
+        let query = "SELECT * FROM t WHERE a = 'x' AND b IN ('y', 'it''s')";
+        assert_eq!(vec!["x", "y", "it''s"], contents(query));
+    }
+
+    #[test]
+    fn valid_literals_skip_comments_and_identifiers() {
+        let query = "SELECT \"it's\" -- 'no'\nFROM t /* 'no' */ WHERE a = 'yes'";
+        assert_eq!(vec!["yes"], contents(query));
+    }
+
+    #[test]
+    fn valid_literals_escape_strings() {
+        let query = r"SELECT E'a\'b', e'c', name'd'";
+        let literals = literals(query.as_bytes());
+        assert_eq!(3, literals.len());
+        assert!(literals[0].escape, "escape string");
+        assert_eq!(r"a\'b", &query[literals[0].content.clone()]);
+        assert!(literals[1].escape, "lowercase escape string");
+        assert!(!literals[2].escape, "not an escape string");
+    }
+
+    #[test]
+    fn valid_literals_empty_and_unterminated() {
+        assert_eq!(vec!["", "abc"], contents("SELECT '', 'abc"));
+    }
+
+    #[test]
+    fn valid_rewrite() {
+        let query = b"SELECT 'a', 'b'";
+        let res = rewrite(query, |content, escape, dst| {
+            if content != b"b" {
+                return false;
+            }
+            put_escaped(b"it's", escape, dst);
+            true
+        });
+        assert_eq!(Some(Bytes::from_static(b"SELECT 'a', 'it''s'")), res);
+
+        assert_eq!(None, rewrite(query, |_, _, _| false), "untouched query");
+    }
+}
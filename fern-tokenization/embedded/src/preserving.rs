@@ -0,0 +1,394 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Format-preserving tokenization, for systems validating data formats.
+//!
+//! Tokens are computed with FF1 format-preserving encryption (NIST SP 800-38G)
+//! over an alphabet: characters of the alphabet are replaced by characters of
+//! the same alphabet, while other characters (e.g. separators) are kept in
+//! place, so that tokens keep the length and layout of original values.
+//!
+//! No vault is needed: tokens are reversible with the key, which must be
+//! protected accordingly. Tokens cannot be told apart from genuine values,
+//! and are thus not detokenized when found in queries.
+
+use aes::Aes256;
+use bytes::Bytes;
+use fpe::ff1::{FlexibleNumeralString, FF1};
+use std::fmt;
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+/// Size in bytes of FF1 keys, for AES-256.
+const KEY_SIZE: usize = 32;
+
+/// Characters substituted in tokens, in a fixed order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alphabet(Vec<char>);
+
+impl Alphabet {
+    /// Decimal digits, e.g. for card or social security numbers.
+    pub fn digits() -> Self {
+        Self(('0'..='9').collect())
+    }
+
+    /// Gets the alphabet called `name`, either `digits`, `hex`, `lower`,
+    /// `upper`, `letters`, or `alphanumeric`. Any other `name` is taken
+    /// as the list of characters of a custom alphabet.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let chars: Vec<char> = match name {
+            "digits" => ('0'..='9').collect(),
+            "hex" => ('0'..='9').chain('a'..='f').collect(),
+            "lower" => ('a'..='z').collect(),
+            "upper" => ('A'..='Z').collect(),
+            "letters" => ('A'..='Z').chain('a'..='z').collect(),
+            "alphanumeric" => ('0'..='9').chain('A'..='Z').chain('a'..='z').collect(),
+            custom => {
+                let mut chars: Vec<char> = vec![];
+                for c in custom.chars() {
+                    if !chars.contains(&c) {
+                        chars.push(c);
+                    }
+                }
+                chars
+            }
+        };
+        (chars.len() >= 2).then_some(Self(chars))
+    }
+
+    fn position(&self, c: char) -> Option<u16> {
+        self.0.iter().position(|a| *a == c).map(|idx| idx as u16)
+    }
+
+    fn is_digits(&self) -> bool {
+        *self == Self::digits()
+    }
+}
+
+/// Returns whether digits of `value` pass the Luhn checksum.
+fn is_luhn_valid(value: &[char]) -> bool {
+    let sum: u32 = value
+        .iter()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(idx, digit)| match idx % 2 {
+            0 => digit,
+            _ if digit > 4 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Maps an FF1 error to an `io::Error`.
+fn to_io_error<E: fmt::Display>(err: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("format-preserving tokenization - {}", err),
+    )
+}
+
+/// A tokenizer keeping length, alphabet, and layout of values,
+/// optionally keeping a prefix, a suffix, and the Luhn checksum validity.
+pub struct FormatPreservingTokenizer {
+    ff1: FF1<Aes256>,
+    alphabet: Alphabet,
+
+    /// Whether Luhn valid values yield Luhn valid tokens, and invalid ones
+    /// invalid tokens, e.g. for card numbers.
+    luhn: bool,
+
+    /// Amount of leading characters of the alphabet kept as is.
+    keep_prefix: usize,
+
+    /// Amount of trailing characters of the alphabet kept as is.
+    keep_suffix: usize,
+
+    /// Public FF1 tweak, so that identical values yield different tokens
+    /// in columns with different tweaks.
+    tweak: Vec<u8>,
+}
+
+impl fmt::Debug for FormatPreservingTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: key material is not disclosed.
+        f.debug_struct("FormatPreservingTokenizer")
+            .field("alphabet", &self.alphabet)
+            .field("luhn", &self.luhn)
+            .field("keep_prefix", &self.keep_prefix)
+            .field("keep_suffix", &self.keep_suffix)
+            .field("tweak", &self.tweak)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FormatPreservingTokenizer {
+    pub fn new(key: &[u8; KEY_SIZE], alphabet: Alphabet) -> Self {
+        let ff1 = FF1::<Aes256>::new(key, alphabet.0.len() as u32).expect("radix below 2^16");
+        Self {
+            ff1,
+            alphabet,
+            luhn: false,
+            keep_prefix: 0,
+            keep_suffix: 0,
+            tweak: vec![],
+        }
+    }
+
+    /// Keeps the Luhn checksum validity of values, with a `digits` alphabet.
+    #[must_use]
+    pub fn with_luhn(mut self) -> Self {
+        self.luhn = true;
+        self
+    }
+
+    /// Keeps `prefix` leading and `suffix` trailing characters of the alphabet.
+    #[must_use]
+    pub fn with_kept(mut self, prefix: usize, suffix: usize) -> Self {
+        self.keep_prefix = prefix;
+        self.keep_suffix = suffix;
+        self
+    }
+
+    #[must_use]
+    pub fn with_tweak(mut self, tweak: &[u8]) -> Self {
+        self.tweak = tweak.to_vec();
+        self
+    }
+
+    /// Creates a `FormatPreservingTokenizer` from settings found in `config`
+    /// under `key`:
+    /// - `fpe.key`: hex-encoded 256-bit key, or `tokenization.fpe.key` if
+    ///   undefined,
+    /// - `fpe.alphabet`: alphabet name or characters, `digits` by default,
+    /// - `fpe.luhn`: whether to keep Luhn checksum validity,
+    /// - `fpe.keep_prefix` and `fpe.keep_suffix`: amounts of characters kept,
+    /// - `fpe.tweak`: public tweak, empty by default.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no key is defined, or if it is invalid: a random key would
+    /// give tokens changing from a connection to another.
+    pub fn from_config(config: &SQLHandlerConfig, key: &str) -> io::Result<Self> {
+        let secret = config
+            .get::<String>(&format!("{}.fpe.key", key))
+            .or_else(|_| config.get::<String>("tokenization.fpe.key"));
+        let secret = match secret.map(|secret| hex::decode(secret.trim())) {
+            Ok(Ok(secret)) if secret.len() == KEY_SIZE => {
+                let mut bytes = [0; KEY_SIZE];
+                bytes.copy_from_slice(&secret);
+                bytes
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid key for '{}.fpe', expected {} hex-encoded bytes",
+                        key, KEY_SIZE
+                    ),
+                ))
+            }
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no key defined for '{}.fpe'", key),
+                ))
+            }
+        };
+
+        let alphabet = config
+            .get::<String>(&format!("{}.fpe.alphabet", key))
+            .map_or_else(
+                |_| Some(Alphabet::digits()),
+                |name| Alphabet::from_name(&name),
+            )
+            .unwrap_or_else(|| {
+                log::warn!("invalid alphabet for '{}.fpe', using 'digits'", key);
+                Alphabet::digits()
+            });
+
+        let mut tokenizer = Self::new(&secret, alphabet);
+        if config
+            .get::<bool>(&format!("{}.fpe.luhn", key))
+            .unwrap_or(false)
+        {
+            if tokenizer.alphabet.is_digits() {
+                tokenizer = tokenizer.with_luhn();
+            } else {
+                log::warn!("Luhn checksum for '{}.fpe' needs 'digits', ignored", key);
+            }
+        }
+        let kept = |setting: &str| {
+            config
+                .get::<usize>(&format!("{}.fpe.{}", key, setting))
+                .unwrap_or(0)
+        };
+        tokenizer = tokenizer.with_kept(kept("keep_prefix"), kept("keep_suffix"));
+        if let Ok(tweak) = config.get::<String>(&format!("{}.fpe.tweak", key)) {
+            tokenizer = tokenizer.with_tweak(tweak.as_bytes());
+        }
+        Ok(tokenizer)
+    }
+
+    /// Returns the token standing for `value`.
+    pub fn tokenize(&self, value: &[u8]) -> io::Result<Bytes> {
+        self.transform(value, |numerals| {
+            self.ff1
+                .encrypt(&self.tweak, &numerals)
+                .map_err(to_io_error)
+        })
+    }
+
+    /// Returns the value `token` stands for.
+    pub fn detokenize(&self, token: &[u8]) -> io::Result<Bytes> {
+        self.transform(token, |numerals| {
+            self.ff1
+                .decrypt(&self.tweak, &numerals)
+                .map_err(to_io_error)
+        })
+    }
+
+    /// Applies `cipher` to the characters of `data` which are in the alphabet
+    /// and not kept, cycling until Luhn checksum validity is kept if needed.
+    fn transform<F>(&self, data: &[u8], cipher: F) -> io::Result<Bytes>
+    where
+        F: Fn(FlexibleNumeralString) -> io::Result<FlexibleNumeralString>,
+    {
+        let mut chars: Vec<char> = std::str::from_utf8(data)
+            .map_err(to_io_error)?
+            .chars()
+            .collect();
+
+        let positions: Vec<usize> = chars
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, c)| self.alphabet.position(*c).map(|_| idx))
+            .collect();
+        if positions.len() < self.keep_prefix + self.keep_suffix {
+            return Err(to_io_error("value shorter than kept characters"));
+        }
+        let positions = &positions[self.keep_prefix..positions.len() - self.keep_suffix];
+
+        let luhn_valid = is_luhn_valid(&chars);
+        let mut numerals: Vec<u16> = positions
+            .iter()
+            .filter_map(|idx| self.alphabet.position(chars[*idx]))
+            .collect();
+        loop {
+            numerals = cipher(numerals.into())?.into();
+            for (idx, numeral) in positions.iter().zip(numerals.iter()) {
+                chars[*idx] = self.alphabet.0[*numeral as usize];
+            }
+            // Note: cycle walking ends, at the latest, back on the original value.
+            if !self.luhn || is_luhn_valid(&chars) == luhn_valid {
+                break;
+            }
+        }
+
+        Ok(Bytes::from(chars.into_iter().collect::<String>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8; KEY_SIZE] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn valid_tokenize_roundtrip() {
+        let tokenizer = FormatPreservingTokenizer::new(KEY, Alphabet::digits());
+        let value = Bytes::from_static(b"123-45-6789");
+
+        let token = tokenizer.tokenize(&value).unwrap();
+        assert_ne!(value, token, "tokenized");
+        assert_eq!(value.len(), token.len(), "same length");
+        assert_eq!(b'-', token[3], "separator kept");
+        assert!(token.iter().all(|c| c.is_ascii_digit() || *c == b'-'));
+
+        assert_eq!(token, tokenizer.tokenize(&value).unwrap(), "deterministic");
+        assert_eq!(value, tokenizer.detokenize(&token).unwrap(), "reversible");
+    }
+
+    #[test]
+    fn valid_tokenize_alphabets() {
+        let alphabet = Alphabet::from_name("upper").unwrap();
+        let tokenizer = FormatPreservingTokenizer::new(KEY, alphabet);
+        let token = tokenizer.tokenize(b"ACCOUNT-ab").unwrap();
+        assert!(
+            token[..7].iter().all(|c| c.is_ascii_uppercase()),
+            "{:?}",
+            token
+        );
+        assert_eq!(&b"-ab"[..], &token[7..], "other characters kept");
+
+        let alphabet = Alphabet::from_name("01").unwrap();
+        assert_eq!(Alphabet(vec!['0', '1']), alphabet, "custom alphabet");
+        assert_eq!(None, Alphabet::from_name("aaa"), "single character");
+    }
+
+    #[test]
+    fn valid_tokenize_kept_and_luhn() {
+        let tokenizer = FormatPreservingTokenizer::new(KEY, Alphabet::digits())
+            .with_kept(6, 4)
+            .with_luhn();
+
+        for card in [
+            "4111 1111 1111 1111",
+            "5500 0055 5555 5559",
+            "4012888888881881",
+        ] {
+            let token = tokenizer.tokenize(card.as_bytes()).unwrap();
+            let token = std::str::from_utf8(&token).unwrap();
+            let chars: Vec<char> = token.chars().collect();
+            assert!(is_luhn_valid(&chars), "Luhn valid token {}", token);
+            assert_eq!(card[..7], token[..7], "prefix kept");
+            assert_eq!(
+                card[card.len() - 4..],
+                token[token.len() - 4..],
+                "suffix kept"
+            );
+            assert_eq!(
+                card.as_bytes(),
+                tokenizer.detokenize(token.as_bytes()).unwrap()
+            );
+        }
+
+        let invalid = b"4111111111111112";
+        let token = tokenizer.tokenize(invalid).unwrap();
+        let chars: Vec<char> = std::str::from_utf8(&token).unwrap().chars().collect();
+        assert!(!is_luhn_valid(&chars), "Luhn invalid token");
+        assert_eq!(&invalid[..], tokenizer.detokenize(&token).unwrap());
+    }
+
+    #[test]
+    fn valid_tokenize_tweaked() {
+        let tokenizer = FormatPreservingTokenizer::new(KEY, Alphabet::digits());
+        let tweaked = FormatPreservingTokenizer::new(KEY, Alphabet::digits()).with_tweak(b"ssn");
+        assert_ne!(
+            tokenizer.tokenize(b"123456789").unwrap(),
+            tweaked.tokenize(b"123456789").unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_tokenize_too_short() {
+        let tokenizer = FormatPreservingTokenizer::new(KEY, Alphabet::digits());
+        assert!(
+            tokenizer.tokenize(b"12345").is_err(),
+            "below FF1 domain size"
+        );
+
+        let tokenizer = tokenizer.with_kept(4, 4);
+        assert!(tokenizer.tokenize(b"1234567").is_err(), "shorter than kept");
+    }
+
+    #[test]
+    fn luhn_checksum() {
+        let chars = |s: &str| s.chars().collect::<Vec<char>>();
+        assert!(is_luhn_valid(&chars("79927398713")));
+        assert!(!is_luhn_valid(&chars("79927398710")));
+    }
+}
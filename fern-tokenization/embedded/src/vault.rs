@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token vault, persisting the mapping between tokens and original values
+//! in an embedded SQLite database.
+//!
+//! Tokens are random, with no relation to the values they stand for: the
+//! vault is the only way back to original values, and must be protected
+//! accordingly. A given value is always replaced by the same token, so that
+//! tokens can be compared, joined, and grouped as original values would be.
+//!
+//! The database is accessed synchronously: handlers look tokens up in batches,
+//! on threads where blocking is acceptable rather than on asynchronous workers.
+
+use bytes::Bytes;
+use rand::Rng;
+use regex::bytes::Regex;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+/// Prefix of tokens, unless defined otherwise.
+const DEFAULT_PREFIX: &str = "tok_";
+
+/// Amount of random hexadecimal characters following the prefix of tokens.
+const TOKEN_RANDOM_CHARS: usize = 32;
+
+/// How long to wait for a database locked by another connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maps a database error to an `io::Error`.
+fn to_io_error(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("token vault - {}", err))
+}
+
+/// Runs `f` on a thread where blocking is acceptable.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("token vault - {}", err)))?
+}
+
+/// A persistent store of tokens and the values they stand for.
+#[derive(Debug)]
+pub struct TokenVault {
+    connection: Mutex<Connection>,
+
+    /// Prefix of tokens, allowing to recognize them in queries.
+    prefix: String,
+
+    /// Pattern matching tokens generated by this vault.
+    pattern: Regex,
+}
+
+impl TokenVault {
+    /// Opens the vault database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P, prefix: &str) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(to_io_error)?;
+        Self::with_connection(connection, prefix)
+    }
+
+    /// Opens a vault only living in memory, whose tokens are lost when dropped.
+    pub fn in_memory(prefix: &str) -> io::Result<Self> {
+        let connection = Connection::open_in_memory().map_err(to_io_error)?;
+        Self::with_connection(connection, prefix)
+    }
+
+    /// Opens the vault defined by settings found in `config`:
+    /// - `tokenization.vault.path`: path of the vault database,
+    /// - `tokenization.vault.prefix`: prefix of tokens, `tok_` by default.
+    ///
+    /// Should no path be defined, or the database fail to open, tokens
+    /// only live in memory, and cannot be detokenized after a restart.
+    pub fn from_config(config: &SQLHandlerConfig) -> Self {
+        let prefix = config
+            .get::<String>("tokenization.vault.prefix")
+            .unwrap_or_else(|_| DEFAULT_PREFIX.to_string());
+
+        match config.get::<String>("tokenization.vault.path") {
+            Ok(path) => match Self::open(&path, &prefix) {
+                Ok(vault) => return vault,
+                Err(err) => log::error!("cannot open '{}', using memory - {}", path, err),
+            },
+            Err(_) => log::warn!("no token vault path defined, tokens will not persist"),
+        }
+
+        //TODO(ppiotr3k): fail at startup rather than degrading to memory
+        Self::in_memory(&prefix).expect("in-memory SQLite database")
+    }
+
+    fn with_connection(connection: Connection, prefix: &str) -> io::Result<Self> {
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(to_io_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS tokens (
+                    token TEXT PRIMARY KEY,
+                    value BLOB NOT NULL UNIQUE
+                );",
+            )
+            .map_err(to_io_error)?;
+
+        let pattern = format!(
+            "{}[0-9a-f]{{{}}}",
+            regex::escape(prefix),
+            TOKEN_RANDOM_CHARS
+        );
+        Ok(Self {
+            connection: Mutex::new(connection),
+            prefix: prefix.to_string(),
+            pattern: Regex::new(&pattern).expect("valid token pattern"),
+        })
+    }
+
+    /// Locks the database connection, regardless of poisoning
+    /// as database transactions keep it consistent.
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the pattern matching tokens of this vault.
+    pub fn pattern(&self) -> &Regex {
+        &self.pattern
+    }
+
+    /// Returns the token standing for `value`, generating it if needed.
+    pub fn tokenize(&self, value: &[u8]) -> io::Result<Bytes> {
+        let connection = self.connection();
+        let select = |connection: &Connection| {
+            connection
+                .query_row(
+                    "SELECT token FROM tokens WHERE value = ?1",
+                    params![value],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+                .map_err(to_io_error)
+        };
+
+        if let Some(token) = select(&connection)? {
+            return Ok(Bytes::from(token));
+        }
+
+        let token = format!(
+            "{}{:0width$x}",
+            self.prefix,
+            rand::thread_rng().gen::<u128>(),
+            width = TOKEN_RANDOM_CHARS
+        );
+        // Note: another connection may have tokenized the same value meanwhile.
+        connection
+            .execute(
+                "INSERT OR IGNORE INTO tokens (token, value) VALUES (?1, ?2)",
+                params![token, value],
+            )
+            .map_err(to_io_error)?;
+
+        match select(&connection)? {
+            Some(token) => Ok(Bytes::from(token)),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "token vault - token not stored",
+            )),
+        }
+    }
+
+    /// Returns the value `token` stands for, if known.
+    pub fn detokenize(&self, token: &[u8]) -> io::Result<Option<Bytes>> {
+        let token = match std::str::from_utf8(token) {
+            Ok(token) => token,
+            Err(_) => return Ok(None),
+        };
+
+        self.connection()
+            .query_row(
+                "SELECT value FROM tokens WHERE token = ?1",
+                params![token],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map(|value| value.map(Bytes::from))
+            .map_err(to_io_error)
+    }
+
+    /// Returns tokens standing for `values`, generating them if needed,
+    /// with the database accessed on a thread where blocking is acceptable.
+    pub async fn tokenize_all(self: Arc<Self>, values: Vec<Bytes>) -> io::Result<Vec<Bytes>> {
+        blocking(move || values.iter().map(|value| self.tokenize(value)).collect()).await
+    }
+
+    /// Returns values `tokens` stand for, if known, with the database
+    /// accessed on a thread where blocking is acceptable.
+    pub async fn detokenize_all(
+        self: Arc<Self>,
+        tokens: Vec<Bytes>,
+    ) -> io::Result<Vec<Option<Bytes>>> {
+        blocking(move || tokens.iter().map(|token| self.detokenize(token)).collect()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_tokenize_roundtrip() {
+        let vault = TokenVault::in_memory("tok_").unwrap();
+
+        let token = vault.tokenize(b"jane.doe@example.com").unwrap();
+        assert!(vault.pattern().is_match(&token), "token: {:?}", token);
+        assert_eq!(4 + 32, token.len(), "token length");
+
+        let value = vault.detokenize(&token).unwrap();
+        assert_eq!(Some(Bytes::from_static(b"jane.doe@example.com")), value);
+    }
+
+    #[test]
+    fn valid_tokenize_consistent() {
+        let vault = TokenVault::in_memory("tok_").unwrap();
+        let token = vault.tokenize(b"a").unwrap();
+        assert_eq!(token, vault.tokenize(b"a").unwrap(), "same value");
+        assert_ne!(token, vault.tokenize(b"b").unwrap(), "other value");
+    }
+
+    #[test]
+    fn valid_detokenize_unknown_token() {
+        let vault = TokenVault::in_memory("tok_").unwrap();
+        let token = b"tok_0123456789abcdef0123456789abcdef";
+        assert_eq!(None, vault.detokenize(token).unwrap());
+    }
+
+    #[tokio::test]
+    async fn valid_batches() {
+        let vault = Arc::new(TokenVault::in_memory("tok_").unwrap());
+        let values = vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")];
+        let tokens = vault.clone().tokenize_all(values.clone()).await.unwrap();
+        assert_eq!(vault.tokenize(b"b").unwrap(), tokens[1]);
+
+        let mut tokens = tokens;
+        tokens.push(Bytes::from_static(b"tok_0123456789abcdef0123456789abcdef"));
+        let found = vault.detokenize_all(tokens).await.unwrap();
+        let expected: Vec<Option<Bytes>> =
+            vec![Some(values[0].clone()), Some(values[1].clone()), None];
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn valid_vault_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.db");
+
+        let token = TokenVault::open(&path, "tok_")
+            .unwrap()
+            .tokenize(b"secret")
+            .unwrap();
+
+        let vault = TokenVault::open(&path, "tok_").unwrap();
+        let value = vault.detokenize(&token).unwrap();
+        assert_eq!(Some(Bytes::from_static(b"secret")), value, "reopened vault");
+    }
+}
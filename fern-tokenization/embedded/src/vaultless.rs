@@ -0,0 +1,330 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Vaultless tokenization, where tokens are derived from values with a key,
+//! for throughput not to depend on a storage.
+//!
+//! Values are encrypted with AES-256-GCM-SIV, under a nonce derived from the
+//! value with HMAC-SHA256: a given value always yields the same token, and
+//! tokens can be compared, joined, and grouped as original values would be.
+//!
+//! Keys are versioned, and the version used is embedded in tokens, so that
+//! tokens issued before a key rotation can still be detokenized, as long as
+//! former keys are kept in configuration.
+
+use aes_gcm_siv::aead::{Aead, KeyInit};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use bytes::Bytes;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use regex::bytes::Regex;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use fern_proxy_interfaces::SQLHandlerConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Prefix of tokens, unless defined otherwise.
+const DEFAULT_PREFIX: &str = "tkv_";
+
+/// Size in bytes of keys, as configured.
+const KEY_SIZE: usize = 32;
+
+/// Size in bytes of AES-GCM-SIV nonces.
+const NONCE_SIZE: usize = 12;
+
+/// Size in bytes of AES-GCM-SIV authentication tags.
+const TAG_SIZE: usize = 16;
+
+/// Maps an error to an `io::Error`.
+fn to_io_error<E: fmt::Display>(err: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("vaultless tokenization - {}", err),
+    )
+}
+
+/// Ciphers derived from a configured key.
+struct VersionKey {
+    cipher: Aes256GcmSiv,
+    nonce_key: [u8; KEY_SIZE],
+}
+
+impl VersionKey {
+    fn new(key: &[u8; KEY_SIZE]) -> Self {
+        // Note: distinct keys for encryption and nonce derivation.
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let mut cipher_key = [0; KEY_SIZE];
+        let mut nonce_key = [0; KEY_SIZE];
+        hkdf.expand(b"fern tokenization cipher", &mut cipher_key)
+            .expect("valid HKDF output length");
+        hkdf.expand(b"fern tokenization nonce", &mut nonce_key)
+            .expect("valid HKDF output length");
+
+        Self {
+            cipher: Aes256GcmSiv::new_from_slice(&cipher_key).expect("valid AES-256 key"),
+            nonce_key,
+        }
+    }
+
+    fn nonce(&self, value: &[u8]) -> [u8; NONCE_SIZE] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.nonce_key)
+            .expect("HMAC can take key of any size");
+        mac.update(value);
+        let mut nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&mac.finalize().into_bytes()[..NONCE_SIZE]);
+        nonce
+    }
+}
+
+/// A tokenizer deriving deterministic and reversible tokens from values,
+/// with no storage involved.
+///
+/// Tokens are made of a prefix, the key version, and the encrypted value,
+/// e.g. `tkv_2_...`, and are thus longer than original values.
+pub struct VaultlessTokenizer {
+    /// Prefix of tokens, allowing to recognize them in queries.
+    prefix: String,
+
+    /// Version of the key used for tokenization.
+    version: u32,
+
+    /// Keys by version, including former ones only used for detokenization.
+    keys: BTreeMap<u32, VersionKey>,
+
+    /// Pattern matching tokens generated by this tokenizer.
+    pattern: Regex,
+}
+
+impl fmt::Debug for VaultlessTokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Note: key material is not disclosed.
+        f.debug_struct("VaultlessTokenizer")
+            .field("prefix", &self.prefix)
+            .field("version", &self.version)
+            .field("versions", &self.keys.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl VaultlessTokenizer {
+    /// Creates a tokenizer using `key` of `version` for new tokens.
+    pub fn new(prefix: &str, version: u32, key: &[u8; KEY_SIZE]) -> Self {
+        let pattern = format!(
+            "{}[0-9]+_[A-Za-z0-9_-]{{{},}}",
+            regex::escape(prefix),
+            // Note: an empty value still yields a nonce and a tag.
+            (NONCE_SIZE + TAG_SIZE) * 4 / 3
+        );
+        Self {
+            prefix: prefix.to_string(),
+            version,
+            keys: BTreeMap::from([(version, VersionKey::new(key))]),
+            pattern: Regex::new(&pattern).expect("valid token pattern"),
+        }
+    }
+
+    /// Adds a former `key` of `version`, to detokenize tokens it was used for.
+    #[must_use]
+    pub fn with_key(mut self, version: u32, key: &[u8; KEY_SIZE]) -> Self {
+        self.keys
+            .entry(version)
+            .or_insert_with(|| VersionKey::new(key));
+        self
+    }
+
+    /// Creates a `VaultlessTokenizer` from settings found in `config`:
+    /// - `tokenization.vaultless.keys`: hex-encoded 256-bit keys, by version,
+    /// - `tokenization.vaultless.version`: version of the key used for new
+    ///   tokens, the highest one by default,
+    /// - `tokenization.vaultless.prefix`: prefix of tokens, `tkv_` by default.
+    ///
+    /// Returns `None` if no valid key is defined for the version in use.
+    pub fn from_config(config: &SQLHandlerConfig) -> Option<Self> {
+        let table = config.get_table("tokenization.vaultless.keys").ok()?;
+
+        let mut keys = BTreeMap::new();
+        for (version, key) in table {
+            let parsed = version.parse::<u32>().ok().zip(
+                key.into_string()
+                    .ok()
+                    .and_then(|key| hex::decode(key.trim()).ok())
+                    .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok()),
+            );
+            match parsed {
+                Some((version, key)) => {
+                    keys.insert(version, key);
+                }
+                None => log::error!("ignoring invalid vaultless tokenization key '{}'", version),
+            }
+        }
+
+        let version = match config.get::<u32>("tokenization.vaultless.version") {
+            Ok(version) => version,
+            Err(_) => *keys.keys().next_back()?,
+        };
+        let key = match keys.get(&version) {
+            Some(key) => key,
+            None => {
+                log::error!("no vaultless tokenization key of version {}", version);
+                return None;
+            }
+        };
+
+        let prefix = config
+            .get::<String>("tokenization.vaultless.prefix")
+            .unwrap_or_else(|_| DEFAULT_PREFIX.to_string());
+        let mut tokenizer = Self::new(&prefix, version, key);
+        for (version, key) in keys.iter() {
+            tokenizer = tokenizer.with_key(*version, key);
+        }
+        Some(tokenizer)
+    }
+
+    /// Returns the pattern matching tokens of this tokenizer.
+    pub fn pattern(&self) -> &Regex {
+        &self.pattern
+    }
+
+    /// Returns the token standing for `value`.
+    pub fn tokenize(&self, value: &[u8]) -> io::Result<Bytes> {
+        let key = &self.keys[&self.version];
+        let nonce = key.nonce(value);
+        let ciphertext = key
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), value)
+            .map_err(to_io_error)?;
+
+        let mut payload = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+        Ok(Bytes::from(format!(
+            "{}{}_{}",
+            self.prefix,
+            self.version,
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        )))
+    }
+
+    /// Returns the value `token` stands for, or `None` if `token` is not
+    /// a token of this tokenizer, or was issued with an unknown key version.
+    pub fn detokenize(&self, token: &[u8]) -> io::Result<Option<Bytes>> {
+        let token = match token.strip_prefix(self.prefix.as_bytes()) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let (version, payload) = match token.iter().position(|c| *c == b'_') {
+            Some(idx) => (&token[..idx], &token[idx + 1..]),
+            None => return Ok(None),
+        };
+        let key = match std::str::from_utf8(version)
+            .ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .and_then(|version| self.keys.get(&version))
+        {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let payload =
+            base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(to_io_error)?;
+        if payload.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(to_io_error("truncated token"));
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+        key.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map(|value| Some(Bytes::from(value)))
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &[u8; KEY_SIZE] = b"0123456789abcdef0123456789abcdef";
+    const KEY_2: &[u8; KEY_SIZE] = b"fedcba9876543210fedcba9876543210";
+
+    #[test]
+    fn valid_tokenize_roundtrip() {
+        let tokenizer = VaultlessTokenizer::new("tkv_", 1, KEY_1);
+        for value in [&b""[..], b"jane.doe@example.com"] {
+            let token = tokenizer.tokenize(value).unwrap();
+            assert!(token.starts_with(b"tkv_1_"), "token: {:?}", token);
+            let found = tokenizer.pattern().find(&token).unwrap();
+            assert_eq!(token.len(), found.end(), "whole token matched");
+
+            let detokenized = tokenizer.detokenize(&token).unwrap();
+            assert_eq!(Some(Bytes::copy_from_slice(value)), detokenized);
+        }
+    }
+
+    #[test]
+    fn valid_tokenize_deterministic() {
+        let tokenizer = VaultlessTokenizer::new("tkv_", 1, KEY_1);
+        let token = tokenizer.tokenize(b"a").unwrap();
+        assert_eq!(token, tokenizer.tokenize(b"a").unwrap(), "same value");
+        assert_ne!(token, tokenizer.tokenize(b"b").unwrap(), "other value");
+    }
+
+    #[test]
+    fn valid_detokenize_after_rotation() {
+        let former = VaultlessTokenizer::new("tkv_", 1, KEY_1);
+        let token = former.tokenize(b"secret").unwrap();
+
+        let rotated = VaultlessTokenizer::new("tkv_", 2, KEY_2).with_key(1, KEY_1);
+        let new_token = rotated.tokenize(b"secret").unwrap();
+        assert!(new_token.starts_with(b"tkv_2_"), "new key version");
+        assert_eq!(
+            Some(Bytes::from_static(b"secret")),
+            rotated.detokenize(&token).unwrap()
+        );
+        assert_eq!(
+            Some(Bytes::from_static(b"secret")),
+            rotated.detokenize(&new_token).unwrap()
+        );
+
+        let retired = VaultlessTokenizer::new("tkv_", 2, KEY_2);
+        assert_eq!(None, retired.detokenize(&token).unwrap(), "retired key");
+    }
+
+    #[test]
+    fn invalid_detokenize_tampered() {
+        let tokenizer = VaultlessTokenizer::new("tkv_", 1, KEY_1);
+        let mut token = tokenizer.tokenize(b"secret").unwrap().to_vec();
+        let last = token.len() - 1;
+        token[last] = if token[last] == b'A' { b'B' } else { b'A' };
+        assert!(
+            tokenizer.detokenize(&token).is_err(),
+            "authenticated tokens"
+        );
+
+        let other = VaultlessTokenizer::new("tkv_", 1, KEY_2);
+        let token = other.tokenize(b"secret").unwrap();
+        assert!(tokenizer.detokenize(&token).is_err(), "other key");
+    }
+
+    #[test]
+    fn valid_from_config() {
+        let config = SQLHandlerConfig::builder()
+            .set_override("tokenization.vaultless.keys.1", hex::encode(KEY_1))
+            .unwrap()
+            .set_override("tokenization.vaultless.keys.2", hex::encode(KEY_2))
+            .unwrap()
+            .build()
+            .unwrap();
+        let tokenizer = VaultlessTokenizer::from_config(&config).unwrap();
+        assert_eq!(2, tokenizer.version, "highest version by default");
+        assert_eq!(2, tokenizer.keys.len(), "former keys");
+
+        let config = SQLHandlerConfig::builder().build().unwrap();
+        assert!(
+            VaultlessTokenizer::from_config(&config).is_none(),
+            "no keys"
+        );
+    }
+}
@@ -17,11 +17,25 @@ use crate::codec::utils::*;
 
 const MESSAGE_ID_AUTHENTICATION: u8 = b'R';
 const MESSAGE_ID_BACKEND_KEY_DATA: u8 = b'K';
+const MESSAGE_ID_BIND_COMPLETE: u8 = b'2';
+const MESSAGE_ID_CLOSE_COMPLETE: u8 = b'3';
 const MESSAGE_ID_COMMAND_COMPLETE: u8 = b'C';
+const MESSAGE_ID_COPY_BOTH_RESPONSE: u8 = b'W';
+const MESSAGE_ID_COPY_DATA: u8 = b'd';
+const MESSAGE_ID_COPY_DONE: u8 = b'c';
+const MESSAGE_ID_COPY_IN_RESPONSE: u8 = b'G';
+const MESSAGE_ID_COPY_OUT_RESPONSE: u8 = b'H';
 const MESSAGE_ID_DATA_ROW: u8 = b'D';
 const MESSAGE_ID_EMPTY_QUERY_RESPONSE: u8 = b'I';
 const MESSAGE_ID_ERROR_RESPONSE: u8 = b'E'; //TODO(ppiotr3k): write tests
+const MESSAGE_ID_FUNCTION_CALL_RESPONSE: u8 = b'V';
+const MESSAGE_ID_NO_DATA: u8 = b'n';
+const MESSAGE_ID_NOTICE_RESPONSE: u8 = b'N';
+const MESSAGE_ID_NOTIFICATION_RESPONSE: u8 = b'A';
+const MESSAGE_ID_PARAMETER_DESCRIPTION: u8 = b't';
 const MESSAGE_ID_PARAMETER_STATUS: u8 = b'S';
+const MESSAGE_ID_PARSE_COMPLETE: u8 = b'1';
+const MESSAGE_ID_PORTAL_SUSPENDED: u8 = b's';
 const MESSAGE_ID_READY_FOR_QUERY: u8 = b'Z';
 const MESSAGE_ID_ROW_DESCRIPTION: u8 = b'T';
 
@@ -33,21 +47,7 @@ const MESSAGE_ID_ROW_DESCRIPTION: u8 = b'T';
 // const MESSAGE_ID_AUTHENTICATION_GSS: u8 = b'R'; // 7
 // const MESSAGE_ID_AUTHENTICATION_GSS_CONTINUE: u8 = b'R'; // 8
 // const MESSAGE_ID_AUTHENTICATION_SSPI: u8 = b'R'; // 9
-// const MESSAGE_ID_BIND_COMPLETE: u8 = b'2';
-// const MESSAGE_ID_CLOSE_COMPLETE: u8 = b'3';
-// const MESSAGE_ID_COPY_DATA: u8 = b'd';
-// const MESSAGE_ID_COPY_DONE: u8 = b'c';
-// const MESSAGE_ID_COPY_IN_RESPONSE: u8 = b'G';
-// const MESSAGE_ID_COPY_OUT_RESPONSE: u8 = b'H';
-// const MESSAGE_ID_COPY_BOTH_RESPONSE: u8 = b'W';
-// const MESSAGE_ID_FUNCTION_CALL_RESPONSE: u8 = b'V';
 // const MESSAGE_ID_NEGOTIATE_PROTOCOL_VERSION: u8 = b'v';
-// const MESSAGE_ID_NO_DATA: u8 = b'n';
-// const MESSAGE_ID_NOTICE_RESPONSE: u8 = b'N';
-// const MESSAGE_ID_NOTIFICATION_RESPONSE: u8 = b'A';
-// const MESSAGE_ID_PARAMETER_DESCRIPTION: u8 = b'B';
-// const MESSAGE_ID_PARSE_COMPLETE: u8 = b'1';
-// const MESSAGE_ID_PORTAL_SUSPENDED: u8 = b's';
 
 ///TODO(ppiotr3k): write description
 //TODO(ppiotr3k): investigate if `Clone` is avoidable; currently only used in tests
@@ -62,12 +62,36 @@ pub enum Message {
     AuthenticationSASL(Bytes),
     AuthenticationSASLContinue(Bytes),
     AuthenticationSASLFinal(Bytes),
+    BindComplete(),
+    CloseComplete(),
     CommandComplete(Bytes),
-    BackendKeyData { process: u32, secret_key: u32 },
-    DataRow(Vec<Bytes>),
+    CopyBothResponse(CopyResponse),
+    CopyData(Bytes),
+    CopyDone(),
+    CopyInResponse(CopyResponse),
+    CopyOutResponse(CopyResponse),
+    BackendKeyData {
+        process: u32,
+        secret_key: u32,
+    },
+    DataRow(Vec<Option<Bytes>>), // `None` fields are SQL `NULL` values
     EmptyQueryResponse(),
     ErrorResponse(Bytes),
-    ParameterStatus { parameter: Bytes, value: Bytes },
+    FunctionCallResponse(Option<Bytes>), // `None` is an SQL `NULL` result
+    NoData(),
+    NoticeResponse(Bytes),
+    NotificationResponse {
+        process: u32,
+        channel: Bytes,
+        payload: Bytes,
+    },
+    ParameterDescription(Vec<u32>), // data type OIDs of parameters
+    ParameterStatus {
+        parameter: Bytes,
+        value: Bytes,
+    },
+    ParseComplete(),
+    PortalSuspended(),
     ReadyForQuery(u8),
     RowDescription(Vec<RowDescription>),
 
@@ -79,21 +103,39 @@ pub enum Message {
     AuthenticationGSS(Bytes),
     AuthenticationGSSContinue(Bytes),
     AuthenticationSSPI(Bytes),
-    BindComplete(Bytes),
-    CloseComplete(Bytes),
-    CopyData(Bytes),
-    CopyDone(Bytes),
-    CopyInResponse(Bytes),
-    CopyOutResponse(Bytes),
-    CopyBothResponse(Bytes),
-    FunctionCallResponse(Bytes),
     NegotiateProtocolVersion(Bytes),
-    NoData(),
-    NoticeResponse(Bytes),
-    NotificationResponse(Bytes),
-    ParameterDescription(Bytes),
-    ParseComplete(),
-    PortalSuspended(),
+}
+
+impl Message {
+    /// Creates an `ErrorResponse` Message of `ERROR` severity,
+    /// with SQLSTATE `code` and human-readable `message`.
+    #[must_use]
+    pub fn error_response(code: &str, message: &str) -> Self {
+        let mut fields = BytesMut::new();
+        for (field_type, value) in [
+            (b'S', "ERROR"),
+            (b'V', "ERROR"),
+            (b'C', code),
+            (b'M', message),
+        ] {
+            fields.put_u8(field_type);
+            fields.put(value.as_bytes());
+            fields.put_u8(b'\0');
+        }
+        fields.put_u8(b'\0'); // fields list terminator
+        Self::ErrorResponse(fields.freeze())
+    }
+}
+
+/// Format of data exchanged in a `COPY` operation.
+//TODO(ppiotr3k): investigate if `Clone` is avoidable; currently only used in tests
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CopyResponse {
+    /// Overall format, 0 for textual (text or CSV), 1 for binary.
+    pub format: u8,
+
+    /// Format code of each column, all 0 if overall format is textual.
+    pub columns_formats: Vec<u16>,
 }
 
 ///TODO(ppiotr3k): write description
@@ -268,10 +310,29 @@ impl Codec {
                 let secret_key = get_u32(&mut frame, "malformed packet - invalid key data")?;
                 Message::BackendKeyData { process, secret_key }
             },
+            MESSAGE_ID_BIND_COMPLETE => Message::BindComplete(),
+            MESSAGE_ID_CLOSE_COMPLETE => Message::CloseComplete(),
             MESSAGE_ID_COMMAND_COMPLETE => {
                 let command = get_cstr(&mut frame)?;
                 Message::CommandComplete(command)
             },
+            MESSAGE_ID_COPY_BOTH_RESPONSE => {
+                let response = self.get_copy_response(&mut frame)?;
+                Message::CopyBothResponse(response)
+            },
+            MESSAGE_ID_COPY_DATA => {
+                let data = frame.copy_to_bytes(msg_length);
+                Message::CopyData(data)
+            },
+            MESSAGE_ID_COPY_DONE => Message::CopyDone(),
+            MESSAGE_ID_COPY_IN_RESPONSE => {
+                let response = self.get_copy_response(&mut frame)?;
+                Message::CopyInResponse(response)
+            },
+            MESSAGE_ID_COPY_OUT_RESPONSE => {
+                let response = self.get_copy_response(&mut frame)?;
+                Message::CopyOutResponse(response)
+            },
             MESSAGE_ID_DATA_ROW => {
                 let fields = self.get_data_row_fields(&mut frame)?;
                 Message::DataRow(fields)
@@ -282,11 +343,38 @@ impl Codec {
                 Message::ErrorResponse(unparsed_fields)
             },
             MESSAGE_ID_EMPTY_QUERY_RESPONSE => Message::EmptyQueryResponse(),
+            MESSAGE_ID_FUNCTION_CALL_RESPONSE => {
+                let result = get_nullable_bytes(&mut frame, "malformed packet - invalid function result")?;
+                Message::FunctionCallResponse(result)
+            },
+            MESSAGE_ID_NO_DATA => Message::NoData(),
+            MESSAGE_ID_NOTICE_RESPONSE => {
+                //TODO(ppiotr3k): identify if parsing those fields is of interest
+                let unparsed_fields = frame.copy_to_bytes(msg_length);
+                Message::NoticeResponse(unparsed_fields)
+            },
+            MESSAGE_ID_NOTIFICATION_RESPONSE => {
+                let process = get_u32(&mut frame, "malformed packet - invalid notification data")?;
+                let channel = get_cstr(&mut frame)?;
+                let payload = get_cstr(&mut frame)?;
+                Message::NotificationResponse { process, channel, payload }
+            },
+            MESSAGE_ID_PARAMETER_DESCRIPTION => {
+                let mut parameters = get_u16(&mut frame, "malformed packet - invalid data size")?;
+                let mut data_type_oids = Vec::with_capacity(parameters as usize);
+                while parameters > 0 {
+                    data_type_oids.push(get_u32(&mut frame, "malformed packet - invalid parameter description")?);
+                    parameters -= 1;
+                }
+                Message::ParameterDescription(data_type_oids)
+            },
             MESSAGE_ID_PARAMETER_STATUS => {
                 let parameter = get_cstr(&mut frame)?;
                 let value = get_cstr(&mut frame)?;
                 Message::ParameterStatus { parameter, value }
             },
+            MESSAGE_ID_PARSE_COMPLETE => Message::ParseComplete(),
+            MESSAGE_ID_PORTAL_SUSPENDED => Message::PortalSuspended(),
             MESSAGE_ID_READY_FOR_QUERY => {
                 let status = get_u8(&mut frame, "malformed packet - missing status indicator")?;
                 match status {
@@ -364,20 +452,44 @@ impl Codec {
         Ok(decoded)
     }
 
+    /// Gets the format of data in a `COPY` operation, common to
+    /// `CopyInResponse`, `CopyOutResponse`, and `CopyBothResponse`.
+    fn get_copy_response(&mut self, buf: &mut BytesMut) -> io::Result<CopyResponse> {
+        let format = get_u8(buf, "malformed packet - missing copy format")?;
+        if format > 1 {
+            let err = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "malformed packet - invalid copy format",
+            );
+            log::error!("{}", err);
+            return Err(err);
+        }
+
+        let mut columns = get_u16(buf, "malformed packet - invalid data size")?;
+        let mut columns_formats = Vec::with_capacity(columns as usize);
+        while columns > 0 {
+            columns_formats.push(get_u16(
+                buf,
+                "malformed packet - invalid copy column format",
+            )?);
+            columns -= 1;
+        }
+
+        Ok(CopyResponse {
+            format,
+            columns_formats,
+        })
+    }
+
     ///TODO(ppiotr3k): write function description
-    fn get_data_row_fields(&mut self, buf: &mut BytesMut) -> io::Result<Vec<Bytes>> {
+    fn get_data_row_fields(&mut self, buf: &mut BytesMut) -> io::Result<Vec<Option<Bytes>>> {
         let mut fields = buf.get_u16();
         log::trace!("decoded number of row fields: {}", fields);
 
         let mut decoded = Vec::new();
 
-        const BYTES_DATA_ROW_FIELD_LENGTH: usize = 4;
         while fields > 0 {
-            let value = get_bytes(
-                buf,
-                BYTES_DATA_ROW_FIELD_LENGTH,
-                "malformed packet - invalid field size",
-            )?;
+            let value = get_nullable_bytes(buf, "malformed packet - invalid field size")?;
 
             log::trace!("decoded field: {:?}", value);
             decoded.push(value);
@@ -395,6 +507,17 @@ impl Codec {
         dst.put_u8(msg_id);
         dst.put_u32((BYTES_MESSAGE_SIZE + msg_size) as u32);
     }
+
+    /// Writes a `CopyInResponse`, `CopyOutResponse`, or `CopyBothResponse`.
+    fn encode_copy_response(&mut self, msg_id: u8, response: CopyResponse, dst: &mut BytesMut) {
+        let msg_size = 1 + 2 + 2 * response.columns_formats.len();
+        self.encode_header(msg_id, msg_size, dst);
+        dst.put_u8(response.format);
+        dst.put_u16(response.columns_formats.len() as u16);
+        for format in response.columns_formats {
+            dst.put_u16(format);
+        }
+    }
 }
 
 impl PostgresMessage for Message {}
@@ -477,21 +600,43 @@ impl Encoder<Message> for Codec {
                 dst.put_i32(process as i32);
                 dst.put_i32(secret_key as i32);
             }
+            Message::BindComplete() => {
+                self.encode_header(MESSAGE_ID_BIND_COMPLETE, 0, dst);
+            }
+            Message::CloseComplete() => {
+                self.encode_header(MESSAGE_ID_CLOSE_COMPLETE, 0, dst);
+            }
             Message::CommandComplete(command) => {
                 self.encode_header(MESSAGE_ID_COMMAND_COMPLETE, command.len() + 1, dst);
                 put_cstr(&command, dst);
             }
+            Message::CopyBothResponse(response) => {
+                self.encode_copy_response(MESSAGE_ID_COPY_BOTH_RESPONSE, response, dst);
+            }
+            Message::CopyData(data) => {
+                self.encode_header(MESSAGE_ID_COPY_DATA, data.len(), dst);
+                dst.put(data);
+            }
+            Message::CopyDone() => {
+                self.encode_header(MESSAGE_ID_COPY_DONE, 0, dst);
+            }
+            Message::CopyInResponse(response) => {
+                self.encode_copy_response(MESSAGE_ID_COPY_IN_RESPONSE, response, dst);
+            }
+            Message::CopyOutResponse(response) => {
+                self.encode_copy_response(MESSAGE_ID_COPY_OUT_RESPONSE, response, dst);
+            }
             Message::DataRow(fields) => {
                 let mut msg_size = 2;
                 for field in fields.iter() {
-                    msg_size += field.len() + 4;
+                    msg_size += nullable_bytes_len(field);
                 }
 
                 self.encode_header(MESSAGE_ID_DATA_ROW, msg_size, dst);
                 dst.put_u16(fields.len() as u16);
 
                 for field in fields.iter() {
-                    put_bytes(field, dst)
+                    put_nullable_bytes(field, dst)
                 }
             }
             Message::EmptyQueryResponse() => {
@@ -501,6 +646,40 @@ impl Encoder<Message> for Codec {
                 self.encode_header(MESSAGE_ID_ERROR_RESPONSE, unparsed_fields.len(), dst);
                 dst.put(unparsed_fields);
             }
+            Message::FunctionCallResponse(result) => {
+                self.encode_header(
+                    MESSAGE_ID_FUNCTION_CALL_RESPONSE,
+                    nullable_bytes_len(&result),
+                    dst,
+                );
+                put_nullable_bytes(&result, dst);
+            }
+            Message::NoData() => {
+                self.encode_header(MESSAGE_ID_NO_DATA, 0, dst);
+            }
+            Message::NoticeResponse(unparsed_fields) => {
+                self.encode_header(MESSAGE_ID_NOTICE_RESPONSE, unparsed_fields.len(), dst);
+                dst.put(unparsed_fields);
+            }
+            Message::NotificationResponse {
+                process,
+                channel,
+                payload,
+            } => {
+                let msg_size = 4 + channel.len() + 1 + payload.len() + 1;
+                self.encode_header(MESSAGE_ID_NOTIFICATION_RESPONSE, msg_size, dst);
+                dst.put_u32(process);
+                put_cstr(&channel, dst);
+                put_cstr(&payload, dst);
+            }
+            Message::ParameterDescription(data_type_oids) => {
+                let msg_size = 2 + 4 * data_type_oids.len();
+                self.encode_header(MESSAGE_ID_PARAMETER_DESCRIPTION, msg_size, dst);
+                dst.put_u16(data_type_oids.len() as u16);
+                for data_type_oid in data_type_oids {
+                    dst.put_u32(data_type_oid);
+                }
+            }
             Message::ParameterStatus { parameter, value } => {
                 self.encode_header(
                     MESSAGE_ID_PARAMETER_STATUS,
@@ -510,6 +689,12 @@ impl Encoder<Message> for Codec {
                 put_cstr(&parameter, dst);
                 put_cstr(&value, dst);
             }
+            Message::ParseComplete() => {
+                self.encode_header(MESSAGE_ID_PARSE_COMPLETE, 0, dst);
+            }
+            Message::PortalSuspended() => {
+                self.encode_header(MESSAGE_ID_PORTAL_SUSPENDED, 0, dst);
+            }
             Message::ReadyForQuery(status) => {
                 self.encode_header(MESSAGE_ID_READY_FOR_QUERY, 1, dst);
                 dst.put_u8(status);
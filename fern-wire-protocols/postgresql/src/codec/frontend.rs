@@ -19,9 +19,13 @@ const BYTES_STARTUP_MESSAGE_HEADER: usize = 8;
 const MESSAGE_ID_SSL_REQUEST: i32 = 80877103;
 const MESSAGE_ID_STARTUP_MESSAGE: i32 = 196608;
 
-// const MESSAGE_ID_BIND: u8 = b'B'; //TODO(ppiotr3k): write tests
+const MESSAGE_ID_BIND: u8 = b'B';
+const MESSAGE_ID_CLOSE: u8 = b'C';
+const MESSAGE_ID_DESCRIBE: u8 = b'D';
 const MESSAGE_ID_EXECUTE: u8 = b'E';
 const MESSAGE_ID_FLUSH: u8 = b'H';
+const MESSAGE_ID_FUNCTION_CALL: u8 = b'F';
+const MESSAGE_ID_PARSE: u8 = b'P';
 const MESSAGE_ID_QUERY: u8 = b'Q';
 const MESSAGE_ID_SASL: u8 = b'p';
 const MESSAGE_ID_SYNC: u8 = b'S';
@@ -29,15 +33,11 @@ const MESSAGE_ID_TERMINATE: u8 = b'X';
 
 // TODO(ppiotr3k): implement following messages
 // const MESSAGE_ID_CANCEL_REQUEST: u8 = b''; // ! no id; maybe MSB will do //TODO(ppiotr3k): write tests
-// const MESSAGE_ID_CLOSE: u8 = b'C'; //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_COPY_DATA: u8 = b'd'; //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_COPY_DONE: u8 = b'c'; //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_COPY_FAIL: u8 = b'f'; //TODO(ppiotr3k): write tests
-// const MESSAGE_ID_DESCRIBE: u8 = b'D'; //TODO(ppiotr3k): write tests
-// const MESSAGE_ID_FUNCTION_CALL: u8 = b'F'; //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_GSSENC_REQUEST: u8 = b''; // ! no id //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_GSS_RESPONSE: u8 = b'p'; // ! shared id //TODO(ppiotr3k): write tests
-// const MESSAGE_ID_PARSE: u8 = b'P'; //TODO(ppiotr3k): write tests
 // const MESSAGE_ID_PASSWORD_MESSAGE: u8 = b'p'; // ! shared id //TODO(ppiotr3k): write tests
 
 ///TODO(ppiotr3k): write description
@@ -55,11 +55,29 @@ pub enum Message {
         parameters: Vec<BindParameter>,
         results_formats: Vec<u16>,
     },
+    Close {
+        kind: u8, // `S` for a prepared statement, `P` for a portal
+        name: Bytes,
+    },
+    Describe {
+        kind: u8, // `S` for a prepared statement, `P` for a portal
+        name: Bytes,
+    },
     Execute {
         portal: Bytes,
         max_rows: u32,
     },
     Flush(),
+    FunctionCall {
+        function_oid: u32,
+        arguments: Vec<BindParameter>,
+        result_format: u16,
+    },
+    Parse {
+        stmt_name: Bytes,
+        query: Bytes,
+        parameters_types: Vec<u32>, // data type OIDs, zero if unspecified
+    },
     Query(Bytes),
     SASLInitialResponse {
         mecanism: Bytes,
@@ -76,15 +94,11 @@ pub enum Message {
 
     //TODO(ppiotr3k): implement following messages
     CancelRequest(Bytes),
-    Close(Bytes),
     CopyData(Bytes),
     CopyDone(Bytes),
     CopyFail(Bytes),
-    Describe(Bytes),
-    FunctionCall(Bytes),
     GSSENCRequest(Bytes),
     GSSResponse(Bytes),
-    Parse(Bytes),
     PasswordMessage(Bytes),
 }
 
@@ -96,7 +110,7 @@ impl SQLMessage for Message {}
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BindParameter {
     pub format: u16,
-    pub value: Bytes,
+    pub value: Option<Bytes>, // `None` values are SQL `NULL` values
 }
 
 ///TODO(ppiotr3k): write description
@@ -196,25 +210,43 @@ impl Codec {
 
             // Canary
             //#[cfg(test)] //TODO(ppiotr3k): fix enabling `Canary` only in tests
-            b'B' /* 0x42 */ => {
-                frame.advance(msg_length);
-                Message::Canary(len as u8)
-            },
-            //#[cfg(test)] //TODO(ppiotr3k): fix enabling `Canary` only in tests
             b'!' /* 0x21 */ => {
                 return Err(io::Error::new(io::ErrorKind::InvalidData, "expected canary error"));
             },
 
             // Frontend
-            // MESSAGE_ID_BIND => {
-            //     //TODO(ppiotr3k): implement this message
-            // },
+            MESSAGE_ID_BIND => self.get_bind(&mut frame)?,
+            MESSAGE_ID_CLOSE => {
+                let (kind, name) = self.get_target(&mut frame)?;
+                Message::Close { kind, name }
+            },
+            MESSAGE_ID_DESCRIBE => {
+                let (kind, name) = self.get_target(&mut frame)?;
+                Message::Describe { kind, name }
+            },
             MESSAGE_ID_EXECUTE => {
                 let portal = get_cstr(&mut frame)?;
                 let max_rows = get_u32(&mut frame, "malformed packet - invalid execute data")?;
                 Message::Execute { portal, max_rows }
             },
             MESSAGE_ID_FLUSH => Message::Flush(),
+            MESSAGE_ID_FUNCTION_CALL => {
+                let function_oid = get_u32(&mut frame, "malformed packet - invalid function call data")?;
+                let arguments = self.get_parameters(&mut frame)?;
+                let result_format = get_u16(&mut frame, "malformed packet - invalid function call data")?;
+                Message::FunctionCall { function_oid, arguments, result_format }
+            },
+            MESSAGE_ID_PARSE => {
+                let stmt_name = get_cstr(&mut frame)?;
+                let query = get_cstr(&mut frame)?;
+                let mut parameters = get_u16(&mut frame, "malformed packet - invalid parse data")?;
+                let mut parameters_types = Vec::with_capacity(parameters as usize);
+                while parameters > 0 {
+                    parameters_types.push(get_u32(&mut frame, "malformed packet - invalid parse data")?);
+                    parameters -= 1;
+                }
+                Message::Parse { stmt_name, query, parameters_types }
+            },
             MESSAGE_ID_QUERY => {
                 let query = get_cstr(&mut frame)?;
                 Message::Query(query)
@@ -273,6 +305,81 @@ impl Codec {
         Ok(Some(msg))
     }
 
+    /// Decodes a `Bind` message payload from `buf`.
+    ///
+    /// Parameters format codes are expanded, so that each `BindParameter`
+    /// holds its own format, whether one code applies to all parameters or not.
+    fn get_bind(&mut self, buf: &mut BytesMut) -> io::Result<Message> {
+        let portal = get_cstr(buf)?;
+        let stmt_name = get_cstr(buf)?;
+        let parameters = self.get_parameters(buf)?;
+
+        let mut results_count = get_u16(buf, "malformed packet - invalid bind data")?;
+        let mut results_formats = Vec::with_capacity(results_count as usize);
+        while results_count > 0 {
+            results_formats.push(get_u16(buf, "malformed packet - invalid bind data")?);
+            results_count -= 1;
+        }
+
+        Ok(Message::Bind {
+            portal,
+            stmt_name,
+            parameters,
+            results_formats,
+        })
+    }
+
+    /// Decodes parameters of a `Bind` or `FunctionCall` message from `buf`,
+    /// with format codes expanded to each parameter.
+    fn get_parameters(&mut self, buf: &mut BytesMut) -> io::Result<Vec<BindParameter>> {
+        let mut formats_count = get_u16(buf, "malformed packet - invalid bind data")?;
+        let mut formats = Vec::with_capacity(formats_count as usize);
+        while formats_count > 0 {
+            formats.push(get_u16(buf, "malformed packet - invalid bind data")?);
+            formats_count -= 1;
+        }
+
+        let parameters_count = get_u16(buf, "malformed packet - invalid bind data")? as usize;
+        if formats.len() > 1 && formats.len() != parameters_count {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "malformed packet - invalid bind parameters formats",
+            );
+            log::error!("{}", err);
+            return Err(err);
+        }
+
+        let mut parameters = Vec::with_capacity(parameters_count);
+        for idx in 0..parameters_count {
+            let format = match formats.len() {
+                0 => 0, // text format by default
+                1 => formats[0],
+                _ => formats[idx],
+            };
+            let value = get_nullable_bytes(buf, "malformed packet - invalid bind parameter")?;
+            parameters.push(BindParameter { format, value });
+        }
+
+        Ok(parameters)
+    }
+
+    /// Decodes the target of a `Close` or `Describe` message from `buf`,
+    /// as a kind (`S` for a prepared statement, `P` for a portal) and a name.
+    fn get_target(&mut self, buf: &mut BytesMut) -> io::Result<(u8, Bytes)> {
+        let kind = get_u8(buf, "malformed packet - missing target kind")?;
+        if kind != b'S' && kind != b'P' {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "malformed packet - invalid target kind",
+            );
+            log::error!("{}", err);
+            return Err(err);
+        }
+
+        let name = get_cstr(buf)?;
+        Ok((kind, name))
+    }
+
     ///TODO(ppiotr3k): write function description
     pub fn decode_startup_message(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
         if src.len() < BYTES_STARTUP_MESSAGE_HEADER {
@@ -452,6 +559,35 @@ impl Encoder<Message> for Codec {
         //TODO(ppiotr3k): rationalize capacity reservation with `dst.reserve(msg.len())`
         // -> pre-requisite: enum variants are considered as types in Rust
         match msg {
+            Message::Bind {
+                portal,
+                stmt_name,
+                parameters,
+                results_formats,
+            } => {
+                let mut msg_size = portal.len() + 1 + stmt_name.len() + 1;
+                msg_size += parameters_len(&parameters);
+                msg_size += 2 + 2 * results_formats.len();
+
+                self.encode_header(MESSAGE_ID_BIND, msg_size, dst);
+                put_cstr(&portal, dst);
+                put_cstr(&stmt_name, dst);
+                put_parameters(&parameters, dst);
+                dst.put_u16(results_formats.len() as u16);
+                for format in results_formats {
+                    dst.put_u16(format);
+                }
+            }
+            Message::Close { kind, name } => {
+                self.encode_header(MESSAGE_ID_CLOSE, 1 + name.len() + 1, dst);
+                dst.put_u8(kind);
+                put_cstr(&name, dst);
+            }
+            Message::Describe { kind, name } => {
+                self.encode_header(MESSAGE_ID_DESCRIBE, 1 + name.len() + 1, dst);
+                dst.put_u8(kind);
+                put_cstr(&name, dst);
+            }
             Message::Execute { portal, max_rows } => {
                 self.encode_header(MESSAGE_ID_EXECUTE, portal.len() + 1 + 4, dst);
                 put_cstr(&portal, dst);
@@ -460,6 +596,32 @@ impl Encoder<Message> for Codec {
             Message::Flush() => {
                 self.encode_header(MESSAGE_ID_FLUSH, 0, dst);
             }
+            Message::FunctionCall {
+                function_oid,
+                arguments,
+                result_format,
+            } => {
+                let msg_size = 4 + parameters_len(&arguments) + 2;
+                self.encode_header(MESSAGE_ID_FUNCTION_CALL, msg_size, dst);
+                dst.put_u32(function_oid);
+                put_parameters(&arguments, dst);
+                dst.put_u16(result_format);
+            }
+            Message::Parse {
+                stmt_name,
+                query,
+                parameters_types,
+            } => {
+                let msg_size =
+                    stmt_name.len() + 1 + query.len() + 1 + 2 + 4 * parameters_types.len();
+                self.encode_header(MESSAGE_ID_PARSE, msg_size, dst);
+                put_cstr(&stmt_name, dst);
+                put_cstr(&query, dst);
+                dst.put_u16(parameters_types.len() as u16);
+                for data_type_oid in parameters_types {
+                    dst.put_u32(data_type_oid);
+                }
+            }
             Message::Query(query) => {
                 self.encode_header(MESSAGE_ID_QUERY, query.len() + 1, dst);
                 put_cstr(&query, dst);
@@ -518,6 +680,28 @@ impl Default for Codec {
     }
 }
 
+/// Returns the amount of bytes required to write `parameters` with [`put_parameters`].
+fn parameters_len(parameters: &[BindParameter]) -> usize {
+    let mut len = 2 + 2 * parameters.len() + 2;
+    for parameter in parameters.iter() {
+        len += nullable_bytes_len(&parameter.value);
+    }
+    len
+}
+
+/// Writes `parameters` of a `Bind` or `FunctionCall` message, with
+/// a format code for each parameter.
+fn put_parameters(parameters: &[BindParameter], dst: &mut BytesMut) {
+    dst.put_u16(parameters.len() as u16);
+    for parameter in parameters.iter() {
+        dst.put_u16(parameter.format);
+    }
+    dst.put_u16(parameters.len() as u16);
+    for parameter in parameters.iter() {
+        put_nullable_bytes(&parameter.value, dst);
+    }
+}
+
 #[cfg(test)]
 mod decode_tests {
 
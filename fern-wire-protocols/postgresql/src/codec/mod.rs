@@ -79,6 +79,47 @@ pub(crate) mod utils {
         Ok(data)
     }
 
+    /// Creates a new optional [`Bytes`] instance by first reading a 4 bytes
+    /// length header, and then getting as many bytes.
+    ///
+    /// Unlike [`get_bytes`], a length header of `-1` is not mapped to an
+    /// empty [`Bytes`] instance, but to `None`, as it denotes an SQL `NULL`.
+    ///
+    /// The current position in `buf` is advanced by 4 bytes and
+    /// the value contained in the size header, if any.
+    ///
+    /// Returns [`io::ErrorKind::UnexpectedEof`] with `error_msg` if there is not enough data.
+    ///
+    /// [`Bytes`]: https://docs.rs/bytes/*/bytes/struct.Bytes.html
+    pub(crate) fn get_nullable_bytes(
+        buf: &mut BytesMut,
+        error_msg: &str,
+    ) -> io::Result<Option<Bytes>> {
+        // Shouldn't happend, unless packet is malformed.
+        if buf.remaining() < 4 {
+            let err = io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "malformed packet - invalid data size",
+            );
+            log::error!("{}", err);
+            return Err(err);
+        }
+
+        let data_length = buf.get_u32();
+        log::trace!("nullable bytes data length header: {}", data_length);
+
+        if data_length == u32::MAX {
+            return Ok(None);
+        }
+
+        if buf.remaining() < data_length as usize {
+            log::error!("{}", error_msg);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, error_msg));
+        }
+
+        Ok(Some(buf.copy_to_bytes(data_length as usize)))
+    }
+
     /// Writes bytes to `buf`, prefixing `data` with a size header.
     ///
     /// The current position in `buf` is advanced by the length of `data`,
@@ -93,6 +134,26 @@ pub(crate) mod utils {
         buf.put((*data).clone());
     }
 
+    /// Writes optional bytes to `buf`, prefixing `data` with a size header.
+    ///
+    /// A `None` value is written as a size header of `-1` with no data bytes,
+    /// which is how an SQL `NULL` is represented in the protocol.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there is not enough remaining capacity in `buf`.
+    pub(crate) fn put_nullable_bytes(data: &Option<Bytes>, buf: &mut BytesMut) {
+        match data {
+            Some(data) => put_bytes(data, buf),
+            None => buf.put_i32(-1),
+        }
+    }
+
+    /// Returns the amount of bytes required to write `data` with [`put_nullable_bytes`].
+    pub(crate) fn nullable_bytes_len(data: &Option<Bytes>) -> usize {
+        4 + data.as_ref().map_or(0, |data| data.len())
+    }
+
     /// Gets a C-style null character terminated string from `buf`
     /// as a new [`Bytes`] instance.
     ///
@@ -24,3 +24,5 @@
 #![forbid(unsafe_code)]
 
 pub mod codec;
+pub mod tracker;
+pub mod types;
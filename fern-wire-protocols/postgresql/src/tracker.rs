@@ -0,0 +1,540 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracking of `RowDescription`s across a PostgreSQL session.
+//!
+//! A `DataRow` does not carry its columns description: depending on the
+//! protocol flow, it is defined by a preceding `RowDescription` of a simple
+//! query, or by a `Describe` of a portal or prepared statement in an
+//! [Extended Query], possibly issued long before the `Execute` producing
+//! rows. With pipelining, many requests may be awaiting responses at once.
+//!
+//! A [`DescriptionTracker`] follows frontend requests and backend responses,
+//! caching descriptions by prepared statement and portal name, so that the
+//! description applying to each `DataRow` is known. It is shared between
+//! the two `Pipe`s of a connection, and never panics on unexpected flows:
+//! when in doubt, no description is returned.
+//!
+//! [Extended Query]: https://www.postgresql.org/docs/current/protocol-flow.html#PROTOCOL-FLOW-EXT-QUERY
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use fern_proxy_interfaces::{SQLHandlerConfig, SQLMessageHandler};
+
+use crate::codec::backend::{self, RowDescription};
+use crate::codec::frontend;
+
+/// Shared description of the columns of `DataRow`s.
+pub type Descriptions = Arc<Vec<RowDescription>>;
+
+/// Frontend requests awaiting a backend response, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pending {
+    Parse(Bytes),
+    Bind {
+        portal: Bytes,
+        statement: Bytes,
+        results_formats: Vec<u16>,
+    },
+    DescribeStatement(Bytes),
+    DescribePortal(Bytes),
+    Execute(Bytes),
+    Close {
+        kind: u8,
+        name: Bytes,
+    },
+    FunctionCall(u32),
+    Query,
+    Sync,
+}
+
+/// Tracking state, behind the shared `DescriptionTracker`.
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Frontend requests awaiting a backend response.
+    pending: VecDeque<Pending>,
+
+    /// Descriptions of described prepared statements, by name.
+    /// Statements returning no data have an empty description.
+    statements: HashMap<Bytes, Descriptions>,
+
+    /// Descriptions of bound or described portals, by name.
+    portals: HashMap<Bytes, Descriptions>,
+
+    /// Description of the current simple query result set, if any.
+    current: Option<Descriptions>,
+}
+
+impl TrackerState {
+    /// Removes the oldest pending request, if it satisfies `predicate`.
+    fn complete(&mut self, predicate: impl Fn(&Pending) -> bool) -> Option<Pending> {
+        match self.pending.front() {
+            Some(pending) if predicate(pending) => self.pending.pop_front(),
+            other => {
+                log::warn!("unexpected response for pending request: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// Applies a backend `ErrorResponse`.
+    fn error(&mut self) {
+        self.current = None;
+        match self.pending.front() {
+            // A simple query or function call is over with `ReadyForQuery`.
+            Some(Pending::Query | Pending::FunctionCall(_)) | None => {}
+            // Backend discards extended query requests until `Sync`.
+            Some(_) => {
+                while !matches!(self.pending.front(), Some(Pending::Sync) | None) {
+                    self.pending.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Applies a backend `ReadyForQuery` with transaction `status`.
+    fn ready(&mut self, status: u8) {
+        self.current = None;
+        while let Some(pending) = self.pending.pop_front() {
+            if matches!(
+                pending,
+                Pending::Sync | Pending::Query | Pending::FunctionCall(_)
+            ) {
+                break;
+            }
+        }
+
+        // Portals only live until the end of a transaction.
+        if status == b'I' {
+            self.portals.clear();
+        }
+    }
+}
+
+/// Tracker of `RowDescription`s by prepared statement and portal name.
+///
+/// Cloning a `DescriptionTracker` gives access to the same tracking state.
+#[derive(Debug, Clone, Default)]
+pub struct DescriptionTracker {
+    state: Arc<Mutex<TrackerState>>,
+}
+
+impl DescriptionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks tracking state, regardless of poisoning as state is always consistent.
+    fn state(&self) -> MutexGuard<'_, TrackerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tracks a request sent by the frontend, awaiting a backend response.
+    pub fn track_frontend(&self, msg: &frontend::Message) {
+        let pending = match msg {
+            frontend::Message::Parse { stmt_name, .. } => Pending::Parse(stmt_name.clone()),
+            frontend::Message::Bind {
+                portal,
+                stmt_name,
+                results_formats,
+                ..
+            } => Pending::Bind {
+                portal: portal.clone(),
+                statement: stmt_name.clone(),
+                results_formats: results_formats.clone(),
+            },
+            frontend::Message::Describe { kind: b'S', name } => {
+                Pending::DescribeStatement(name.clone())
+            }
+            frontend::Message::Describe { name, .. } => Pending::DescribePortal(name.clone()),
+            frontend::Message::Execute { portal, .. } => Pending::Execute(portal.clone()),
+            frontend::Message::Close { kind, name } => Pending::Close {
+                kind: *kind,
+                name: name.clone(),
+            },
+            frontend::Message::FunctionCall { function_oid, .. } => {
+                Pending::FunctionCall(*function_oid)
+            }
+            frontend::Message::Query(_) => Pending::Query,
+            frontend::Message::Sync() => Pending::Sync,
+            _ => return,
+        };
+
+        log::trace!("tracking pending request: {:?}", pending);
+        self.state().pending.push_back(pending);
+    }
+
+    /// Tracks a response sent by the backend.
+    pub fn track_backend(&self, msg: &backend::Message) {
+        let mut state = self.state();
+        match msg {
+            backend::Message::ParseComplete() => {
+                if let Some(Pending::Parse(name)) =
+                    state.complete(|p| matches!(p, Pending::Parse(_)))
+                {
+                    // A prepared statement may be redefined, e.g. the unnamed one.
+                    state.statements.remove(&name);
+                }
+            }
+            backend::Message::BindComplete() => {
+                if let Some(Pending::Bind {
+                    portal,
+                    statement,
+                    results_formats,
+                }) = state.complete(|p| matches!(p, Pending::Bind { .. }))
+                {
+                    match state.statements.get(&statement) {
+                        Some(descriptions) => {
+                            let descriptions = with_formats(descriptions, &results_formats);
+                            state.portals.insert(portal, descriptions);
+                        }
+                        None => {
+                            state.portals.remove(&portal);
+                        }
+                    }
+                }
+            }
+            backend::Message::RowDescription(descriptions) => {
+                let descriptions = Arc::new(descriptions.clone());
+                match state.pending.front().cloned() {
+                    Some(Pending::DescribeStatement(name)) => {
+                        state.pending.pop_front();
+                        state.statements.insert(name, descriptions);
+                    }
+                    Some(Pending::DescribePortal(name)) => {
+                        state.pending.pop_front();
+                        state.portals.insert(name, descriptions);
+                    }
+                    // Simple query, possibly with multiple result sets.
+                    _ => state.current = Some(descriptions),
+                }
+            }
+            backend::Message::NoData() => {
+                match state.complete(|p| {
+                    matches!(
+                        p,
+                        Pending::DescribeStatement(_) | Pending::DescribePortal(_)
+                    )
+                }) {
+                    Some(Pending::DescribeStatement(name)) => {
+                        state.statements.insert(name, Arc::new(vec![]));
+                    }
+                    Some(Pending::DescribePortal(name)) => {
+                        state.portals.insert(name, Arc::new(vec![]));
+                    }
+                    _ => {}
+                }
+            }
+            backend::Message::CommandComplete(_)
+            | backend::Message::EmptyQueryResponse()
+            | backend::Message::PortalSuspended() => match state.pending.front() {
+                Some(Pending::Execute(_)) => {
+                    state.pending.pop_front();
+                }
+                // End of a result set in a simple query.
+                _ => state.current = None,
+            },
+            backend::Message::CloseComplete() => {
+                if let Some(Pending::Close { kind, name }) =
+                    state.complete(|p| matches!(p, Pending::Close { .. }))
+                {
+                    if kind == b'S' {
+                        state.statements.remove(&name);
+                    } else {
+                        state.portals.remove(&name);
+                    }
+                }
+            }
+            backend::Message::ErrorResponse(_) => state.error(),
+            backend::Message::ReadyForQuery(status) => state.ready(*status),
+            _ => {}
+        }
+    }
+
+    /// Returns the OID of the function whose result is awaited, if known.
+    pub fn function_call(&self) -> Option<u32> {
+        match self.state().pending.front() {
+            Some(Pending::FunctionCall(function_oid)) => Some(*function_oid),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the prepared statement whose description is
+    /// awaited, e.g. when a `ParameterDescription` is received.
+    pub fn described_statement(&self) -> Option<Bytes> {
+        match self.state().pending.front() {
+            Some(Pending::DescribeStatement(name)) => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the description of incoming `DataRow`s, if known.
+    pub fn row_description(&self) -> Option<Descriptions> {
+        let state = self.state();
+        match state.pending.front() {
+            Some(Pending::Execute(portal)) => state.portals.get(portal).cloned(),
+            _ => state.current.clone(),
+        }
+    }
+}
+
+/// Applies `Bind` results formats to `descriptions` of a prepared statement.
+fn with_formats(descriptions: &Descriptions, results_formats: &[u16]) -> Descriptions {
+    let format = |idx: usize| match results_formats.len() {
+        0 => 0, // text format by default
+        1 => results_formats[0],
+        _ => results_formats.get(idx).copied().unwrap_or(0),
+    };
+
+    if descriptions
+        .iter()
+        .enumerate()
+        .all(|(idx, description)| description.format == format(idx))
+    {
+        return descriptions.clone();
+    }
+
+    let mut descriptions = descriptions.as_ref().clone();
+    for (idx, description) in descriptions.iter_mut().enumerate() {
+        description.format = format(idx);
+    }
+    Arc::new(descriptions)
+}
+
+/// Tracking frontend requests, as a passthrough `SQLMessageHandler`.
+#[async_trait]
+impl SQLMessageHandler<frontend::Message> for DescriptionTracker {
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self::default()
+    }
+
+    async fn process(&mut self, msg: frontend::Message) -> Option<frontend::Message> {
+        self.track_frontend(&msg);
+        Some(msg)
+    }
+}
+
+/// Tracking backend responses, as a passthrough `SQLMessageHandler`.
+///
+/// Placed first in a chain of handlers, tracking is shared by all following ones.
+#[async_trait]
+impl SQLMessageHandler<backend::Message> for DescriptionTracker {
+    fn new(_config: &SQLHandlerConfig) -> Self {
+        Self::default()
+    }
+
+    async fn process(&mut self, msg: backend::Message) -> Option<backend::Message> {
+        self.track_backend(&msg);
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function building a description for a text column.
+    fn column(name: &'static str) -> RowDescription {
+        RowDescription {
+            name: Bytes::from_static(name.as_bytes()),
+            table_oid: 0,
+            column_attr: 0,
+            data_type_oid: 25,
+            data_type_size: -1,
+            type_modifier: -1,
+            format: 0,
+        }
+    }
+
+    fn parse(name: &'static str) -> frontend::Message {
+        frontend::Message::Parse {
+            stmt_name: Bytes::from_static(name.as_bytes()),
+            query: Bytes::from_static(b"SELECT secret FROM t"),
+            parameters_types: vec![],
+        }
+    }
+
+    fn bind(portal: &'static str, statement: &'static str) -> frontend::Message {
+        frontend::Message::Bind {
+            portal: Bytes::from_static(portal.as_bytes()),
+            stmt_name: Bytes::from_static(statement.as_bytes()),
+            parameters: vec![],
+            results_formats: vec![],
+        }
+    }
+
+    fn describe(kind: u8, name: &'static str) -> frontend::Message {
+        frontend::Message::Describe {
+            kind,
+            name: Bytes::from_static(name.as_bytes()),
+        }
+    }
+
+    fn execute(portal: &'static str) -> frontend::Message {
+        frontend::Message::Execute {
+            portal: Bytes::from_static(portal.as_bytes()),
+            max_rows: 0,
+        }
+    }
+
+    fn names(descriptions: Option<Descriptions>) -> Option<Vec<Bytes>> {
+        descriptions.map(|d| d.iter().map(|c| c.name.clone()).collect())
+    }
+
+    #[test]
+    fn simple_query_multiple_result_sets() {
+        let tracker = DescriptionTracker::new();
+        tracker.track_frontend(&frontend::Message::Query(Bytes::from_static(b"...")));
+
+        tracker.track_backend(&backend::Message::RowDescription(vec![column("a")]));
+        assert_eq!(
+            Some(vec![Bytes::from_static(b"a")]),
+            names(tracker.row_description())
+        );
+        tracker.track_backend(&backend::Message::CommandComplete(Bytes::new()));
+        assert_eq!(
+            None,
+            names(tracker.row_description()),
+            "between result sets"
+        );
+
+        tracker.track_backend(&backend::Message::RowDescription(vec![column("b")]));
+        assert_eq!(
+            Some(vec![Bytes::from_static(b"b")]),
+            names(tracker.row_description())
+        );
+        tracker.track_backend(&backend::Message::CommandComplete(Bytes::new()));
+        tracker.track_backend(&backend::Message::ReadyForQuery(b'I'));
+        assert!(tracker.state().pending.is_empty(), "no pending request");
+    }
+
+    #[test]
+    fn extended_query_statement_describe() {
+        let tracker = DescriptionTracker::new();
+        for msg in [
+            parse("stmt"),
+            describe(b'S', "stmt"),
+            bind("", "stmt"),
+            execute(""),
+            frontend::Message::Sync(),
+        ] {
+            tracker.track_frontend(&msg);
+        }
+
+        tracker.track_backend(&backend::Message::ParseComplete());
+        tracker.track_backend(&backend::Message::ParameterDescription(vec![]));
+        let described = Some(Bytes::from_static(b"stmt"));
+        assert_eq!(
+            described,
+            tracker.described_statement(),
+            "awaiting description"
+        );
+        tracker.track_backend(&backend::Message::RowDescription(vec![column("secret")]));
+        assert_eq!(None, tracker.described_statement(), "described");
+        tracker.track_backend(&backend::Message::BindComplete());
+        let expected = Some(vec![Bytes::from_static(b"secret")]);
+        assert_eq!(expected, names(tracker.row_description()), "portal rows");
+        tracker.track_backend(&backend::Message::CommandComplete(Bytes::new()));
+        tracker.track_backend(&backend::Message::ReadyForQuery(b'I'));
+
+        // Statement description is kept for later executions.
+        tracker.track_frontend(&bind("", "stmt"));
+        tracker.track_frontend(&execute(""));
+        tracker.track_backend(&backend::Message::BindComplete());
+        assert_eq!(
+            expected,
+            names(tracker.row_description()),
+            "reused statement"
+        );
+    }
+
+    #[test]
+    fn extended_query_portal_suspended() {
+        let tracker = DescriptionTracker::new();
+        for msg in [parse(""), bind("cursor", ""), describe(b'P', "cursor")] {
+            tracker.track_frontend(&msg);
+        }
+        tracker.track_frontend(&execute("cursor"));
+        tracker.track_frontend(&execute("cursor"));
+
+        tracker.track_backend(&backend::Message::ParseComplete());
+        tracker.track_backend(&backend::Message::BindComplete());
+        tracker.track_backend(&backend::Message::RowDescription(vec![column("x")]));
+        let expected = Some(vec![Bytes::from_static(b"x")]);
+        assert_eq!(expected, names(tracker.row_description()), "first batch");
+        tracker.track_backend(&backend::Message::PortalSuspended());
+        assert_eq!(expected, names(tracker.row_description()), "second batch");
+    }
+
+    #[test]
+    fn extended_query_error_discards_until_sync() {
+        let tracker = DescriptionTracker::new();
+        for msg in [
+            parse(""),
+            bind("", ""),
+            describe(b'P', ""),
+            execute(""),
+            frontend::Message::Sync(),
+            frontend::Message::Query(Bytes::from_static(b"...")),
+        ] {
+            tracker.track_frontend(&msg);
+        }
+
+        tracker.track_backend(&backend::Message::ErrorResponse(Bytes::new()));
+        assert_eq!(Some(&Pending::Sync), tracker.state().pending.front());
+        tracker.track_backend(&backend::Message::ReadyForQuery(b'I'));
+
+        // Next request is processed normally.
+        tracker.track_backend(&backend::Message::RowDescription(vec![column("y")]));
+        let expected = Some(vec![Bytes::from_static(b"y")]);
+        assert_eq!(expected, names(tracker.row_description()), "next query");
+    }
+
+    #[test]
+    fn unknown_portal_has_no_description() {
+        let tracker = DescriptionTracker::new();
+        tracker.track_frontend(&bind("", "never_described"));
+        tracker.track_frontend(&execute(""));
+        tracker.track_backend(&backend::Message::BindComplete());
+        assert_eq!(None, names(tracker.row_description()));
+
+        // Unexpected responses are ignored.
+        tracker.track_backend(&backend::Message::CloseComplete());
+        tracker.track_backend(&backend::Message::NoData());
+    }
+
+    #[test]
+    fn bind_results_formats_applied() {
+        let descriptions = Arc::new(vec![column("a"), column("b")]);
+        let binary = with_formats(&descriptions, &[1]);
+        assert!(binary.iter().all(|c| c.format == 1), "all binary");
+        let mixed = with_formats(&descriptions, &[0, 1]);
+        assert_eq!(
+            vec![0, 1],
+            mixed.iter().map(|c| c.format).collect::<Vec<_>>()
+        );
+        assert!(Arc::ptr_eq(
+            &descriptions,
+            &with_formats(&descriptions, &[])
+        ));
+    }
+
+    #[test]
+    fn function_call_until_ready_for_query() {
+        let tracker = DescriptionTracker::new();
+        tracker.track_frontend(&frontend::Message::FunctionCall {
+            function_oid: 1598,
+            arguments: vec![],
+            result_format: 0,
+        });
+        assert_eq!(Some(1598), tracker.function_call(), "awaited result");
+
+        tracker.track_backend(&backend::Message::FunctionCallResponse(None));
+        assert_eq!(Some(1598), tracker.function_call(), "until ready");
+        tracker.track_backend(&backend::Message::ReadyForQuery(b'I'));
+        assert_eq!(None, tracker.function_call(), "after ready");
+    }
+}
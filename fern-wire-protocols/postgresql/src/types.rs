@@ -0,0 +1,39 @@
+// SPDX-FileCopyrightText:  Copyright © 2022 The Fern Authors <team@fernproxy.io>
+// SPDX-License-Identifier: Apache-2.0
+
+//! Object identifiers (OIDs) of PostgreSQL built-in data types, as found in
+//! `RowDescription` and `ParameterDescription` Messages.
+//!
+//! See `pg_type.dat` in PostgreSQL sources for the full list.
+
+pub const BOOL: u32 = 16;
+pub const BYTEA: u32 = 17;
+pub const INT8: u32 = 20;
+pub const INT2: u32 = 21;
+pub const INT4: u32 = 23;
+pub const TEXT: u32 = 25;
+pub const JSON: u32 = 114;
+pub const FLOAT4: u32 = 700;
+pub const FLOAT8: u32 = 701;
+pub const BPCHAR: u32 = 1042;
+pub const VARCHAR: u32 = 1043;
+pub const DATE: u32 = 1082;
+pub const TIMESTAMP: u32 = 1114;
+pub const TIMESTAMPTZ: u32 = 1184;
+pub const NUMERIC: u32 = 1700;
+pub const JSONB: u32 = 3802;
+
+/// Returns `true` if `oid` is an integer type.
+pub const fn is_integer(oid: u32) -> bool {
+    matches!(oid, INT2 | INT4 | INT8)
+}
+
+/// Returns `true` if `oid` is a numeric type, integers included.
+pub const fn is_numeric(oid: u32) -> bool {
+    matches!(oid, INT2 | INT4 | INT8 | FLOAT4 | FLOAT8 | NUMERIC)
+}
+
+/// Returns `true` if `oid` is a date or timestamp type.
+pub const fn is_temporal(oid: u32) -> bool {
+    matches!(oid, DATE | TIMESTAMP | TIMESTAMPTZ)
+}
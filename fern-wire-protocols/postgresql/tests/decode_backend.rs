@@ -9,7 +9,7 @@ mod decode_backend {
     use test_log::test;
     use tokio_util::codec::Decoder;
 
-    use fern_protocol_postgresql::codec::backend::{Codec, Message, RowDescription};
+    use fern_protocol_postgresql::codec::backend::{Codec, CopyResponse, Message, RowDescription};
 
     /// Helper function to ease writing decoding tests.
     fn assert_decode(data: &[u8], expected: &[Message], remaining: usize) {
@@ -355,6 +355,38 @@ mod decode_backend {
         assert_decode(&data[..], &expected, remaining);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_bind_complete() {
+        let data = [
+            50,         // msg id: '2'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::BindComplete(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_close_complete() {
+        let data = [
+            51,         // msg id: '3'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::CloseComplete(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_command_complete() {
@@ -386,6 +418,75 @@ mod decode_backend {
         assert_decode(&data[..], &expected, remaining);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_data() {
+        let data = [
+            100,                          // msg id: 'd'
+            0, 0, 0, 10,                  // payload length: 10
+            49, 9, 98, 111, 98, 10,       // data: "1\tbob\n"
+        ];
+
+        let expected = vec![
+            Message::CopyData(Bytes::from_static(b"1\tbob\n")),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_done() {
+        let data = [
+            99,         // msg id: 'c'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::CopyDone(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_out_response() {
+        let data = [
+            72,             // msg id: 'H'
+            0, 0, 0, 11,    // payload length: 11
+            0,              // format: textual
+            0, 2,           // columns: 2
+            0, 0,           // c1: format: text
+            0, 0,           // c2: format: text
+        ];
+
+        let expected = vec![
+            Message::CopyOutResponse(CopyResponse { format: 0, columns_formats: vec![0, 0] }),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_copy_out_response_unknown_format() {
+        let data = [
+            72,             // msg id: 'H'
+            0, 0, 0, 7,     // payload length: 7
+            2,              // format: unknown
+            0, 0,           // columns: 0
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_data_row_simple() {
@@ -400,7 +501,7 @@ mod decode_backend {
 
         let expected = vec![
             Message::DataRow(vec![
-                Bytes::from_static(b"1"),
+                Some(Bytes::from_static(b"1")),
             ]),
         ];
         let remaining = 0;
@@ -516,14 +617,14 @@ mod decode_backend {
 
         let expected = vec![
             Message::DataRow(vec![
-                Bytes::from_static(b"pg_catalog"),
-                Bytes::from_static(b"pg_aggregate"),
-                Bytes::from_static(b"table"),
-                Bytes::from_static(b"root"),
-                Bytes::from_static(b"permanent"),
-                Bytes::from_static(b"heap"),
-                Bytes::from_static(b"56 kB"),
-                Bytes::from_static(b""),
+                Some(Bytes::from_static(b"pg_catalog")),
+                Some(Bytes::from_static(b"pg_aggregate")),
+                Some(Bytes::from_static(b"table")),
+                Some(Bytes::from_static(b"root")),
+                Some(Bytes::from_static(b"permanent")),
+                Some(Bytes::from_static(b"heap")),
+                Some(Bytes::from_static(b"56 kB")),
+                None,
             ]),
         ];
         let remaining = 0;
@@ -564,14 +665,38 @@ mod decode_backend {
 
         let expected = vec![
             Message::DataRow(vec![
-                Bytes::from_static(b"pg_catalog"),
-                Bytes::from_static(b"pg_user"),
-                Bytes::from_static(b"view"),
-                Bytes::from_static(b"root"),
-                Bytes::from_static(b"permanent"),
-                Bytes::from_static(b""),
-                Bytes::from_static(b"0 bytes"),
-                Bytes::from_static(b""),
+                Some(Bytes::from_static(b"pg_catalog")),
+                Some(Bytes::from_static(b"pg_user")),
+                Some(Bytes::from_static(b"view")),
+                Some(Bytes::from_static(b"root")),
+                Some(Bytes::from_static(b"permanent")),
+                None,
+                Some(Bytes::from_static(b"0 bytes")),
+                None,
+            ]),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_data_row_null_and_empty_fields() {
+        let data = [
+            68,                 // msg id: 'D'
+            0, 0, 0, 14,        // payload length: 14
+            0, 2,               // number of columns: 2
+
+            0, 0, 0, 0,         // c1: field length: 0 (empty string)
+
+            255, 255, 255, 255, // c2: NULL field, no value bytes
+        ];
+
+        let expected = vec![
+            Message::DataRow(vec![
+                Some(Bytes::from_static(b"")),
+                None,
             ]),
         ];
         let remaining = 0;
@@ -595,6 +720,151 @@ mod decode_backend {
         assert_decode(&data[..], &expected, remaining);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call_response() {
+        let data = [
+            86,             // msg id: 'V'
+            0, 0, 0, 10,    // payload length: 10
+            0, 0, 0, 2,     // result length: 2
+            52, 50,         // result: "42"
+        ];
+
+        let expected = vec![
+            Message::FunctionCallResponse(Some(Bytes::from_static(b"42"))),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call_response_null() {
+        let data = [
+            86,                     // msg id: 'V'
+            0, 0, 0, 8,             // payload length: 8
+            255, 255, 255, 255,     // result length: -1 (NULL)
+        ];
+
+        let expected = vec![
+            Message::FunctionCallResponse(None),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_no_data() {
+        let data = [
+            110,        // msg id: 'n'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::NoData(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_notice_response() {
+        let data = [
+            78,                             // msg id: 'N'
+            0, 0, 0, 13,                    // payload length: 13
+            83, 78, 79, 84, 73, 67, 69, 0,  // field: 'S' severity: "NOTICE\0"
+            0,                              // fields terminator
+        ];
+
+        let expected = vec![
+            Message::NoticeResponse(Bytes::from_static(b"SNOTICE\0\0")),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_notification_response() {
+        let data = [
+            65,                     // msg id: 'A'
+            0, 0, 0, 16,            // payload length: 16
+            0, 0, 0, 42,            // process: 42
+            99, 104, 97, 110, 0,    // channel: "chan\0"
+            123, 125, 0,            // payload: "{}\0"
+        ];
+
+        let expected = vec![
+            Message::NotificationResponse {
+                process: 42,
+                channel: Bytes::from_static(b"chan"),
+                payload: Bytes::from_static(b"{}"),
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_notification_response_missing_payload() {
+        let data = [
+            65,                     // msg id: 'A'
+            0, 0, 0, 13,            // payload length: 13
+            0, 0, 0, 42,            // process: 42
+            99, 104, 97, 110, 0,    // channel: "chan\0"
+                                    // missing payload
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parameter_description() {
+        let data = [
+            116,          // msg id: 't'
+            0, 0, 0, 14,  // payload length: 14
+            0, 2,         // parameters: 2
+            0, 0, 0, 23,  // p1: data type OID: 23 (int4)
+            0, 0, 0, 25,  // p2: data type OID: 25 (text)
+        ];
+
+        let expected = vec![
+            Message::ParameterDescription(vec![23, 25]),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_parameter_description_missing_type() {
+        let data = [
+            116,          // msg id: 't'
+            0, 0, 0, 10,  // payload length: 10
+            0, 2,         // parameters: 2
+            0, 0, 0, 23,  // p1: data type OID: 23 (int4)
+                          // missing p2
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_parameter_status_app_name() {
@@ -896,6 +1166,38 @@ mod decode_backend {
         assert_decode(&data[..], &expected, remaining);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parse_complete() {
+        let data = [
+            49,         // msg id: '1'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::ParseComplete(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_portal_suspended() {
+        let data = [
+            115,        // msg id: 's'
+            0, 0, 0, 4, // payload length: 4
+        ];
+
+        let expected = vec![
+            Message::PortalSuspended(),
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_ready_for_query() {
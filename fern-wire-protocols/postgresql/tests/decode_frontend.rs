@@ -9,7 +9,7 @@ mod decode_frontend {
     use test_log::test;
     use tokio_util::codec::Decoder;
 
-    use fern_protocol_postgresql::codec::frontend::{Codec, Message};
+    use fern_protocol_postgresql::codec::frontend::{BindParameter, Codec, Message};
 
     /// Helper function to ease writing decoding tests.
     fn assert_decode(data: &[u8], expected: &[Message], remaining: usize) {
@@ -28,6 +28,121 @@ mod decode_frontend {
         assert_eq!(expected, decoded, "decoded messages");
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_bind_text_parameters() {
+        let data = [
+            66,                 // msg id: 'B'
+            0, 0, 0, 28,        // payload length: 28
+            0,                  // cstr: "\0" (unnamed portal)
+            115, 49, 0,         // cstr: "s1\0"
+            0, 1,               // parameters format codes: 1
+            0, 0,               // format code: text, for all parameters
+            0, 2,               // parameters: 2
+            0, 0, 0, 2,         // p1: length: 2
+            52, 50,             // p1: value: "42"
+            255, 255, 255, 255, // p2: length: -1 (NULL)
+            0, 1,               // results format codes: 1
+            0, 1,               // format code: binary, for all results
+        ];
+
+        let expected = vec![
+            Message::Bind {
+                portal: Bytes::from_static(b""),
+                stmt_name: Bytes::from_static(b"s1"),
+                parameters: vec![
+                    BindParameter { format: 0, value: Some(Bytes::from_static(b"42")) },
+                    BindParameter { format: 0, value: None },
+                ],
+                results_formats: vec![1],
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_bind_formats_parameters_mismatch() {
+        let data = [
+            66,          // msg id: 'B'
+            0, 0, 0, 21, // payload length: 21
+            0,           // cstr: "\0" (unnamed portal)
+            0,           // cstr: "\0" (unnamed statement)
+            0, 2,        // parameters format codes: 2
+            0, 0,        // format code: text
+            0, 1,        // format code: binary
+            0, 1,        // parameters: 1 (mismatching format codes)
+            0, 0, 0, 1,  // p1: length: 1
+            49,          // p1: value: "1"
+            0, 0,        // results format codes: 0
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_close_statement() {
+        let data = [
+            67,         // msg id: 'C'
+            0, 0, 0, 8, // payload length: 8
+            83,         // kind: 'S' (prepared statement)
+            115, 49, 0, // cstr: "s1\0"
+        ];
+
+        let expected = vec![
+            Message::Close {
+                kind: b'S',
+                name: Bytes::from_static(b"s1"),
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_close_unknown_kind() {
+        let data = [
+            67,         // msg id: 'C'
+            0, 0, 0, 6, // payload length: 6
+            88,         // unknown kind: 'X'
+            0,          // cstr: "\0"
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_describe_unnamed_portal() {
+        let data = [
+            68,         // msg id: 'D'
+            0, 0, 0, 6, // payload length: 6
+            80,         // kind: 'P' (portal)
+            0,          // cstr: "\0" (unnamed portal)
+        ];
+
+        let expected = vec![
+            Message::Describe {
+                kind: b'P',
+                name: Bytes::from_static(b""),
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_execute_no_limit() {
@@ -102,6 +217,77 @@ mod decode_frontend {
         assert_decode(&data[..], &expected, remaining);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call() {
+        let data = [
+            70,                 // msg id: 'F'
+            0, 0, 0, 22,        // payload length: 22
+            0, 0, 6, 62,        // function OID: 1598
+            0, 1,               // arguments format codes: 1
+            0, 0,               // format code: text, for all arguments
+            0, 1,               // arguments: 1
+            0, 0, 0, 2,         // a1: length: 2
+            52, 50,             // a1: value: "42"
+            0, 0,               // result format code: text
+        ];
+
+        let expected = vec![
+            Message::FunctionCall {
+                function_oid: 1598,
+                arguments: vec![
+                    BindParameter { format: 0, value: Some(Bytes::from_static(b"42")) },
+                ],
+                result_format: 0,
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn invalid_function_call_missing_result_format() {
+        let data = [
+            70,                 // msg id: 'F'
+            0, 0, 0, 12,        // payload length: 12
+            0, 0, 6, 62,        // function OID: 1598
+            0, 0,               // arguments format codes: 0
+            0, 0,               // arguments: 0
+                                // missing result format code
+        ];
+
+        let expected = vec![];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parse_with_parameters_types() {
+        let data = [
+            80,                                      // msg id: 'P'
+            0, 0, 0, 23,                             // payload length: 23
+            115, 49, 0,                              // cstr: "s1\0"
+            83, 69, 76, 69, 67, 84, 32, 36, 49, 0,   // cstr: "SELECT $1\0"
+            0, 1,                                    // parameters types: 1
+            0, 0, 0, 23,                             // p1: data type OID: 23 (int4)
+        ];
+
+        let expected = vec![
+            Message::Parse {
+                stmt_name: Bytes::from_static(b"s1"),
+                query: Bytes::from_static(b"SELECT $1"),
+                parameters_types: vec![23],
+            },
+        ];
+        let remaining = 0;
+
+        assert_decode(&data[..], &expected, remaining);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_sasl_initial_response() {
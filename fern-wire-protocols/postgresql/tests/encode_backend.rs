@@ -9,7 +9,7 @@ mod encode_backend {
     use test_log::test;
     use tokio_util::codec::{Decoder, Encoder};
 
-    use fern_protocol_postgresql::codec::backend::{Codec, Message, RowDescription};
+    use fern_protocol_postgresql::codec::backend::{Codec, CopyResponse, Message, RowDescription};
 
     /// Helper function to ease writing encoding tests.
     fn assert_encode(msg: Message) {
@@ -69,6 +69,22 @@ mod encode_backend {
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_bind_complete() {
+        let msg = Message::BindComplete();
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_close_complete() {
+        let msg = Message::CloseComplete();
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_command_complete() {
@@ -79,11 +95,51 @@ mod encode_backend {
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_both_response() {
+        let msg = Message::CopyBothResponse(CopyResponse { format: 1, columns_formats: vec![1] });
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_data() {
+        let msg = Message::CopyData(Bytes::from_static(b"1\tbob\n"));
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_done() {
+        let msg = Message::CopyDone();
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_in_response() {
+        let msg = Message::CopyInResponse(CopyResponse { format: 0, columns_formats: vec![0, 0] });
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_copy_out_response() {
+        let msg = Message::CopyOutResponse(CopyResponse { format: 0, columns_formats: vec![] });
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_data_row_simple() {
         let msg = Message::DataRow(vec![
-            Bytes::from_static(b"1"),
+            Some(Bytes::from_static(b"1")),
         ]);
 
         assert_encode(msg);
@@ -101,14 +157,14 @@ mod encode_backend {
     #[rustfmt::skip]
     fn valid_data_row_complex_null_columns_ending() {
         let msg = Message::DataRow(vec![
-            Bytes::from_static(b"pg_catalog"),
-            Bytes::from_static(b"pg_aggregate"),
-            Bytes::from_static(b"table"),
-            Bytes::from_static(b"root"),
-            Bytes::from_static(b"permanent"),
-            Bytes::from_static(b"heap"),
-            Bytes::from_static(b"56 kB"),
-            Bytes::from_static(b""),
+            Some(Bytes::from_static(b"pg_catalog")),
+            Some(Bytes::from_static(b"pg_aggregate")),
+            Some(Bytes::from_static(b"table")),
+            Some(Bytes::from_static(b"root")),
+            Some(Bytes::from_static(b"permanent")),
+            Some(Bytes::from_static(b"heap")),
+            Some(Bytes::from_static(b"56 kB")),
+            None,
         ]);
 
         assert_encode(msg);
@@ -118,19 +174,51 @@ mod encode_backend {
     #[rustfmt::skip]
     fn valid_data_row_complex_null_columns_interleaved() {
         let msg = Message::DataRow(vec![
-            Bytes::from_static(b"pg_catalog"),
-            Bytes::from_static(b"pg_user"),
-            Bytes::from_static(b"view"),
-            Bytes::from_static(b"root"),
-            Bytes::from_static(b"permanent"),
-            Bytes::from_static(b""),
-            Bytes::from_static(b"0 bytes"),
-            Bytes::from_static(b""),
+            Some(Bytes::from_static(b"pg_catalog")),
+            Some(Bytes::from_static(b"pg_user")),
+            Some(Bytes::from_static(b"view")),
+            Some(Bytes::from_static(b"root")),
+            Some(Bytes::from_static(b"permanent")),
+            None,
+            Some(Bytes::from_static(b"0 bytes")),
+            None,
+        ]);
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_data_row_null_and_empty_fields() {
+        let msg = Message::DataRow(vec![
+            Some(Bytes::from_static(b"")),
+            None,
         ]);
 
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_data_row_null_field_wire_format() {
+        let msg = Message::DataRow(vec![
+            Some(Bytes::from_static(b"")),
+            None,
+        ]);
+        let expected = [
+            68,                 // msg id: 'D'
+            0, 0, 0, 14,        // payload length: 14
+            0, 2,               // number of columns: 2
+            0, 0, 0, 0,         // c1: field length: 0 (empty string)
+            255, 255, 255, 255, // c2: NULL field, no value bytes
+        ];
+
+        let mut codec = Codec::new();
+        let buf = &mut BytesMut::new();
+        codec.encode(msg, buf).unwrap();
+        assert_eq!(&expected[..], &buf[..], "encoded bytes");
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_empty_query_response() {
@@ -139,6 +227,70 @@ mod encode_backend {
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_error_response() {
+        let msg = Message::error_response("42501", "permission denied");
+        let expected = Message::ErrorResponse(Bytes::from_static(
+            b"SERROR\0VERROR\0C42501\0Mpermission denied\0\0",
+        ));
+        assert_eq!(expected, msg);
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call_response() {
+        let msg = Message::FunctionCallResponse(Some(Bytes::from_static(b"42")));
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call_response_null() {
+        let msg = Message::FunctionCallResponse(None);
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_no_data() {
+        let msg = Message::NoData();
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_notice_response() {
+        let msg = Message::NoticeResponse(Bytes::from_static(b"SNOTICE\0\0"));
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_notification_response() {
+        let msg = Message::NotificationResponse {
+            process: 42,
+            channel: Bytes::from_static(b"chan"),
+            payload: Bytes::from_static(b"{}"),
+        };
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parameter_description() {
+        let msg = Message::ParameterDescription(vec![23, 25]);
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_parameter_status_app_name() {
@@ -150,6 +302,22 @@ mod encode_backend {
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parse_complete() {
+        let msg = Message::ParseComplete();
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_portal_suspended() {
+        let msg = Message::PortalSuspended();
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_ready_for_query() {
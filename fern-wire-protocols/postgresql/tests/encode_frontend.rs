@@ -9,7 +9,7 @@ mod encode_frontend {
     use test_log::test;
     use tokio_util::codec::{Decoder, Encoder};
 
-    use fern_protocol_postgresql::codec::frontend::{Codec, Message};
+    use fern_protocol_postgresql::codec::frontend::{BindParameter, Codec, Message};
 
     /// Helper function to ease writing encoding tests.
     fn assert_encode(msg: Message) {
@@ -21,6 +21,45 @@ mod encode_frontend {
         assert_eq!(Some(msg), codec.decode(buf).unwrap());
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_bind_mixed_parameters() {
+        let msg = Message::Bind {
+            portal: Bytes::from_static(b"cursor"),
+            stmt_name: Bytes::from_static(b"s1"),
+            parameters: vec![
+                BindParameter { format: 0, value: Some(Bytes::from_static(b"42")) },
+                BindParameter { format: 1, value: Some(Bytes::from_static(&[0, 0, 0, 42])) },
+                BindParameter { format: 0, value: None },
+            ],
+            results_formats: vec![0, 1],
+        };
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_close_portal() {
+        let msg = Message::Close {
+            kind: b'P',
+            name: Bytes::from_static(b"cursor"),
+        };
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_describe_statement() {
+        let msg = Message::Describe {
+            kind: b'S',
+            name: Bytes::from_static(b"s1"),
+        };
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_execute_no_limit() {
@@ -51,6 +90,33 @@ mod encode_frontend {
         assert_encode(msg);
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn valid_function_call() {
+        let msg = Message::FunctionCall {
+            function_oid: 1598,
+            arguments: vec![
+                BindParameter { format: 1, value: Some(Bytes::from_static(&[0, 0, 0, 42])) },
+                BindParameter { format: 0, value: None },
+            ],
+            result_format: 1,
+        };
+
+        assert_encode(msg);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn valid_parse_unnamed_statement() {
+        let msg = Message::Parse {
+            stmt_name: Bytes::from_static(b""),
+            query: Bytes::from_static(b"SELECT $1, $2"),
+            parameters_types: vec![0, 25],
+        };
+
+        assert_encode(msg);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn valid_query_simple() {